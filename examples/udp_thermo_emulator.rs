@@ -0,0 +1,96 @@
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize)]
+pub struct SmartThermometer {
+    temperature: f32,
+}
+
+impl Default for SmartThermometer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SmartThermometer {
+    pub fn new() -> Self {
+        Self { temperature: 20.0 }
+    }
+
+    pub fn update(&mut self) {
+        // random walk around the initial temperature
+        self.temperature += rand::random::<f32>() - 0.5;
+    }
+
+    pub fn get_temperature(&self) -> f32 {
+        self.temperature
+    }
+
+    pub fn get_status(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+// thermometer server periodically pushes its status
+// to the receiver address the backend listens on
+//
+pub struct SmartThermometerServer {
+    pub device: SmartThermometer,
+    pub socket: UdpSocket,
+    pub receiver: SocketAddr,
+    pub period: Duration,
+}
+
+impl SmartThermometerServer {
+    pub fn new(socket: UdpSocket, receiver: SocketAddr, period: Duration) -> Self {
+        Self {
+            device: SmartThermometer::new(),
+            socket,
+            receiver,
+            period,
+        }
+    }
+
+    pub fn broadcast(&mut self) {
+        println!(
+            "[SmartThermometer] sending from {} to {}",
+            &self.socket.local_addr().expect("Couldnt get local addr"),
+            &self.receiver
+        );
+        loop {
+            self.device.update();
+            let status = self.device.get_status();
+            match self.socket.send_to(status.as_bytes(), self.receiver) {
+                Ok(_) => println!("[SmartThermometer] {}: {}", self.receiver, status),
+                Err(e) => eprintln!("fail: {}", e),
+            }
+            thread::sleep(self.period);
+        }
+    }
+}
+
+/// Simple UDP thermometer device emulator
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// IP:PORT to send from
+    #[clap(short, long, value_parser, default_value = "127.0.0.1:0")]
+    address: String,
+    /// IP:PORT the thermometer is registered with in the backend
+    #[clap(short, long, value_parser, default_value = "127.0.0.1:9001")]
+    receiver: SocketAddr,
+    /// milliseconds between two measurements
+    #[clap(short, long, value_parser, default_value = "1000")]
+    period: u64,
+}
+
+fn main() {
+    let args = Args::parse();
+    let socket = UdpSocket::bind(args.address).expect("Could not bind to given address");
+    let mut thermo =
+        SmartThermometerServer::new(socket, args.receiver, Duration::from_millis(args.period));
+    thermo.broadcast();
+}
@@ -0,0 +1,45 @@
+use crate::api::auth::AuthConfig;
+use crate::domain::service::api_key;
+use crate::repository::api_key::ApiKeyStore;
+use crate::repository::user::UserStore;
+use actix_web::{web, HttpResponse};
+
+pub async fn add_key<R: ApiKeyStore + UserStore>(
+    req: web::Json<api_key::KeyRequest>,
+    repo: web::Data<R>,
+    auth: web::Data<AuthConfig>,
+) -> HttpResponse {
+    match api_key::add_key(
+        repo.into_inner(),
+        auth.admin_key.as_deref(),
+        req.into_inner(),
+    ) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn fetch_keys<R: ApiKeyStore>(repo: web::Data<R>) -> HttpResponse {
+    match api_key::fetch_keys(repo.into_inner()) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn delete_key<R: ApiKeyStore>(id: web::Path<String>, repo: web::Data<R>) -> HttpResponse {
+    match api_key::delete_key(repo.into_inner(), id.into_inner()) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(err) => error_response(err),
+    }
+}
+
+fn error_response(err: api_key::KeyError) -> HttpResponse {
+    match err {
+        api_key::KeyError::BadRequest => HttpResponse::BadRequest()
+            .body("the key needs a name and a scope, either read, control or admin"),
+        api_key::KeyError::NotFound => HttpResponse::NotFound().body("key not found"),
+        api_key::KeyError::NotAdmin => HttpResponse::Conflict()
+            .body("the first key has to be an admin key, or nobody could manage the house"),
+        api_key::KeyError::Unknown => HttpResponse::InternalServerError().finish(),
+    }
+}
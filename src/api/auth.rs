@@ -0,0 +1,186 @@
+use crate::domain::entity::Scope;
+use crate::domain::service::access::{self, AuthError};
+use crate::domain::service::user::Sessions;
+use crate::repository::api_key::ApiKeyStore;
+use crate::repository::user::UserStore;
+use actix_web::body::{EitherBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpMessage, HttpResponse};
+
+/// The admin key from the settings and the signer of the user sessions,
+/// handed to the middleware and the login route in the app data.
+pub struct AuthConfig {
+    pub admin_key: Option<String>,
+    pub sessions: Sessions,
+}
+
+/// The scope a request needs, `None` for the healthcheck and the login.
+/// Reading takes `read`, commands to the sockets `control` and everything
+/// else, like managing the keys, the users and the webhooks, `admin`.
+pub fn required_scope(method: &Method, path: &str) -> Option<Scope> {
+    // matched by whole segments, a room may well be called "command"
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        (_, [""]) | (&Method::POST, ["login"]) => None,
+        // the webhooks post every event, whatever rooms a key or user may see
+        (_, ["keys", ..]) | (_, ["users", ..]) | (_, ["webhooks", ..]) => Some(Scope::Admin),
+        (&Method::GET, _) | (&Method::HEAD, _) => Some(Scope::Read),
+        (&Method::POST, ["device", _, _, "command"])
+        | (&Method::POST, ["groups", _, "command"])
+        | (&Method::POST, ["scenes", _, "activate"]) => Some(Scope::Control),
+        _ => Some(Scope::Admin),
+    }
+}
+
+/// Lets the request through if its `Authorization: Bearer` key or session
+/// token has the scope the route needs. The principal is left in the
+/// request extensions for the services to check the rooms and devices.
+pub async fn authorize<R: ApiKeyStore + UserStore, B: MessageBody>(
+    req: ServiceRequest,
+    next: Next<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    // the router matches the percent-decoded path, so must the scope, or
+    // `/%6Beys` would reach the keys with the scope of any other route
+    let required = match required_scope(req.method(), req.match_info().as_str()) {
+        Some(scope) => scope,
+        None => {
+            return next
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        }
+    };
+    let (repo, auth) = match (
+        req.app_data::<web::Data<R>>(),
+        req.app_data::<web::Data<AuthConfig>>(),
+    ) {
+        (Some(repo), Some(auth)) => (repo.clone(), auth.clone()),
+        _ => {
+            let response = HttpResponse::InternalServerError().finish();
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    };
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    let response = match access::authenticate(
+        repo.as_ref(),
+        auth.admin_key.as_deref(),
+        &auth.sessions,
+        token,
+    ) {
+        Ok(principal) if principal.scope().is_some_and(|scope| scope >= required) => {
+            req.extensions_mut().insert(principal);
+            return next
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body);
+        }
+        Ok(_) => HttpResponse::Forbidden().body(format!(
+            "the API key or user lacks the {} scope",
+            String::from(required)
+        )),
+        Err(AuthError::Missing) | Err(AuthError::Invalid) => HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .body("missing or unknown API key or session"),
+        Err(AuthError::Unknown) => HttpResponse::InternalServerError().finish(),
+    };
+    Ok(req.into_response(response).map_into_right_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::entity::{ApiKey, ApiKeyId};
+    use crate::domain::service::api_key::hash_token;
+    use crate::repository::room::InMemoryRepository;
+    use actix_web::{middleware, test, App};
+
+    #[actix_web::test]
+    async fn read_keys_may_not_see_the_webhooks() {
+        let repo = web::Data::new(InMemoryRepository::new());
+        repo.add_key(ApiKey {
+            id: ApiKeyId::generate(),
+            name: "dashboard".to_string(),
+            scope: Scope::Read,
+            token_hash: hash_token("read token"),
+        })
+        .ok();
+        let auth = web::Data::new(AuthConfig {
+            admin_key: None,
+            sessions: Sessions::new(None),
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(repo)
+                .app_data(auth)
+                .wrap(middleware::from_fn(authorize::<InMemoryRepository, _>))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for (path, status) in [
+            ("/rooms", 200),
+            ("/webhooks", 403),
+            ("/webhooks/id/deliveries", 403),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(path)
+                .insert_header((header::AUTHORIZATION, "Bearer read token"))
+                .to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), status);
+        }
+    }
+
+    #[actix_web::test]
+    async fn percent_encoded_paths_need_the_scope_of_their_route() {
+        let repo = web::Data::new(InMemoryRepository::new());
+        repo.add_key(ApiKey {
+            id: ApiKeyId::generate(),
+            name: "dashboard".to_string(),
+            scope: Scope::Read,
+            token_hash: hash_token("read token"),
+        })
+        .ok();
+        let auth = web::Data::new(AuthConfig {
+            admin_key: None,
+            sessions: Sessions::new(None),
+        });
+        let app = test::init_service(
+            App::new()
+                .app_data(repo)
+                .app_data(auth)
+                .wrap(middleware::from_fn(authorize::<InMemoryRepository, _>))
+                .route("/rooms", web::get().to(HttpResponse::Ok))
+                .route("/keys", web::get().to(HttpResponse::Ok))
+                .route("/users", web::get().to(HttpResponse::Ok))
+                .route("/webhooks", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for (path, status) in [
+            ("/%72ooms", 200),
+            ("/%6Beys", 403),
+            ("/%6beys", 403),
+            ("/%75sers", 403),
+            ("/%77ebhooks", 403),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(path)
+                .insert_header((header::AUTHORIZATION, "Bearer read token"))
+                .to_request();
+            assert_eq!(
+                test::call_service(&app, req).await.status(),
+                status,
+                "{}",
+                path
+            );
+        }
+    }
+}
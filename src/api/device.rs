@@ -1,6 +1,7 @@
 use actix_web::{web, HttpResponse};
 use serde::{Deserialize, Serialize};
 
+use crate::domain::service::access::Principal;
 use crate::domain::service::device;
 use crate::repository::room::Repository;
 
@@ -11,21 +12,33 @@ pub struct AddDeviceRequest {
     pub device_type: String,
 }
 
+#[derive(Deserialize)]
+pub struct UpdateDeviceRequest {
+    pub room_name: Option<String>,
+    pub device_name: Option<String>,
+    pub address: Option<String>,
+    pub device_type: Option<String>,
+}
+
 #[derive(Serialize)]
 pub struct AddDeviceResponse {
+    pub id: String,
     pub room_name: String,
     pub device_name: String,
     pub address: String,
     pub device_type: String,
+    pub groups: Vec<String>,
 }
 
 impl From<device::Response> for AddDeviceResponse {
     fn from(inner: device::Response) -> Self {
         Self {
+            id: inner.id,
             room_name: inner.room_name,
             device_name: inner.device_name,
             address: inner.address,
             device_type: inner.device_type,
+            groups: inner.groups,
         }
     }
 }
@@ -56,6 +69,7 @@ pub async fn add_device<R: Repository>(
 pub async fn fetch_device<R: Repository>(
     param: web::Path<(String, String)>,
     repo: web::Data<R>,
+    principal: web::ReqData<Principal>,
 ) -> HttpResponse {
     let (room_name, device_name) = param.into_inner();
     let service_req = device::FetchRequest {
@@ -63,12 +77,35 @@ pub async fn fetch_device<R: Repository>(
         device_name,
     };
 
-    match device::fetch_device(repo.into_inner(), service_req) {
+    match device::fetch_device(repo.into_inner(), service_req, &principal) {
         Ok(res) => HttpResponse::Ok().json(web::Json(AddDeviceResponse::from(res))),
         Err(device::Error::BadRequest) => HttpResponse::BadRequest().body("Wrong device format"),
         Err(device::Error::NotFound) => {
             HttpResponse::NotFound().body("requested device or room were not found")
         }
+        Err(device::Error::Forbidden) => {
+            HttpResponse::Forbidden().body("the device may not be read")
+        }
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn fetch_device_by_id<R: Repository>(
+    id: web::Path<String>,
+    repo: web::Data<R>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    let service_req = device::FetchByIdRequest {
+        id: id.into_inner(),
+    };
+
+    match device::fetch_device_by_id(repo.into_inner(), service_req, &principal) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(AddDeviceResponse::from(res))),
+        Err(device::Error::BadRequest) => HttpResponse::BadRequest().body("Wrong device id format"),
+        Err(device::Error::NotFound) => HttpResponse::NotFound().body("device not found"),
+        Err(device::Error::Forbidden) => {
+            HttpResponse::Forbidden().body("the device may not be read")
+        }
         _ => HttpResponse::InternalServerError().finish(),
     }
 }
@@ -91,3 +128,32 @@ pub async fn delete_device<R: Repository>(
         _ => HttpResponse::InternalServerError().finish(),
     }
 }
+
+pub async fn update_device<R: Repository>(
+    param: web::Path<(String, String)>,
+    req: web::Json<UpdateDeviceRequest>,
+    repo: web::Data<R>,
+) -> HttpResponse {
+    let (room_name, device_name) = param.into_inner();
+    let req = req.into_inner();
+    let service_req = device::UpdateRequest {
+        room_name,
+        device_name,
+        new_room_name: req.room_name,
+        new_device_name: req.device_name,
+        address: req.address,
+        device_type: req.device_type,
+    };
+
+    match device::update_device(repo.into_inner(), service_req) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(AddDeviceResponse::from(res))),
+        Err(device::Error::BadRequest) => HttpResponse::BadRequest().body("Wrong device format"),
+        Err(device::Error::NotFound) => {
+            HttpResponse::NotFound().body("requested device or room were not found")
+        }
+        Err(device::Error::Conflict) => HttpResponse::Conflict().body(
+            "target room doesn't exist or device with this name or IP address already exists",
+        ),
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
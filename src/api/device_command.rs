@@ -0,0 +1,91 @@
+use crate::domain::cache::StatusCache;
+use crate::domain::client::DeviceClient;
+use crate::domain::service::access::Principal;
+use crate::domain::service::device_command;
+use crate::repository::history::HistoryStore;
+use crate::repository::room::Repository;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct CommandBody {
+    pub command: String,
+}
+
+pub async fn send_device_command<R: Repository + HistoryStore>(
+    param: web::Path<(String, String)>,
+    body: web::Json<CommandBody>,
+    repo: web::Data<R>,
+    client: web::Data<DeviceClient>,
+    cache: web::Data<StatusCache>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    let (room_id, device_id) = param.into_inner();
+    let service_req = device_command::CommandRequest {
+        room_id,
+        device_id,
+        command: body.into_inner().command,
+    };
+
+    match device_command::send_device_command(
+        service_req,
+        repo.into_inner(),
+        &client,
+        &cache,
+        &principal,
+    )
+    .await
+    {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(device_command::CommandError::BadRequest) => {
+            HttpResponse::BadRequest().body("command should be either \"on\" or \"off\"")
+        }
+        Err(device_command::CommandError::NotFound) => {
+            HttpResponse::NotFound().body("requested device or room were not found")
+        }
+        Err(device_command::CommandError::NotSupported) => {
+            HttpResponse::BadRequest().body("device does not accept commands")
+        }
+        Err(device_command::CommandError::DeviceUnavailable(e)) => {
+            HttpResponse::BadGateway().body(e)
+        }
+        Err(device_command::CommandError::Forbidden) => {
+            HttpResponse::Forbidden().body("the device may not be controlled")
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn send_group_command<R: Repository + HistoryStore>(
+    group: web::Path<String>,
+    body: web::Json<CommandBody>,
+    repo: web::Data<R>,
+    client: web::Data<DeviceClient>,
+    cache: web::Data<StatusCache>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    match device_command::send_group_command(
+        group.into_inner(),
+        body.into_inner().command,
+        repo.into_inner(),
+        &client,
+        &cache,
+        &principal,
+    )
+    .await
+    {
+        Ok(res) if res.applied => HttpResponse::Ok().json(web::Json(res)),
+        // some member did not take the command, the body tells which
+        Ok(res) => HttpResponse::BadGateway().json(web::Json(res)),
+        Err(device_command::CommandError::BadRequest) => {
+            HttpResponse::BadRequest().body("command should be either \"on\" or \"off\"")
+        }
+        Err(device_command::CommandError::NotFound) => {
+            HttpResponse::NotFound().body("group has no members")
+        }
+        Err(device_command::CommandError::Forbidden) => {
+            HttpResponse::Forbidden().body("some member of the group may not be controlled")
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -1,30 +1,106 @@
+use crate::domain::cache::StatusCache;
+use crate::domain::client::DeviceClient;
+use crate::domain::service::access::Principal;
 use crate::domain::service::device_query;
+use crate::repository::history::HistoryStore;
 use crate::repository::room::Repository;
 use actix_web::{web, HttpResponse};
+use serde::Deserialize;
 
-pub async fn get_device_status<R: Repository>(
+#[derive(Deserialize)]
+pub struct StatusQuery {
+    /// query the devices instead of serving the last polled reading
+    #[serde(default)]
+    pub fresh: bool,
+}
+
+fn source<'a>(
+    client: &'a DeviceClient,
+    cache: &'a StatusCache,
+    query: &StatusQuery,
+) -> device_query::StatusSource<'a> {
+    device_query::StatusSource {
+        client,
+        cache,
+        fresh: query.fresh,
+    }
+}
+
+pub async fn get_device_status<R: Repository + HistoryStore>(
     param: web::Path<(String, String)>,
+    query: web::Query<StatusQuery>,
     repo: web::Data<R>,
+    client: web::Data<DeviceClient>,
+    cache: web::Data<StatusCache>,
+    principal: web::ReqData<Principal>,
 ) -> HttpResponse {
     let (room_id, device_id) = param.into_inner();
     let service_req = device_query::StatusRequest { room_id, device_id };
+    let source = source(&client, &cache, &query);
 
-    match device_query::get_device_status(service_req, repo.into_inner()) {
+    match device_query::get_device_status(service_req, repo.into_inner(), source, &principal).await
+    {
         Ok(res) => HttpResponse::Ok().json(web::Json(res)),
         Err(device_query::StatusError::BadRequest) => HttpResponse::BadRequest().finish(),
         Err(device_query::StatusError::NotFound) => HttpResponse::NotFound().finish(),
+        Err(device_query::StatusError::Forbidden) => HttpResponse::Forbidden().finish(),
         Err(_) => HttpResponse::InternalServerError().finish(),
     }
 }
 
-pub async fn get_room_status<R: Repository>(
+pub async fn get_room_status<R: Repository + HistoryStore>(
     room_id: web::Path<String>,
+    query: web::Query<StatusQuery>,
+    repo: web::Data<R>,
+    client: web::Data<DeviceClient>,
+    cache: web::Data<StatusCache>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    let source = source(&client, &cache, &query);
+
+    match device_query::get_room_status(room_id.into_inner(), repo.into_inner(), source, &principal)
+        .await
+    {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(device_query::StatusError::BadRequest) => HttpResponse::BadRequest().finish(),
+        Err(device_query::StatusError::NotFound) => HttpResponse::NotFound().finish(),
+        Err(device_query::StatusError::Forbidden) => HttpResponse::Forbidden().finish(),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn get_house_status<R: Repository + HistoryStore>(
+    query: web::Query<StatusQuery>,
+    repo: web::Data<R>,
+    client: web::Data<DeviceClient>,
+    cache: web::Data<StatusCache>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    let source = source(&client, &cache, &query);
+
+    match device_query::get_house_status(repo.into_inner(), source, &principal).await {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn get_group_status<R: Repository + HistoryStore>(
+    group: web::Path<String>,
+    query: web::Query<StatusQuery>,
     repo: web::Data<R>,
+    client: web::Data<DeviceClient>,
+    cache: web::Data<StatusCache>,
+    principal: web::ReqData<Principal>,
 ) -> HttpResponse {
-    match device_query::get_room_status(room_id.into_inner(), repo.into_inner()) {
+    let source = source(&client, &cache, &query);
+
+    match device_query::get_group_status(group.into_inner(), repo.into_inner(), source, &principal)
+        .await
+    {
         Ok(res) => HttpResponse::Ok().json(web::Json(res)),
         Err(device_query::StatusError::BadRequest) => HttpResponse::BadRequest().finish(),
         Err(device_query::StatusError::NotFound) => HttpResponse::NotFound().finish(),
+        Err(device_query::StatusError::Forbidden) => HttpResponse::Forbidden().finish(),
         Err(_) => HttpResponse::InternalServerError().finish(),
     }
 }
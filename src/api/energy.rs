@@ -0,0 +1,90 @@
+use crate::domain::service::access::Principal;
+use crate::domain::service::energy;
+use crate::repository::history::HistoryStore;
+use crate::repository::room::Repository;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// The poll interval from the settings, how long a reading is trusted
+/// for depends on it.
+pub struct PollInterval(pub Duration);
+
+/// `bucket` is `hour` or `day`, the bounds are milliseconds since the unix epoch.
+#[derive(Deserialize)]
+pub struct EnergyQuery {
+    pub bucket: Option<String>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+impl EnergyQuery {
+    fn request(self, room_id: Option<String>, device_id: Option<String>) -> energy::EnergyRequest {
+        energy::EnergyRequest {
+            room_id,
+            device_id,
+            bucket: self.bucket,
+            from: self.from,
+            to: self.to,
+        }
+    }
+}
+
+pub async fn get_device_energy<R: Repository + HistoryStore>(
+    param: web::Path<(String, String)>,
+    query: web::Query<EnergyQuery>,
+    repo: web::Data<R>,
+    poll_interval: web::Data<PollInterval>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    let (room_id, device_id) = param.into_inner();
+    let service_req = query.into_inner().request(Some(room_id), Some(device_id));
+
+    match energy::get_device_energy(repo.into_inner(), service_req, poll_interval.0, &principal) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn get_room_energy<R: Repository + HistoryStore>(
+    param: web::Path<String>,
+    query: web::Query<EnergyQuery>,
+    repo: web::Data<R>,
+    poll_interval: web::Data<PollInterval>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    let service_req = query.into_inner().request(Some(param.into_inner()), None);
+
+    match energy::get_room_energy(repo.into_inner(), service_req, poll_interval.0, &principal) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn get_house_energy<R: Repository + HistoryStore>(
+    query: web::Query<EnergyQuery>,
+    repo: web::Data<R>,
+    poll_interval: web::Data<PollInterval>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    let service_req = query.into_inner().request(None, None);
+
+    match energy::get_house_energy(repo.into_inner(), service_req, poll_interval.0, &principal) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+fn error_response(err: energy::EnergyError) -> HttpResponse {
+    match err {
+        energy::EnergyError::BadRequest => HttpResponse::BadRequest()
+            .body("Wrong room or device format, unknown bucket or invalid range"),
+        energy::EnergyError::NotFound => {
+            HttpResponse::NotFound().body("requested device or room were not found")
+        }
+        energy::EnergyError::Forbidden => {
+            HttpResponse::Forbidden().body("the device or room may not be read")
+        }
+        energy::EnergyError::Unknown => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -0,0 +1,77 @@
+use crate::domain::events::{self, Event, EventFilter};
+use crate::domain::service::access::{self, Principal};
+use crate::repository::room::Repository;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpResponse};
+use futures::stream;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{self, MissedTickBehavior};
+
+// a comment line now and then keeps proxies from closing an idle stream
+// and lets the server notice clients that went away
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    pub room_id: Option<String>,
+    pub device_id: Option<String>,
+}
+
+/// Streams the events as Server-Sent Events, `event:` is the event type
+/// and `data:` the event as JSON. A client too slow to keep up gets a
+/// `lagged` event telling how many it missed. Users only get the events
+/// of the devices they may read.
+pub async fn stream_events<R: Repository>(
+    query: web::Query<EventsQuery>,
+    repo: web::Data<R>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    let query = query.into_inner();
+    let filter = EventFilter {
+        room_id: query.room_id,
+        device_id: query.device_id,
+    };
+    let mut keep_alive = time::interval_at(time::Instant::now() + KEEP_ALIVE, KEEP_ALIVE);
+    keep_alive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let (repo, principal) = (repo.into_inner(), principal.into_inner());
+    let visible = move |event: &Event| {
+        filter.matches(event) && access::may_see(repo.as_ref(), &principal, event)
+    };
+
+    let state = (events::subscribe(), keep_alive, visible);
+    let body = stream::unfold(
+        state,
+        |(mut receiver, mut keep_alive, visible)| async move {
+            loop {
+                let message = tokio::select! {
+                    received = receiver.recv() => match received {
+                        Ok(event) if visible(&event) => format_event(&event),
+                        Ok(_) => continue,
+                        Err(RecvError::Lagged(missed)) => {
+                            format!("event: lagged\ndata: {{\"missed\":{}}}\n\n", missed)
+                        }
+                        Err(RecvError::Closed) => return None,
+                    },
+                    _ = keep_alive.tick() => ":\n\n".to_string(),
+                };
+                let chunk: Result<Bytes, Infallible> = Ok(Bytes::from(message));
+                return Some((chunk, (receiver, keep_alive, visible)));
+            }
+        },
+    );
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body)
+}
+
+fn format_event(event: &Event) -> String {
+    // the events are plain data, serializing them can't fail
+    let data = serde_json::to_string(event).unwrap_or_default();
+    format!("event: {}\ndata: {}\n\n", event.kind(), data)
+}
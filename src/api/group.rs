@@ -0,0 +1,65 @@
+use crate::api::device::AddDeviceResponse;
+use crate::domain::service::access::Principal;
+use crate::domain::service::group;
+use crate::repository::room::Repository;
+use actix_web::{web, HttpResponse};
+
+pub async fn fetch_groups<R: Repository>(
+    repo: web::Data<R>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    match group::fetch_groups(repo.into_inner(), &principal) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn fetch_group<R: Repository>(
+    name: web::Path<String>,
+    repo: web::Data<R>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    match group::fetch_group(repo.into_inner(), name.into_inner(), &principal) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn add_member<R: Repository>(
+    param: web::Path<(String, String)>,
+    repo: web::Data<R>,
+) -> HttpResponse {
+    let (name, id) = param.into_inner();
+
+    match group::add_member(repo.into_inner(), name, id) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(AddDeviceResponse::from(res))),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn remove_member<R: Repository>(
+    param: web::Path<(String, String)>,
+    repo: web::Data<R>,
+) -> HttpResponse {
+    let (name, id) = param.into_inner();
+
+    match group::remove_member(repo.into_inner(), name, id) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(AddDeviceResponse::from(res))),
+        Err(err) => error_response(err),
+    }
+}
+
+fn error_response(err: group::GroupError) -> HttpResponse {
+    match err {
+        group::GroupError::BadRequest => {
+            HttpResponse::BadRequest().body("Wrong group name or device id format")
+        }
+        group::GroupError::NotFound => {
+            HttpResponse::NotFound().body("group or group member not found")
+        }
+        group::GroupError::Conflict => {
+            HttpResponse::Conflict().body("device was changed meanwhile, try again")
+        }
+        group::GroupError::Unknown => HttpResponse::InternalServerError().finish(),
+    }
+}
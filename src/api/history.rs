@@ -0,0 +1,43 @@
+use crate::domain::service::access::Principal;
+use crate::domain::service::history;
+use crate::repository::history::HistoryStore;
+use crate::repository::room::Repository;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+
+/// Bounds of the requested range in milliseconds since the unix epoch, both inclusive.
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+pub async fn fetch_history<R: Repository + HistoryStore>(
+    param: web::Path<(String, String)>,
+    query: web::Query<HistoryQuery>,
+    repo: web::Data<R>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    let (room_id, device_id) = param.into_inner();
+    let query = query.into_inner();
+    let service_req = history::HistoryRequest {
+        room_id,
+        device_id,
+        from: query.from,
+        to: query.to,
+    };
+
+    match history::fetch_history(repo.into_inner(), service_req, &principal) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(history::HistoryError::BadRequest) => {
+            HttpResponse::BadRequest().body("Wrong device format or from is after to")
+        }
+        Err(history::HistoryError::NotFound) => {
+            HttpResponse::NotFound().body("requested device or room were not found")
+        }
+        Err(history::HistoryError::Forbidden) => {
+            HttpResponse::Forbidden().body("the device may not be read")
+        }
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -1,31 +1,73 @@
+use crate::domain::cache::StatusCache;
+use crate::domain::client::DeviceClient;
+use crate::repository::api_key::ApiKeyStore;
+use crate::repository::history::HistoryStore;
 use crate::repository::room::Repository;
+use crate::repository::rule::RuleStore;
+use crate::repository::schedule::ScheduleStore;
+use crate::repository::user::UserStore;
+use crate::repository::webhook::WebhookStore;
 use actix_web::dev::Server;
-use actix_web::middleware::Logger;
+use actix_web::middleware::{self, Logger};
 use actix_web::{web, App, HttpResponse, HttpServer};
-use env_logger::Env;
 use std::net::TcpListener;
 use std::sync::Arc;
+use std::time::Duration;
 
+pub mod api_key;
+pub mod auth;
 pub mod device;
+pub mod device_command;
 pub mod device_query;
+pub mod energy;
+pub mod events;
+pub mod group;
+pub mod history;
 pub mod room;
+pub mod rule;
+pub mod scene;
+pub mod schedule;
+pub mod user;
+pub mod webhook;
+pub mod ws;
 
 async fn healthcheck() -> HttpResponse {
     HttpResponse::Ok().finish()
 }
 
-pub fn spawn<R: Repository>(listener: TcpListener, repo: Arc<R>) -> Result<Server, std::io::Error> {
+pub fn spawn<
+    R: Repository + HistoryStore + RuleStore + ScheduleStore + WebhookStore + ApiKeyStore + UserStore,
+>(
+    listener: TcpListener,
+    repo: Arc<R>,
+    client: DeviceClient,
+    cache: StatusCache,
+    workers: Option<usize>,
+    poll_interval: Duration,
+    auth: auth::AuthConfig,
+) -> Result<Server, std::io::Error> {
     let app_data = web::Data::from(repo);
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let client_data = web::Data::new(client);
+    let cache_data = web::Data::new(cache);
+    let poll_interval_data = web::Data::new(energy::PollInterval(poll_interval));
+    let auth_data = web::Data::new(auth);
 
-    let server = HttpServer::new(move || {
+    let mut server = HttpServer::new(move || {
         App::new()
+            .wrap(middleware::from_fn(auth::authorize::<R, _>))
             .wrap(Logger::default())
             .app_data(app_data.clone())
+            .app_data(client_data.clone())
+            .app_data(cache_data.clone())
+            .app_data(poll_interval_data.clone())
+            .app_data(auth_data.clone())
             .route("/", web::get().to(healthcheck))
+            .route("/events", web::get().to(events::stream_events::<R>))
+            .route("/ws", web::get().to(ws::connect::<R>))
             .route("/room/{room_id}", web::post().to(room::add_room::<R>))
             .route("/room/{room_id}", web::get().to(room::fetch_room::<R>))
             .route("/room/{room_id}", web::delete().to(room::delete_room::<R>))
+            .route("/room/{room_id}", web::patch().to(room::rename_room::<R>))
             .route("/room", web::get().to(room::fetch_rooms::<R>))
             .route("/device/{room_id}", web::post().to(device::add_device::<R>))
             .route(
@@ -36,6 +78,23 @@ pub fn spawn<R: Repository>(listener: TcpListener, repo: Arc<R>) -> Result<Serve
                 "/device/{room_id}/{device_id}",
                 web::delete().to(device::delete_device::<R>),
             )
+            .route(
+                "/device/{room_id}/{device_id}",
+                web::patch().to(device::update_device::<R>),
+            )
+            .route(
+                "/devices/{id}",
+                web::get().to(device::fetch_device_by_id::<R>),
+            )
+            .route(
+                "/device/{room_id}/{device_id}/command",
+                web::post().to(device_command::send_device_command::<R>),
+            )
+            // before the device route, which would take "group" for a room
+            .route(
+                "/status/group/{group}",
+                web::get().to(device_query::get_group_status::<R>),
+            )
             .route(
                 "/status/{room_id}/{device_id}",
                 web::get().to(device_query::get_device_status::<R>),
@@ -44,8 +103,89 @@ pub fn spawn<R: Repository>(listener: TcpListener, repo: Arc<R>) -> Result<Serve
                 "/status/{room_id}",
                 web::get().to(device_query::get_room_status::<R>),
             )
-    })
-    .listen(listener)?
-    .run();
-    Ok(server)
+            .route(
+                "/status",
+                web::get().to(device_query::get_house_status::<R>),
+            )
+            .route(
+                "/energy/{room_id}/{device_id}",
+                web::get().to(energy::get_device_energy::<R>),
+            )
+            .route(
+                "/energy/{room_id}",
+                web::get().to(energy::get_room_energy::<R>),
+            )
+            .route("/energy", web::get().to(energy::get_house_energy::<R>))
+            .route(
+                "/history/{room_id}/{device_id}",
+                web::get().to(history::fetch_history::<R>),
+            )
+            .route("/groups", web::get().to(group::fetch_groups::<R>))
+            .route("/groups/{group}", web::get().to(group::fetch_group::<R>))
+            .route(
+                "/groups/{group}/devices/{id}",
+                web::put().to(group::add_member::<R>),
+            )
+            .route(
+                "/groups/{group}/devices/{id}",
+                web::delete().to(group::remove_member::<R>),
+            )
+            .route(
+                "/groups/{group}/command",
+                web::post().to(device_command::send_group_command::<R>),
+            )
+            .route("/scenes", web::post().to(scene::add_scene::<R>))
+            .route("/scenes", web::get().to(scene::fetch_scenes::<R>))
+            .route("/scenes/{name}", web::get().to(scene::fetch_scene::<R>))
+            .route("/scenes/{name}", web::put().to(scene::update_scene::<R>))
+            .route("/scenes/{name}", web::delete().to(scene::delete_scene::<R>))
+            .route(
+                "/scenes/{name}/activate",
+                web::post().to(scene::activate_scene::<R>),
+            )
+            .route("/rules", web::post().to(rule::add_rule::<R>))
+            .route("/rules", web::get().to(rule::fetch_rules::<R>))
+            .route("/rules/{id}", web::get().to(rule::fetch_rule::<R>))
+            .route("/rules/{id}", web::put().to(rule::update_rule::<R>))
+            .route("/rules/{id}", web::delete().to(rule::delete_rule::<R>))
+            .route("/schedules", web::post().to(schedule::add_schedule::<R>))
+            .route("/schedules", web::get().to(schedule::fetch_schedules::<R>))
+            .route(
+                "/schedules/{id}",
+                web::get().to(schedule::fetch_schedule::<R>),
+            )
+            .route(
+                "/schedules/{id}",
+                web::delete().to(schedule::delete_schedule::<R>),
+            )
+            .route(
+                "/schedules/{id}/runs",
+                web::get().to(schedule::fetch_runs::<R>),
+            )
+            .route("/keys", web::post().to(api_key::add_key::<R>))
+            .route("/keys", web::get().to(api_key::fetch_keys::<R>))
+            .route("/keys/{id}", web::delete().to(api_key::delete_key::<R>))
+            .route("/login", web::post().to(user::login::<R>))
+            .route("/users", web::post().to(user::add_user::<R>))
+            .route("/users", web::get().to(user::fetch_users::<R>))
+            .route("/users/{id}", web::get().to(user::fetch_user::<R>))
+            .route("/users/{id}", web::patch().to(user::update_user::<R>))
+            .route("/users/{id}", web::delete().to(user::delete_user::<R>))
+            .route("/users/{id}/grants", web::put().to(user::set_grants::<R>))
+            .route("/webhooks", web::post().to(webhook::add_webhook::<R>))
+            .route("/webhooks", web::get().to(webhook::fetch_webhooks::<R>))
+            .route("/webhooks/{id}", web::get().to(webhook::fetch_webhook::<R>))
+            .route(
+                "/webhooks/{id}",
+                web::delete().to(webhook::delete_webhook::<R>),
+            )
+            .route(
+                "/webhooks/{id}/deliveries",
+                web::get().to(webhook::fetch_deliveries::<R>),
+            )
+    });
+    if let Some(workers) = workers {
+        server = server.workers(workers);
+    }
+    Ok(server.listen(listener)?.run())
 }
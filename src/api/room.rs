@@ -2,6 +2,7 @@ use actix_web::{web, HttpResponse};
 use serde::{Deserialize, Serialize};
 
 use crate::domain::entity;
+use crate::domain::service::access::Principal;
 use crate::domain::service::room;
 use crate::repository::room::Repository;
 
@@ -10,19 +11,28 @@ pub struct RoomRequest {
     pub name: String,
 }
 
+#[derive(Deserialize)]
+pub struct DeleteRoomQuery {
+    #[serde(default)]
+    pub cascade: bool,
+}
+
 #[derive(Serialize)]
 pub struct AddRoomResponse {
+    pub id: String,
     pub name: String,
 }
 
 #[derive(Serialize)]
 pub struct FetchRoomResponse {
+    pub id: String,
     pub name: String,
     pub devices: Vec<RoomDeviceResponse>,
 }
 
 #[derive(Serialize)]
 pub struct RoomDeviceResponse {
+    id: String,
     name: String,
     address: String,
     device_type: String,
@@ -36,18 +46,23 @@ impl From<RoomRequest> for room::RoomRequest {
 
 impl From<room::RoomResponse> for AddRoomResponse {
     fn from(inner: room::RoomResponse) -> Self {
-        Self { name: inner.name }
+        Self {
+            id: inner.id,
+            name: inner.name,
+        }
     }
 }
 
 impl From<room::RoomResponse> for FetchRoomResponse {
     fn from(inner: room::RoomResponse) -> Self {
         Self {
+            id: inner.id,
             name: inner.name,
             devices: inner
                 .devices
                 .into_iter()
                 .map(|res| RoomDeviceResponse {
+                    id: res.id,
                     name: res.name,
                     address: res.address,
                     device_type: res.device_type,
@@ -83,6 +98,7 @@ pub async fn add_room<R: Repository>(
 pub async fn fetch_room<R: Repository>(
     room_id: web::Path<String>,
     repo: web::Data<R>,
+    principal: web::ReqData<Principal>,
 ) -> HttpResponse {
     let service_req = match entity::RoomName::try_from(room_id.into_inner()) {
         Ok(name) => room::RoomRequest {
@@ -93,16 +109,22 @@ pub async fn fetch_room<R: Repository>(
         }
     };
 
-    match room::fetch_room(repo.into_inner(), service_req) {
+    match room::fetch_room(repo.into_inner(), service_req, &principal) {
         Ok(res) => HttpResponse::Ok().json(web::Json(FetchRoomResponse::from(res))),
         Err(room::Error::BadRequest) => HttpResponse::BadRequest().body("Wrong room format"),
         Err(room::Error::NotFound) => HttpResponse::NotFound().body("room not found"),
+        Err(room::Error::Forbidden) => {
+            HttpResponse::Forbidden().body("no device of the room may be read")
+        }
         _ => HttpResponse::InternalServerError().finish(),
     }
 }
 
-pub async fn fetch_rooms<R: Repository>(repo: web::Data<R>) -> HttpResponse {
-    match room::fetch_rooms(repo.into_inner()) {
+pub async fn fetch_rooms<R: Repository>(
+    repo: web::Data<R>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    match room::fetch_rooms(repo.into_inner(), &principal) {
         Ok(res) => HttpResponse::Ok().json(web::Json::<Vec<FetchRoomResponse>>(
             res.into_iter().map(FetchRoomResponse::from).collect(),
         )),
@@ -114,11 +136,13 @@ pub async fn fetch_rooms<R: Repository>(repo: web::Data<R>) -> HttpResponse {
 
 pub async fn delete_room<R: Repository>(
     room_id: web::Path<String>,
+    query: web::Query<DeleteRoomQuery>,
     repo: web::Data<R>,
 ) -> HttpResponse {
     let service_req = match entity::RoomName::try_from(room_id.into_inner()) {
-        Ok(name) => room::RoomRequest {
+        Ok(name) => room::DeleteRequest {
             name: String::from(name),
+            cascade: query.cascade,
         },
         Err(_) => {
             return HttpResponse::BadRequest().body("wrong format for room name");
@@ -129,6 +153,29 @@ pub async fn delete_room<R: Repository>(
         Ok(_) => HttpResponse::Ok().finish(),
         Err(room::Error::BadRequest) => HttpResponse::BadRequest().body("Wrong room format"),
         Err(room::Error::NotFound) => HttpResponse::NotFound().body("room not found"),
+        Err(room::Error::NotEmpty) => HttpResponse::Conflict()
+            .body("room still has devices, delete them first or pass ?cascade=true"),
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn rename_room<R: Repository>(
+    room_id: web::Path<String>,
+    req: web::Json<RoomRequest>,
+    repo: web::Data<R>,
+) -> HttpResponse {
+    let service_req = room::RenameRequest {
+        name: room_id.into_inner(),
+        new_name: req.into_inner().name,
+    };
+
+    match room::rename_room(repo.into_inner(), service_req) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(FetchRoomResponse::from(res))),
+        Err(room::Error::BadRequest) => HttpResponse::BadRequest().body("Wrong room format"),
+        Err(room::Error::NotFound) => HttpResponse::NotFound().body("room not found"),
+        Err(room::Error::Conflict) => {
+            HttpResponse::Conflict().body("room with this name already exists")
+        }
         _ => HttpResponse::InternalServerError().finish(),
     }
 }
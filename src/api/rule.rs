@@ -0,0 +1,69 @@
+use crate::domain::service::access::Principal;
+use crate::domain::service::rule;
+use crate::repository::room::Repository;
+use crate::repository::rule::RuleStore;
+use actix_web::{web, HttpResponse};
+
+pub async fn add_rule<R: Repository + RuleStore>(
+    req: web::Json<rule::RuleRequest>,
+    repo: web::Data<R>,
+) -> HttpResponse {
+    match rule::add_rule(repo.into_inner(), req.into_inner()) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn fetch_rules<R: Repository + RuleStore>(
+    repo: web::Data<R>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    match rule::fetch_rules(repo.into_inner(), &principal) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn fetch_rule<R: Repository + RuleStore>(
+    id: web::Path<String>,
+    repo: web::Data<R>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    match rule::fetch_rule(repo.into_inner(), id.into_inner(), &principal) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn update_rule<R: Repository + RuleStore>(
+    id: web::Path<String>,
+    req: web::Json<rule::RuleRequest>,
+    repo: web::Data<R>,
+) -> HttpResponse {
+    match rule::update_rule(repo.into_inner(), id.into_inner(), req.into_inner()) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn delete_rule<R: RuleStore>(id: web::Path<String>, repo: web::Data<R>) -> HttpResponse {
+    match rule::delete_rule(repo.into_inner(), id.into_inner()) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(err) => error_response(err),
+    }
+}
+
+fn error_response(err: rule::RuleError) -> HttpResponse {
+    match err {
+        rule::RuleError::BadRequest => HttpResponse::BadRequest()
+            .body("Wrong rule format or the rule refers to a missing or unsuitable device"),
+        rule::RuleError::NotFound => HttpResponse::NotFound().body("rule not found"),
+        rule::RuleError::Conflict => {
+            HttpResponse::Conflict().body("rule with this name already exists")
+        }
+        rule::RuleError::Forbidden => {
+            HttpResponse::Forbidden().body("some device of the rule may not be read")
+        }
+        rule::RuleError::Unknown => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -0,0 +1,115 @@
+use crate::domain::cache::StatusCache;
+use crate::domain::client::DeviceClient;
+use crate::domain::service::access::Principal;
+use crate::domain::service::scene;
+use crate::repository::history::HistoryStore;
+use crate::repository::room::Repository;
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct StepsBody {
+    pub steps: Vec<scene::StepRequest>,
+}
+
+#[derive(Deserialize)]
+pub struct ActivateQuery {
+    #[serde(default)]
+    pub rollback: bool,
+}
+
+pub async fn add_scene<R: Repository>(
+    req: web::Json<scene::SceneRequest>,
+    repo: web::Data<R>,
+) -> HttpResponse {
+    match scene::add_scene(repo.into_inner(), req.into_inner()) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn fetch_scenes<R: Repository>(
+    repo: web::Data<R>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    match scene::fetch_scenes(repo.into_inner(), &principal) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn fetch_scene<R: Repository>(
+    name: web::Path<String>,
+    repo: web::Data<R>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    match scene::fetch_scene(repo.into_inner(), name.into_inner(), &principal) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn update_scene<R: Repository>(
+    name: web::Path<String>,
+    body: web::Json<StepsBody>,
+    repo: web::Data<R>,
+) -> HttpResponse {
+    match scene::update_scene(
+        repo.into_inner(),
+        name.into_inner(),
+        body.into_inner().steps,
+    ) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn delete_scene<R: Repository>(
+    name: web::Path<String>,
+    repo: web::Data<R>,
+) -> HttpResponse {
+    match scene::delete_scene(repo.into_inner(), name.into_inner()) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn activate_scene<R: Repository + HistoryStore>(
+    name: web::Path<String>,
+    query: web::Query<ActivateQuery>,
+    repo: web::Data<R>,
+    client: web::Data<DeviceClient>,
+    cache: web::Data<StatusCache>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    match scene::activate_scene(
+        repo.into_inner(),
+        &client,
+        &cache,
+        name.into_inner(),
+        query.rollback,
+        &principal,
+    )
+    .await
+    {
+        Ok(res) if res.applied => HttpResponse::Ok().json(web::Json(res)),
+        // some device did not take its command, the body tells which
+        Ok(res) => HttpResponse::BadGateway().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+fn error_response(err: scene::SceneError) -> HttpResponse {
+    match err {
+        scene::SceneError::BadRequest => HttpResponse::BadRequest()
+            .body("Wrong scene format or a step refers to a missing or unsuitable device"),
+        scene::SceneError::NotFound => HttpResponse::NotFound().body("scene not found"),
+        scene::SceneError::Conflict => {
+            HttpResponse::Conflict().body("scene with this name already exists")
+        }
+        scene::SceneError::Forbidden => {
+            HttpResponse::Forbidden().body("some device of the scene may not be used")
+        }
+        scene::SceneError::Unknown => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -0,0 +1,69 @@
+use crate::domain::service::access::Principal;
+use crate::domain::service::schedule;
+use crate::repository::room::Repository;
+use crate::repository::schedule::ScheduleStore;
+use actix_web::{web, HttpResponse};
+
+pub async fn add_schedule<R: Repository + ScheduleStore>(
+    req: web::Json<schedule::ScheduleRequest>,
+    repo: web::Data<R>,
+) -> HttpResponse {
+    match schedule::add_schedule(repo.into_inner(), req.into_inner()) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn fetch_schedules<R: Repository + ScheduleStore>(
+    repo: web::Data<R>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    match schedule::fetch_schedules(repo.into_inner(), &principal) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn fetch_schedule<R: Repository + ScheduleStore>(
+    id: web::Path<String>,
+    repo: web::Data<R>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    match schedule::fetch_schedule(repo.into_inner(), id.into_inner(), &principal) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn delete_schedule<R: ScheduleStore>(
+    id: web::Path<String>,
+    repo: web::Data<R>,
+) -> HttpResponse {
+    match schedule::delete_schedule(repo.into_inner(), id.into_inner()) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn fetch_runs<R: Repository + ScheduleStore>(
+    id: web::Path<String>,
+    repo: web::Data<R>,
+    principal: web::ReqData<Principal>,
+) -> HttpResponse {
+    match schedule::fetch_runs(repo.into_inner(), id.into_inner(), &principal) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+fn error_response(err: schedule::ScheduleError) -> HttpResponse {
+    match err {
+        schedule::ScheduleError::BadRequest => HttpResponse::BadRequest()
+            .body("Wrong schedule format or the target is not a registered socket"),
+        schedule::ScheduleError::NotFound => HttpResponse::NotFound().body("schedule not found"),
+        schedule::ScheduleError::Forbidden => {
+            HttpResponse::Forbidden().body("the device of the schedule may not be read")
+        }
+        schedule::ScheduleError::Unknown => HttpResponse::InternalServerError().finish(),
+    }
+}
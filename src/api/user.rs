@@ -0,0 +1,118 @@
+use crate::api::auth::AuthConfig;
+use crate::domain::service::access::AuthError;
+use crate::domain::service::user;
+use crate::repository::api_key::ApiKeyStore;
+use crate::repository::room::Repository;
+use crate::repository::user::UserStore;
+use actix_web::{web, HttpResponse};
+
+pub async fn login<R: UserStore>(
+    req: web::Json<user::LoginRequest>,
+    repo: web::Data<R>,
+    auth: web::Data<AuthConfig>,
+) -> HttpResponse {
+    // hashing the password takes a while, so it stays off the workers
+    let repo = repo.into_inner();
+    let result = web::block(move || user::login(repo, &auth.sessions, req.into_inner())).await;
+    match result {
+        Ok(Ok(res)) => HttpResponse::Ok().json(web::Json(res)),
+        Ok(Err(AuthError::Unknown)) | Err(_) => HttpResponse::InternalServerError().finish(),
+        Ok(Err(_)) => HttpResponse::Unauthorized().body("wrong user name or password"),
+    }
+}
+
+pub async fn add_user<R: ApiKeyStore + UserStore>(
+    req: web::Json<user::UserRequest>,
+    repo: web::Data<R>,
+    auth: web::Data<AuthConfig>,
+) -> HttpResponse {
+    let repo = repo.into_inner();
+    let result =
+        web::block(move || user::add_user(repo, auth.admin_key.as_deref(), req.into_inner())).await;
+    match result {
+        Ok(Ok(res)) => HttpResponse::Ok().json(web::Json(res)),
+        Ok(Err(err)) => error_response(err),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn fetch_users<R: UserStore>(repo: web::Data<R>) -> HttpResponse {
+    match user::fetch_users(repo.into_inner()) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn fetch_user<R: UserStore>(id: web::Path<String>, repo: web::Data<R>) -> HttpResponse {
+    match user::fetch_user(repo.into_inner(), id.into_inner()) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn update_user<R: ApiKeyStore + UserStore>(
+    id: web::Path<String>,
+    req: web::Json<user::UpdateRequest>,
+    repo: web::Data<R>,
+    auth: web::Data<AuthConfig>,
+) -> HttpResponse {
+    let repo = repo.into_inner();
+    let result = web::block(move || {
+        user::update_user(
+            repo,
+            auth.admin_key.as_deref(),
+            id.into_inner(),
+            req.into_inner(),
+        )
+    })
+    .await;
+    match result {
+        Ok(Ok(res)) => HttpResponse::Ok().json(web::Json(res)),
+        Ok(Err(err)) => error_response(err),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+pub async fn set_grants<R: Repository + UserStore>(
+    id: web::Path<String>,
+    req: web::Json<Vec<user::GrantSpec>>,
+    repo: web::Data<R>,
+) -> HttpResponse {
+    match user::set_grants(repo.into_inner(), id.into_inner(), req.into_inner()) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn delete_user<R: ApiKeyStore + UserStore>(
+    id: web::Path<String>,
+    repo: web::Data<R>,
+    auth: web::Data<AuthConfig>,
+) -> HttpResponse {
+    match user::delete_user(
+        repo.into_inner(),
+        auth.admin_key.as_deref(),
+        id.into_inner(),
+    ) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(err) => error_response(err),
+    }
+}
+
+fn error_response(err: user::UserError) -> HttpResponse {
+    match err {
+        user::UserError::BadRequest => HttpResponse::BadRequest().body(
+            "Wrong user format, the password needs 8 characters and a grant \
+             one existing room or device and a role, either read or control",
+        ),
+        user::UserError::NotFound => HttpResponse::NotFound().body("user not found"),
+        user::UserError::Conflict => {
+            HttpResponse::Conflict().body("user with this name already exists")
+        }
+        user::UserError::NotAdmin => HttpResponse::Conflict()
+            .body("the first user has to be an admin, or nobody could manage the house"),
+        user::UserError::LastAdmin => HttpResponse::Conflict()
+            .body("the last admin has to stay an admin, or nobody could manage the house"),
+        user::UserError::Unknown => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -0,0 +1,62 @@
+use crate::domain::service::webhook;
+use crate::repository::room::Repository;
+use crate::repository::webhook::WebhookStore;
+use actix_web::{web, HttpResponse};
+
+pub async fn add_webhook<R: Repository + WebhookStore>(
+    req: web::Json<webhook::WebhookRequest>,
+    repo: web::Data<R>,
+) -> HttpResponse {
+    match webhook::add_webhook(repo.into_inner(), req.into_inner()) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn fetch_webhooks<R: WebhookStore>(repo: web::Data<R>) -> HttpResponse {
+    match webhook::fetch_webhooks(repo.into_inner()) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn fetch_webhook<R: WebhookStore>(
+    id: web::Path<String>,
+    repo: web::Data<R>,
+) -> HttpResponse {
+    match webhook::fetch_webhook(repo.into_inner(), id.into_inner()) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn delete_webhook<R: WebhookStore>(
+    id: web::Path<String>,
+    repo: web::Data<R>,
+) -> HttpResponse {
+    match webhook::delete_webhook(repo.into_inner(), id.into_inner()) {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(err) => error_response(err),
+    }
+}
+
+pub async fn fetch_deliveries<R: WebhookStore>(
+    id: web::Path<String>,
+    repo: web::Data<R>,
+) -> HttpResponse {
+    match webhook::fetch_deliveries(repo.into_inner(), id.into_inner()) {
+        Ok(res) => HttpResponse::Ok().json(web::Json(res)),
+        Err(err) => error_response(err),
+    }
+}
+
+fn error_response(err: webhook::WebhookError) -> HttpResponse {
+    match err {
+        webhook::WebhookError::BadRequest => HttpResponse::BadRequest().body(
+            "Wrong webhook format, the url should be http or https, the events known \
+             and the room and device ids existing",
+        ),
+        webhook::WebhookError::NotFound => HttpResponse::NotFound().body("webhook not found"),
+        webhook::WebhookError::Unknown => HttpResponse::InternalServerError().finish(),
+    }
+}
@@ -0,0 +1,344 @@
+use crate::domain::cache::StatusCache;
+use crate::domain::client::DeviceClient;
+use crate::domain::events::{self, Event, EventFilter};
+use crate::domain::service::access::{self, Principal};
+use crate::domain::service::{device_command, device_query};
+use crate::repository::history::HistoryStore;
+use crate::repository::room::Repository;
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_ws::{Message, Session};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{self, MissedTickBehavior};
+
+// pinged as often as the SSE stream sends its keep-alive, a client that
+// stays silent for two rounds is gone
+const HEARTBEAT: Duration = Duration::from_secs(15);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A message from the client, `request_id` is echoed in the reply.
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    request_id: Option<String>,
+    #[serde(flatten)]
+    message: ClientMessage,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// Pushes the events of the room or device from now on, no fields
+    /// subscribes to everything.
+    Subscribe {
+        room_id: Option<String>,
+        device_id: Option<String>,
+    },
+    /// Drops the subscription made with the same fields.
+    Unsubscribe {
+        room_id: Option<String>,
+        device_id: Option<String>,
+    },
+    Command {
+        room_id: String,
+        device_id: String,
+        command: String,
+    },
+    Status {
+        room_id: String,
+        device_id: String,
+        #[serde(default)]
+        fresh: bool,
+    },
+}
+
+// only the id of a message that could not be parsed, to still tell
+// the client which one was wrong
+#[derive(Deserialize)]
+struct RequestId {
+    request_id: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage<'a> {
+    /// Either `result` or `error` is set, like the body of the REST route.
+    Reply {
+        request_id: Option<String>,
+        ok: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        result: Option<serde_json::Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Event {
+        event: &'a Event,
+    },
+    Lagged {
+        missed: u64,
+    },
+}
+
+impl ServerMessage<'_> {
+    fn ok(request_id: Option<String>, result: impl Serialize) -> Self {
+        Self::Reply {
+            request_id,
+            ok: true,
+            result: serde_json::to_value(result).ok(),
+            error: None,
+        }
+    }
+
+    fn error(request_id: Option<String>, error: impl Into<String>) -> Self {
+        Self::Reply {
+            request_id,
+            ok: false,
+            result: None,
+            error: Some(error.into()),
+        }
+    }
+
+    fn to_text(&self) -> String {
+        // the messages are plain data, serializing them can't fail
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Upgrades the connection to a WebSocket speaking JSON text messages.
+/// Clients subscribe to rooms and devices to get their events pushed, and
+/// send commands and status queries that are answered like the REST routes.
+pub async fn connect<R: Repository + HistoryStore>(
+    req: HttpRequest,
+    body: web::Payload,
+    repo: web::Data<R>,
+    client: web::Data<DeviceClient>,
+    cache: web::Data<StatusCache>,
+    principal: web::ReqData<Principal>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (response, session, stream) = actix_ws::handle(&req, body)?;
+    let context = Context {
+        repo: repo.into_inner(),
+        client: client.get_ref().clone(),
+        cache: cache.get_ref().clone(),
+        principal: principal.into_inner(),
+    };
+    actix_web::rt::spawn(run_session(session, stream, context));
+    Ok(response)
+}
+
+struct Context<R> {
+    repo: Arc<R>,
+    client: DeviceClient,
+    cache: StatusCache,
+    /// who opened the socket, events, commands and queries are checked
+    /// against it like the REST routes
+    principal: Principal,
+}
+
+impl<R> Clone for Context<R> {
+    fn clone(&self) -> Self {
+        Self {
+            repo: self.repo.clone(),
+            client: self.client.clone(),
+            cache: self.cache.clone(),
+            principal: self.principal.clone(),
+        }
+    }
+}
+
+async fn run_session<R: Repository + HistoryStore>(
+    mut session: Session,
+    mut stream: actix_ws::MessageStream,
+    context: Context<R>,
+) {
+    let mut receiver = events::subscribe();
+    let mut subscriptions: Vec<EventFilter> = Vec::new();
+    let mut heartbeat = time::interval_at(time::Instant::now() + HEARTBEAT, HEARTBEAT);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_heard = Instant::now();
+
+    loop {
+        let sent = tokio::select! {
+            message = stream.recv() => {
+                last_heard = Instant::now();
+                match message {
+                    Some(Ok(Message::Text(text))) => {
+                        handle_text(&session, &mut subscriptions, &context, &text).await
+                    }
+                    Some(Ok(Message::Ping(bytes))) => session.pong(&bytes).await,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => Ok(()),
+                }
+            }
+            received = receiver.recv() => match received {
+                Ok(event)
+                    if subscriptions.iter().any(|s| s.matches(&event))
+                        && access::may_see(context.repo.as_ref(), &context.principal, &event) =>
+                {
+                    session.text(ServerMessage::Event { event: &event }.to_text()).await
+                }
+                Ok(_) => Ok(()),
+                Err(RecvError::Lagged(missed)) if !subscriptions.is_empty() => {
+                    session.text(ServerMessage::Lagged { missed }.to_text()).await
+                }
+                Err(RecvError::Lagged(_)) => Ok(()),
+                Err(RecvError::Closed) => break,
+            },
+            _ = heartbeat.tick() => {
+                if last_heard.elapsed() > CLIENT_TIMEOUT {
+                    break;
+                }
+                session.ping(b"").await
+            }
+        };
+        if sent.is_err() {
+            // the client went away
+            return;
+        }
+    }
+    session.close(None).await.ok();
+}
+
+// subscriptions are answered right away, commands and queries wait on the
+// devices so they run on their own and the events keep flowing meanwhile
+async fn handle_text<R: Repository + HistoryStore>(
+    session: &Session,
+    subscriptions: &mut Vec<EventFilter>,
+    context: &Context<R>,
+    text: &str,
+) -> Result<(), actix_ws::Closed> {
+    let Request {
+        request_id,
+        message,
+    } = match serde_json::from_str(text) {
+        Ok(request) => request,
+        Err(e) => {
+            let request_id = serde_json::from_str::<RequestId>(text)
+                .ok()
+                .and_then(|r| r.request_id);
+            let reply = ServerMessage::error(request_id, format!("invalid message: {}", e));
+            return session.clone().text(reply.to_text()).await;
+        }
+    };
+
+    let reply = match message {
+        ClientMessage::Subscribe { room_id, device_id } => {
+            let filter = EventFilter { room_id, device_id };
+            if !subscriptions.contains(&filter) {
+                subscriptions.push(filter);
+            }
+            ServerMessage::ok(request_id, ())
+        }
+        ClientMessage::Unsubscribe { room_id, device_id } => {
+            let filter = EventFilter { room_id, device_id };
+            let before = subscriptions.len();
+            subscriptions.retain(|s| *s != filter);
+            if subscriptions.len() == before {
+                ServerMessage::error(request_id, "not subscribed")
+            } else {
+                ServerMessage::ok(request_id, ())
+            }
+        }
+        ClientMessage::Command {
+            room_id,
+            device_id,
+            command,
+        } => {
+            let request = device_command::CommandRequest {
+                room_id,
+                device_id,
+                command,
+            };
+            let mut session = session.clone();
+            let context = context.clone();
+            actix_web::rt::spawn(async move {
+                let reply = send_command(request_id, request, context).await;
+                session.text(reply.to_text()).await.ok();
+            });
+            return Ok(());
+        }
+        ClientMessage::Status {
+            room_id,
+            device_id,
+            fresh,
+        } => {
+            let request = device_query::StatusRequest { room_id, device_id };
+            let mut session = session.clone();
+            let context = context.clone();
+            actix_web::rt::spawn(async move {
+                let reply = get_status(request_id, request, fresh, context).await;
+                session.text(reply.to_text()).await.ok();
+            });
+            return Ok(());
+        }
+    };
+    session.clone().text(reply.to_text()).await
+}
+
+async fn send_command<R: Repository + HistoryStore>(
+    request_id: Option<String>,
+    request: device_command::CommandRequest,
+    context: Context<R>,
+) -> ServerMessage<'static> {
+    match device_command::send_device_command(
+        request,
+        context.repo,
+        &context.client,
+        &context.cache,
+        &context.principal,
+    )
+    .await
+    {
+        Ok(res) => ServerMessage::ok(request_id, res),
+        Err(device_command::CommandError::BadRequest) => {
+            ServerMessage::error(request_id, "command should be either \"on\" or \"off\"")
+        }
+        Err(device_command::CommandError::NotFound) => {
+            ServerMessage::error(request_id, "requested device or room were not found")
+        }
+        Err(device_command::CommandError::NotSupported) => {
+            ServerMessage::error(request_id, "device does not accept commands")
+        }
+        Err(device_command::CommandError::DeviceUnavailable(e)) => {
+            ServerMessage::error(request_id, e)
+        }
+        Err(device_command::CommandError::Forbidden) => {
+            ServerMessage::error(request_id, "the device may not be controlled")
+        }
+        Err(device_command::CommandError::Unknown) => {
+            ServerMessage::error(request_id, "internal error")
+        }
+    }
+}
+
+async fn get_status<R: Repository + HistoryStore>(
+    request_id: Option<String>,
+    request: device_query::StatusRequest,
+    fresh: bool,
+    context: Context<R>,
+) -> ServerMessage<'static> {
+    let source = device_query::StatusSource {
+        client: &context.client,
+        cache: &context.cache,
+        fresh,
+    };
+    match device_query::get_device_status(request, context.repo.clone(), source, &context.principal)
+        .await
+    {
+        Ok(res) => ServerMessage::ok(request_id, res),
+        Err(device_query::StatusError::BadRequest) => {
+            ServerMessage::error(request_id, "invalid room or device name")
+        }
+        Err(device_query::StatusError::NotFound) => {
+            ServerMessage::error(request_id, "requested device or room were not found")
+        }
+        Err(device_query::StatusError::Forbidden) => {
+            ServerMessage::error(request_id, "the device may not be read")
+        }
+        Err(device_query::StatusError::Unknown) => {
+            ServerMessage::error(request_id, "internal error")
+        }
+    }
+}
@@ -0,0 +1,507 @@
+use crate::domain::service::user::SESSION_PREFIX;
+use clap::{Parser, ValueEnum};
+use serde::Deserialize;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+// the admin key is typed in by hand, but should still not be guessable
+const MIN_ADMIN_KEY_LEN: usize = 16;
+const MIN_SESSION_SECRET_LEN: usize = 32;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("could not read config file {0}: {1}")]
+    ReadError(PathBuf, String),
+    #[error("invalid config file {0}: {1}")]
+    FormatError(PathBuf, String),
+    #[error("invalid value for {0}: {1}")]
+    InvalidValue(&'static str, String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Backend {
+    Memory,
+    File,
+    Sqlite,
+}
+
+/// Smart home HTTP backend
+///
+/// Every option can also be given in the config file or as an environment variable,
+/// command line flags win over the environment which wins over the config file.
+#[derive(Parser, Debug, Default)]
+#[clap(author, version, about, long_about = None)]
+pub struct Args {
+    /// path to a TOML config file
+    #[clap(short, long, value_parser, env = "SMART_HOME_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// IP:PORT to serve the HTTP API on
+    #[clap(short, long, value_parser, env = "SMART_HOME_BIND")]
+    pub bind: Option<String>,
+    /// where the house layout is stored
+    #[clap(short, long, value_enum, env = "SMART_HOME_REPOSITORY")]
+    pub repository: Option<Backend>,
+    /// file the repository is stored in, ignored by the memory backend
+    #[clap(long, value_parser, env = "SMART_HOME_REPOSITORY_PATH")]
+    pub repository_path: Option<PathBuf>,
+    /// number of HTTP worker threads, defaults to the number of CPUs
+    #[clap(short, long, value_parser, env = "SMART_HOME_WORKERS")]
+    pub workers: Option<usize>,
+    /// milliseconds to wait for a device connection
+    #[clap(long, value_parser, env = "SMART_HOME_CONNECT_TIMEOUT_MS")]
+    pub connect_timeout_ms: Option<u64>,
+    /// milliseconds to wait for a device reply
+    #[clap(long, value_parser, env = "SMART_HOME_READ_TIMEOUT_MS")]
+    pub read_timeout_ms: Option<u64>,
+    /// milliseconds between two background polls of every device
+    #[clap(long, value_parser, env = "SMART_HOME_POLL_INTERVAL_MS")]
+    pub poll_interval_ms: Option<u64>,
+    /// hours the device history is kept for
+    #[clap(long, value_parser, env = "SMART_HOME_HISTORY_RETENTION_HOURS")]
+    pub history_retention_hours: Option<u64>,
+    /// log filter, e.g. `info` or `actix_web=debug`
+    #[clap(short, long, value_parser, env = "RUST_LOG")]
+    pub log_level: Option<String>,
+    /// API key with the admin scope that is not stored, to create the first keys with
+    #[clap(long, value_parser, env = "SMART_HOME_ADMIN_KEY")]
+    pub admin_key: Option<String>,
+    /// secret the user session tokens are signed with, without one the
+    /// sessions end when the server restarts
+    #[clap(long, value_parser, env = "SMART_HOME_SESSION_SECRET")]
+    pub session_secret: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    bind: Option<String>,
+    workers: Option<usize>,
+    log_level: Option<String>,
+    repository: FileRepositoryConfig,
+    client: FileClientConfig,
+    history: FileHistoryConfig,
+    auth: FileAuthConfig,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileRepositoryConfig {
+    backend: Option<Backend>,
+    path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileClientConfig {
+    connect_timeout_ms: Option<u64>,
+    read_timeout_ms: Option<u64>,
+    poll_interval_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileHistoryConfig {
+    retention_hours: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileAuthConfig {
+    admin_key: Option<String>,
+    session_secret: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Settings {
+    pub bind: SocketAddr,
+    pub backend: Backend,
+    pub repository_path: PathBuf,
+    pub workers: Option<usize>,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub poll_interval: Duration,
+    pub history_retention: Duration,
+    pub log_level: String,
+    pub admin_key: Option<String>,
+    pub session_secret: Option<String>,
+}
+
+impl Settings {
+    /// Resolves the settings from the command line, environment and config file.
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::from_args(Args::parse())
+    }
+
+    pub fn from_args(args: Args) -> Result<Self, ConfigError> {
+        let file = match &args.config {
+            Some(path) => {
+                let content = fs::read_to_string(path)
+                    .map_err(|e| ConfigError::ReadError(path.clone(), e.to_string()))?;
+                toml::from_str::<FileConfig>(&content)
+                    .map_err(|e| ConfigError::FormatError(path.clone(), e.to_string()))?
+            }
+            None => FileConfig::default(),
+        };
+
+        let bind = args
+            .bind
+            .or(file.bind)
+            .unwrap_or_else(|| "127.0.0.1:8888".to_string());
+        let bind = SocketAddr::from_str(&bind)
+            .map_err(|_| ConfigError::InvalidValue("bind", format!("{} is not IP:PORT", bind)))?;
+
+        let backend = args
+            .repository
+            .or(file.repository.backend)
+            .unwrap_or(Backend::File);
+        let repository_path = args
+            .repository_path
+            .or(file.repository.path)
+            .unwrap_or_else(|| match backend {
+                Backend::Sqlite => PathBuf::from("smart_home.db"),
+                _ => PathBuf::from("smart_home.json"),
+            });
+
+        let workers = args.workers.or(file.workers);
+        if workers == Some(0) {
+            return Err(ConfigError::InvalidValue(
+                "workers",
+                "at least one worker is required".into(),
+            ));
+        }
+
+        let connect_timeout = positive_millis(
+            "connect_timeout_ms",
+            args.connect_timeout_ms
+                .or(file.client.connect_timeout_ms)
+                .unwrap_or(1000),
+        )?;
+        let read_timeout = positive_millis(
+            "read_timeout_ms",
+            args.read_timeout_ms
+                .or(file.client.read_timeout_ms)
+                .unwrap_or(2000),
+        )?;
+
+        let poll_interval = positive_millis(
+            "poll_interval_ms",
+            args.poll_interval_ms
+                .or(file.client.poll_interval_ms)
+                .unwrap_or(10_000),
+        )?;
+
+        let retention_hours = args
+            .history_retention_hours
+            .or(file.history.retention_hours)
+            .unwrap_or(24 * 7);
+        if retention_hours == 0 {
+            return Err(ConfigError::InvalidValue(
+                "history_retention_hours",
+                "retention must be positive".into(),
+            ));
+        }
+        let history_retention = retention_hours
+            .checked_mul(60 * 60)
+            .map(Duration::from_secs)
+            .ok_or_else(|| {
+                ConfigError::InvalidValue(
+                    "history_retention_hours",
+                    format!("{} hours is too long", retention_hours),
+                )
+            })?;
+
+        let log_level = args
+            .log_level
+            .or(file.log_level)
+            .unwrap_or_else(|| "info".to_string());
+        if log_level.trim().is_empty() {
+            return Err(ConfigError::InvalidValue(
+                "log_level",
+                "log filter is empty".into(),
+            ));
+        }
+        check_log_filter(&log_level)?;
+
+        let admin_key = args.admin_key.or(file.auth.admin_key);
+        if admin_key
+            .as_ref()
+            .is_some_and(|key| key.len() < MIN_ADMIN_KEY_LEN)
+        {
+            return Err(ConfigError::InvalidValue(
+                "admin_key",
+                format!("should be at least {} characters", MIN_ADMIN_KEY_LEN),
+            ));
+        }
+        if admin_key
+            .as_ref()
+            .is_some_and(|key| key.starts_with(SESSION_PREFIX))
+        {
+            return Err(ConfigError::InvalidValue(
+                "admin_key",
+                format!(
+                    "may not start with {:?}, as session tokens do",
+                    SESSION_PREFIX
+                ),
+            ));
+        }
+        let session_secret = args.session_secret.or(file.auth.session_secret);
+        if session_secret
+            .as_ref()
+            .is_some_and(|secret| secret.len() < MIN_SESSION_SECRET_LEN)
+        {
+            return Err(ConfigError::InvalidValue(
+                "session_secret",
+                format!("should be at least {} characters", MIN_SESSION_SECRET_LEN),
+            ));
+        }
+
+        Ok(Self {
+            bind,
+            backend,
+            repository_path,
+            workers,
+            connect_timeout,
+            read_timeout,
+            poll_interval,
+            history_retention,
+            log_level,
+            admin_key,
+            session_secret,
+        })
+    }
+}
+
+fn positive_millis(name: &'static str, millis: u64) -> Result<Duration, ConfigError> {
+    if millis == 0 {
+        return Err(ConfigError::InvalidValue(
+            name,
+            "value must be positive".into(),
+        ));
+    }
+    Ok(Duration::from_millis(millis))
+}
+
+// env_logger drops a directive it cannot parse without complaint, and
+// takes a bare word for a module, so a mistyped level would go unnoticed;
+// every directive has to be a level or `module=level`, optionally
+// followed by one `/regex` for the whole filter
+fn check_log_filter(filter: &str) -> Result<(), ConfigError> {
+    const LEVELS: [&str; 6] = ["off", "error", "warn", "info", "debug", "trace"];
+    let is_level = |level: &str| LEVELS.iter().any(|l| l.eq_ignore_ascii_case(level));
+    let is_module = |module: &str| {
+        !module.is_empty()
+            && module.split("::").all(|part| {
+                !part.is_empty() && part.chars().all(|c| c.is_alphanumeric() || c == '_')
+            })
+    };
+
+    let directives = filter
+        .split_once('/')
+        .map_or(filter, |(directives, _)| directives);
+    for directive in directives
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+    {
+        let valid = match directive.split_once('=') {
+            Some((module, level)) => is_module(module.trim()) && is_level(level.trim()),
+            None => is_level(directive),
+        };
+        if !valid {
+            return Err(ConfigError::InvalidValue(
+                "log_level",
+                format!("{} is not a level or module=level", directive),
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(dir: &tempfile::TempDir, content: &str) -> PathBuf {
+        let path = dir.path().join("smart_home.toml");
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn from_args_uses_defaults_without_config() {
+        match Settings::from_args(Args::default()) {
+            Ok(settings) => assert_eq!(
+                settings,
+                Settings {
+                    bind: SocketAddr::from_str("127.0.0.1:8888").unwrap(),
+                    backend: Backend::File,
+                    repository_path: PathBuf::from("smart_home.json"),
+                    workers: None,
+                    connect_timeout: Duration::from_millis(1000),
+                    read_timeout: Duration::from_millis(2000),
+                    poll_interval: Duration::from_millis(10_000),
+                    history_retention: Duration::from_secs(7 * 24 * 60 * 60),
+                    log_level: "info".to_string(),
+                    admin_key: None,
+                    session_secret: None,
+                }
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn from_args_prefers_flags_over_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_config(
+            &dir,
+            r#"
+            bind = "0.0.0.0:9999"
+            workers = 2
+
+            [repository]
+            backend = "sqlite"
+
+            [client]
+            read_timeout_ms = 500
+
+            [history]
+            retention_hours = 48
+            "#,
+        );
+        let args = Args::try_parse_from([
+            "smart_home_backend",
+            "--config",
+            path.to_str().unwrap(),
+            "--workers",
+            "4",
+        ])
+        .unwrap();
+
+        match Settings::from_args(args) {
+            Ok(settings) => {
+                assert_eq!(settings.bind, SocketAddr::from_str("0.0.0.0:9999").unwrap());
+                assert_eq!(settings.backend, Backend::Sqlite);
+                assert_eq!(settings.repository_path, PathBuf::from("smart_home.db"));
+                assert_eq!(settings.workers, Some(4));
+                assert_eq!(settings.read_timeout, Duration::from_millis(500));
+                assert_eq!(
+                    settings.history_retention,
+                    Duration::from_secs(48 * 60 * 60)
+                );
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn from_args_rejects_unknown_config_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_config(&dir, "bnid = \"0.0.0.0:9999\"");
+        let args = Args {
+            config: Some(path),
+            ..Args::default()
+        };
+
+        match Settings::from_args(args) {
+            Err(ConfigError::FormatError(_, _)) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn from_args_accepts_levels_and_module_levels_as_log_filter() {
+        let args = Args {
+            log_level: Some("warn,smart_home_backend::api=DEBUG, actix_web=info/hook".to_string()),
+            ..Args::default()
+        };
+        match Settings::from_args(args) {
+            Ok(settings) => assert_eq!(
+                settings.log_level,
+                "warn,smart_home_backend::api=DEBUG, actix_web=info/hook"
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn from_args_rejects_invalid_values() {
+        let args = Args {
+            bind: Some("localhost".to_string()),
+            ..Args::default()
+        };
+        match Settings::from_args(args) {
+            Err(ConfigError::InvalidValue("bind", _)) => {}
+            _ => unreachable!(),
+        }
+
+        let args = Args {
+            workers: Some(0),
+            ..Args::default()
+        };
+        match Settings::from_args(args) {
+            Err(ConfigError::InvalidValue("workers", _)) => {}
+            _ => unreachable!(),
+        }
+
+        let args = Args {
+            connect_timeout_ms: Some(0),
+            ..Args::default()
+        };
+        match Settings::from_args(args) {
+            Err(ConfigError::InvalidValue("connect_timeout_ms", _)) => {}
+            _ => unreachable!(),
+        }
+
+        let args = Args {
+            admin_key: Some("secret".to_string()),
+            ..Args::default()
+        };
+        match Settings::from_args(args) {
+            Err(ConfigError::InvalidValue("admin_key", _)) => {}
+            _ => unreachable!(),
+        }
+
+        for log_level in ["debgu", "actix_web=verbose", "=debug", "info,::x=warn"] {
+            let args = Args {
+                log_level: Some(log_level.to_string()),
+                ..Args::default()
+            };
+            match Settings::from_args(args) {
+                Err(ConfigError::InvalidValue("log_level", _)) => {}
+                _ => unreachable!(),
+            }
+        }
+
+        let args = Args {
+            history_retention_hours: Some(u64::MAX),
+            ..Args::default()
+        };
+        match Settings::from_args(args) {
+            Err(ConfigError::InvalidValue("history_retention_hours", _)) => {}
+            _ => unreachable!(),
+        }
+
+        let args = Args {
+            admin_key: Some("session:0123456789abcdef".to_string()),
+            ..Args::default()
+        };
+        match Settings::from_args(args) {
+            Err(ConfigError::InvalidValue("admin_key", _)) => {}
+            _ => unreachable!(),
+        }
+
+        let args = Args {
+            session_secret: Some("too short to sign with".to_string()),
+            ..Args::default()
+        };
+        match Settings::from_args(args) {
+            Err(ConfigError::InvalidValue("session_secret", _)) => {}
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use crate::domain::client::ClientError;
+use crate::domain::entity::{DeviceId, DeviceStatus};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+/// Last known status of every device, shared by the poller that keeps it
+/// fresh and the status endpoints that serve it.
+#[derive(Clone, Debug, Default)]
+pub struct StatusCache {
+    readings: Arc<Mutex<HashMap<DeviceId, CachedReading>>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CachedReading {
+    pub result: Result<DeviceStatus, ClientError>,
+    /// milliseconds since the unix epoch
+    pub read_at: u64,
+}
+
+impl StatusCache {
+    pub fn get(&self, id: DeviceId) -> Option<CachedReading> {
+        match self.readings.lock() {
+            Ok(readings) => readings.get(&id).cloned(),
+            _ => None,
+        }
+    }
+
+    pub fn insert(&self, id: DeviceId, reading: CachedReading) {
+        if let Ok(mut readings) = self.readings.lock() {
+            readings.insert(id, reading);
+        }
+    }
+
+    /// Forgets the readings of devices that are no longer registered.
+    pub fn retain(&self, ids: &HashSet<DeviceId>) {
+        if let Ok(mut readings) = self.readings.lock() {
+            readings.retain(|id, _| ids.contains(id));
+        }
+    }
+}
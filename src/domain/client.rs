@@ -1,41 +1,614 @@
-use std::io::{BufRead, BufReader, Write};
-use std::net::{SocketAddr, TcpStream};
+use crate::domain::entity::{DeviceCommand, SocketStatus, ThermoStatus};
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
 use std::str;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
 
-#[derive(thiserror::Error, Debug)]
+const THERMO_POLL_INTERVAL: Duration = Duration::from_millis(200);
+// thermometers send every second or so, a reading five times that old
+// belongs to one that stopped sending
+const THERMO_STALE_AFTER: Duration = Duration::from_secs(5);
+const RECONNECT_BACKOFF: Duration = Duration::from_millis(200);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(10);
+
+#[derive(thiserror::Error, Debug, Clone)]
 pub enum ClientError {
     #[error("IoError: {0}")]
     IoError(String),
     #[error("ConnectionError: {0}")]
     ConnectionError(String),
+    #[error("TimeoutError: {0}")]
+    Timeout(String),
+    #[error("ParseError: {0}")]
+    ParseError(String),
+    #[error("NoDataError: {0}")]
+    NoData(String),
     #[error("UnknownError: {0}")]
     Unknown(String),
 }
 
-pub fn get_socket_status(address: SocketAddr) -> Result<String, ClientError> {
-    // connect, send tcp, disconnect
-    let mut stream =
-        TcpStream::connect(address).map_err(|e| ClientError::ConnectionError(e.to_string()))?;
+/// Talks to the devices without blocking the async runtime,
+/// every socket round trip is bounded by the configured timeouts.
+///
+/// Socket connections are kept open between requests and shared by all
+/// clients, commands to the same device are sent one at a time. A
+/// connection is kept until its address is [`forget`]ten.
+#[derive(Clone, Debug)]
+pub struct DeviceClient {
+    connect_timeout: Duration,
+    read_timeout: Duration,
+}
 
-    // write a command to get status
-    stream
-        .write_all("GET".as_bytes())
-        .map_err(|e| ClientError::IoError(e.to_string()))?;
+type SharedConnection = Arc<tokio::sync::Mutex<SocketConnection>>;
+
+// connection state of a single device, after a failed connect
+// no new attempt is made until the backoff has passed
+#[derive(Debug, Default)]
+struct SocketConnection {
+    stream: Option<BufReader<TcpStream>>,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl SocketConnection {
+    fn record_failure(&mut self) {
+        self.stream = None;
+        self.failures += 1;
+        let backoff = RECONNECT_BACKOFF
+            .saturating_mul(2u32.saturating_pow(self.failures - 1))
+            .min(MAX_RECONNECT_BACKOFF);
+        self.retry_at = Some(Instant::now() + backoff);
+    }
+
+    fn record_success(&mut self) {
+        self.failures = 0;
+        self.retry_at = None;
+    }
+}
+
+impl Default for DeviceClient {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(2))
+    }
+}
+
+impl DeviceClient {
+    pub fn new(connect_timeout: Duration, read_timeout: Duration) -> Self {
+        Self {
+            connect_timeout,
+            read_timeout,
+        }
+    }
+
+    pub async fn get_socket_status(
+        &self,
+        address: SocketAddr,
+    ) -> Result<SocketStatus, ClientError> {
+        let response = self.query_socket(address, "GET").await?;
+        parse_status(&response)
+    }
+
+    pub async fn send_socket_command(
+        &self,
+        address: SocketAddr,
+        command: DeviceCommand,
+    ) -> Result<SocketStatus, ClientError> {
+        let query = match command {
+            DeviceCommand::TurnOn => "SET1",
+            DeviceCommand::TurnOff => "SET0",
+        };
+        let response = self.query_socket(address, query).await?;
+        parse_status(&response)
+    }
+
+    pub async fn get_thermo_status(
+        &self,
+        address: SocketAddr,
+    ) -> Result<ThermoStatus, ClientError> {
+        // served from the listener cache, never waits on the network
+        let datagram = get_thermo_status(address)?;
+        parse_status(&datagram)
+    }
+
+    fn connection(&self, address: SocketAddr) -> Result<SharedConnection, ClientError> {
+        let mut connections = socket_connections()
+            .lock()
+            .map_err(|e| ClientError::Unknown(e.to_string()))?;
+        Ok(connections.entry(address).or_default().clone())
+    }
+
+    async fn query_socket(&self, address: SocketAddr, query: &str) -> Result<String, ClientError> {
+        let connection = self.connection(address)?;
+        // held for the whole round trip, so replies never interleave
+        let mut connection = connection.lock().await;
+
+        // a kept alive connection may have been closed by the device in the
+        // meantime, that gets one immediate retry on a fresh connection
+        if let Some(stream) = connection.stream.as_mut() {
+            match self.round_trip(stream, address, query).await {
+                Ok(response) => return Ok(response),
+                Err(ClientError::IoError(_)) => connection.stream = None,
+                Err(e) => {
+                    connection.record_failure();
+                    return Err(e);
+                }
+            }
+        }
+
+        if let Some(retry_at) = connection.retry_at {
+            let now = Instant::now();
+            if retry_at > now {
+                return Err(ClientError::ConnectionError(format!(
+                    "{} is unreachable, next attempt in {:?}",
+                    address,
+                    retry_at - now
+                )));
+            }
+        }
+
+        let mut stream = match self.connect(address).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                connection.record_failure();
+                return Err(e);
+            }
+        };
+        match self.round_trip(&mut stream, address, query).await {
+            Ok(response) => {
+                connection.stream = Some(stream);
+                connection.record_success();
+                Ok(response)
+            }
+            Err(e) => {
+                connection.record_failure();
+                Err(e)
+            }
+        }
+    }
+
+    async fn connect(&self, address: SocketAddr) -> Result<BufReader<TcpStream>, ClientError> {
+        let stream = timeout(self.connect_timeout, TcpStream::connect(address))
+            .await
+            .map_err(|_| {
+                ClientError::Timeout(format!(
+                    "no connection to {} within {:?}",
+                    address, self.connect_timeout
+                ))
+            })?
+            .map_err(|e| ClientError::ConnectionError(e.to_string()))?;
+        Ok(BufReader::new(stream))
+    }
+
+    async fn round_trip(
+        &self,
+        stream: &mut BufReader<TcpStream>,
+        address: SocketAddr,
+        query: &str,
+    ) -> Result<String, ClientError> {
+        // write a command, the socket replies with its state after executing it
+        stream
+            .get_mut()
+            .write_all(query.as_bytes())
+            .await
+            .map_err(|e| ClientError::IoError(e.to_string()))?;
+
+        // unpack the result
+        let mut buf: Vec<u8> = Vec::new();
+        let bytes_read = timeout(self.read_timeout, stream.read_until(b'\n', &mut buf))
+            .await
+            .map_err(|_| {
+                ClientError::Timeout(format!(
+                    "no reply from {} within {:?}",
+                    address, self.read_timeout
+                ))
+            })?
+            .map_err(|e| ClientError::IoError(e.to_string()))?;
+        if bytes_read == 0 {
+            return Err(ClientError::IoError(format!(
+                "{} closed the connection",
+                address
+            )));
+        }
+
+        let response = str::from_utf8(&buf).unwrap_or_default();
+        Ok(response.to_string())
+    }
+}
+
+// devices reply with their state as JSON, anything else
+// (e.g. an error text from the emulator) is a parse error
+fn parse_status<T: serde::de::DeserializeOwned>(response: &str) -> Result<T, ClientError> {
+    serde_json::from_str(response.trim())
+        .map_err(|_| ClientError::ParseError(format!("unexpected reply {:?}", response.trim())))
+}
+
+fn socket_connections() -> &'static Mutex<HashMap<SocketAddr, SharedConnection>> {
+    static CONNECTIONS: OnceLock<Mutex<HashMap<SocketAddr, SharedConnection>>> = OnceLock::new();
+    CONNECTIONS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Closes the kept open connection to a socket that was deleted or moved
+/// to another address, a round trip under way still finishes.
+pub fn forget(address: SocketAddr) {
+    if let Ok(mut connections) = socket_connections().lock() {
+        connections.remove(&address);
+    }
+}
+
+// every registered thermometer pushes datagrams to its own address,
+// a background thread per address keeps the latest one around
+struct ThermoListener {
+    latest: Arc<Mutex<Option<Datagram>>>,
+    state: Arc<AtomicU8>,
+    thread: thread::JoinHandle<()>,
+}
+
+// a stopped listener stays registered until its thread notices, at most
+// `THERMO_POLL_INTERVAL` later, and lets go of the socket
+const LISTENING: u8 = 0;
+const STOPPING: u8 = 1;
+const STOPPED: u8 = 2;
+
+struct Datagram {
+    text: String,
+    received: Instant,
+}
+
+fn thermo_listeners() -> &'static Mutex<HashMap<SocketAddr, ThermoListener>> {
+    static LISTENERS: OnceLock<Mutex<HashMap<SocketAddr, ThermoListener>>> = OnceLock::new();
+    LISTENERS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub fn listen_thermo(address: SocketAddr) -> Result<(), ClientError> {
+    let mut listeners = thermo_listeners()
+        .lock()
+        .map_err(|e| ClientError::Unknown(e.to_string()))?;
+    if let Some(listener) = listeners.get(&address) {
+        // a listener stopped a moment ago still holds the socket, taking it
+        // back is quicker than waiting for the thread to let go
+        match listener.state.compare_exchange(
+            STOPPING,
+            LISTENING,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                if let Ok(mut latest) = listener.latest.lock() {
+                    *latest = None;
+                }
+                return Ok(());
+            }
+            Err(LISTENING) => return Ok(()),
+            // the thread is on its way out and about to drop the socket
+            Err(_) => {
+                if let Some(listener) = listeners.remove(&address) {
+                    listener.thread.join().ok();
+                }
+            }
+        }
+    }
+    listeners.retain(|_, listener| !listener.thread.is_finished());
 
-    // unpack the result
-    let mut buf: Vec<u8> = Vec::new();
-    let mut reader = BufReader::new(&stream);
-    reader
-        .read_until(b'\n', &mut buf)
+    let socket =
+        UdpSocket::bind(address).map_err(|e| ClientError::ConnectionError(e.to_string()))?;
+    socket
+        .set_read_timeout(Some(THERMO_POLL_INTERVAL))
         .map_err(|e| ClientError::IoError(e.to_string()))?;
 
-    let response = str::from_utf8(&buf).unwrap_or_default();
-    Ok(response.to_string())
+    let latest = Arc::new(Mutex::new(None));
+    let state = Arc::new(AtomicU8::new(LISTENING));
+    let thread = {
+        let latest = latest.clone();
+        let state = state.clone();
+        thread::spawn(move || receive_thermo_datagrams(socket, latest, state))
+    };
+
+    listeners.insert(
+        address,
+        ThermoListener {
+            latest,
+            state,
+            thread,
+        },
+    );
+    Ok(())
+}
+
+/// Stops listening on the address without waiting for the thread, which
+/// lets go of its socket within `THERMO_POLL_INTERVAL`.
+pub fn stop_thermo(address: SocketAddr) {
+    if let Ok(listeners) = thermo_listeners().lock() {
+        if let Some(listener) = listeners.get(&address) {
+            listener
+                .state
+                .compare_exchange(LISTENING, STOPPING, Ordering::AcqRel, Ordering::Acquire)
+                .ok();
+        }
+    }
+}
+
+/// The latest datagram of the thermometer, `NoData` before the first one,
+/// once it is older than `THERMO_STALE_AFTER` and when nobody listens on
+/// the address.
+pub fn get_thermo_status(address: SocketAddr) -> Result<String, ClientError> {
+    latest_thermo_datagram(address, THERMO_STALE_AFTER)
+}
+
+fn latest_thermo_datagram(
+    address: SocketAddr,
+    stale_after: Duration,
+) -> Result<String, ClientError> {
+    let listeners = thermo_listeners()
+        .lock()
+        .map_err(|e| ClientError::Unknown(e.to_string()))?;
+    let latest = match listeners.get(&address) {
+        Some(listener) if listener.state.load(Ordering::Acquire) == LISTENING => {
+            listener.latest.lock()
+        }
+        _ => {
+            return Err(ClientError::NoData(
+                "nobody listens for the thermometer".into(),
+            ))
+        }
+    };
+
+    match latest {
+        Ok(latest) => match latest.as_ref() {
+            Some(datagram) if datagram.received.elapsed() <= stale_after => {
+                Ok(datagram.text.clone())
+            }
+            Some(datagram) => Err(ClientError::NoData(format!(
+                "thermometer has not reported for {:?}",
+                datagram.received.elapsed()
+            ))),
+            None => Err(ClientError::NoData(
+                "thermometer has not reported yet".into(),
+            )),
+        },
+        Err(e) => Err(ClientError::Unknown(e.to_string())),
+    }
+}
+
+fn receive_thermo_datagrams(
+    socket: UdpSocket,
+    latest: Arc<Mutex<Option<Datagram>>>,
+    state: Arc<AtomicU8>,
+) {
+    let mut buf = [0; 128];
+    while state
+        .compare_exchange(STOPPING, STOPPED, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        match socket.recv_from(&mut buf) {
+            Ok((bytes_read, _)) => {
+                let datagram = str::from_utf8(&buf[..bytes_read]).unwrap_or_default();
+                if let Ok(mut latest) = latest.lock() {
+                    *latest = Some(Datagram {
+                        text: datagram.trim().to_string(),
+                        received: Instant::now(),
+                    });
+                }
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(_) => {
+                state.store(STOPPED, Ordering::Release);
+                break;
+            }
+        }
+    }
 }
 
-pub fn get_thermo_status(_address: SocketAddr) -> Result<String, ClientError> {
-    // if not connected to that address -> connect and keep athread for connection, check the thread on request
-    Err(ClientError::Unknown(
-        "UDP Thermomemter is not supported".into(),
-    ))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::fixture::{unused_tcp_address, unused_udp_address};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream as StdTcpStream};
+
+    const STATUS_REPLY: &[u8] = b"{\"enabled\":false,\"power\":0.0}\n";
+
+    // answers every command on the connection until the client hangs up
+    fn serve_connection(mut stream: StdTcpStream) {
+        let mut buf = [0; 10];
+        while let Ok(bytes_read) = stream.read(&mut buf) {
+            if bytes_read == 0 || stream.write_all(STATUS_REPLY).is_err() {
+                return;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn get_socket_status_reuses_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // only ever accepts a single connection
+        thread::spawn(move || serve_connection(listener.accept().unwrap().0));
+
+        let client = DeviceClient::default();
+        for _ in 0..3 {
+            assert!(client.get_socket_status(address).await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn forget_closes_the_kept_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let served = thread::spawn(move || serve_connection(listener.accept().unwrap().0));
+
+        let client = DeviceClient::default();
+        assert!(client.get_socket_status(address).await.is_ok());
+        forget(address);
+        // the device only stops serving once the client hung up
+        served.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn get_socket_status_reconnects_if_device_closed_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            // reply once and hang up, then serve the reconnected client
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 10];
+            let _ = stream.read(&mut buf);
+            stream.write_all(STATUS_REPLY).unwrap();
+            drop(stream);
+            serve_connection(listener.accept().unwrap().0);
+        });
+
+        let client = DeviceClient::default();
+        assert!(client.get_socket_status(address).await.is_ok());
+        assert!(client.get_socket_status(address).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn get_socket_status_backs_off_after_failed_connect() {
+        let address = unused_tcp_address();
+
+        let client = DeviceClient::default();
+        assert!(client.get_socket_status(address).await.is_err());
+
+        // device comes up, but the client waits for the backoff to pass
+        let listener = TcpListener::bind(address).unwrap();
+        thread::spawn(move || serve_connection(listener.accept().unwrap().0));
+        match client.get_socket_status(address).await {
+            Err(ClientError::ConnectionError(message)) => assert!(message.contains("next attempt")),
+            _ => unreachable!(),
+        }
+
+        tokio::time::sleep(RECONNECT_BACKOFF).await;
+        assert!(client.get_socket_status(address).await.is_ok());
+    }
+
+    #[test]
+    fn get_thermo_status_returns_no_data_before_first_datagram() {
+        let address = unused_udp_address();
+        listen_thermo(address).ok();
+
+        match get_thermo_status(address) {
+            Err(ClientError::NoData(_)) => {}
+            _ => unreachable!(),
+        }
+        stop_thermo(address);
+    }
+
+    #[test]
+    fn get_thermo_status_returns_latest_datagram() {
+        let address = unused_udp_address();
+        listen_thermo(address).ok();
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(b"{\"temperature\":20.5}", address).unwrap();
+        sender.send_to(b"{\"temperature\":21.0}", address).unwrap();
+
+        let mut status = get_thermo_status(address);
+        for _ in 0..50 {
+            if let Ok(ref message) = status {
+                if message.contains("21.0") {
+                    break;
+                }
+            }
+            thread::sleep(Duration::from_millis(20));
+            status = get_thermo_status(address);
+        }
+
+        match status {
+            Ok(message) => assert_eq!(message, "{\"temperature\":21.0}"),
+            _ => unreachable!(),
+        }
+        stop_thermo(address);
+    }
+
+    #[test]
+    fn get_thermo_status_returns_no_data_once_the_datagram_is_stale() {
+        let address = unused_udp_address();
+        listen_thermo(address).ok();
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(b"{\"temperature\":20.5}", address).unwrap();
+        let mut status = latest_thermo_datagram(address, THERMO_STALE_AFTER);
+        for _ in 0..50 {
+            if status.is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+            status = latest_thermo_datagram(address, THERMO_STALE_AFTER);
+        }
+        assert!(status.is_ok());
+
+        thread::sleep(Duration::from_millis(50));
+        match latest_thermo_datagram(address, Duration::from_millis(10)) {
+            Err(ClientError::NoData(message)) => assert!(message.contains("not reported for")),
+            _ => unreachable!(),
+        }
+        stop_thermo(address);
+    }
+
+    #[test]
+    fn get_thermo_status_returns_no_data_without_a_listener() {
+        let address = unused_udp_address();
+        match get_thermo_status(address) {
+            Err(ClientError::NoData(_)) => {}
+            _ => unreachable!(),
+        }
+        // reading does not start listening
+        assert!(UdpSocket::bind(address).is_ok());
+    }
+
+    #[test]
+    fn get_thermo_status_returns_no_data_after_stop_thermo() {
+        let address = unused_udp_address();
+        listen_thermo(address).ok();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(b"{\"temperature\":20.5}", address).unwrap();
+        let mut status = get_thermo_status(address);
+        for _ in 0..50 {
+            if status.is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+            status = get_thermo_status(address);
+        }
+        assert!(status.is_ok());
+
+        stop_thermo(address);
+        match get_thermo_status(address) {
+            Err(ClientError::NoData(_)) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn stop_thermo_does_not_wait_for_the_listener() {
+        let address = unused_udp_address();
+        listen_thermo(address).ok();
+
+        let started = Instant::now();
+        stop_thermo(address);
+        assert!(started.elapsed() < THERMO_POLL_INTERVAL / 2);
+        // the thread lets go of the socket on its own
+        thread::sleep(THERMO_POLL_INTERVAL * 2);
+        assert!(UdpSocket::bind(address).is_ok());
+    }
+
+    #[test]
+    fn listen_thermo_rebinds_right_after_stop_thermo() {
+        let address = unused_udp_address();
+        for _ in 0..3 {
+            assert!(listen_thermo(address).is_ok());
+            stop_thermo(address);
+        }
+    }
 }
@@ -0,0 +1,206 @@
+const MINUTE_MS: u64 = 60 * 1000;
+const DAY_MINUTES: u64 = 24 * 60;
+
+/// A classic five field cron expression, `minute hour day-of-month month
+/// day-of-week`, evaluated in UTC.
+///
+/// Every field takes `*`, numbers, ranges `1-5`, lists `1,15` and steps
+/// `*/15` or `8-18/2`. Day of week runs from 0 to 7, both 0 and 7 are
+/// Sunday. As in cron, when both day fields are restricted a day matching
+/// either of them is enough.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CronExpr {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl TryFrom<String> for CronExpr {
+    type Error = ();
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        let fields: Vec<&str> = source.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(());
+        };
+
+        let mut weekday_bits = parse_field(weekdays, 0, 7)?;
+        // 7 is another name for Sunday
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits = (weekday_bits | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            any_day: days.starts_with('*'),
+            any_weekday: weekdays.starts_with('*'),
+            source: fields.join(" "),
+        })
+    }
+}
+
+impl From<CronExpr> for String {
+    fn from(cron: CronExpr) -> Self {
+        cron.source
+    }
+}
+
+impl CronExpr {
+    /// Whether the minute `timestamp` falls into, in milliseconds
+    /// since the unix epoch, is one the expression fires at.
+    pub fn matches(&self, timestamp: u64) -> bool {
+        let minutes = timestamp / MINUTE_MS;
+        let days = minutes / DAY_MINUTES;
+        let minute_of_day = minutes % DAY_MINUTES;
+        let (_, month, day) = civil_from_days(days);
+        // the epoch was a Thursday
+        let weekday = (days + 4) % 7;
+
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => has(self.days, day) || has(self.weekdays, weekday),
+            _ => has(self.days, day) && has(self.weekdays, weekday),
+        };
+        has(self.minutes, minute_of_day % 60)
+            && has(self.hours, minute_of_day / 60)
+            && has(self.months, month)
+            && day_matches
+    }
+}
+
+fn has(bits: u64, value: u64) -> bool {
+    bits & (1 << value) != 0
+}
+
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, ()> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u64>().map_err(|_| ())?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (
+                    start.parse::<u64>().map_err(|_| ())?,
+                    end.parse::<u64>().map_err(|_| ())?,
+                ),
+                // `5/10` means from 5 to the end in steps of 10
+                None if part.contains('/') => (range.parse().map_err(|_| ())?, max),
+                None => {
+                    let value = range.parse().map_err(|_| ())?;
+                    (value, value)
+                }
+            },
+        };
+        if step == 0 || start < min || end > max || start > end {
+            return Err(());
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+// (year, month, day) of the given number of days since the unix epoch,
+// from Howard Hinnant's `civil_from_days`
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-01T00:00:00Z, a Monday
+    const NEW_YEAR_2024: u64 = 1_704_067_200_000;
+    const HOUR_MS: u64 = 60 * MINUTE_MS;
+    const DAY_MS: u64 = 24 * HOUR_MS;
+
+    fn cron(source: &str) -> CronExpr {
+        CronExpr::try_from(source.to_string()).unwrap()
+    }
+
+    #[test]
+    fn civil_from_days_handles_leap_years() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(NEW_YEAR_2024 / DAY_MS), (2024, 1, 1));
+        assert_eq!(civil_from_days(NEW_YEAR_2024 / DAY_MS + 59), (2024, 2, 29));
+    }
+
+    #[test]
+    fn weekday_mornings_match_only_on_weekdays() {
+        let expr = cron("0 7 * * 1-5");
+        let seven = NEW_YEAR_2024 + 7 * HOUR_MS;
+
+        let matching: Vec<_> = (0..7)
+            .filter(|day| expr.matches(seven + day * DAY_MS))
+            .collect();
+        assert_eq!(matching, vec![0, 1, 2, 3, 4]);
+        assert!(!expr.matches(seven + MINUTE_MS));
+        // anywhere within the minute
+        assert!(expr.matches(seven + 59_000));
+    }
+
+    #[test]
+    fn restricted_day_fields_match_either() {
+        // the 1st of the month or any Sunday, 2024-01-07 is a Sunday
+        let expr = cron("30 12 1 * 0");
+        let noon = NEW_YEAR_2024 + 12 * HOUR_MS + 30 * MINUTE_MS;
+
+        assert!(expr.matches(noon));
+        assert!(expr.matches(noon + 6 * DAY_MS));
+        assert!(!expr.matches(noon + 2 * DAY_MS));
+        assert_eq!(cron("0 0 * * 7").weekdays, cron("0 0 * * 0").weekdays);
+    }
+
+    #[test]
+    fn steps_lists_and_ranges() {
+        let expr = cron("*/15 8-18/2 * 1,7 *");
+
+        assert!(expr.matches(NEW_YEAR_2024 + 8 * HOUR_MS + 45 * MINUTE_MS));
+        assert!(!expr.matches(NEW_YEAR_2024 + 9 * HOUR_MS));
+        assert!(!expr.matches(NEW_YEAR_2024 + 8 * HOUR_MS + 10 * MINUTE_MS));
+        // February
+        assert!(!expr.matches(NEW_YEAR_2024 + 31 * DAY_MS + 8 * HOUR_MS));
+    }
+
+    #[test]
+    fn invalid_expressions_are_rejected() {
+        for source in [
+            "",
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+            "* * * * * *",
+        ] {
+            assert!(
+                CronExpr::try_from(source.to_string()).is_err(),
+                "{}",
+                source
+            );
+        }
+    }
+}
@@ -1,11 +1,39 @@
+use crate::domain::cron::CronExpr;
+use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
+use uuid::Uuid;
 
 #[derive(Clone)]
 pub struct RoomInfo {
+    pub id: RoomId,
     pub name: RoomName,
     pub devices: Vec<DeviceInfo>,
 }
 
+/// Generated when a room is created, unlike the name it never changes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct RoomId(Uuid);
+
+impl RoomId {
+    pub fn generate() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl TryFrom<String> for RoomId {
+    type Error = ();
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        Uuid::parse_str(&id).map(Self).map_err(|_| ())
+    }
+}
+
+impl From<RoomId> for String {
+    fn from(id: RoomId) -> Self {
+        id.0.to_string()
+    }
+}
+
 #[derive(Clone, Eq, PartialEq, PartialOrd, Ord)]
 pub struct RoomName(String);
 
@@ -27,7 +55,7 @@ impl From<RoomName> for String {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum DeviceType {
     TcpSocket,
     UdpThermo,
@@ -54,11 +82,154 @@ impl From<DeviceType> for String {
     }
 }
 
+#[derive(Clone)]
+pub enum DeviceCommand {
+    TurnOn,
+    TurnOff,
+}
+
+impl TryFrom<String> for DeviceCommand {
+    type Error = ();
+
+    fn try_from(c: String) -> Result<Self, Self::Error> {
+        match c.as_str() {
+            "on" => Ok(Self::TurnOn),
+            "off" => Ok(Self::TurnOff),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<DeviceCommand> for String {
+    fn from(c: DeviceCommand) -> Self {
+        String::from(match c {
+            DeviceCommand::TurnOn => "on",
+            DeviceCommand::TurnOff => "off",
+        })
+    }
+}
+
+/// State reported by a smart socket, same shape the socket sends over the wire.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SocketStatus {
+    pub enabled: bool,
+    pub power: f32,
+}
+
+/// State reported by a thermometer, same shape the thermometer sends over the wire.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ThermoStatus {
+    pub temperature: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DeviceStatus {
+    Socket(SocketStatus),
+    Thermo(ThermoStatus),
+}
+
+/// Something that happened to a device, either `status` or `error` is set.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DeviceEvent {
+    Reading {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        status: Option<DeviceStatus>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Command {
+        command: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        status: Option<SocketStatus>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    pub device_id: DeviceId,
+    /// milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub event: DeviceEvent,
+}
+
 #[derive(Clone)]
 pub struct DeviceInfo {
+    pub id: DeviceId,
     pub name: DeviceName,
     pub address: SocketAddr,
     pub device_type: DeviceType,
+    /// Sorted and without duplicates, see `GroupName::normalize`.
+    pub groups: Vec<GroupName>,
+}
+
+/// Changes to apply to a registered device, `None` keeps the current value.
+#[derive(Clone, Default)]
+pub struct DeviceUpdate {
+    pub room_name: Option<RoomName>,
+    pub name: Option<DeviceName>,
+    pub address: Option<SocketAddr>,
+    pub device_type: Option<DeviceType>,
+    pub groups: Option<Vec<GroupName>>,
+}
+
+/// A named set of devices across rooms, like "heaters" or "outdoor". A
+/// group exists for as long as some device is a member of it.
+#[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord, Hash)]
+pub struct GroupName(String);
+
+impl TryFrom<String> for GroupName {
+    type Error = ();
+
+    fn try_from(n: String) -> Result<Self, Self::Error> {
+        if n.is_empty() {
+            Err(())
+        } else {
+            Ok(Self(n))
+        }
+    }
+}
+
+impl From<GroupName> for String {
+    fn from(n: GroupName) -> Self {
+        n.0
+    }
+}
+
+impl GroupName {
+    /// Sorts the groups and drops the duplicates, the form `DeviceInfo` keeps them in.
+    pub fn normalize(mut groups: Vec<GroupName>) -> Vec<GroupName> {
+        groups.sort();
+        groups.dedup();
+        groups
+    }
+}
+
+/// Generated when a device is added, unlike the name it survives renames and moves.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct DeviceId(Uuid);
+
+impl DeviceId {
+    pub fn generate() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl TryFrom<String> for DeviceId {
+    type Error = ();
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        Uuid::parse_str(&id).map(Self).map_err(|_| ())
+    }
+}
+
+impl From<DeviceId> for String {
+    fn from(id: DeviceId) -> Self {
+        id.0.to_string()
+    }
 }
 
 #[derive(Clone, Eq, PartialEq, PartialOrd, Ord)]
@@ -82,6 +253,524 @@ impl From<DeviceName> for String {
     }
 }
 
+/// Generated when a rule is created, rules are addressed by it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct RuleId(Uuid);
+
+impl RuleId {
+    pub fn generate() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl TryFrom<String> for RuleId {
+    type Error = ();
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        Uuid::parse_str(&id).map(Self).map_err(|_| ())
+    }
+}
+
+impl From<RuleId> for String {
+    fn from(id: RuleId) -> Self {
+        id.0.to_string()
+    }
+}
+
+#[derive(Clone, Eq, PartialEq)]
+pub struct RuleName(String);
+
+impl TryFrom<String> for RuleName {
+    type Error = ();
+
+    fn try_from(n: String) -> Result<Self, Self::Error> {
+        if n.is_empty() {
+            Err(())
+        } else {
+            Ok(Self(n))
+        }
+    }
+}
+
+impl From<RuleName> for String {
+    fn from(n: RuleName) -> Self {
+        n.0
+    }
+}
+
+/// Minutes since midnight UTC, written as `HH:MM`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TimeOfDay(u16);
+
+impl TimeOfDay {
+    pub fn minutes(self) -> u16 {
+        self.0
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = ();
+
+    fn try_from(t: String) -> Result<Self, Self::Error> {
+        let (hours, minutes) = t.split_once(':').ok_or(())?;
+        if hours.len() != 2 || minutes.len() != 2 {
+            return Err(());
+        }
+        match (hours.parse::<u16>(), minutes.parse::<u16>()) {
+            (Ok(hours), Ok(minutes)) if hours < 24 && minutes < 60 => {
+                Ok(Self(hours * 60 + minutes))
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(t: TimeOfDay) -> Self {
+        format!("{:02}:{:02}", t.0 / 60, t.0 % 60)
+    }
+}
+
+/// What makes a rule fire, thresholds are in degrees Celsius or watts.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RuleTrigger {
+    TemperatureBelow { device_id: DeviceId, threshold: f32 },
+    TemperatureAbove { device_id: DeviceId, threshold: f32 },
+    PowerAbove { device_id: DeviceId, threshold: f32 },
+    PowerBelow { device_id: DeviceId, threshold: f32 },
+    TimeOfDay(TimeOfDay),
+}
+
+/// Flat form of a trigger, the shape it has in requests and in storage.
+#[derive(Clone, Default)]
+pub struct TriggerParts {
+    pub kind: String,
+    pub device_id: Option<DeviceId>,
+    pub threshold: Option<f32>,
+    pub at: Option<TimeOfDay>,
+}
+
+impl TryFrom<TriggerParts> for RuleTrigger {
+    type Error = ();
+
+    fn try_from(parts: TriggerParts) -> Result<Self, Self::Error> {
+        match (
+            parts.kind.as_str(),
+            parts.device_id,
+            parts.threshold,
+            parts.at,
+        ) {
+            ("temperature_below", Some(device_id), Some(threshold), None) => {
+                Ok(Self::TemperatureBelow {
+                    device_id,
+                    threshold,
+                })
+            }
+            ("temperature_above", Some(device_id), Some(threshold), None) => {
+                Ok(Self::TemperatureAbove {
+                    device_id,
+                    threshold,
+                })
+            }
+            ("power_above", Some(device_id), Some(threshold), None) => Ok(Self::PowerAbove {
+                device_id,
+                threshold,
+            }),
+            ("power_below", Some(device_id), Some(threshold), None) => Ok(Self::PowerBelow {
+                device_id,
+                threshold,
+            }),
+            ("time_of_day", None, None, Some(at)) => Ok(Self::TimeOfDay(at)),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<RuleTrigger> for TriggerParts {
+    fn from(trigger: RuleTrigger) -> Self {
+        let (kind, device_id, threshold) = match trigger {
+            RuleTrigger::TemperatureBelow {
+                device_id,
+                threshold,
+            } => ("temperature_below", device_id, threshold),
+            RuleTrigger::TemperatureAbove {
+                device_id,
+                threshold,
+            } => ("temperature_above", device_id, threshold),
+            RuleTrigger::PowerAbove {
+                device_id,
+                threshold,
+            } => ("power_above", device_id, threshold),
+            RuleTrigger::PowerBelow {
+                device_id,
+                threshold,
+            } => ("power_below", device_id, threshold),
+            RuleTrigger::TimeOfDay(at) => {
+                return Self {
+                    kind: "time_of_day".to_string(),
+                    at: Some(at),
+                    ..Self::default()
+                }
+            }
+        };
+        Self {
+            kind: kind.to_string(),
+            device_id: Some(device_id),
+            threshold: Some(threshold),
+            at: None,
+        }
+    }
+}
+
+/// A command sent to a socket when the rule fires.
+#[derive(Clone)]
+pub struct RuleAction {
+    pub device_id: DeviceId,
+    pub command: DeviceCommand,
+}
+
+#[derive(Clone)]
+pub struct Rule {
+    pub id: RuleId,
+    pub name: RuleName,
+    pub enabled: bool,
+    pub trigger: RuleTrigger,
+    /// `None` for a rule that only tells, through the `rule_fired` event
+    /// and the webhooks.
+    pub action: Option<RuleAction>,
+}
+
+/// Generated when a schedule is created, schedules are addressed by it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ScheduleId(Uuid);
+
+impl ScheduleId {
+    pub fn generate() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl TryFrom<String> for ScheduleId {
+    type Error = ();
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        Uuid::parse_str(&id).map(Self).map_err(|_| ())
+    }
+}
+
+impl From<ScheduleId> for String {
+    fn from(id: ScheduleId) -> Self {
+        id.0.to_string()
+    }
+}
+
+/// Sends `command` to the socket whenever `cron` matches.
+#[derive(Clone)]
+pub struct Schedule {
+    pub id: ScheduleId,
+    pub cron: CronExpr,
+    pub device_id: DeviceId,
+    pub command: DeviceCommand,
+}
+
+/// One execution of a schedule, either `status` or `error` is set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScheduleRun {
+    pub schedule_id: ScheduleId,
+    /// milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub status: Option<SocketStatus>,
+    pub error: Option<String>,
+}
+
+/// Generated when a webhook is registered, webhooks are addressed by it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct WebhookId(Uuid);
+
+impl WebhookId {
+    pub fn generate() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl TryFrom<String> for WebhookId {
+    type Error = ();
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        Uuid::parse_str(&id).map(Self).map_err(|_| ())
+    }
+}
+
+impl From<WebhookId> for String {
+    fn from(id: WebhookId) -> Self {
+        id.0.to_string()
+    }
+}
+
+/// An absolute `http` or `https` URL.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WebhookUrl(String);
+
+impl TryFrom<String> for WebhookUrl {
+    type Error = ();
+
+    fn try_from(url: String) -> Result<Self, Self::Error> {
+        let rest = url
+            .strip_prefix("http://")
+            .or_else(|| url.strip_prefix("https://"))
+            .ok_or(())?;
+        if rest.is_empty() || rest.starts_with('/') || url.contains(char::is_whitespace) {
+            return Err(());
+        }
+        Ok(Self(url))
+    }
+}
+
+impl From<WebhookUrl> for String {
+    fn from(url: WebhookUrl) -> Self {
+        url.0
+    }
+}
+
+/// Posts the events that pass its filters to `url`, signed with `secret`.
+/// Empty `events` lets every kind of event through.
+#[derive(Clone)]
+pub struct Webhook {
+    pub id: WebhookId,
+    pub url: WebhookUrl,
+    pub secret: String,
+    pub events: Vec<String>,
+    pub room_id: Option<RoomId>,
+    pub device_id: Option<DeviceId>,
+}
+
+/// What an API key may do, every scope includes the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    /// statuses, history and configuration, nothing changes
+    Read,
+    /// also commands to the sockets, directly or through groups and scenes
+    Control,
+    /// also changes to the house, the automation and the keys
+    Admin,
+}
+
+impl TryFrom<String> for Scope {
+    type Error = ();
+
+    fn try_from(scope: String) -> Result<Self, Self::Error> {
+        match scope.as_str() {
+            "read" => Ok(Self::Read),
+            "control" => Ok(Self::Control),
+            "admin" => Ok(Self::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<Scope> for String {
+    fn from(scope: Scope) -> Self {
+        match scope {
+            Scope::Read => "read",
+            Scope::Control => "control",
+            Scope::Admin => "admin",
+        }
+        .to_string()
+    }
+}
+
+/// Generated when an API key is created, keys are addressed by it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ApiKeyId(Uuid);
+
+impl ApiKeyId {
+    pub fn generate() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl TryFrom<String> for ApiKeyId {
+    type Error = ();
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        Uuid::parse_str(&id).map(Self).map_err(|_| ())
+    }
+}
+
+impl From<ApiKeyId> for String {
+    fn from(id: ApiKeyId) -> Self {
+        id.0.to_string()
+    }
+}
+
+/// Only the hex SHA-256 of the token is kept, the token itself is told
+/// once when the key is created.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiKey {
+    pub id: ApiKeyId,
+    pub name: String,
+    pub scope: Scope,
+    pub token_hash: String,
+}
+
+/// What a user may do with a room or device granted to them, `control`
+/// includes `read`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Read,
+    Control,
+}
+
+impl Role {
+    /// The API key scope that allows the same everywhere.
+    pub fn scope(self) -> Scope {
+        match self {
+            Self::Read => Scope::Read,
+            Self::Control => Scope::Control,
+        }
+    }
+}
+
+impl TryFrom<String> for Role {
+    type Error = ();
+
+    fn try_from(role: String) -> Result<Self, Self::Error> {
+        match role.as_str() {
+            "read" => Ok(Self::Read),
+            "control" => Ok(Self::Control),
+            _ => Err(()),
+        }
+    }
+}
+
+impl From<Role> for String {
+    fn from(role: Role) -> Self {
+        match role {
+            Role::Read => "read",
+            Role::Control => "control",
+        }
+        .to_string()
+    }
+}
+
+/// A room with every device that is in it at the time, or a single device
+/// wherever it moves. Both by their stable ids, so renames keep the grant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GrantTarget {
+    Room(RoomId),
+    Device(DeviceId),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Grant {
+    pub target: GrantTarget,
+    pub role: Role,
+}
+
+/// Generated when a user is created, unlike the name it never changes.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct UserId(Uuid);
+
+impl UserId {
+    pub fn generate() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+impl TryFrom<String> for UserId {
+    type Error = ();
+
+    fn try_from(id: String) -> Result<Self, Self::Error> {
+        Uuid::parse_str(&id).map(Self).map_err(|_| ())
+    }
+}
+
+impl From<UserId> for String {
+    fn from(id: UserId) -> Self {
+        id.0.to_string()
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UserName(String);
+
+impl TryFrom<String> for UserName {
+    type Error = ();
+
+    fn try_from(n: String) -> Result<Self, Self::Error> {
+        if n.trim().is_empty() {
+            Err(())
+        } else {
+            Ok(Self(n))
+        }
+    }
+}
+
+impl From<UserName> for String {
+    fn from(n: UserName) -> Self {
+        n.0
+    }
+}
+
+/// Someone who logs in with a name and a password. An admin may do
+/// everything, anyone else only what their grants allow.
+#[derive(Clone, Debug, PartialEq)]
+pub struct User {
+    pub id: UserId,
+    pub name: UserName,
+    /// the Argon2 PHC string, the password itself is never kept
+    pub password_hash: String,
+    pub admin: bool,
+    pub grants: Vec<Grant>,
+}
+
+/// One event posted to a webhook, `status` is the HTTP status of the last
+/// attempt if it got a response, `error` is set unless it was a success.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WebhookDelivery {
+    pub webhook_id: WebhookId,
+    /// milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub event: String,
+    pub attempts: u32,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Eq, PartialEq)]
+pub struct SceneName(String);
+
+impl TryFrom<String> for SceneName {
+    type Error = ();
+
+    fn try_from(n: String) -> Result<Self, Self::Error> {
+        if n.is_empty() {
+            Err(())
+        } else {
+            Ok(Self(n))
+        }
+    }
+}
+
+impl From<SceneName> for String {
+    fn from(n: SceneName) -> Self {
+        n.0
+    }
+}
+
+#[derive(Clone)]
+pub struct SceneStep {
+    pub device_id: DeviceId,
+    pub command: DeviceCommand,
+}
+
+/// A named set of socket commands applied together, like "movie night".
+#[derive(Clone)]
+pub struct Scene {
+    pub name: SceneName,
+    pub steps: Vec<SceneStep>,
+}
+
 #[cfg(test)]
 impl RoomName {
     pub fn bathroom() -> Self {
@@ -111,3 +800,49 @@ impl DeviceName {
         Self("".to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_of_day_parses_hours_and_minutes() {
+        match TimeOfDay::try_from("07:30".to_string()) {
+            Ok(at) => {
+                assert_eq!(at.minutes(), 7 * 60 + 30);
+                assert_eq!(String::from(at), "07:30");
+            }
+            _ => unreachable!(),
+        }
+        for t in ["24:00", "7:30", "07:60", "0730", ""] {
+            assert!(TimeOfDay::try_from(t.to_string()).is_err());
+        }
+    }
+
+    #[test]
+    fn trigger_parts_must_match_the_kind() {
+        let parts = TriggerParts {
+            kind: "power_above".to_string(),
+            device_id: Some(DeviceId::generate()),
+            threshold: Some(100.0),
+            at: None,
+        };
+        match RuleTrigger::try_from(parts.clone()) {
+            Ok(trigger @ RuleTrigger::PowerAbove { .. }) => {
+                assert_eq!(TriggerParts::from(trigger).kind, "power_above")
+            }
+            _ => unreachable!(),
+        }
+
+        let no_threshold = TriggerParts {
+            threshold: None,
+            ..parts.clone()
+        };
+        let unknown_kind = TriggerParts {
+            kind: "humidity_above".to_string(),
+            ..parts
+        };
+        assert!(RuleTrigger::try_from(no_threshold).is_err());
+        assert!(RuleTrigger::try_from(unknown_kind).is_err());
+    }
+}
@@ -0,0 +1,218 @@
+use crate::domain::entity::{DeviceStatus, SocketStatus};
+use serde::Serialize;
+use std::sync::OnceLock;
+use tokio::sync::broadcast;
+
+// events a slow subscriber may fall behind by before it starts missing some
+const CAPACITY: usize = 256;
+
+/// Something that changed in the house. `room_id` and `device_id` are
+/// names, like in the routes, `id` is the stable device id.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    RoomAdded {
+        room_id: String,
+    },
+    RoomDeleted {
+        room_id: String,
+    },
+    DeviceAdded {
+        room_id: String,
+        device_id: String,
+        id: String,
+    },
+    DeviceDeleted {
+        room_id: String,
+        device_id: String,
+        id: String,
+    },
+    /// A live reading differs from the one before, `status` is missing
+    /// while the device is unreachable.
+    StatusChanged {
+        room_id: String,
+        device_id: String,
+        id: String,
+        reachable: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status: Option<DeviceStatus>,
+    },
+    /// Either `status` or `error` is set.
+    CommandSent {
+        room_id: String,
+        device_id: String,
+        id: String,
+        command: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status: Option<SocketStatus>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// The device is the one the trigger watches, or the one the rule
+    /// acts on for a time of day.
+    RuleFired {
+        room_id: String,
+        device_id: String,
+        id: String,
+        rule_id: String,
+        rule: String,
+    },
+}
+
+/// Every `Event::kind`.
+pub const KINDS: &[&str] = &[
+    "room_added",
+    "room_deleted",
+    "device_added",
+    "device_deleted",
+    "status_changed",
+    "command_sent",
+    "rule_fired",
+];
+
+impl Event {
+    /// Name of the event, the same as its `type` field.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::RoomAdded { .. } => "room_added",
+            Self::RoomDeleted { .. } => "room_deleted",
+            Self::DeviceAdded { .. } => "device_added",
+            Self::DeviceDeleted { .. } => "device_deleted",
+            Self::StatusChanged { .. } => "status_changed",
+            Self::CommandSent { .. } => "command_sent",
+            Self::RuleFired { .. } => "rule_fired",
+        }
+    }
+
+    /// The stable id of the device, `None` for the room events.
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            Self::RoomAdded { .. } | Self::RoomDeleted { .. } => None,
+            Self::DeviceAdded { id, .. }
+            | Self::DeviceDeleted { id, .. }
+            | Self::StatusChanged { id, .. }
+            | Self::CommandSent { id, .. }
+            | Self::RuleFired { id, .. } => Some(id),
+        }
+    }
+
+    fn room_id(&self) -> &str {
+        match self {
+            Self::RoomAdded { room_id }
+            | Self::RoomDeleted { room_id }
+            | Self::DeviceAdded { room_id, .. }
+            | Self::DeviceDeleted { room_id, .. }
+            | Self::StatusChanged { room_id, .. }
+            | Self::CommandSent { room_id, .. }
+            | Self::RuleFired { room_id, .. } => room_id,
+        }
+    }
+
+    fn device_id(&self) -> Option<&str> {
+        match self {
+            Self::RoomAdded { .. } | Self::RoomDeleted { .. } => None,
+            Self::DeviceAdded { device_id, .. }
+            | Self::DeviceDeleted { device_id, .. }
+            | Self::StatusChanged { device_id, .. }
+            | Self::CommandSent { device_id, .. }
+            | Self::RuleFired { device_id, .. } => Some(device_id),
+        }
+    }
+}
+
+/// Narrows the events down to a room or a device, `None` lets everything through.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EventFilter {
+    pub room_id: Option<String>,
+    pub device_id: Option<String>,
+}
+
+impl EventFilter {
+    /// A device filter drops the room events, a room is not a device.
+    pub fn matches(&self, event: &Event) -> bool {
+        let room_matches = match &self.room_id {
+            Some(room_id) => event.room_id() == room_id,
+            None => true,
+        };
+        let device_matches = match &self.device_id {
+            Some(device_id) => event.device_id() == Some(device_id.as_str()),
+            None => true,
+        };
+        room_matches && device_matches
+    }
+}
+
+// a single bus for the process, like the thermometer listeners
+fn bus() -> &'static broadcast::Sender<Event> {
+    static BUS: OnceLock<broadcast::Sender<Event>> = OnceLock::new();
+    BUS.get_or_init(|| broadcast::channel(CAPACITY).0)
+}
+
+/// Hands the event to every current subscriber, nobody listening is fine.
+pub fn publish(event: Event) {
+    bus().send(event).ok();
+}
+
+/// Receives the events published from now on.
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    bus().subscribe()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device_added(room_id: &str, device_id: &str) -> Event {
+        Event::DeviceAdded {
+            room_id: room_id.to_string(),
+            device_id: device_id.to_string(),
+            id: "id".to_string(),
+        }
+    }
+
+    #[test]
+    fn filter_matches_room_and_device() {
+        let room = EventFilter {
+            room_id: Some("kitchen".to_string()),
+            device_id: None,
+        };
+        let device = EventFilter {
+            room_id: Some("kitchen".to_string()),
+            device_id: Some("socket".to_string()),
+        };
+        let room_added = Event::RoomAdded {
+            room_id: "kitchen".to_string(),
+        };
+
+        assert!(EventFilter::default().matches(&room_added));
+        assert!(room.matches(&room_added));
+        assert!(room.matches(&device_added("kitchen", "socket")));
+        assert!(!room.matches(&device_added("bathroom", "socket")));
+        assert!(!device.matches(&room_added));
+        assert!(device.matches(&device_added("kitchen", "socket")));
+        assert!(!device.matches(&device_added("kitchen", "thermo")));
+    }
+
+    #[test]
+    fn events_are_serialized_with_their_kind() {
+        let event = device_added("kitchen", "socket");
+        let json = serde_json::to_value(&event).unwrap();
+
+        assert_eq!(json["type"], event.kind());
+        assert_eq!(json["room_id"], "kitchen");
+    }
+
+    #[tokio::test]
+    async fn subscribers_receive_events_published_after_subscribing() {
+        // other tests publish to the same bus, so look for this one
+        let event = device_added("events test room", "socket");
+        let mut receiver = subscribe();
+        publish(event.clone());
+
+        loop {
+            if receiver.recv().await.unwrap() == event {
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//! Fixtures the domain tests share.
+
+use crate::domain::entity::{DeviceId, DeviceInfo, DeviceName, DeviceType, RoomName};
+use crate::repository::room::Repository;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+
+/// A local address no socket answers on, bound and dropped right away.
+pub fn unused_tcp_address() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// A local address a thermometer listener can bind.
+pub fn unused_udp_address() -> SocketAddr {
+    UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Stores a device in a room that already exists, without the side
+/// effects of `service::device::add_device`, and returns its id.
+pub fn add_device<R: Repository>(
+    repo: &R,
+    room_name: RoomName,
+    name: &str,
+    address: SocketAddr,
+    device_type: DeviceType,
+) -> DeviceId {
+    let device_info = DeviceInfo {
+        id: DeviceId::generate(),
+        name: DeviceName::try_from(name.to_string()).unwrap(),
+        address,
+        device_type,
+        groups: Vec::new(),
+    };
+    let id = device_info.id;
+    repo.add_device(room_name, device_info).ok();
+    id
+}
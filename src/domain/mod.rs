@@ -1,3 +1,8 @@
+pub mod cache;
 pub mod client;
+pub mod cron;
 pub mod entity;
+pub mod events;
+#[cfg(test)]
+pub(crate) mod fixture;
 pub mod service;
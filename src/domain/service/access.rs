@@ -0,0 +1,376 @@
+use crate::domain::entity::{
+    DeviceId, DeviceInfo, GrantTarget, Role, RoomId, RoomInfo, RoomName, Scope, User,
+};
+use crate::domain::events::Event;
+use crate::domain::service::api_key;
+use crate::domain::service::user::{self, Sessions};
+use crate::repository::api_key::ApiKeyStore;
+use crate::repository::room::{FetchError, Repository};
+use crate::repository::user::UserStore;
+
+/// Who a request is made by. The services that read or drive devices are
+/// handed one and check it themselves, so the REST routes, the event
+/// stream and the websocket all follow the same rules.
+#[derive(Clone, Debug)]
+pub enum Principal {
+    /// An API key, the admin key from the settings or anyone while the
+    /// house is open, the scope holds in every room.
+    Key(Scope),
+    /// A logged in user, limited to their grants unless an admin.
+    User(User),
+}
+
+pub enum AuthError {
+    /// no token while the house is not open
+    Missing,
+    /// a token of no key or of no valid session
+    Invalid,
+    Unknown,
+}
+
+pub(crate) enum AccessError {
+    Forbidden,
+    Unknown,
+}
+
+impl Principal {
+    /// The server itself, for what the rules and the schedules do.
+    pub const SYSTEM: Self = Self::Key(Scope::Admin);
+
+    /// The most the principal may do anywhere, what the routes are checked
+    /// against before the services narrow it down to rooms and devices.
+    /// `None` for a user without grants, who may not do anything.
+    pub fn scope(&self) -> Option<Scope> {
+        match self {
+            Self::Key(scope) => Some(*scope),
+            Self::User(user) if user.admin => Some(Scope::Admin),
+            Self::User(user) => user.grants.iter().map(|grant| grant.role.scope()).max(),
+        }
+    }
+
+    // `Some` if the answer is the same for every room and device
+    fn house_wide(&self, role: Role) -> Option<bool> {
+        match self {
+            Self::Key(scope) => Some(*scope >= role.scope()),
+            Self::User(user) if user.admin => Some(true),
+            Self::User(_) => None,
+        }
+    }
+
+    fn granted(&self, role: Role, target: GrantTarget) -> bool {
+        match self {
+            Self::Key(_) => false,
+            Self::User(user) => user
+                .grants
+                .iter()
+                .any(|grant| grant.role >= role && grant.target == target),
+        }
+    }
+
+    /// Whether the principal may `role` the device in the room with `room_id`.
+    pub fn may(&self, role: Role, room_id: RoomId, device_id: DeviceId) -> bool {
+        self.house_wide(role).unwrap_or_else(|| {
+            self.granted(role, GrantTarget::Room(room_id))
+                || self.granted(role, GrantTarget::Device(device_id))
+        })
+    }
+
+    /// The room with only the devices the principal may `role`, `None` if
+    /// that leaves nothing. A room granted as a whole stays even if empty.
+    pub fn narrow_room(&self, role: Role, mut room: RoomInfo) -> Option<RoomInfo> {
+        let whole = self
+            .house_wide(role)
+            .unwrap_or_else(|| self.granted(role, GrantTarget::Room(room.id)));
+        if whole {
+            return Some(room);
+        }
+
+        let room_id = room.id;
+        room.devices
+            .retain(|device| self.may(role, room_id, device.id));
+        (!room.devices.is_empty()).then_some(room)
+    }
+
+    pub fn narrow_rooms(&self, role: Role, rooms: Vec<RoomInfo>) -> Vec<RoomInfo> {
+        rooms
+            .into_iter()
+            .filter_map(|room| self.narrow_room(role, room))
+            .collect()
+    }
+}
+
+/// Checks the principal may `role` the device found in the room, keys and
+/// admins do not need the room looked up.
+pub(crate) fn check_device<R: Repository>(
+    repo: &R,
+    principal: &Principal,
+    role: Role,
+    room_name: &RoomName,
+    device: &DeviceInfo,
+) -> Result<(), AccessError> {
+    let allowed = match principal.house_wide(role) {
+        Some(allowed) => allowed,
+        None => match repo.fetch_room(room_name.clone()) {
+            Ok(room) => principal.may(role, room.id, device.id),
+            Err(_) => return Err(AccessError::Unknown),
+        },
+    };
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(AccessError::Forbidden)
+    }
+}
+
+/// Checks the principal may `role` every device, for what refers to the
+/// devices by id like the rules, the schedules and the scenes. Users that
+/// are not admins may not `role` a device that is gone, there is no room
+/// left to tell by.
+pub(crate) fn check_devices<R: Repository>(
+    repo: &R,
+    principal: &Principal,
+    role: Role,
+    devices: impl IntoIterator<Item = DeviceId>,
+) -> Result<(), AccessError> {
+    if let Some(allowed) = principal.house_wide(role) {
+        return if allowed {
+            Ok(())
+        } else {
+            Err(AccessError::Forbidden)
+        };
+    }
+
+    for id in devices {
+        match repo.fetch_device_by_id(id) {
+            Ok((room_name, device)) => check_device(repo, principal, role, &room_name, &device)?,
+            Err(FetchError::NotFound) => return Err(AccessError::Forbidden),
+            Err(FetchError::Unknown) => return Err(AccessError::Unknown),
+        }
+    }
+    Ok(())
+}
+
+/// The items the principal may read every device of, `devices` tells which
+/// devices an item refers to.
+pub(crate) fn readable<R: Repository, T>(
+    repo: &R,
+    principal: &Principal,
+    items: Vec<T>,
+    devices: impl Fn(&T) -> Vec<DeviceId>,
+) -> Result<Vec<T>, AccessError> {
+    let mut readable = Vec::with_capacity(items.len());
+    for item in items {
+        match check_devices(repo, principal, Role::Read, devices(&item)) {
+            Ok(()) => readable.push(item),
+            Err(AccessError::Forbidden) => {}
+            Err(AccessError::Unknown) => return Err(AccessError::Unknown),
+        }
+    }
+    Ok(readable)
+}
+
+/// Whether the principal may see the event. Users that are not admins only
+/// get the events of the devices they may read, as long as the device is
+/// still there to tell the room by, and none of the room events.
+pub fn may_see<R: Repository>(repo: &R, principal: &Principal, event: &Event) -> bool {
+    if let Some(allowed) = principal.house_wide(Role::Read) {
+        return allowed;
+    }
+
+    let id = match event.id().map(|id| DeviceId::try_from(id.to_string())) {
+        Some(Ok(id)) => id,
+        _ => return false,
+    };
+    match repo.fetch_device_by_id(id) {
+        Ok((room_name, device)) => {
+            check_device(repo, principal, Role::Read, &room_name, &device).is_ok()
+        }
+        Err(_) => false,
+    }
+}
+
+/// Who the bearer `token` belongs to, a user session or an API key. Until
+/// there is an admin key, a stored key or a user the house is open and
+/// requests without a token act with the admin scope.
+pub fn authenticate<R: ApiKeyStore + UserStore>(
+    repo: &R,
+    admin_key: Option<&str>,
+    sessions: &Sessions,
+    token: Option<&str>,
+) -> Result<Principal, AuthError> {
+    match token {
+        Some(token) if user::is_session_token(token) => {
+            user::verify_session(repo, sessions, token).map(Principal::User)
+        }
+        Some(token) => api_key::authenticate(repo, admin_key, token).map(Principal::Key),
+        None if is_open(repo, admin_key)? => Ok(Principal::Key(Scope::Admin)),
+        None => Err(AuthError::Missing),
+    }
+}
+
+/// Whether the house is still open, without an admin key in the settings
+/// and before the first key or user. The first one has to be an admin, or
+/// nobody could manage the house after it.
+pub fn is_open<R: ApiKeyStore + UserStore>(
+    repo: &R,
+    admin_key: Option<&str>,
+) -> Result<bool, AuthError> {
+    if admin_key.is_some() {
+        return Ok(false);
+    }
+    match (repo.fetch_keys(), repo.fetch_users()) {
+        (Ok(keys), Ok(users)) => Ok(keys.is_empty() && users.is_empty()),
+        _ => Err(AuthError::Unknown),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::entity::{DeviceName, DeviceType, Grant, UserId, UserName};
+    use crate::domain::service::user::{add_user, login, LoginRequest, UserError, UserRequest};
+    use crate::repository::room::InMemoryRepository;
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::sync::Arc;
+
+    fn device() -> DeviceInfo {
+        DeviceInfo {
+            id: DeviceId::generate(),
+            name: DeviceName::socket(),
+            address: SocketAddr::from_str("127.0.0.1:8080").unwrap(),
+            device_type: DeviceType::TcpSocket,
+            groups: Vec::new(),
+        }
+    }
+
+    fn user(grants: Vec<Grant>) -> Principal {
+        Principal::User(User {
+            id: UserId::generate(),
+            name: UserName::try_from("kid".to_string()).unwrap(),
+            password_hash: String::new(),
+            admin: false,
+            grants,
+        })
+    }
+
+    #[test]
+    fn users_may_only_what_their_grants_allow() {
+        let bedroom = RoomId::generate();
+        let oven = DeviceId::generate();
+        let lamp = DeviceId::generate();
+        let kid = user(vec![
+            Grant {
+                target: GrantTarget::Room(bedroom),
+                role: Role::Control,
+            },
+            Grant {
+                target: GrantTarget::Device(oven),
+                role: Role::Read,
+            },
+        ]);
+
+        assert!(kid.may(Role::Control, bedroom, lamp));
+        assert!(kid.may(Role::Read, RoomId::generate(), oven));
+        assert!(!kid.may(Role::Control, RoomId::generate(), oven));
+        assert!(!kid.may(Role::Read, RoomId::generate(), lamp));
+        assert_eq!(kid.scope(), Some(Scope::Control));
+        assert_eq!(user(Vec::new()).scope(), None);
+
+        assert!(Principal::Key(Scope::Read).may(Role::Read, bedroom, oven));
+        assert!(!Principal::Key(Scope::Read).may(Role::Control, bedroom, oven));
+    }
+
+    #[test]
+    fn narrow_room_keeps_the_granted_devices() {
+        let oven = device();
+        let kettle = device();
+        let room = RoomInfo {
+            id: RoomId::generate(),
+            name: RoomName::kitchen(),
+            devices: vec![oven.clone(), kettle],
+        };
+        let kid = user(vec![Grant {
+            target: GrantTarget::Device(oven.id),
+            role: Role::Read,
+        }]);
+
+        match kid.narrow_room(Role::Read, room.clone()) {
+            Some(narrowed) => {
+                assert_eq!(narrowed.devices.len(), 1);
+                assert_eq!(narrowed.devices[0].id, oven.id);
+            }
+            None => unreachable!(),
+        }
+        assert!(kid.narrow_room(Role::Control, room.clone()).is_none());
+        match Principal::SYSTEM.narrow_room(Role::Control, room) {
+            Some(narrowed) => assert_eq!(narrowed.devices.len(), 2),
+            None => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn authenticate_is_open_until_the_first_key_or_user() {
+        let repo = Arc::new(InMemoryRepository::new());
+        let sessions = Sessions::new(None);
+        match authenticate(repo.as_ref(), None, &sessions, None) {
+            Ok(Principal::Key(Scope::Admin)) => {}
+            _ => unreachable!(),
+        }
+
+        // a first user that is not an admin would lock everyone out
+        let request = |name: &str, admin: bool| UserRequest {
+            name: name.to_string(),
+            password: "correct horse".to_string(),
+            admin,
+        };
+        match add_user(repo.clone(), None, request("kid", false)) {
+            Err(UserError::NotAdmin) => {}
+            _ => unreachable!(),
+        }
+        match authenticate(repo.as_ref(), None, &sessions, None) {
+            Ok(Principal::Key(Scope::Admin)) => {}
+            _ => unreachable!(),
+        }
+
+        if add_user(repo.clone(), None, request("owner", true)).is_err() {
+            unreachable!()
+        }
+        match authenticate(repo.as_ref(), None, &sessions, None) {
+            Err(AuthError::Missing) => {}
+            _ => unreachable!(),
+        }
+        if add_user(repo.clone(), None, request("kid", false)).is_err() {
+            unreachable!()
+        }
+
+        let login_request = LoginRequest {
+            name: "kid".to_string(),
+            password: "correct horse".to_string(),
+        };
+        let token = match login(repo.clone(), &sessions, login_request) {
+            Ok(session) => session.token,
+            _ => unreachable!(),
+        };
+        match authenticate(repo.as_ref(), None, &sessions, Some(&token)) {
+            Ok(Principal::User(user)) => assert_eq!(String::from(user.name), "kid"),
+            _ => unreachable!(),
+        }
+        match authenticate(repo.as_ref(), None, &sessions, Some("guessed")) {
+            Err(AuthError::Invalid) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn authenticate_takes_admin_keys_with_dots_for_keys() {
+        let repo = InMemoryRepository::new();
+        let sessions = Sessions::new(None);
+        let admin_key = "my.admin.key.0123456789";
+        match authenticate(&repo, Some(admin_key), &sessions, Some(admin_key)) {
+            Ok(Principal::Key(Scope::Admin)) => {}
+            _ => unreachable!(),
+        }
+    }
+}
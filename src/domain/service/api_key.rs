@@ -0,0 +1,206 @@
+use crate::domain::entity::{ApiKey, ApiKeyId, Scope};
+use crate::domain::service::access::{self, AuthError};
+use crate::repository::api_key::ApiKeyStore;
+use crate::repository::room::{DeleteError, FetchError, InsertError};
+use crate::repository::user::UserStore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct KeyRequest {
+    pub name: String,
+    pub scope: String,
+}
+
+#[derive(Serialize)]
+pub struct KeyResponse {
+    id: String,
+    name: String,
+    scope: String,
+    /// only told once, when the key is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+impl From<ApiKey> for KeyResponse {
+    fn from(key: ApiKey) -> Self {
+        Self {
+            id: key.id.into(),
+            name: key.name,
+            scope: key.scope.into(),
+            token: None,
+        }
+    }
+}
+
+pub enum KeyError {
+    BadRequest,
+    NotFound,
+    /// the first key of an open house has to be an admin key
+    NotAdmin,
+    Unknown,
+}
+
+/// Creates a key with a random token, the response is the only place the
+/// token appears, only its hash is stored.
+pub fn add_key<R: ApiKeyStore + UserStore>(
+    repo: Arc<R>,
+    admin_key: Option<&str>,
+    request: KeyRequest,
+) -> Result<KeyResponse, KeyError> {
+    let scope = Scope::try_from(request.scope).map_err(|_| KeyError::BadRequest)?;
+    if request.name.trim().is_empty() {
+        return Err(KeyError::BadRequest);
+    }
+    if scope != Scope::Admin
+        && access::is_open(repo.as_ref(), admin_key).map_err(|_| KeyError::Unknown)?
+    {
+        return Err(KeyError::NotAdmin);
+    }
+    let token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let key = ApiKey {
+        id: ApiKeyId::generate(),
+        name: request.name,
+        scope,
+        token_hash: hash_token(&token),
+    };
+
+    match repo.add_key(key) {
+        Ok(key) => Ok(KeyResponse {
+            token: Some(token),
+            ..KeyResponse::from(key)
+        }),
+        Err(InsertError::Conflict) | Err(InsertError::Unknown) => Err(KeyError::Unknown),
+    }
+}
+
+pub fn fetch_keys<R: ApiKeyStore>(repo: Arc<R>) -> Result<Vec<KeyResponse>, KeyError> {
+    match repo.fetch_keys() {
+        Ok(keys) => Ok(keys.into_iter().map(KeyResponse::from).collect()),
+        Err(FetchError::NotFound) => Err(KeyError::NotFound),
+        Err(FetchError::Unknown) => Err(KeyError::Unknown),
+    }
+}
+
+pub fn delete_key<R: ApiKeyStore>(repo: Arc<R>, id: String) -> Result<(), KeyError> {
+    let id = ApiKeyId::try_from(id).map_err(|_| KeyError::BadRequest)?;
+
+    match repo.delete_key(id) {
+        Ok(()) => Ok(()),
+        Err(DeleteError::NotFound) => Err(KeyError::NotFound),
+        Err(_) => Err(KeyError::Unknown),
+    }
+}
+
+/// The hex SHA-256 the key with this token is stored under. Tokens are
+/// random, so a fast hash is enough.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// The scope of the `token`, the `admin_key` from the settings is an admin
+/// key that is not stored.
+pub fn authenticate<R: ApiKeyStore>(
+    repo: &R,
+    admin_key: Option<&str>,
+    token: &str,
+) -> Result<Scope, AuthError> {
+    // compared by hash, so the time taken tells nothing about the admin key
+    let token_hash = hash_token(token);
+    if admin_key.map(hash_token) == Some(token_hash.clone()) {
+        return Ok(Scope::Admin);
+    }
+    match repo.fetch_key_by_hash(&token_hash) {
+        Ok(key) => Ok(key.scope),
+        Err(FetchError::NotFound) => Err(AuthError::Invalid),
+        Err(FetchError::Unknown) => Err(AuthError::Unknown),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::room::InMemoryRepository;
+
+    const ADMIN_KEY: &str = "a long enough admin key";
+
+    fn request(scope: &str) -> KeyRequest {
+        KeyRequest {
+            name: "dashboard".to_string(),
+            scope: scope.to_string(),
+        }
+    }
+
+    #[test]
+    fn add_key_tells_the_token_once() {
+        let repo = Arc::new(InMemoryRepository::new());
+
+        match add_key(repo.clone(), None, request("owner")) {
+            Err(KeyError::BadRequest) => {}
+            _ => unreachable!(),
+        }
+        match add_key(repo.clone(), Some(ADMIN_KEY), request("read")) {
+            Ok(key) => assert_eq!(key.token.map(|t| t.len()), Some(64)),
+            _ => unreachable!(),
+        }
+        match fetch_keys(repo) {
+            Ok(keys) => {
+                assert_eq!(keys.len(), 1);
+                assert!(keys[0].token.is_none());
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn authenticate_finds_the_key_by_its_token() {
+        let repo = Arc::new(InMemoryRepository::new());
+        let token = match add_key(repo.clone(), Some(ADMIN_KEY), request("control")) {
+            Ok(key) => key.token.unwrap(),
+            _ => unreachable!(),
+        };
+        match authenticate(repo.as_ref(), None, "guessed") {
+            Err(AuthError::Invalid) => {}
+            _ => unreachable!(),
+        }
+        match authenticate(repo.as_ref(), None, &token) {
+            Ok(Scope::Control) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn authenticate_accepts_the_admin_key_from_the_settings() {
+        let repo = InMemoryRepository::new();
+
+        match authenticate(&repo, Some(ADMIN_KEY), "guessed") {
+            Err(AuthError::Invalid) => {}
+            _ => unreachable!(),
+        }
+        match authenticate(&repo, Some(ADMIN_KEY), ADMIN_KEY) {
+            Ok(Scope::Admin) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn add_key_makes_the_first_key_of_an_open_house_an_admin_key() {
+        let repo = Arc::new(InMemoryRepository::new());
+
+        for scope in ["read", "control"] {
+            match add_key(repo.clone(), None, request(scope)) {
+                Err(KeyError::NotAdmin) => {}
+                _ => unreachable!(),
+            }
+        }
+        if add_key(repo.clone(), None, request("admin")).is_err() {
+            unreachable!()
+        }
+        match add_key(repo.clone(), None, request("read")) {
+            Ok(key) => assert_eq!(key.scope, "read"),
+            _ => unreachable!(),
+        }
+    }
+}
@@ -1,5 +1,10 @@
-use crate::domain::entity::{DeviceInfo, DeviceName, DeviceType, RoomName};
-use crate::repository::room::{DeleteError, FetchError, InsertError, Repository};
+use crate::domain::client;
+use crate::domain::entity::{
+    DeviceId, DeviceInfo, DeviceName, DeviceType, DeviceUpdate, Role, RoomName,
+};
+use crate::domain::events::{self, Event};
+use crate::domain::service::access::{self, AccessError, Principal};
+use crate::repository::room::{DeleteError, FetchError, InsertError, Repository, UpdateError};
 use std::convert::TryFrom;
 use std::net::SocketAddr;
 use std::str::FromStr;
@@ -15,11 +20,24 @@ pub struct FetchRequest {
     pub room_name: String,
     pub device_name: String,
 }
+pub struct FetchByIdRequest {
+    pub id: String,
+}
+pub struct UpdateRequest {
+    pub room_name: String,
+    pub device_name: String,
+    pub new_room_name: Option<String>,
+    pub new_device_name: Option<String>,
+    pub address: Option<String>,
+    pub device_type: Option<String>,
+}
 pub struct Response {
+    pub id: String,
     pub room_name: String,
     pub device_name: String,
     pub address: String,
     pub device_type: String,
+    pub groups: Vec<String>,
 }
 
 pub enum Error {
@@ -27,6 +45,29 @@ pub enum Error {
     Conflict,
     Unknown,
     NotFound,
+    Forbidden,
+}
+
+impl From<AccessError> for Error {
+    fn from(err: AccessError) -> Self {
+        match err {
+            AccessError::Forbidden => Self::Forbidden,
+            AccessError::Unknown => Self::Unknown,
+        }
+    }
+}
+
+impl Response {
+    pub(crate) fn new(room_name: RoomName, device_info: DeviceInfo) -> Self {
+        Self {
+            id: device_info.id.into(),
+            room_name: room_name.into(),
+            device_name: device_info.name.into(),
+            address: device_info.address.to_string(),
+            device_type: device_info.device_type.into(),
+            groups: device_info.groups.into_iter().map(String::from).collect(),
+        }
+    }
 }
 
 pub fn add_device<R: Repository>(repo: Arc<R>, request: AddRequest) -> Result<Response, Error> {
@@ -39,17 +80,25 @@ pub fn add_device<R: Repository>(repo: Arc<R>, request: AddRequest) -> Result<Re
     ) {
         (Ok(name), Ok(address), Ok(device_type)) => {
             let device_info = DeviceInfo {
+                id: DeviceId::generate(),
                 name,
                 address,
                 device_type,
+                groups: Vec::new(),
             };
             match repo.add_device(room_name.clone(), device_info) {
-                Ok(device_info) => Ok(Response {
-                    room_name: room_name.into(),
-                    device_name: device_info.name.into(),
-                    address: device_info.address.to_string(),
-                    device_type: device_info.device_type.into(),
-                }),
+                Ok(device_info) => {
+                    if let DeviceType::UdpThermo = device_info.device_type {
+                        // a failed bind leaves the thermometer without data
+                        client::listen_thermo(device_info.address).ok();
+                    }
+                    events::publish(Event::DeviceAdded {
+                        room_id: room_name.clone().into(),
+                        device_id: device_info.name.clone().into(),
+                        id: device_info.id.into(),
+                    });
+                    Ok(Response::new(room_name, device_info))
+                }
                 Err(InsertError::Conflict) => Err(Error::Conflict),
                 Err(InsertError::Unknown) => Err(Error::Unknown),
             }
@@ -58,17 +107,61 @@ pub fn add_device<R: Repository>(repo: Arc<R>, request: AddRequest) -> Result<Re
     }
 }
 
-pub fn fetch_device<R: Repository>(repo: Arc<R>, request: FetchRequest) -> Result<Response, Error> {
+/// Listens for every stored thermometer, the ones added later get their
+/// listener when they are registered.
+pub fn listen_thermometers<R: Repository>(repo: &R) -> Result<(), Error> {
+    let rooms = repo.fetch_rooms().map_err(|_| Error::Unknown)?;
+    for device_info in rooms.into_iter().flat_map(|room| room.devices) {
+        if let DeviceType::UdpThermo = device_info.device_type {
+            // a failed bind leaves the thermometer without data
+            client::listen_thermo(device_info.address).ok();
+        }
+    }
+    Ok(())
+}
+
+pub fn fetch_device<R: Repository>(
+    repo: Arc<R>,
+    request: FetchRequest,
+    principal: &Principal,
+) -> Result<Response, Error> {
     let device_name = DeviceName::try_from(request.device_name).map_err(|_| Error::BadRequest)?;
-    let room_name = RoomName::try_from(request.room_name.clone()).map_err(|_| Error::BadRequest)?;
+    let room_name = RoomName::try_from(request.room_name).map_err(|_| Error::BadRequest)?;
 
-    match repo.fetch_device(room_name, device_name) {
-        Ok(device_info) => Ok(Response {
-            room_name: request.room_name,
-            device_name: device_info.name.into(),
-            address: device_info.address.to_string(),
-            device_type: device_info.device_type.into(),
-        }),
+    match repo.fetch_device(room_name.clone(), device_name) {
+        Ok(device_info) => {
+            access::check_device(
+                repo.as_ref(),
+                principal,
+                Role::Read,
+                &room_name,
+                &device_info,
+            )?;
+            Ok(Response::new(room_name, device_info))
+        }
+        Err(FetchError::Unknown) => Err(Error::Unknown),
+        Err(FetchError::NotFound) => Err(Error::NotFound),
+    }
+}
+
+pub fn fetch_device_by_id<R: Repository>(
+    repo: Arc<R>,
+    request: FetchByIdRequest,
+    principal: &Principal,
+) -> Result<Response, Error> {
+    let id = DeviceId::try_from(request.id).map_err(|_| Error::BadRequest)?;
+
+    match repo.fetch_device_by_id(id) {
+        Ok((room_name, device_info)) => {
+            access::check_device(
+                repo.as_ref(),
+                principal,
+                Role::Read,
+                &room_name,
+                &device_info,
+            )?;
+            Ok(Response::new(room_name, device_info))
+        }
         Err(FetchError::Unknown) => Err(Error::Unknown),
         Err(FetchError::NotFound) => Err(Error::NotFound),
     }
@@ -78,13 +171,95 @@ pub fn delete_device<R: Repository>(repo: Arc<R>, request: FetchRequest) -> Resu
     let device_name = DeviceName::try_from(request.device_name).map_err(|_| Error::BadRequest)?;
     let room_name = RoomName::try_from(request.room_name).map_err(|_| Error::BadRequest)?;
 
-    match repo.delete_device(room_name, device_name) {
-        Ok(_) => Ok(()),
-        Err(DeleteError::Unknown) => Err(Error::Unknown),
+    let device_info = match repo.fetch_device(room_name.clone(), device_name.clone()) {
+        Ok(device_info) => device_info,
+        Err(FetchError::Unknown) => return Err(Error::Unknown),
+        Err(FetchError::NotFound) => return Err(Error::NotFound),
+    };
+
+    match repo.delete_device(room_name.clone(), device_name) {
+        Ok(_) => {
+            match device_info.device_type {
+                DeviceType::UdpThermo => client::stop_thermo(device_info.address),
+                DeviceType::TcpSocket => client::forget(device_info.address),
+            }
+            events::publish(Event::DeviceDeleted {
+                room_id: room_name.into(),
+                device_id: device_info.name.into(),
+                id: device_info.id.into(),
+            });
+            Ok(())
+        }
         Err(DeleteError::NotFound) => Err(Error::NotFound),
+        Err(_) => Err(Error::Unknown),
     }
 }
 
+pub fn update_device<R: Repository>(
+    repo: Arc<R>,
+    request: UpdateRequest,
+) -> Result<Response, Error> {
+    let device_name = DeviceName::try_from(request.device_name).map_err(|_| Error::BadRequest)?;
+    let room_name = RoomName::try_from(request.room_name).map_err(|_| Error::BadRequest)?;
+
+    let update = DeviceUpdate {
+        room_name: parse_optional(request.new_room_name, RoomName::try_from)?,
+        name: parse_optional(request.new_device_name, DeviceName::try_from)?,
+        address: parse_optional(request.address, |a| SocketAddr::from_str(&a))?,
+        device_type: parse_optional(request.device_type, DeviceType::try_from)?,
+        groups: None,
+    };
+    if update.room_name.is_none()
+        && update.name.is_none()
+        && update.address.is_none()
+        && update.device_type.is_none()
+    {
+        return Err(Error::BadRequest);
+    }
+
+    let target_room_name = update
+        .room_name
+        .clone()
+        .unwrap_or_else(|| room_name.clone());
+    let current = match repo.fetch_device(room_name.clone(), device_name.clone()) {
+        Ok(device_info) => device_info,
+        Err(FetchError::Unknown) => return Err(Error::Unknown),
+        Err(FetchError::NotFound) => return Err(Error::NotFound),
+    };
+
+    match repo.update_device(room_name, device_name, update) {
+        Ok(device_info) => {
+            // a thermometer on a new address needs its listener moved along,
+            // a socket's connection to the old address is closed
+            let was_thermo = matches!(current.device_type, DeviceType::UdpThermo);
+            let is_thermo = matches!(device_info.device_type, DeviceType::UdpThermo);
+            let moved = current.address != device_info.address;
+            if was_thermo && (!is_thermo || moved) {
+                client::stop_thermo(current.address);
+            }
+            if !was_thermo && (is_thermo || moved) {
+                client::forget(current.address);
+            }
+            if is_thermo {
+                client::listen_thermo(device_info.address).ok();
+            }
+            Ok(Response::new(target_room_name, device_info))
+        }
+        Err(UpdateError::Conflict) => Err(Error::Conflict),
+        Err(UpdateError::NotFound) => Err(Error::NotFound),
+        Err(UpdateError::Unknown) => Err(Error::Unknown),
+    }
+}
+
+fn parse_optional<T, E>(
+    value: Option<String>,
+    parse: impl FnOnce(String) -> Result<T, E>,
+) -> Result<Option<T>, Error> {
+    value
+        .map(|v| parse(v).map_err(|_| Error::BadRequest))
+        .transpose()
+}
+
 impl AddRequest {
     pub fn new(room_name: &str, device_name: &str, address: &str, device_type: &str) -> Self {
         Self {
@@ -99,126 +274,11 @@ impl AddRequest {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::domain::fixture::{self, unused_udp_address};
     use crate::repository::room::InMemoryRepository;
 
-    #[test]
-    fn add_device_returns_bad_request_on_invalid_input() {
-        let repo = Arc::new(InMemoryRepository::new());
-        repo.add_room(RoomName::kitchen()).ok();
-
-        // empty device name
-        let request = AddRequest {
-            room_name: RoomName::kitchen().into(),
-            device_name: DeviceName::empty().into(),
-            address: "127.0.0.1:8888".to_string(),
-            device_type: DeviceType::TcpSocket.into(),
-        };
-
-        match add_device(repo.clone(), request) {
-            Err(Error::BadRequest) => {}
-            _ => unreachable!(),
-        }
-
-        // incorrect ip adress
-        let request = AddRequest {
-            room_name: RoomName::kitchen().into(),
-            device_name: DeviceName::socket().into(),
-            address: "127.0.0:8888".to_string(),
-            device_type: DeviceType::TcpSocket.into(),
-        };
-
-        match add_device(repo.clone(), request) {
-            Err(Error::BadRequest) => {}
-            _ => unreachable!(),
-        }
-
-        // incorrect device type
-        let request = AddRequest {
-            room_name: RoomName::kitchen().into(),
-            device_name: DeviceName::socket().into(),
-            address: "127.0.0.1:8888".to_string(),
-            device_type: "dumb_socket".to_string(),
-        };
-
-        match add_device(repo, request) {
-            Err(Error::BadRequest) => {}
-            _ => unreachable!(),
-        }
-    }
-
-    #[test]
-    fn add_device_returns_not_found_error_if_target_room_not_found() {
-        let repo = Arc::new(InMemoryRepository::new());
-        repo.add_room(RoomName::kitchen()).ok();
-
-        // empty device name
-        let request = AddRequest {
-            room_name: RoomName::bathroom().into(),
-            device_name: DeviceName::socket().into(),
-            address: "127.0.0.1:8888".to_string(),
-            device_type: DeviceType::TcpSocket.into(),
-        };
-
-        match add_device(repo, request) {
-            Err(Error::Conflict) => {}
-            _ => unreachable!(),
-        }
-    }
-
-    #[test]
-    fn add_device_returns_conflict_if_device_already_exists_in_the_same_room() {
-        // in the same room_name or in general?
-
-        let repo = Arc::new(InMemoryRepository::new());
-        repo.add_room(RoomName::kitchen()).ok();
-
-        let request = AddRequest {
-            room_name: RoomName::kitchen().into(),
-            device_name: DeviceName::socket().into(),
-            address: "127.0.0.1:8888".to_string(),
-            device_type: DeviceType::TcpSocket.into(),
-        };
-        add_device(repo.clone(), request).ok();
-
-        let request_again = AddRequest {
-            room_name: RoomName::kitchen().into(),
-            device_name: DeviceName::socket().into(),
-            address: "127.0.0.1:9999".to_string(),
-            device_type: DeviceType::TcpSocket.into(),
-        };
-        match add_device(repo, request_again) {
-            Err(Error::Conflict) => {}
-            _ => unreachable!(),
-        }
-    }
-
-    #[test]
-    fn add_device_returns_conflict_if_new_device_is_on_the_same_address_across_entire_home() {
-        // in the same room_name or in general?
-
-        let repo = Arc::new(InMemoryRepository::new());
-        repo.add_room(RoomName::kitchen()).ok();
-        repo.add_room(RoomName::bathroom()).ok();
-
-        let request = AddRequest {
-            room_name: RoomName::bathroom().into(),
-            device_name: DeviceName::socket().into(),
-            address: "127.0.0.1:8888".to_string(),
-            device_type: DeviceType::TcpSocket.into(),
-        };
-        add_device(repo.clone(), request).ok();
-
-        let request_again = AddRequest {
-            room_name: RoomName::kitchen().into(),
-            device_name: DeviceName::thermo().into(),
-            address: "127.0.0.1:8888".to_string(),
-            device_type: DeviceType::TcpSocket.into(),
-        };
-        match add_device(repo, request_again) {
-            Err(Error::Conflict) => {}
-            _ => unreachable!(),
-        }
-    }
+    // what holds for every backend is in `repository::suite`, these need
+    // the failing in-memory repository
 
     #[test]
     fn add_device_returns_unknown_error_if_repo_errors_unexpectidly() {
@@ -239,28 +299,6 @@ mod tests {
         }
     }
 
-    #[test]
-    fn add_device_returns_device_info_on_success() {
-        let repo = Arc::new(InMemoryRepository::new());
-        repo.add_room(RoomName::kitchen()).ok();
-
-        let request = AddRequest {
-            room_name: RoomName::kitchen().into(),
-            device_name: DeviceName::socket().into(),
-            address: "127.0.0.1:8888".to_string(),
-            device_type: DeviceType::TcpSocket.into(),
-        };
-
-        match add_device(repo, request) {
-            Ok(result) => {
-                assert_eq!(result.device_name, String::from(DeviceName::socket()));
-                assert_eq!(result.address, String::from("127.0.0.1:8888"));
-                assert_eq!(result.device_type, String::from(DeviceType::TcpSocket));
-            }
-            _ => unreachable!(),
-        }
-    }
-
     #[test]
     fn fetch_device_returns_unknown_error_if_repo_errors_unexpectidly() {
         let repo = Arc::new(InMemoryRepository::new().with_error());
@@ -271,105 +309,38 @@ mod tests {
             device_name: DeviceName::socket().into(),
         };
 
-        match fetch_device(repo, request) {
+        match fetch_device(repo, request, &Principal::SYSTEM) {
             Err(Error::Unknown) => {}
             _ => unreachable!(),
         }
     }
 
     #[test]
-    fn fetch_device_returns_not_found_if_repo_doesnt_contain_device() {
-        let repo = Arc::new(InMemoryRepository::new());
-        repo.add_room(RoomName::kitchen()).ok();
-
-        let request = FetchRequest {
-            room_name: RoomName::kitchen().into(),
-            device_name: DeviceName::socket().into(),
-        };
-
-        match fetch_device(repo, request) {
-            Err(Error::NotFound) => {}
-            _ => unreachable!(),
-        }
-    }
-
-    #[test]
-    fn fetch_device_returns_device_info_on_success() {
-        let repo = Arc::new(InMemoryRepository::new());
+    fn listen_thermometers_listens_for_the_stored_thermometers() {
+        let repo = InMemoryRepository::new();
         repo.add_room(RoomName::kitchen()).ok();
-
-        let request = AddRequest {
-            room_name: RoomName::kitchen().into(),
-            device_name: DeviceName::socket().into(),
-            address: "127.0.0.1:8888".to_string(),
-            device_type: DeviceType::TcpSocket.into(),
-        };
-        add_device(repo.clone(), request).ok();
-
-        let request = FetchRequest {
-            room_name: RoomName::kitchen().into(),
-            device_name: DeviceName::socket().into(),
-        };
-
-        match fetch_device(repo, request) {
-            Ok(result) => {
-                assert_eq!(result.device_name, String::from(DeviceName::socket()));
-                assert_eq!(result.address, String::from("127.0.0.1:8888"));
-                assert_eq!(result.device_type, String::from(DeviceType::TcpSocket));
+        let address = unused_udp_address();
+        fixture::add_device(
+            &repo,
+            RoomName::kitchen(),
+            "thermo",
+            address,
+            DeviceType::UdpThermo,
+        );
+        assert!(client::get_thermo_status(address).is_err());
+
+        assert!(listen_thermometers(&repo).is_ok());
+        let sender = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut status = client::get_thermo_status(address);
+        for _ in 0..50 {
+            if status.is_ok() {
+                break;
             }
-            _ => unreachable!(),
-        }
-    }
-
-    #[test]
-    fn delete_device_errors_if_device_doesnt_exist() {
-        let repo = Arc::new(InMemoryRepository::new());
-        repo.add_room(RoomName::kitchen()).ok();
-
-        let request = FetchRequest {
-            room_name: RoomName::kitchen().into(),
-            device_name: DeviceName::socket().into(),
-        };
-        match delete_device(repo, request) {
-            Err(Error::NotFound) => {}
-            _ => unreachable!(),
-        }
-    }
-
-    #[test]
-    fn delete_device_errors_if_room_doesnt_exist() {
-        let repo = Arc::new(InMemoryRepository::new());
-
-        let request = FetchRequest {
-            room_name: RoomName::kitchen().into(),
-            device_name: DeviceName::socket().into(),
-        };
-        match delete_device(repo, request) {
-            Err(Error::NotFound) => {}
-            _ => unreachable!(),
-        }
-    }
-
-    #[test]
-    fn delete_device_success() {
-        let repo = Arc::new(InMemoryRepository::new());
-        repo.add_room(RoomName::kitchen()).ok();
-
-        let request = AddRequest {
-            room_name: RoomName::kitchen().into(),
-            device_name: DeviceName::socket().into(),
-            address: "127.0.0.1:8888".to_string(),
-            device_type: DeviceType::TcpSocket.into(),
-        };
-        add_device(repo.clone(), request).ok();
-
-        let request = FetchRequest {
-            room_name: RoomName::kitchen().into(),
-            device_name: DeviceName::socket().into(),
-        };
-        match delete_device(repo, request) {
-            Ok(_) => {}
-            _ => unreachable!(),
+            sender.send_to(b"{\"temperature\":20.5}", address).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            status = client::get_thermo_status(address);
         }
+        assert!(status.is_ok());
+        client::stop_thermo(address);
     }
 }
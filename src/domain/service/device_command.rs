@@ -0,0 +1,379 @@
+use crate::domain::cache::{CachedReading, StatusCache};
+use crate::domain::client::{ClientError, DeviceClient};
+use crate::domain::entity::{
+    DeviceCommand, DeviceId, DeviceInfo, DeviceName, DeviceStatus, DeviceType, GroupName, Role,
+    RoomName, SocketStatus,
+};
+use crate::domain::events::{self, Event};
+use crate::domain::service::access::{self, AccessError, Principal};
+use crate::domain::service::{group, history};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::repository::history::HistoryStore;
+use crate::repository::room::{FetchError, Repository};
+
+#[derive(Deserialize, Debug)]
+pub struct CommandRequest {
+    pub room_id: String,
+    pub device_id: String,
+    pub command: String,
+}
+
+#[derive(Serialize)]
+pub struct CommandResponse {
+    room_id: String,
+    device_id: String,
+    status: SocketStatus,
+}
+
+/// `applied` is set when every member took the command.
+#[derive(Serialize)]
+pub struct GroupCommandResponse {
+    group: String,
+    pub applied: bool,
+    devices: Vec<MemberCommandResponse>,
+}
+
+/// Either `status` or `error` is set.
+#[derive(Serialize)]
+pub struct MemberCommandResponse {
+    room_id: String,
+    device_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<SocketStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+pub enum CommandError {
+    NotFound,
+    BadRequest,
+    NotSupported,
+    DeviceUnavailable(String),
+    Forbidden,
+    Unknown,
+}
+
+impl From<AccessError> for CommandError {
+    fn from(err: AccessError) -> Self {
+        match err {
+            AccessError::Forbidden => Self::Forbidden,
+            AccessError::Unknown => Self::Unknown,
+        }
+    }
+}
+
+pub async fn send_device_command<R: Repository + HistoryStore>(
+    request: CommandRequest,
+    repo: Arc<R>,
+    client: &DeviceClient,
+    cache: &StatusCache,
+    principal: &Principal,
+) -> Result<CommandResponse, CommandError> {
+    let command = DeviceCommand::try_from(request.command).map_err(|_| CommandError::BadRequest)?;
+    let device_name =
+        DeviceName::try_from(request.device_id.clone()).map_err(|_| CommandError::BadRequest)?;
+    let room_name =
+        RoomName::try_from(request.room_id.clone()).map_err(|_| CommandError::BadRequest)?;
+
+    let device_info = match repo.fetch_device(room_name.clone(), device_name) {
+        Ok(device_info) => device_info,
+        Err(FetchError::Unknown) => return Err(CommandError::Unknown),
+        Err(FetchError::NotFound) => return Err(CommandError::NotFound),
+    };
+    access::check_device(
+        repo.as_ref(),
+        principal,
+        Role::Control,
+        &room_name,
+        &device_info,
+    )?;
+
+    match device_info {
+        DeviceInfo {
+            id,
+            address,
+            device_type: DeviceType::TcpSocket,
+            ..
+        } => match command_socket(repo.as_ref(), client, cache, id, address, command).await {
+            Ok(status) => Ok(CommandResponse {
+                room_id: request.room_id,
+                device_id: request.device_id,
+                status,
+            }),
+            Err(e) => Err(CommandError::DeviceUnavailable(e.to_string())),
+        },
+        _ => Err(CommandError::NotSupported),
+    }
+}
+
+/// Sends the command to every member of the group at once, members
+/// that do not take commands are reported as failed. `Forbidden` unless
+/// the principal may control every member.
+pub async fn send_group_command<R: Repository + HistoryStore>(
+    group_name: String,
+    command: String,
+    repo: Arc<R>,
+    client: &DeviceClient,
+    cache: &StatusCache,
+    principal: &Principal,
+) -> Result<GroupCommandResponse, CommandError> {
+    let command = DeviceCommand::try_from(command).map_err(|_| CommandError::BadRequest)?;
+    let group_name =
+        GroupName::try_from(group_name.clone()).map_err(|_| CommandError::BadRequest)?;
+    let rooms = match repo.fetch_rooms() {
+        Ok(rooms) => rooms,
+        Err(FetchError::Unknown) => return Err(CommandError::Unknown),
+        Err(FetchError::NotFound) => return Err(CommandError::NotFound),
+    };
+    let members = group::group_members(rooms.clone(), &group_name);
+    if members.is_empty() {
+        return Err(CommandError::NotFound);
+    }
+    let controllable = principal.narrow_rooms(Role::Control, rooms);
+    if group::group_members(controllable, &group_name).len() < members.len() {
+        return Err(CommandError::Forbidden);
+    }
+
+    let repo = repo.as_ref();
+    let devices: Vec<MemberCommandResponse> =
+        join_all(members.into_iter().map(|(room_name, info)| {
+            let command = command.clone();
+            async move {
+                let result = match info.device_type {
+                    DeviceType::TcpSocket => {
+                        command_socket(repo, client, cache, info.id, info.address, command)
+                            .await
+                            .map_err(|e| e.to_string())
+                    }
+                    _ => Err("device does not accept commands".to_string()),
+                };
+                MemberCommandResponse {
+                    room_id: room_name.into(),
+                    device_id: info.name.into(),
+                    status: result.as_ref().ok().cloned(),
+                    error: result.err(),
+                }
+            }
+        }))
+        .await;
+
+    Ok(GroupCommandResponse {
+        group: group_name.into(),
+        applied: devices.iter().all(|d| d.error.is_none()),
+        devices,
+    })
+}
+
+/// Sends the command to the socket, records it in the history, caches
+/// the state the socket replies with, no need to wait for the poller,
+/// and publishes the result.
+pub(crate) async fn command_socket<R: Repository + HistoryStore>(
+    repo: &R,
+    client: &DeviceClient,
+    cache: &StatusCache,
+    id: DeviceId,
+    address: SocketAddr,
+    command: DeviceCommand,
+) -> Result<SocketStatus, ClientError> {
+    let result = client.send_socket_command(address, command.clone()).await;
+    history::record_command(repo, id, command.clone(), &result);
+    if let Ok((room_name, info)) = repo.fetch_device_by_id(id) {
+        events::publish(Event::CommandSent {
+            room_id: room_name.into(),
+            device_id: info.name.into(),
+            id: id.into(),
+            command: command.into(),
+            status: result.as_ref().ok().cloned(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+    }
+    cache.insert(
+        id,
+        CachedReading {
+            result: result.clone().map(DeviceStatus::Socket),
+            read_at: history::now_millis(),
+        },
+    );
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::entity::{Grant, GrantTarget, User, UserId, UserName};
+    use crate::domain::fixture::{add_device, unused_tcp_address};
+    use crate::repository::room::InMemoryRepository;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::str::FromStr;
+    use std::thread;
+
+    fn repo_with_device(address: SocketAddr, device_type: DeviceType) -> Arc<InMemoryRepository> {
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::kitchen()).ok();
+        add_device(
+            repo.as_ref(),
+            RoomName::kitchen(),
+            "socket",
+            address,
+            device_type,
+        );
+        repo
+    }
+
+    fn request(command: &str) -> CommandRequest {
+        CommandRequest {
+            room_id: RoomName::kitchen().into(),
+            device_id: DeviceName::socket().into(),
+            command: command.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_device_command_returns_bad_request_on_unknown_command() {
+        let address = SocketAddr::from_str("127.0.0.1:8888").unwrap();
+        let repo = repo_with_device(address, DeviceType::TcpSocket);
+
+        match send_device_command(
+            request("toggle"),
+            repo,
+            &DeviceClient::default(),
+            &StatusCache::default(),
+            &Principal::SYSTEM,
+        )
+        .await
+        {
+            Err(CommandError::BadRequest) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_device_command_returns_not_found_if_repo_doesnt_contain_device() {
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::kitchen()).ok();
+
+        match send_device_command(
+            request("on"),
+            repo,
+            &DeviceClient::default(),
+            &StatusCache::default(),
+            &Principal::SYSTEM,
+        )
+        .await
+        {
+            Err(CommandError::NotFound) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_device_command_returns_not_supported_for_thermometer() {
+        let address = SocketAddr::from_str("127.0.0.1:8888").unwrap();
+        let repo = repo_with_device(address, DeviceType::UdpThermo);
+
+        match send_device_command(
+            request("on"),
+            repo,
+            &DeviceClient::default(),
+            &StatusCache::default(),
+            &Principal::SYSTEM,
+        )
+        .await
+        {
+            Err(CommandError::NotSupported) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_device_command_returns_device_unavailable_if_socket_is_offline() {
+        let repo = repo_with_device(unused_tcp_address(), DeviceType::TcpSocket);
+
+        match send_device_command(
+            request("off"),
+            repo,
+            &DeviceClient::default(),
+            &StatusCache::default(),
+            &Principal::SYSTEM,
+        )
+        .await
+        {
+            Err(CommandError::DeviceUnavailable(_)) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_device_command_returns_socket_state_on_success() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 10];
+            let bytes_read = stream.read(&mut buf).unwrap();
+            assert_eq!(&buf[..bytes_read], b"SET1");
+            stream
+                .write_all(b"{\"enabled\":true,\"power\":0.0}\n")
+                .unwrap();
+        });
+        let repo = repo_with_device(address, DeviceType::TcpSocket);
+
+        match send_device_command(
+            request("on"),
+            repo,
+            &DeviceClient::default(),
+            &StatusCache::default(),
+            &Principal::SYSTEM,
+        )
+        .await
+        {
+            Ok(result) => assert_eq!(
+                result.status,
+                SocketStatus {
+                    enabled: true,
+                    power: 0.0
+                }
+            ),
+            _ => unreachable!(),
+        }
+        handle.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn send_device_command_returns_forbidden_to_users_who_may_only_read() {
+        let address = SocketAddr::from_str("127.0.0.1:8888").unwrap();
+        let repo = repo_with_device(address, DeviceType::TcpSocket);
+        let kitchen = match repo.fetch_room(RoomName::kitchen()) {
+            Ok(room) => room.id,
+            _ => unreachable!(),
+        };
+        let kid = Principal::User(User {
+            id: UserId::generate(),
+            name: UserName::try_from("kid".to_string()).unwrap(),
+            password_hash: String::new(),
+            admin: false,
+            grants: vec![Grant {
+                target: GrantTarget::Room(kitchen),
+                role: Role::Read,
+            }],
+        });
+
+        match send_device_command(
+            request("on"),
+            repo,
+            &DeviceClient::default(),
+            &StatusCache::default(),
+            &kid,
+        )
+        .await
+        {
+            Err(CommandError::Forbidden) => {}
+            _ => unreachable!(),
+        }
+    }
+}
@@ -1,32 +1,137 @@
-use crate::domain::client;
-use crate::domain::entity::{DeviceInfo, DeviceName, DeviceType, RoomName};
+use crate::domain::cache::{CachedReading, StatusCache};
+use crate::domain::client::{ClientError, DeviceClient};
+use crate::domain::entity::{
+    DeviceId, DeviceInfo, DeviceName, DeviceStatus, DeviceType, GroupName, Role, RoomName,
+};
+use crate::domain::events::{self, Event};
+use crate::domain::service::access::{self, AccessError, Principal};
+use crate::domain::service::{group, history};
+use futures::stream::{self, StreamExt};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
 
+use crate::repository::history::HistoryStore;
 use crate::repository::room::{FetchError, Repository};
+
+// upper bound of devices polled at the same time, so that a big house
+// does not open hundreds of connections at once
+const MAX_CONCURRENT_QUERIES: usize = 8;
+
 #[derive(Deserialize, Debug)]
 pub struct StatusRequest {
     pub room_id: String,
     pub device_id: String,
 }
 
+/// Where the status comes from, the cache unless `fresh` asks for a live query.
+#[derive(Clone, Copy)]
+pub struct StatusSource<'a> {
+    pub client: &'a DeviceClient,
+    pub cache: &'a StatusCache,
+    pub fresh: bool,
+}
+
 #[derive(Serialize)]
 pub struct StatusResponse {
     room_id: String,
     device_id: String,
+    reachable: bool,
+    /// milliseconds since the device was actually queried
+    age_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<DeviceStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<DeviceError>,
+}
+
+#[derive(Serialize)]
+pub struct DeviceError {
+    kind: DeviceErrorKind,
     message: String,
 }
 
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceErrorKind {
+    Connection,
+    Timeout,
+    Io,
+    Parse,
+    NoData,
+    Unknown,
+}
+
+impl From<&ClientError> for DeviceErrorKind {
+    fn from(e: &ClientError) -> Self {
+        match e {
+            ClientError::ConnectionError(_) => Self::Connection,
+            ClientError::Timeout(_) => Self::Timeout,
+            ClientError::IoError(_) => Self::Io,
+            ClientError::ParseError(_) => Self::Parse,
+            ClientError::NoData(_) => Self::NoData,
+            ClientError::Unknown(_) => Self::Unknown,
+        }
+    }
+}
+
+impl StatusResponse {
+    fn new(room_id: String, device_id: String, reading: CachedReading) -> Self {
+        let age_ms = history::now_millis().saturating_sub(reading.read_at);
+        match reading.result {
+            Ok(status) => Self {
+                room_id,
+                device_id,
+                reachable: true,
+                age_ms,
+                status: Some(status),
+                error: None,
+            },
+            Err(e) => Self {
+                room_id,
+                device_id,
+                reachable: false,
+                age_ms,
+                status: None,
+                error: Some(DeviceError {
+                    kind: DeviceErrorKind::from(&e),
+                    message: e.to_string(),
+                }),
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RoomStatusResponse {
+    room_id: String,
+    devices: Vec<StatusResponse>,
+}
+
 pub enum StatusError {
     NotFound,
     BadRequest,
+    Forbidden,
     Unknown,
 }
 
-pub fn get_device_status<R: Repository>(
+impl From<AccessError> for StatusError {
+    fn from(err: AccessError) -> Self {
+        match err {
+            AccessError::Forbidden => Self::Forbidden,
+            AccessError::Unknown => Self::Unknown,
+        }
+    }
+}
+
+pub async fn get_device_status<R: Repository + HistoryStore>(
     request: StatusRequest,
     repo: Arc<R>,
+    source: StatusSource<'_>,
+    principal: &Principal,
 ) -> Result<StatusResponse, StatusError> {
     // try pull the DeviceInfo from the repository
     let device_name =
@@ -34,52 +139,517 @@ pub fn get_device_status<R: Repository>(
     let room_name =
         RoomName::try_from(request.room_id.clone()).map_err(|_| StatusError::BadRequest)?;
 
-    match repo.fetch_device(room_name, device_name) {
-        Ok(DeviceInfo {
-            name: _name,
-            address,
-            device_type,
-        }) => {
-            let message = get_device_status_message(address, device_type);
-            Ok(StatusResponse {
-                room_id: request.room_id,
-                device_id: request.device_id,
-                message,
-            })
+    match repo.fetch_device(room_name.clone(), device_name) {
+        Ok(device_info) => {
+            access::check_device(
+                repo.as_ref(),
+                principal,
+                Role::Read,
+                &room_name,
+                &device_info,
+            )?;
+            let reading = read_device(repo.as_ref(), source, &device_info).await;
+            Ok(StatusResponse::new(
+                request.room_id,
+                request.device_id,
+                reading,
+            ))
         }
         Err(FetchError::Unknown) => Err(StatusError::Unknown),
         Err(FetchError::NotFound) => Err(StatusError::NotFound),
     }
 }
 
-pub fn get_room_status<R: Repository>(
+/// Statuses of the devices in the room the principal may read,
+/// `Forbidden` if there are none.
+pub async fn get_room_status<R: Repository + HistoryStore>(
     room_name: String,
     repo: Arc<R>,
+    source: StatusSource<'_>,
+    principal: &Principal,
 ) -> Result<Vec<StatusResponse>, StatusError> {
     let room_name = RoomName::try_from(room_name).map_err(|_| StatusError::BadRequest)?;
 
-    match repo.fetch_devices(room_name.clone()) {
-        Ok(device_infos) => {
-            let mut responses = vec![];
-            for info in device_infos {
-                let response = StatusResponse {
-                    room_id: String::from(room_name.clone()),
-                    device_id: String::from(info.name),
-                    message: get_device_status_message(info.address, info.device_type),
-                };
-                responses.push(response);
-            }
-            Ok(responses)
+    match repo.fetch_room(room_name.clone()) {
+        Ok(room_info) => {
+            let room_info = principal
+                .narrow_room(Role::Read, room_info)
+                .ok_or(StatusError::Forbidden)?;
+            let devices = room_info
+                .devices
+                .into_iter()
+                .map(|info| (room_name.clone(), info))
+                .collect();
+            Ok(query_devices(repo.as_ref(), source, devices).await)
         }
         Err(FetchError::Unknown) => Err(StatusError::Unknown),
         Err(FetchError::NotFound) => Err(StatusError::NotFound),
     }
 }
 
-fn get_device_status_message(address: SocketAddr, device_type: DeviceType) -> String {
-    let result = match device_type {
-        DeviceType::TcpSocket => client::get_socket_status(address),
-        DeviceType::UdpThermo => client::get_thermo_status(address),
+/// Statuses of every device the principal may read, by room.
+pub async fn get_house_status<R: Repository + HistoryStore>(
+    repo: Arc<R>,
+    source: StatusSource<'_>,
+    principal: &Principal,
+) -> Result<Vec<RoomStatusResponse>, StatusError> {
+    let room_infos = match repo.fetch_rooms() {
+        Ok(room_infos) => principal.narrow_rooms(Role::Read, room_infos),
+        Err(FetchError::Unknown) => return Err(StatusError::Unknown),
+        Err(FetchError::NotFound) => return Err(StatusError::NotFound),
+    };
+
+    // the whole house shares one concurrency limit, results are regrouped after
+    let mut rooms: Vec<RoomStatusResponse> = room_infos
+        .iter()
+        .map(|room| RoomStatusResponse {
+            room_id: String::from(room.name.clone()),
+            devices: Vec::new(),
+        })
+        .collect();
+    let devices = room_infos
+        .into_iter()
+        .flat_map(|room| {
+            let name = room.name;
+            room.devices
+                .into_iter()
+                .map(move |info| (name.clone(), info))
+        })
+        .collect();
+
+    for response in query_devices(repo.as_ref(), source, devices).await {
+        if let Some(room) = rooms.iter_mut().find(|r| r.room_id == response.room_id) {
+            room.devices.push(response);
+        }
+    }
+    Ok(rooms)
+}
+
+/// Statuses of every member of the group across the rooms, `NotFound`
+/// if the group has no members the principal may read.
+pub async fn get_group_status<R: Repository + HistoryStore>(
+    group_name: String,
+    repo: Arc<R>,
+    source: StatusSource<'_>,
+    principal: &Principal,
+) -> Result<Vec<StatusResponse>, StatusError> {
+    let group_name = GroupName::try_from(group_name).map_err(|_| StatusError::BadRequest)?;
+    let room_infos = match repo.fetch_rooms() {
+        Ok(room_infos) => principal.narrow_rooms(Role::Read, room_infos),
+        Err(FetchError::Unknown) => return Err(StatusError::Unknown),
+        Err(FetchError::NotFound) => return Err(StatusError::NotFound),
+    };
+
+    let devices = group::group_members(room_infos, &group_name);
+    if devices.is_empty() {
+        return Err(StatusError::NotFound);
+    }
+    Ok(query_devices(repo.as_ref(), source, devices).await)
+}
+
+/// Queries every registered device once and refreshes the cache with the
+/// readings, readings of devices that are gone are dropped.
+pub async fn poll_devices<R: Repository + HistoryStore>(
+    repo: &R,
+    client: &DeviceClient,
+    cache: &StatusCache,
+) -> Result<(), StatusError> {
+    let room_infos = match repo.fetch_rooms() {
+        Ok(room_infos) => room_infos,
+        Err(FetchError::Unknown) => return Err(StatusError::Unknown),
+        Err(FetchError::NotFound) => return Err(StatusError::NotFound),
+    };
+    let devices: Vec<_> = room_infos
+        .into_iter()
+        .flat_map(|room| {
+            let name = room.name;
+            room.devices
+                .into_iter()
+                .map(move |info| (name.clone(), info))
+        })
+        .collect();
+
+    let ids: HashSet<DeviceId> = devices.iter().map(|(_, info)| info.id).collect();
+    let source = StatusSource {
+        client,
+        cache,
+        fresh: true,
+    };
+    query_devices(repo, source, devices).await;
+    cache.retain(&ids);
+    Ok(())
+}
+
+/// Polls the devices every `interval` until the runtime stops, a slow
+/// round delays the next one instead of piling up.
+pub async fn run_poller<R: Repository + HistoryStore>(
+    repo: Arc<R>,
+    client: DeviceClient,
+    cache: StatusCache,
+    interval: Duration,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        poll_devices(repo.as_ref(), &client, &cache).await.ok();
+    }
+}
+
+// polls the devices concurrently, failures end up in the message
+// of the device they belong to and keep the input order
+async fn query_devices<R: Repository + HistoryStore>(
+    repo: &R,
+    source: StatusSource<'_>,
+    devices: Vec<(RoomName, DeviceInfo)>,
+) -> Vec<StatusResponse> {
+    stream::iter(devices)
+        .map(|(room_name, info)| async move {
+            let reading = read_device(repo, source, &info).await;
+            StatusResponse::new(String::from(room_name), String::from(info.name), reading)
+        })
+        .buffered(MAX_CONCURRENT_QUERIES)
+        .collect()
+        .await
+}
+
+// a device without a cached reading yet is queried live, every live
+// reading goes to the history, refreshes the cache and, if it differs
+// from the one before, is published as a status change
+pub(crate) async fn read_device<R: Repository + HistoryStore>(
+    repo: &R,
+    source: StatusSource<'_>,
+    info: &DeviceInfo,
+) -> CachedReading {
+    let previous = source.cache.get(info.id);
+    if !source.fresh {
+        if let Some(reading) = previous {
+            return reading;
+        }
+    }
+
+    let result = query_device_status(source.client, info.address, info.device_type.clone()).await;
+    history::record_reading(repo, info.id, &result);
+    let reading = CachedReading {
+        result,
+        read_at: history::now_millis(),
     };
-    result.unwrap_or_else(|e| e.to_string())
+    source.cache.insert(info.id, reading.clone());
+
+    let previous_status = previous.map(|p| p.result.ok());
+    let status = reading.result.as_ref().ok().cloned();
+    if previous_status.as_ref() != Some(&status) {
+        // the device may have been deleted while it was read
+        if let Ok((room_name, _)) = repo.fetch_device_by_id(info.id) {
+            events::publish(Event::StatusChanged {
+                room_id: room_name.into(),
+                device_id: info.name.clone().into(),
+                id: info.id.into(),
+                reachable: status.is_some(),
+                status,
+            });
+        }
+    }
+    reading
+}
+
+async fn query_device_status(
+    client: &DeviceClient,
+    address: SocketAddr,
+    device_type: DeviceType,
+) -> Result<DeviceStatus, ClientError> {
+    match device_type {
+        DeviceType::TcpSocket => client
+            .get_socket_status(address)
+            .await
+            .map(DeviceStatus::Socket),
+        DeviceType::UdpThermo => client
+            .get_thermo_status(address)
+            .await
+            .map(DeviceStatus::Thermo),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::entity::{DeviceId, SocketStatus};
+    use crate::domain::fixture::{add_device, unused_tcp_address, unused_udp_address};
+    use crate::repository::room::InMemoryRepository;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // answers a single GET after `delay`, like a slow smart socket would
+    fn spawn_slow_socket(delay: Duration) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 10];
+            let _ = stream.read(&mut buf);
+            thread::sleep(delay);
+            stream
+                .write_all(b"{\"enabled\":false,\"power\":0.0}\n")
+                .ok();
+        });
+        address
+    }
+
+    fn source<'a>(client: &'a DeviceClient, cache: &'a StatusCache) -> StatusSource<'a> {
+        StatusSource {
+            client,
+            cache,
+            fresh: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn get_device_status_reports_timeout_if_socket_never_answers() {
+        // accepted by the OS backlog, but nobody ever replies
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::kitchen()).ok();
+        add_device(
+            repo.as_ref(),
+            RoomName::kitchen(),
+            "socket",
+            listener.local_addr().unwrap(),
+            DeviceType::TcpSocket,
+        );
+
+        let request = StatusRequest {
+            room_id: RoomName::kitchen().into(),
+            device_id: DeviceName::socket().into(),
+        };
+        let client = DeviceClient::new(Duration::from_millis(100), Duration::from_millis(100));
+        match get_device_status(
+            request,
+            repo,
+            source(&client, &StatusCache::default()),
+            &Principal::SYSTEM,
+        )
+        .await
+        {
+            Ok(result) => {
+                assert!(!result.reachable);
+                assert_eq!(result.error.unwrap().kind, DeviceErrorKind::Timeout);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn get_room_status_queries_devices_concurrently() {
+        let delay = Duration::from_millis(300);
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::kitchen()).ok();
+        for name in ["socket_1", "socket_2", "socket_3"] {
+            add_device(
+                repo.as_ref(),
+                RoomName::kitchen(),
+                name,
+                spawn_slow_socket(delay),
+                DeviceType::TcpSocket,
+            );
+        }
+
+        let client = DeviceClient::default();
+        let started = Instant::now();
+        let result = get_room_status(
+            RoomName::kitchen().into(),
+            repo,
+            source(&client, &StatusCache::default()),
+            &Principal::SYSTEM,
+        )
+        .await;
+        assert!(started.elapsed() < delay * 2);
+
+        match result {
+            Ok(result) => {
+                let device_ids: Vec<_> = result.iter().map(|r| r.device_id.as_str()).collect();
+                assert_eq!(device_ids, vec!["socket_1", "socket_2", "socket_3"]);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn get_house_status_groups_by_room_and_reports_failures_inline() {
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::kitchen()).ok();
+        repo.add_room(RoomName::bathroom()).ok();
+        add_device(
+            repo.as_ref(),
+            RoomName::kitchen(),
+            "socket_1",
+            spawn_slow_socket(Duration::ZERO),
+            DeviceType::TcpSocket,
+        );
+        add_device(
+            repo.as_ref(),
+            RoomName::bathroom(),
+            "socket_2",
+            unused_tcp_address(),
+            DeviceType::TcpSocket,
+        );
+
+        let client = DeviceClient::default();
+        match get_house_status(
+            repo,
+            source(&client, &StatusCache::default()),
+            &Principal::SYSTEM,
+        )
+        .await
+        {
+            Ok(result) => {
+                assert_eq!(result.len(), 2);
+                assert_eq!(result[0].room_id, "kitchen");
+                assert_eq!(
+                    result[0].devices[0].status,
+                    Some(DeviceStatus::Socket(SocketStatus {
+                        enabled: false,
+                        power: 0.0
+                    }))
+                );
+                assert_eq!(result[1].room_id, "bathroom");
+                assert!(!result[1].devices[0].reachable);
+                assert_eq!(
+                    result[1].devices[0].error.as_ref().unwrap().kind,
+                    DeviceErrorKind::Connection
+                );
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn get_device_status_reports_no_data_for_silent_thermometer() {
+        let address = unused_udp_address();
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::bathroom()).ok();
+        let id = add_device(
+            repo.as_ref(),
+            RoomName::bathroom(),
+            "thermo",
+            address,
+            DeviceType::UdpThermo,
+        );
+
+        let request = StatusRequest {
+            room_id: RoomName::bathroom().into(),
+            device_id: DeviceName::thermo().into(),
+        };
+        let client = DeviceClient::default();
+        let cache = StatusCache::default();
+        match get_device_status(
+            request,
+            repo.clone(),
+            source(&client, &cache),
+            &Principal::SYSTEM,
+        )
+        .await
+        {
+            Ok(result) => {
+                assert!(!result.reachable);
+                assert_eq!(result.error.unwrap().kind, DeviceErrorKind::NoData);
+            }
+            _ => unreachable!(),
+        }
+        crate::domain::client::stop_thermo(address);
+
+        // the failed reading still ends up in the history
+        match repo.fetch_history(id, 0, u64::MAX) {
+            Ok(entries) => assert_eq!(entries.len(), 1),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn get_device_status_serves_cached_reading_unless_fresh_is_requested() {
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::kitchen()).ok();
+        let id = add_device(
+            repo.as_ref(),
+            RoomName::kitchen(),
+            "socket",
+            unused_tcp_address(),
+            DeviceType::TcpSocket,
+        );
+
+        let client = DeviceClient::default();
+        let cache = StatusCache::default();
+        let status = DeviceStatus::Socket(SocketStatus {
+            enabled: true,
+            power: 1.5,
+        });
+        cache.insert(
+            id,
+            CachedReading {
+                result: Ok(status.clone()),
+                read_at: history::now_millis() - 5_000,
+            },
+        );
+        let request = || StatusRequest {
+            room_id: RoomName::kitchen().into(),
+            device_id: DeviceName::socket().into(),
+        };
+
+        match get_device_status(
+            request(),
+            repo.clone(),
+            source(&client, &cache),
+            &Principal::SYSTEM,
+        )
+        .await
+        {
+            Ok(result) => {
+                assert_eq!(result.status, Some(status));
+                assert!(result.age_ms >= 5_000);
+            }
+            _ => unreachable!(),
+        }
+
+        let fresh = StatusSource {
+            fresh: true,
+            ..source(&client, &cache)
+        };
+        match get_device_status(request(), repo, fresh, &Principal::SYSTEM).await {
+            Ok(result) => assert!(!result.reachable),
+            _ => unreachable!(),
+        }
+        // the live reading replaced the cached one
+        assert!(cache.get(id).unwrap().result.is_err());
+    }
+
+    #[tokio::test]
+    async fn poll_devices_refreshes_cache_and_forgets_deleted_devices() {
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::kitchen()).ok();
+        let id = add_device(
+            repo.as_ref(),
+            RoomName::kitchen(),
+            "socket",
+            spawn_slow_socket(Duration::ZERO),
+            DeviceType::TcpSocket,
+        );
+        let gone = DeviceId::generate();
+        let cache = StatusCache::default();
+        cache.insert(
+            gone,
+            CachedReading {
+                result: Err(ClientError::NoData("gone".into())),
+                read_at: 0,
+            },
+        );
+
+        assert!(
+            poll_devices(repo.as_ref(), &DeviceClient::default(), &cache)
+                .await
+                .is_ok()
+        );
+        assert!(cache.get(id).unwrap().result.is_ok());
+        assert!(cache.get(gone).is_none());
+    }
 }
@@ -0,0 +1,609 @@
+use crate::domain::entity::{
+    DeviceEvent, DeviceInfo, DeviceName, DeviceStatus, DeviceType, HistoryEntry, Role, RoomName,
+};
+use crate::domain::service::access::{self, AccessError, Principal};
+use crate::domain::service::history;
+use crate::repository::history::HistoryStore;
+use crate::repository::room::{FetchError, Repository};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+const HOUR_MS: u64 = 60 * 60 * 1000;
+const DAY_MS: u64 = 24 * HOUR_MS;
+
+// a reading is trusted for at most this many poll intervals, a longer gap
+// between two readings means the poller or the device was down and is not
+// counted
+const MAX_SAMPLE_GAP_POLLS: u64 = 3;
+
+// keeps a careless `from=0&bucket=hour` from building millions of buckets
+const MAX_BUCKETS: u64 = 10_000;
+
+/// Energy is integrated from the socket readings kept in the history,
+/// so it only reaches back as far as the history retention.
+pub struct EnergyRequest {
+    pub room_id: Option<String>,
+    pub device_id: Option<String>,
+    pub bucket: Option<String>,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BucketSize {
+    Hour,
+    Day,
+}
+
+impl TryFrom<String> for BucketSize {
+    type Error = ();
+
+    fn try_from(b: String) -> Result<Self, Self::Error> {
+        match b.as_str() {
+            "hour" => Ok(Self::Hour),
+            "day" => Ok(Self::Day),
+            _ => Err(()),
+        }
+    }
+}
+
+impl BucketSize {
+    fn millis(self) -> u64 {
+        match self {
+            Self::Hour => HOUR_MS,
+            Self::Day => DAY_MS,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct EnergyBucket {
+    start: u64,
+    end: u64,
+    kwh: f64,
+}
+
+#[derive(Serialize)]
+pub struct DeviceEnergyResponse {
+    room_id: String,
+    device_id: String,
+    total_kwh: f64,
+    buckets: Vec<EnergyBucket>,
+}
+
+#[derive(Serialize)]
+pub struct RoomEnergyResponse {
+    room_id: String,
+    total_kwh: f64,
+    buckets: Vec<EnergyBucket>,
+    devices: Vec<DeviceEnergyResponse>,
+}
+
+#[derive(Serialize)]
+pub struct HouseEnergyResponse {
+    total_kwh: f64,
+    buckets: Vec<EnergyBucket>,
+    /// the room burning the most electricity comes first
+    rooms: Vec<RoomEnergyResponse>,
+}
+
+pub enum EnergyError {
+    NotFound,
+    BadRequest,
+    Forbidden,
+    Unknown,
+}
+
+impl From<AccessError> for EnergyError {
+    fn from(err: AccessError) -> Self {
+        match err {
+            AccessError::Forbidden => Self::Forbidden,
+            AccessError::Unknown => Self::Unknown,
+        }
+    }
+}
+
+// the bucket grid shared by every device of one request, buckets are
+// aligned to whole UTC hours or days and cover `from..to`
+#[derive(Clone, Copy)]
+struct Range {
+    from: u64,
+    to: u64,
+    bucket_ms: u64,
+    start: u64,
+    max_gap_ms: u64,
+}
+
+impl Range {
+    fn parse(request: &EnergyRequest, poll_interval: Duration) -> Result<Self, EnergyError> {
+        let bucket = match request.bucket.clone() {
+            Some(bucket) => BucketSize::try_from(bucket).map_err(|_| EnergyError::BadRequest)?,
+            None => BucketSize::Day,
+        };
+        let bucket_ms = bucket.millis();
+        let to = request.to.unwrap_or_else(history::now_millis);
+        let from = request.from.unwrap_or_else(|| match bucket {
+            BucketSize::Hour => to.saturating_sub(DAY_MS),
+            BucketSize::Day => to.saturating_sub(7 * DAY_MS),
+        });
+        if from >= to || (to - from) / bucket_ms >= MAX_BUCKETS {
+            return Err(EnergyError::BadRequest);
+        }
+
+        Ok(Self {
+            from,
+            to,
+            bucket_ms,
+            start: from / bucket_ms * bucket_ms,
+            max_gap_ms: poll_interval.as_millis() as u64 * MAX_SAMPLE_GAP_POLLS,
+        })
+    }
+
+    fn len(&self) -> usize {
+        ((self.to - self.start).div_ceil(self.bucket_ms)) as usize
+    }
+
+    fn buckets(&self, watt_hours: &[f64]) -> Vec<EnergyBucket> {
+        watt_hours
+            .iter()
+            .enumerate()
+            .map(|(idx, wh)| {
+                let start = self.start + idx as u64 * self.bucket_ms;
+                EnergyBucket {
+                    start,
+                    end: start + self.bucket_ms,
+                    kwh: wh / 1000.0,
+                }
+            })
+            .collect()
+    }
+}
+
+pub fn get_device_energy<R: Repository + HistoryStore>(
+    repo: Arc<R>,
+    request: EnergyRequest,
+    poll_interval: Duration,
+    principal: &Principal,
+) -> Result<DeviceEnergyResponse, EnergyError> {
+    let range = Range::parse(&request, poll_interval)?;
+    let room_name = RoomName::try_from(request.room_id.unwrap_or_default())
+        .map_err(|_| EnergyError::BadRequest)?;
+    let device_name = DeviceName::try_from(request.device_id.unwrap_or_default())
+        .map_err(|_| EnergyError::BadRequest)?;
+
+    match repo.fetch_device(room_name.clone(), device_name) {
+        Ok(device_info) => {
+            access::check_device(
+                repo.as_ref(),
+                principal,
+                Role::Read,
+                &room_name,
+                &device_info,
+            )?;
+            let watt_hours = device_watt_hours(repo.as_ref(), &device_info, range)?;
+            Ok(device_response(room_name, device_info, &watt_hours, range))
+        }
+        Err(FetchError::Unknown) => Err(EnergyError::Unknown),
+        Err(FetchError::NotFound) => Err(EnergyError::NotFound),
+    }
+}
+
+/// Energy of the devices in the room the principal may read, `Forbidden`
+/// if there are none.
+pub fn get_room_energy<R: Repository + HistoryStore>(
+    repo: Arc<R>,
+    request: EnergyRequest,
+    poll_interval: Duration,
+    principal: &Principal,
+) -> Result<RoomEnergyResponse, EnergyError> {
+    let range = Range::parse(&request, poll_interval)?;
+    let room_name = RoomName::try_from(request.room_id.unwrap_or_default())
+        .map_err(|_| EnergyError::BadRequest)?;
+
+    match repo.fetch_room(room_name.clone()) {
+        Ok(room_info) => match principal.narrow_room(Role::Read, room_info) {
+            Some(room_info) => room_energy(repo.as_ref(), room_name, room_info.devices, range),
+            None => Err(EnergyError::Forbidden),
+        },
+        Err(FetchError::Unknown) => Err(EnergyError::Unknown),
+        Err(FetchError::NotFound) => Err(EnergyError::NotFound),
+    }
+}
+
+/// Energy of every device the principal may read.
+pub fn get_house_energy<R: Repository + HistoryStore>(
+    repo: Arc<R>,
+    request: EnergyRequest,
+    poll_interval: Duration,
+    principal: &Principal,
+) -> Result<HouseEnergyResponse, EnergyError> {
+    let range = Range::parse(&request, poll_interval)?;
+    let room_infos = match repo.fetch_rooms() {
+        Ok(room_infos) => principal.narrow_rooms(Role::Read, room_infos),
+        Err(FetchError::Unknown) => return Err(EnergyError::Unknown),
+        Err(FetchError::NotFound) => return Err(EnergyError::NotFound),
+    };
+
+    let mut rooms = room_infos
+        .into_iter()
+        .map(|room| room_energy(repo.as_ref(), room.name, room.devices, range))
+        .collect::<Result<Vec<_>, _>>()?;
+    rooms.sort_by(|a, b| b.total_kwh.total_cmp(&a.total_kwh));
+
+    let buckets = sum_buckets(rooms.iter().map(|r| &r.buckets), range);
+    Ok(HouseEnergyResponse {
+        total_kwh: rooms.iter().map(|r| r.total_kwh).sum(),
+        buckets,
+        rooms,
+    })
+}
+
+fn room_energy<H: HistoryStore>(
+    store: &H,
+    room_name: RoomName,
+    device_infos: Vec<DeviceInfo>,
+    range: Range,
+) -> Result<RoomEnergyResponse, EnergyError> {
+    // thermometers don't draw any measured power
+    let devices = device_infos
+        .into_iter()
+        .filter(|d| matches!(d.device_type, DeviceType::TcpSocket))
+        .map(|device_info| {
+            let watt_hours = device_watt_hours(store, &device_info, range)?;
+            Ok(device_response(
+                room_name.clone(),
+                device_info,
+                &watt_hours,
+                range,
+            ))
+        })
+        .collect::<Result<Vec<_>, EnergyError>>()?;
+
+    Ok(RoomEnergyResponse {
+        room_id: room_name.into(),
+        total_kwh: devices.iter().map(|d| d.total_kwh).sum(),
+        buckets: sum_buckets(devices.iter().map(|d| &d.buckets), range),
+        devices,
+    })
+}
+
+fn device_response(
+    room_name: RoomName,
+    device_info: DeviceInfo,
+    watt_hours: &[f64],
+    range: Range,
+) -> DeviceEnergyResponse {
+    DeviceEnergyResponse {
+        room_id: room_name.into(),
+        device_id: device_info.name.into(),
+        total_kwh: watt_hours.iter().sum::<f64>() / 1000.0,
+        buckets: range.buckets(watt_hours),
+    }
+}
+
+fn sum_buckets<'a>(
+    all: impl Iterator<Item = &'a Vec<EnergyBucket>>,
+    range: Range,
+) -> Vec<EnergyBucket> {
+    let mut watt_hours = vec![0.0; range.len()];
+    for buckets in all {
+        for (total, bucket) in watt_hours.iter_mut().zip(buckets) {
+            *total += bucket.kwh * 1000.0;
+        }
+    }
+    range.buckets(&watt_hours)
+}
+
+fn device_watt_hours<H: HistoryStore>(
+    store: &H,
+    device_info: &DeviceInfo,
+    range: Range,
+) -> Result<Vec<f64>, EnergyError> {
+    // the reading just before `from` tells the power at `from`
+    let entries = store
+        .fetch_history(
+            device_info.id,
+            range.from.saturating_sub(range.max_gap_ms),
+            range.to,
+        )
+        .map_err(|_| EnergyError::Unknown)?;
+    Ok(integrate(&entries, range, history::now_millis()))
+}
+
+// power in watts of a reading, `None` for readings that failed
+fn power(entry: &HistoryEntry) -> Option<f64> {
+    let status = match &entry.event {
+        DeviceEvent::Reading {
+            status: Some(DeviceStatus::Socket(status)),
+            ..
+        } => status,
+        DeviceEvent::Command {
+            status: Some(status),
+            ..
+        } => status,
+        _ => return None,
+    };
+    Some(if status.enabled {
+        status.power as f64
+    } else {
+        0.0
+    })
+}
+
+// every reading is assumed to hold until the next one, up to
+// `max_gap_ms`, the last one holds until `now` at most
+fn integrate(entries: &[HistoryEntry], range: Range, now: u64) -> Vec<f64> {
+    let mut watt_hours = vec![0.0; range.len()];
+    for (idx, entry) in entries.iter().enumerate() {
+        let watts = match power(entry) {
+            Some(watts) => watts,
+            None => continue,
+        };
+        let until = entries
+            .get(idx + 1)
+            .map(|next| next.timestamp)
+            .unwrap_or(now)
+            .min(entry.timestamp + range.max_gap_ms);
+
+        let mut start = entry.timestamp.max(range.from);
+        let end = until.min(range.to);
+        while start < end {
+            let bucket = ((start - range.start) / range.bucket_ms) as usize;
+            let bucket_end = (range.start + (bucket as u64 + 1) * range.bucket_ms).min(end);
+            watt_hours[bucket] += watts * (bucket_end - start) as f64 / HOUR_MS as f64;
+            start = bucket_end;
+        }
+    }
+    watt_hours
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::entity::{DeviceId, SocketStatus};
+    use crate::repository::room::InMemoryRepository;
+    use float_cmp::approx_eq;
+
+    const MINUTE_MS: u64 = 60 * 1000;
+    // three polls make for readings trusted five minutes
+    const POLL_INTERVAL: Duration = Duration::from_secs(100);
+
+    fn reading(device_id: DeviceId, timestamp: u64, enabled: bool, power: f32) -> HistoryEntry {
+        HistoryEntry {
+            device_id,
+            timestamp,
+            event: DeviceEvent::Reading {
+                status: Some(DeviceStatus::Socket(SocketStatus { enabled, power })),
+                error: None,
+            },
+        }
+    }
+
+    fn failed_reading(device_id: DeviceId, timestamp: u64) -> HistoryEntry {
+        HistoryEntry {
+            device_id,
+            timestamp,
+            event: DeviceEvent::Reading {
+                status: None,
+                error: Some("timeout".to_string()),
+            },
+        }
+    }
+
+    fn request(room_id: &str, device_id: Option<&str>, bucket: &str) -> EnergyRequest {
+        EnergyRequest {
+            room_id: Some(room_id.to_string()),
+            device_id: device_id.map(str::to_string),
+            bucket: Some(bucket.to_string()),
+            from: Some(0),
+            to: Some(2 * HOUR_MS),
+        }
+    }
+
+    fn add_socket(
+        repo: &InMemoryRepository,
+        room_name: RoomName,
+        name: &str,
+        port: u16,
+    ) -> DeviceId {
+        let device_info = DeviceInfo {
+            id: DeviceId::generate(),
+            name: DeviceName::try_from(name.to_string()).unwrap(),
+            address: format!("127.0.0.1:{}", port).parse().unwrap(),
+            device_type: DeviceType::TcpSocket,
+            groups: Vec::new(),
+        };
+        let id = device_info.id;
+        repo.add_device(room_name, device_info).ok();
+        id
+    }
+
+    #[test]
+    fn integrate_splits_readings_across_buckets() {
+        let id = DeviceId::generate();
+        let range = Range {
+            from: 0,
+            to: 2 * HOUR_MS,
+            bucket_ms: HOUR_MS,
+            start: 0,
+            max_gap_ms: 5 * MINUTE_MS,
+        };
+        // 1000 W from 58 to 62 minutes, then switched off
+        let entries = vec![
+            reading(id, 58 * MINUTE_MS, true, 1000.0),
+            reading(id, 60 * MINUTE_MS, true, 1000.0),
+            reading(id, 62 * MINUTE_MS, false, 1000.0),
+        ];
+
+        let watt_hours = integrate(&entries, range, 2 * HOUR_MS);
+        assert!(approx_eq!(
+            f64,
+            watt_hours[0],
+            1000.0 * 2.0 / 60.0,
+            ulps = 4
+        ));
+        assert!(approx_eq!(
+            f64,
+            watt_hours[1],
+            1000.0 * 2.0 / 60.0,
+            ulps = 4
+        ));
+    }
+
+    #[test]
+    fn integrate_skips_gaps_and_failed_readings() {
+        let id = DeviceId::generate();
+        let range = Range {
+            from: 0,
+            to: HOUR_MS,
+            bucket_ms: HOUR_MS,
+            start: 0,
+            max_gap_ms: 5 * MINUTE_MS,
+        };
+        let entries = vec![
+            // trusted for five minutes only, the next reading comes much later
+            reading(id, 0, true, 60.0),
+            failed_reading(id, 30 * MINUTE_MS),
+            reading(id, 40 * MINUTE_MS, true, 60.0),
+            reading(id, 41 * MINUTE_MS, true, 60.0),
+        ];
+
+        // the last reading holds until `now`
+        let watt_hours = integrate(&entries, range, 42 * MINUTE_MS);
+        assert!(approx_eq!(f64, watt_hours[0], 60.0 * 7.0 / 60.0, ulps = 4));
+    }
+
+    #[test]
+    fn get_room_energy_sums_devices_and_skips_thermometers() {
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::kitchen()).ok();
+        let kettle = add_socket(&repo, RoomName::kitchen(), "kettle", 8080);
+        let fridge = add_socket(&repo, RoomName::kitchen(), "fridge", 8081);
+        let thermo = DeviceInfo {
+            id: DeviceId::generate(),
+            name: DeviceName::thermo(),
+            address: "127.0.0.1:9001".parse().unwrap(),
+            device_type: DeviceType::UdpThermo,
+            groups: Vec::new(),
+        };
+        repo.add_device(RoomName::kitchen(), thermo).ok();
+        for minute in 0..=5 {
+            repo.record_event(reading(kettle, minute * MINUTE_MS, true, 2000.0))
+                .ok();
+            repo.record_event(reading(fridge, minute * MINUTE_MS, true, 100.0))
+                .ok();
+        }
+
+        match get_room_energy(
+            repo,
+            request("kitchen", None, "hour"),
+            POLL_INTERVAL,
+            &Principal::SYSTEM,
+        ) {
+            Ok(result) => {
+                assert_eq!(result.devices.len(), 2);
+                // 2100 W for the five minutes between the first and the last reading,
+                // plus the last one trusted for another five minutes
+                let expected = 2100.0 * 10.0 / 60.0 / 1000.0;
+                assert!(approx_eq!(f64, result.total_kwh, expected, ulps = 4));
+                assert!(approx_eq!(f64, result.buckets[0].kwh, expected, ulps = 4));
+                assert!(approx_eq!(f64, result.buckets[1].kwh, 0.0, ulps = 4));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn get_house_energy_puts_hungriest_room_first() {
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::bathroom()).ok();
+        repo.add_room(RoomName::kitchen()).ok();
+        let lamp = add_socket(&repo, RoomName::bathroom(), "lamp", 8080);
+        let kettle = add_socket(&repo, RoomName::kitchen(), "kettle", 8081);
+        repo.record_event(reading(lamp, 0, true, 10.0)).ok();
+        repo.record_event(reading(kettle, 0, true, 2000.0)).ok();
+
+        match get_house_energy(
+            repo,
+            request("", None, "day"),
+            POLL_INTERVAL,
+            &Principal::SYSTEM,
+        ) {
+            Ok(result) => {
+                let rooms: Vec<_> = result.rooms.iter().map(|r| r.room_id.as_str()).collect();
+                assert_eq!(rooms, vec!["kitchen", "bathroom"]);
+                assert_eq!(result.buckets.len(), 1);
+                assert!(approx_eq!(
+                    f64,
+                    result.total_kwh,
+                    2010.0 * 5.0 / 60.0 / 1000.0,
+                    ulps = 4
+                ));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn get_device_energy_trusts_readings_for_three_poll_intervals() {
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::kitchen()).ok();
+        let heater = add_socket(&repo, RoomName::kitchen(), "heater", 8080);
+        for minute in [0, 10, 20] {
+            repo.record_event(reading(heater, minute * MINUTE_MS, true, 100.0))
+                .ok();
+        }
+
+        match get_device_energy(
+            repo,
+            request("kitchen", Some("heater"), "hour"),
+            Duration::from_secs(10 * 60),
+            &Principal::SYSTEM,
+        ) {
+            Ok(result) => {
+                // twenty minutes between the readings, the last one trusted for thirty more
+                let expected = 100.0 * 50.0 / 60.0 / 1000.0;
+                assert!(approx_eq!(f64, result.total_kwh, expected, ulps = 4));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn get_device_energy_rejects_bad_ranges_and_buckets() {
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::kitchen()).ok();
+        add_socket(&repo, RoomName::kitchen(), "kettle", 8080);
+
+        let reversed = EnergyRequest {
+            from: Some(2 * HOUR_MS),
+            to: Some(HOUR_MS),
+            ..request("kitchen", Some("kettle"), "hour")
+        };
+        let too_many = EnergyRequest {
+            to: Some(MAX_BUCKETS * HOUR_MS),
+            ..request("kitchen", Some("kettle"), "hour")
+        };
+        for request in [
+            reversed,
+            too_many,
+            request("kitchen", Some("kettle"), "week"),
+        ] {
+            match get_device_energy(repo.clone(), request, POLL_INTERVAL, &Principal::SYSTEM) {
+                Err(EnergyError::BadRequest) => {}
+                _ => unreachable!(),
+            }
+        }
+
+        match get_device_energy(
+            repo,
+            request("kitchen", Some("socket"), "hour"),
+            POLL_INTERVAL,
+            &Principal::SYSTEM,
+        ) {
+            Err(EnergyError::NotFound) => {}
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use crate::domain::entity::{
+    DeviceId, DeviceInfo, DeviceUpdate, GroupName, Role, RoomInfo, RoomName,
+};
+use crate::domain::service::access::Principal;
+use crate::domain::service::device;
+use crate::repository::room::{FetchError, Repository, UpdateError};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[derive(Serialize)]
+pub struct GroupResponse {
+    name: String,
+    devices: Vec<MemberResponse>,
+}
+
+#[derive(Serialize)]
+pub struct MemberResponse {
+    id: String,
+    room_id: String,
+    device_id: String,
+}
+
+pub enum GroupError {
+    BadRequest,
+    NotFound,
+    Conflict,
+    Unknown,
+}
+
+/// Every group that has at least one member the principal may read,
+/// ordered by name.
+pub fn fetch_groups<R: Repository>(
+    repo: Arc<R>,
+    principal: &Principal,
+) -> Result<Vec<GroupResponse>, GroupError> {
+    let rooms = principal.narrow_rooms(Role::Read, fetch_rooms(repo.as_ref())?);
+
+    let mut groups: BTreeMap<GroupName, Vec<MemberResponse>> = BTreeMap::new();
+    for room in rooms {
+        for info in room.devices {
+            for group in info.groups.clone() {
+                groups
+                    .entry(group)
+                    .or_default()
+                    .push(MemberResponse::new(room.name.clone(), info.clone()));
+            }
+        }
+    }
+    Ok(groups
+        .into_iter()
+        .map(|(name, devices)| GroupResponse {
+            name: name.into(),
+            devices,
+        })
+        .collect())
+}
+
+/// The members of the group the principal may read, `NotFound` if none.
+pub fn fetch_group<R: Repository>(
+    repo: Arc<R>,
+    name: String,
+    principal: &Principal,
+) -> Result<GroupResponse, GroupError> {
+    let group = GroupName::try_from(name.clone()).map_err(|_| GroupError::BadRequest)?;
+    let rooms = principal.narrow_rooms(Role::Read, fetch_rooms(repo.as_ref())?);
+
+    let devices: Vec<_> = group_members(rooms, &group)
+        .into_iter()
+        .map(|(room_name, info)| MemberResponse::new(room_name, info))
+        .collect();
+    if devices.is_empty() {
+        return Err(GroupError::NotFound);
+    }
+    Ok(GroupResponse { name, devices })
+}
+
+/// Adds the device with the stable `id` to the group, adding it twice is a no-op.
+pub fn add_member<R: Repository>(
+    repo: Arc<R>,
+    name: String,
+    id: String,
+) -> Result<device::Response, GroupError> {
+    let group = GroupName::try_from(name).map_err(|_| GroupError::BadRequest)?;
+    update_groups(repo.as_ref(), id, |groups| {
+        groups.push(group);
+        true
+    })
+}
+
+/// Removes the device with the stable `id` from the group, `NotFound`
+/// if it was not a member.
+pub fn remove_member<R: Repository>(
+    repo: Arc<R>,
+    name: String,
+    id: String,
+) -> Result<device::Response, GroupError> {
+    let group = GroupName::try_from(name).map_err(|_| GroupError::BadRequest)?;
+    update_groups(repo.as_ref(), id, |groups| {
+        let before = groups.len();
+        groups.retain(|g| *g != group);
+        groups.len() != before
+    })
+}
+
+/// The devices of the group, in the order of `rooms`.
+pub(crate) fn group_members(
+    rooms: Vec<RoomInfo>,
+    group: &GroupName,
+) -> Vec<(RoomName, DeviceInfo)> {
+    rooms
+        .into_iter()
+        .flat_map(|room| {
+            let name = room.name;
+            room.devices
+                .into_iter()
+                .filter(|info| info.groups.contains(group))
+                .map(move |info| (name.clone(), info))
+        })
+        .collect()
+}
+
+// `change` edits the groups of the device and tells whether it found
+// anything to change
+fn update_groups<R: Repository>(
+    repo: &R,
+    id: String,
+    change: impl FnOnce(&mut Vec<GroupName>) -> bool,
+) -> Result<device::Response, GroupError> {
+    let id = DeviceId::try_from(id).map_err(|_| GroupError::BadRequest)?;
+    let (room_name, info) = match repo.fetch_device_by_id(id) {
+        Ok(device) => device,
+        Err(FetchError::NotFound) => return Err(GroupError::NotFound),
+        Err(FetchError::Unknown) => return Err(GroupError::Unknown),
+    };
+
+    let mut groups = info.groups.clone();
+    if !change(&mut groups) {
+        return Err(GroupError::NotFound);
+    }
+    let update = DeviceUpdate {
+        groups: Some(GroupName::normalize(groups)),
+        ..DeviceUpdate::default()
+    };
+    match repo.update_device(room_name.clone(), info.name, update) {
+        Ok(info) => Ok(device::Response::new(room_name, info)),
+        // renamed or moved in the meantime
+        Err(UpdateError::NotFound) | Err(UpdateError::Conflict) => Err(GroupError::Conflict),
+        Err(UpdateError::Unknown) => Err(GroupError::Unknown),
+    }
+}
+
+fn fetch_rooms<R: Repository>(repo: &R) -> Result<Vec<RoomInfo>, GroupError> {
+    match repo.fetch_rooms() {
+        Ok(rooms) => Ok(rooms),
+        Err(FetchError::NotFound) => Err(GroupError::NotFound),
+        Err(FetchError::Unknown) => Err(GroupError::Unknown),
+    }
+}
+
+impl MemberResponse {
+    fn new(room_name: RoomName, info: DeviceInfo) -> Self {
+        Self {
+            id: info.id.into(),
+            room_id: room_name.into(),
+            device_id: info.name.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::entity::{DeviceName, DeviceType};
+    use crate::repository::room::InMemoryRepository;
+
+    fn add_device(repo: &InMemoryRepository, room_name: RoomName, port: u16) -> String {
+        let device_info = DeviceInfo {
+            id: DeviceId::generate(),
+            name: DeviceName::socket(),
+            address: format!("127.0.0.1:{}", port).parse().unwrap(),
+            device_type: DeviceType::TcpSocket,
+            groups: Vec::new(),
+        };
+        let id = device_info.id;
+        repo.add_room(room_name.clone()).ok();
+        repo.add_device(room_name, device_info).ok();
+        id.into()
+    }
+
+    #[test]
+    fn groups_span_rooms_and_disappear_with_their_last_member() {
+        let repo = Arc::new(InMemoryRepository::new());
+        let kitchen = add_device(&repo, RoomName::kitchen(), 8001);
+        let bathroom = add_device(&repo, RoomName::bathroom(), 8002);
+        for id in [&kitchen, &bathroom] {
+            add_member(repo.clone(), "heaters".to_string(), id.clone()).ok();
+        }
+        add_member(repo.clone(), "outdoor".to_string(), kitchen.clone()).ok();
+
+        match add_member(repo.clone(), "heaters".to_string(), kitchen.clone()) {
+            Ok(device) => assert_eq!(device.groups, vec!["heaters", "outdoor"]),
+            _ => unreachable!(),
+        }
+        match fetch_group(repo.clone(), "heaters".to_string(), &Principal::SYSTEM) {
+            Ok(group) => assert_eq!(group.devices.len(), 2),
+            _ => unreachable!(),
+        }
+
+        remove_member(repo.clone(), "outdoor".to_string(), kitchen.clone()).ok();
+        match remove_member(repo.clone(), "outdoor".to_string(), kitchen) {
+            Err(GroupError::NotFound) => {}
+            _ => unreachable!(),
+        }
+        match fetch_group(repo.clone(), "outdoor".to_string(), &Principal::SYSTEM) {
+            Err(GroupError::NotFound) => {}
+            _ => unreachable!(),
+        }
+        match fetch_groups(repo, &Principal::SYSTEM) {
+            Ok(groups) => {
+                assert_eq!(groups.len(), 1);
+                assert_eq!(groups[0].name, "heaters");
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn add_member_returns_not_found_for_unknown_device() {
+        let repo = Arc::new(InMemoryRepository::new());
+
+        match add_member(repo, "heaters".to_string(), DeviceId::generate().into()) {
+            Err(GroupError::NotFound) => {}
+            _ => unreachable!(),
+        }
+    }
+}
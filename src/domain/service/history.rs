@@ -0,0 +1,281 @@
+use crate::domain::client::ClientError;
+use crate::domain::entity::{
+    DeviceCommand, DeviceEvent, DeviceId, DeviceName, DeviceStatus, HistoryEntry, Role, RoomName,
+    SocketStatus,
+};
+use crate::domain::service::access::{self, AccessError, Principal};
+use crate::repository::history::HistoryStore;
+use crate::repository::room::{FetchError, Repository};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// how often the entries past the retention are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+pub struct HistoryRequest {
+    pub room_id: String,
+    pub device_id: String,
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct HistoryResponse {
+    room_id: String,
+    device_id: String,
+    entries: Vec<EntryResponse>,
+}
+
+#[derive(Serialize)]
+pub struct EntryResponse {
+    timestamp: u64,
+    #[serde(flatten)]
+    event: DeviceEvent,
+}
+
+pub enum HistoryError {
+    NotFound,
+    BadRequest,
+    Forbidden,
+    Unknown,
+}
+
+impl From<AccessError> for HistoryError {
+    fn from(err: AccessError) -> Self {
+        match err {
+            AccessError::Forbidden => Self::Forbidden,
+            AccessError::Unknown => Self::Unknown,
+        }
+    }
+}
+
+/// Milliseconds since the unix epoch, the unit of every history timestamp.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub fn fetch_history<R: Repository + HistoryStore>(
+    repo: Arc<R>,
+    request: HistoryRequest,
+    principal: &Principal,
+) -> Result<HistoryResponse, HistoryError> {
+    let device_name =
+        DeviceName::try_from(request.device_id.clone()).map_err(|_| HistoryError::BadRequest)?;
+    let room_name =
+        RoomName::try_from(request.room_id.clone()).map_err(|_| HistoryError::BadRequest)?;
+    let from = request.from.unwrap_or(0);
+    let to = request.to.unwrap_or(u64::MAX);
+    if from > to {
+        return Err(HistoryError::BadRequest);
+    }
+
+    let device_info = match repo.fetch_device(room_name.clone(), device_name) {
+        Ok(device_info) => device_info,
+        Err(FetchError::Unknown) => return Err(HistoryError::Unknown),
+        Err(FetchError::NotFound) => return Err(HistoryError::NotFound),
+    };
+    access::check_device(
+        repo.as_ref(),
+        principal,
+        Role::Read,
+        &room_name,
+        &device_info,
+    )?;
+
+    match repo.fetch_history(device_info.id, from, to) {
+        Ok(entries) => Ok(HistoryResponse {
+            room_id: request.room_id,
+            device_id: request.device_id,
+            entries: entries
+                .into_iter()
+                .map(|e| EntryResponse {
+                    timestamp: e.timestamp,
+                    event: e.event,
+                })
+                .collect(),
+        }),
+        Err(FetchError::Unknown) => Err(HistoryError::Unknown),
+        Err(FetchError::NotFound) => Err(HistoryError::NotFound),
+    }
+}
+
+// recording is best effort, a failing history store
+// must not fail the query or command it belongs to
+
+pub fn record_reading<H: HistoryStore>(
+    store: &H,
+    device_id: DeviceId,
+    result: &Result<DeviceStatus, ClientError>,
+) {
+    let event = DeviceEvent::Reading {
+        status: result.as_ref().ok().cloned(),
+        error: result.as_ref().err().map(|e| e.to_string()),
+    };
+    record(store, device_id, event);
+}
+
+pub fn record_command<H: HistoryStore>(
+    store: &H,
+    device_id: DeviceId,
+    command: DeviceCommand,
+    result: &Result<SocketStatus, ClientError>,
+) {
+    let event = DeviceEvent::Command {
+        command: command.into(),
+        status: result.as_ref().ok().cloned(),
+        error: result.as_ref().err().map(|e| e.to_string()),
+    };
+    record(store, device_id, event);
+}
+
+fn record<H: HistoryStore>(store: &H, device_id: DeviceId, event: DeviceEvent) {
+    let entry = HistoryEntry {
+        device_id,
+        timestamp: now_millis(),
+        event,
+    };
+    store.record_event(entry).ok();
+}
+
+/// Drops the entries older than `retention`, returns how many were dropped.
+pub fn prune_history<H: HistoryStore>(
+    store: &H,
+    retention: Duration,
+) -> Result<usize, HistoryError> {
+    let before = now_millis().saturating_sub(retention.as_millis() as u64);
+    store
+        .prune_history(before)
+        .map_err(|_| HistoryError::Unknown)
+}
+
+/// Applies the retention right away and then every hour, runs until the runtime stops.
+pub async fn run_retention<H: HistoryStore>(store: Arc<H>, retention: Duration) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        prune_history(store.as_ref(), retention).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::entity::{DeviceInfo, DeviceType, ThermoStatus};
+    use crate::repository::room::InMemoryRepository;
+
+    fn repo_with_thermo() -> (Arc<InMemoryRepository>, DeviceId) {
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::bathroom()).ok();
+        let device_info = DeviceInfo {
+            id: DeviceId::generate(),
+            name: DeviceName::thermo(),
+            address: "127.0.0.1:9001".parse().unwrap(),
+            device_type: DeviceType::UdpThermo,
+            groups: Vec::new(),
+        };
+        let id = device_info.id;
+        repo.add_device(RoomName::bathroom(), device_info).ok();
+        (repo, id)
+    }
+
+    fn reading(device_id: DeviceId, timestamp: u64, temperature: f32) -> HistoryEntry {
+        HistoryEntry {
+            device_id,
+            timestamp,
+            event: DeviceEvent::Reading {
+                status: Some(DeviceStatus::Thermo(ThermoStatus { temperature })),
+                error: None,
+            },
+        }
+    }
+
+    fn request(from: Option<u64>, to: Option<u64>) -> HistoryRequest {
+        HistoryRequest {
+            room_id: RoomName::bathroom().into(),
+            device_id: DeviceName::thermo().into(),
+            from,
+            to,
+        }
+    }
+
+    #[test]
+    fn fetch_history_returns_entries_in_range_oldest_first() {
+        let (repo, id) = repo_with_thermo();
+        for (timestamp, temperature) in [(300, 23.0), (100, 21.0), (200, 22.0)] {
+            repo.record_event(reading(id, timestamp, temperature)).ok();
+        }
+        repo.record_event(reading(DeviceId::generate(), 150, 30.0))
+            .ok();
+
+        match fetch_history(repo, request(Some(100), Some(200)), &Principal::SYSTEM) {
+            Ok(result) => {
+                let timestamps: Vec<_> = result.entries.iter().map(|e| e.timestamp).collect();
+                assert_eq!(timestamps, vec![100, 200]);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn fetch_history_returns_bad_request_if_range_is_reversed() {
+        let (repo, _) = repo_with_thermo();
+
+        match fetch_history(repo, request(Some(200), Some(100)), &Principal::SYSTEM) {
+            Err(HistoryError::BadRequest) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn fetch_history_returns_not_found_if_repo_doesnt_contain_device() {
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::bathroom()).ok();
+
+        match fetch_history(repo, request(None, None), &Principal::SYSTEM) {
+            Err(HistoryError::NotFound) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn prune_history_drops_entries_past_retention() {
+        let (repo, id) = repo_with_thermo();
+        let now = now_millis();
+        repo.record_event(reading(id, now - 10_000, 21.0)).ok();
+        repo.record_event(reading(id, now, 22.0)).ok();
+
+        match prune_history(repo.as_ref(), Duration::from_secs(5)) {
+            Ok(pruned) => assert_eq!(pruned, 1),
+            _ => unreachable!(),
+        }
+        match fetch_history(repo, request(None, None), &Principal::SYSTEM) {
+            Ok(result) => assert_eq!(result.entries.len(), 1),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn record_reading_keeps_failures_too() {
+        let (repo, id) = repo_with_thermo();
+        record_reading(
+            repo.as_ref(),
+            id,
+            &Err(ClientError::NoData("no datagram yet".into())),
+        );
+
+        match repo.fetch_history(id, 0, u64::MAX) {
+            Ok(entries) => match &entries[0].event {
+                DeviceEvent::Reading {
+                    status: None,
+                    error: Some(_),
+                } => {}
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+}
@@ -1,3 +1,14 @@
+pub mod access;
+pub mod api_key;
 pub mod device;
+pub mod device_command;
 pub mod device_query;
+pub mod energy;
+pub mod group;
+pub mod history;
 pub mod room;
+pub mod rule;
+pub mod scene;
+pub mod schedule;
+pub mod user;
+pub mod webhook;
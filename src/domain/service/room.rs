@@ -1,12 +1,17 @@
-use crate::domain::entity::{self, RoomName};
-use crate::repository::room::{DeleteError, FetchError, InsertError, Repository};
+use crate::domain::client;
+use crate::domain::entity::{self, DeviceType, Role, RoomName};
+use crate::domain::events::{self, Event};
+use crate::domain::service::access::Principal;
+use crate::repository::room::{DeleteError, FetchError, InsertError, Repository, UpdateError};
 use std::sync::Arc;
 
 pub enum Error {
     BadRequest,
     Conflict,
+    NotEmpty,
     Unknown,
     NotFound,
+    Forbidden,
 }
 
 #[derive(Debug)]
@@ -14,14 +19,28 @@ pub struct RoomRequest {
     pub name: String,
 }
 
+#[derive(Debug)]
+pub struct DeleteRequest {
+    pub name: String,
+    pub cascade: bool,
+}
+
+#[derive(Debug)]
+pub struct RenameRequest {
+    pub name: String,
+    pub new_name: String,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct RoomResponse {
+    pub id: String,
     pub name: String,
     pub devices: Vec<DeviceResponse>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct DeviceResponse {
+    pub id: String,
     pub name: String,
     pub address: String,
     pub device_type: String,
@@ -30,6 +49,7 @@ pub struct DeviceResponse {
 impl From<entity::RoomInfo> for RoomResponse {
     fn from(inner: entity::RoomInfo) -> Self {
         Self {
+            id: String::from(inner.id),
             name: String::from(inner.name),
             devices: inner
                 .devices
@@ -42,6 +62,7 @@ impl From<entity::RoomInfo> for RoomResponse {
 impl From<entity::DeviceInfo> for DeviceResponse {
     fn from(inner: entity::DeviceInfo) -> Self {
         Self {
+            id: String::from(inner.id),
             name: String::from(inner.name),
             address: inner.address.to_string(),
             device_type: String::from(inner.device_type),
@@ -52,45 +73,87 @@ impl From<entity::DeviceInfo> for DeviceResponse {
 pub fn add_room<R: Repository>(repo: Arc<R>, req: RoomRequest) -> Result<RoomResponse, Error> {
     let room_name = RoomName::try_from(req.name).map_err(|_| Error::BadRequest)?;
     match repo.add_room(room_name) {
-        Ok(room_info) => Ok(RoomResponse {
-            name: String::from(room_info.name),
-            devices: Vec::new(),
-        }),
+        Ok(room_info) => {
+            events::publish(Event::RoomAdded {
+                room_id: room_info.name.clone().into(),
+            });
+            Ok(RoomResponse::from(room_info))
+        }
         Err(InsertError::Conflict) => Err(Error::Conflict),
         Err(InsertError::Unknown) => Err(Error::Unknown),
     }
 }
 
-pub fn fetch_room<R: Repository>(repo: Arc<R>, req: RoomRequest) -> Result<RoomResponse, Error> {
+/// The room with the devices the principal may read, `Forbidden` if none.
+pub fn fetch_room<R: Repository>(
+    repo: Arc<R>,
+    req: RoomRequest,
+    principal: &Principal,
+) -> Result<RoomResponse, Error> {
     let room_name = RoomName::try_from(req.name).map_err(|_| Error::BadRequest)?;
     match repo.fetch_room(room_name) {
-        Ok(room_info) => Ok(RoomResponse {
-            name: String::from(room_info.name),
-            devices: room_info
-                .devices
-                .into_iter()
-                .map(DeviceResponse::from)
-                .collect(),
-        }),
+        Ok(room_info) => match principal.narrow_room(Role::Read, room_info) {
+            Some(room_info) => Ok(RoomResponse::from(room_info)),
+            None => Err(Error::Forbidden),
+        },
         Err(FetchError::NotFound) => Err(Error::NotFound),
         Err(FetchError::Unknown) => Err(Error::Unknown),
     }
 }
 
-pub fn fetch_rooms<R: Repository>(repo: Arc<R>) -> Result<Vec<RoomResponse>, Error> {
+/// Only the rooms and devices the principal may read.
+pub fn fetch_rooms<R: Repository>(
+    repo: Arc<R>,
+    principal: &Principal,
+) -> Result<Vec<RoomResponse>, Error> {
     match repo.fetch_rooms() {
-        Ok(room_infos) => Ok(room_infos.into_iter().map(RoomResponse::from).collect()),
+        Ok(room_infos) => Ok(principal
+            .narrow_rooms(Role::Read, room_infos)
+            .into_iter()
+            .map(RoomResponse::from)
+            .collect()),
         Err(FetchError::NotFound) => Err(Error::NotFound),
         Err(FetchError::Unknown) => Err(Error::Unknown),
     }
 }
 
-pub fn delete_room<R: Repository>(repo: Arc<R>, req: RoomRequest) -> Result<(), Error> {
+pub fn delete_room<R: Repository>(repo: Arc<R>, req: DeleteRequest) -> Result<(), Error> {
     let room_name = RoomName::try_from(req.name).map_err(|_| Error::BadRequest)?;
-    match repo.delete_room(room_name) {
-        Ok(()) => Ok(()),
+
+    // cleans up after the devices the repository actually deleted, not the
+    // ones of an earlier fetch that may have missed one added in between
+    match repo.delete_room(room_name, req.cascade) {
+        Ok(room_info) => {
+            for device in room_info.devices {
+                match device.device_type {
+                    DeviceType::UdpThermo => client::stop_thermo(device.address),
+                    DeviceType::TcpSocket => client::forget(device.address),
+                }
+                events::publish(Event::DeviceDeleted {
+                    room_id: room_info.name.clone().into(),
+                    device_id: device.name.into(),
+                    id: device.id.into(),
+                });
+            }
+            events::publish(Event::RoomDeleted {
+                room_id: room_info.name.into(),
+            });
+            Ok(())
+        }
         Err(DeleteError::NotFound) => Err(Error::NotFound),
-        Err(_) => Err(Error::Unknown),
+        Err(DeleteError::NotEmpty) => Err(Error::NotEmpty),
+        Err(DeleteError::Unknown) => Err(Error::Unknown),
+    }
+}
+
+pub fn rename_room<R: Repository>(repo: Arc<R>, req: RenameRequest) -> Result<RoomResponse, Error> {
+    let room_name = RoomName::try_from(req.name).map_err(|_| Error::BadRequest)?;
+    let new_name = RoomName::try_from(req.new_name).map_err(|_| Error::BadRequest)?;
+    match repo.rename_room(room_name, new_name) {
+        Ok(room_info) => Ok(RoomResponse::from(room_info)),
+        Err(UpdateError::Conflict) => Err(Error::Conflict),
+        Err(UpdateError::NotFound) => Err(Error::NotFound),
+        Err(UpdateError::Unknown) => Err(Error::Unknown),
     }
 }
 
@@ -98,32 +161,9 @@ pub fn delete_room<R: Repository>(repo: Arc<R>, req: RoomRequest) -> Result<(),
 mod tests {
     use super::*;
     use crate::repository::room::InMemoryRepository;
-    #[test]
-    fn add_room_returns_bad_request_error_on_invalid_input() {
-        // invalid input is empty room name
-        let repo = Arc::new(InMemoryRepository::new());
-        let request = RoomRequest {
-            name: RoomName::empty().into(),
-        };
-        match add_room(repo, request) {
-            Err(Error::BadRequest) => {}
-            _ => unreachable!(),
-        };
-    }
-
-    #[test]
-    fn add_room_returns_conflict_error_if_room_already_exists() {
-        let repo = Arc::new(InMemoryRepository::new());
-        repo.add_room(RoomName::kitchen()).ok();
 
-        let request = RoomRequest {
-            name: RoomName::kitchen().into(),
-        };
-        match add_room(repo, request) {
-            Err(Error::Conflict) => {}
-            _ => unreachable!(),
-        };
-    }
+    // what holds for every backend is in `repository::suite`, these need
+    // the failing in-memory repository or the event bus
 
     #[test]
     fn add_room_returns_unknown_error_if_if_repo_errors_unexpectidly() {
@@ -137,120 +177,51 @@ mod tests {
         };
     }
 
-    #[test]
-    fn add_room_returns_empty_room_on_success() {
-        let repo = Arc::new(InMemoryRepository::new());
-        let request = RoomRequest {
-            name: RoomName::kitchen().into(),
-        };
-        match add_room(repo, request) {
-            Ok(result) => {
-                assert_eq!(result.name, String::from(RoomName::kitchen()));
-                assert_eq!(result.devices, Vec::new());
-            }
-            _ => unreachable!(),
-        };
-    }
-
-    #[test]
-    fn fetch_room_returns_not_found_error_if_repo_doesnt_contain_room() {
-        let repo = Arc::new(InMemoryRepository::new());
-        let request = RoomRequest {
-            name: RoomName::kitchen().into(),
-        };
-        match fetch_room(repo, request) {
-            Err(Error::NotFound) => {}
-            _ => unreachable!(),
-        };
-    }
-
     #[test]
     fn fetch_room_returns_unknown_error_if_repo_errors_unexpectidly() {
         let repo = Arc::new(InMemoryRepository::new().with_error());
         let request = RoomRequest {
             name: RoomName::kitchen().into(),
         };
-        match fetch_room(repo, request) {
+        match fetch_room(repo, request, &Principal::SYSTEM) {
             Err(Error::Unknown) => {}
             _ => unreachable!(),
         };
     }
 
-    #[test]
-    fn fetch_room_returns_room_on_success() {
+    #[tokio::test]
+    async fn delete_room_with_cascade_publishes_device_and_room_deletions() {
+        // other tests publish to the same bus, a room of its own tells ours apart
+        let room_name = RoomName::try_from("cascade events".to_string()).unwrap();
         let repo = Arc::new(InMemoryRepository::new());
-        repo.add_room(RoomName::kitchen()).ok();
-        repo.add_room(RoomName::bathroom()).ok();
-
-        let request = RoomRequest {
-            name: RoomName::kitchen().into(),
-        };
-        match fetch_room(repo, request) {
-            Ok(result) => {
-                assert_eq!(result.name, String::from(RoomName::kitchen()));
-                assert_eq!(result.devices, Vec::new());
+        repo.add_room(room_name.clone()).ok();
+        let device_info = entity::DeviceInfo {
+            id: entity::DeviceId::generate(),
+            name: entity::DeviceName::socket(),
+            address: "127.0.0.1:8888".parse().unwrap(),
+            device_type: DeviceType::TcpSocket,
+            groups: Vec::new(),
+        };
+        repo.add_device(room_name.clone(), device_info).ok();
+        let mut receiver = events::subscribe();
+
+        let request = DeleteRequest {
+            name: room_name.into(),
+            cascade: true,
+        };
+        delete_room(repo, request).ok();
+
+        let mut kinds = Vec::new();
+        while kinds.len() < 2 {
+            match receiver.recv().await.unwrap() {
+                event @ (Event::DeviceDeleted { .. } | Event::RoomDeleted { .. })
+                    if serde_json::to_value(&event).unwrap()["room_id"] == "cascade events" =>
+                {
+                    kinds.push(event.kind())
+                }
+                _ => {}
             }
-            _ => unreachable!(),
-        };
-    }
-
-    #[test]
-    fn fetch_rooms_returns_zero_rooms() {
-        let repo = Arc::new(InMemoryRepository::new());
-
-        match fetch_rooms(repo) {
-            Ok(result) => assert_eq!(result, vec![]),
-            _ => unreachable!(),
-        };
-    }
-    #[test]
-    fn fetch_rooms_returns_two_rooms() {
-        let repo = Arc::new(InMemoryRepository::new());
-        repo.add_room(RoomName::kitchen()).ok();
-        repo.add_room(RoomName::bathroom()).ok();
-
-        match fetch_rooms(repo) {
-            Ok(result) => assert_eq!(
-                result,
-                vec![
-                    RoomResponse {
-                        name: "kitchen".to_string(),
-                        devices: vec![]
-                    },
-                    RoomResponse {
-                        name: "bathroom".to_string(),
-                        devices: vec![]
-                    }
-                ]
-            ),
-            _ => unreachable!(),
-        };
-    }
-
-    #[test]
-    fn delete_room_errors_if_room_doesnt_exist() {
-        let repo = Arc::new(InMemoryRepository::new());
-        let request = RoomRequest {
-            name: RoomName::kitchen().into(),
-        };
-        match delete_room(repo, request) {
-            Err(Error::NotFound) => {}
-            _ => unreachable!(),
-        };
-    }
-
-    #[test]
-    fn delete_room_deletes_room() {
-        let repo = Arc::new(InMemoryRepository::new());
-        repo.add_room(RoomName::kitchen()).ok();
-        let request = RoomRequest {
-            name: RoomName::kitchen().into(),
-        };
-        delete_room(repo.clone(), request).ok();
-
-        match fetch_rooms(repo) {
-            Ok(result) => assert_eq!(result, vec![]),
-            _ => unreachable!(),
-        };
+        }
+        assert_eq!(kinds, vec!["device_deleted", "room_deleted"]);
     }
 }
@@ -0,0 +1,710 @@
+use crate::domain::cache::StatusCache;
+use crate::domain::client::DeviceClient;
+use crate::domain::entity::{
+    DeviceCommand, DeviceId, DeviceInfo, DeviceStatus, DeviceType, Role, Rule, RuleAction, RuleId,
+    RuleName, RuleTrigger, TimeOfDay, TriggerParts,
+};
+use crate::domain::events::{self, Event};
+use crate::domain::service::access::{self, AccessError, Principal};
+use crate::domain::service::device_command::command_socket;
+use crate::domain::service::device_query::{read_device, StatusSource};
+use crate::domain::service::history;
+use crate::repository::history::HistoryStore;
+use crate::repository::room::{DeleteError, FetchError, InsertError, Repository, UpdateError};
+use crate::repository::rule::RuleStore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+
+const MINUTE_MS: u64 = 60 * 1000;
+const DAY_MS: u64 = 24 * 60 * MINUTE_MS;
+
+#[derive(Deserialize)]
+pub struct RuleRequest {
+    pub name: String,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    pub trigger: TriggerSpec,
+    #[serde(default)]
+    pub action: Option<ActionSpec>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/// `kind` is one of `temperature_below`, `temperature_above`, `power_above`
+/// and `power_below`, which need `device_id` and `threshold`, or
+/// `time_of_day`, which needs `at` as `HH:MM` in UTC.
+#[derive(Deserialize, Serialize)]
+pub struct TriggerSpec {
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ActionSpec {
+    pub device_id: String,
+    pub command: String,
+}
+
+#[derive(Serialize)]
+pub struct RuleResponse {
+    id: String,
+    name: String,
+    enabled: bool,
+    trigger: TriggerSpec,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    action: Option<ActionSpec>,
+}
+
+impl From<Rule> for RuleResponse {
+    fn from(rule: Rule) -> Self {
+        let trigger = TriggerParts::from(rule.trigger);
+        Self {
+            id: rule.id.into(),
+            name: rule.name.into(),
+            enabled: rule.enabled,
+            trigger: TriggerSpec {
+                kind: trigger.kind,
+                device_id: trigger.device_id.map(String::from),
+                threshold: trigger.threshold,
+                at: trigger.at.map(String::from),
+            },
+            action: rule.action.map(|action| ActionSpec {
+                device_id: action.device_id.into(),
+                command: action.command.into(),
+            }),
+        }
+    }
+}
+
+pub enum RuleError {
+    BadRequest,
+    NotFound,
+    Conflict,
+    Forbidden,
+    Unknown,
+}
+
+impl From<AccessError> for RuleError {
+    fn from(err: AccessError) -> Self {
+        match err {
+            AccessError::Forbidden => Self::Forbidden,
+            AccessError::Unknown => Self::Unknown,
+        }
+    }
+}
+
+pub fn add_rule<R: Repository + RuleStore>(
+    repo: Arc<R>,
+    request: RuleRequest,
+) -> Result<RuleResponse, RuleError> {
+    let rule = parse_rule(repo.as_ref(), RuleId::generate(), request)?;
+
+    match repo.add_rule(rule) {
+        Ok(rule) => Ok(RuleResponse::from(rule)),
+        Err(InsertError::Conflict) => Err(RuleError::Conflict),
+        Err(InsertError::Unknown) => Err(RuleError::Unknown),
+    }
+}
+
+/// `Forbidden` unless the principal may read every device of the rule.
+pub fn fetch_rule<R: Repository + RuleStore>(
+    repo: Arc<R>,
+    id: String,
+    principal: &Principal,
+) -> Result<RuleResponse, RuleError> {
+    let id = RuleId::try_from(id).map_err(|_| RuleError::BadRequest)?;
+    let rule = match repo.fetch_rule(id) {
+        Ok(rule) => rule,
+        Err(FetchError::NotFound) => return Err(RuleError::NotFound),
+        Err(FetchError::Unknown) => return Err(RuleError::Unknown),
+    };
+    access::check_devices(repo.as_ref(), principal, Role::Read, devices(&rule))?;
+
+    Ok(RuleResponse::from(rule))
+}
+
+/// The rules the principal may read every device of.
+pub fn fetch_rules<R: Repository + RuleStore>(
+    repo: Arc<R>,
+    principal: &Principal,
+) -> Result<Vec<RuleResponse>, RuleError> {
+    let rules = match repo.fetch_rules() {
+        Ok(rules) => rules,
+        Err(FetchError::NotFound) => return Err(RuleError::NotFound),
+        Err(FetchError::Unknown) => return Err(RuleError::Unknown),
+    };
+    let rules = access::readable(repo.as_ref(), principal, rules, devices)?;
+
+    Ok(rules.into_iter().map(RuleResponse::from).collect())
+}
+
+// the device the trigger watches and the one the action drives
+fn devices(rule: &Rule) -> Vec<DeviceId> {
+    let trigger = TriggerParts::from(rule.trigger.clone());
+    trigger
+        .device_id
+        .into_iter()
+        .chain(rule.action.as_ref().map(|action| action.device_id))
+        .collect()
+}
+
+/// Replaces the whole rule, the id stays the same.
+pub fn update_rule<R: Repository + RuleStore>(
+    repo: Arc<R>,
+    id: String,
+    request: RuleRequest,
+) -> Result<RuleResponse, RuleError> {
+    let id = RuleId::try_from(id).map_err(|_| RuleError::BadRequest)?;
+    let rule = parse_rule(repo.as_ref(), id, request)?;
+
+    match repo.update_rule(rule) {
+        Ok(rule) => Ok(RuleResponse::from(rule)),
+        Err(UpdateError::NotFound) => Err(RuleError::NotFound),
+        Err(UpdateError::Conflict) => Err(RuleError::Conflict),
+        Err(UpdateError::Unknown) => Err(RuleError::Unknown),
+    }
+}
+
+pub fn delete_rule<R: RuleStore>(repo: Arc<R>, id: String) -> Result<(), RuleError> {
+    let id = RuleId::try_from(id).map_err(|_| RuleError::BadRequest)?;
+
+    match repo.delete_rule(id) {
+        Ok(()) => Ok(()),
+        Err(DeleteError::NotFound) => Err(RuleError::NotFound),
+        Err(_) => Err(RuleError::Unknown),
+    }
+}
+
+// besides the format, the devices a rule refers to must exist and be of the
+// right type, temperature comes from thermometers, power and commands are sockets.
+// A time of day has no device to tell about, so it needs an action.
+fn parse_rule<R: Repository>(
+    repo: &R,
+    id: RuleId,
+    request: RuleRequest,
+) -> Result<Rule, RuleError> {
+    let name = RuleName::try_from(request.name).map_err(|_| RuleError::BadRequest)?;
+    let trigger = TriggerParts {
+        kind: request.trigger.kind,
+        device_id: request
+            .trigger
+            .device_id
+            .map(DeviceId::try_from)
+            .transpose()
+            .map_err(|_| RuleError::BadRequest)?,
+        threshold: request.trigger.threshold,
+        at: request
+            .trigger
+            .at
+            .map(TimeOfDay::try_from)
+            .transpose()
+            .map_err(|_| RuleError::BadRequest)?,
+    };
+    let trigger = RuleTrigger::try_from(trigger).map_err(|_| RuleError::BadRequest)?;
+    let action = match request.action {
+        Some(action) => Some(RuleAction {
+            device_id: DeviceId::try_from(action.device_id).map_err(|_| RuleError::BadRequest)?,
+            command: DeviceCommand::try_from(action.command).map_err(|_| RuleError::BadRequest)?,
+        }),
+        None => None,
+    };
+
+    match &trigger {
+        RuleTrigger::TemperatureBelow { device_id, .. }
+        | RuleTrigger::TemperatureAbove { device_id, .. } => {
+            expect_device(repo, *device_id, DeviceType::UdpThermo)?
+        }
+        RuleTrigger::PowerAbove { device_id, .. } | RuleTrigger::PowerBelow { device_id, .. } => {
+            expect_device(repo, *device_id, DeviceType::TcpSocket)?
+        }
+        RuleTrigger::TimeOfDay(_) if action.is_none() => return Err(RuleError::BadRequest),
+        RuleTrigger::TimeOfDay(_) => {}
+    }
+    if let Some(action) = &action {
+        expect_device(repo, action.device_id, DeviceType::TcpSocket)?;
+    }
+
+    Ok(Rule {
+        id,
+        name,
+        enabled: request.enabled,
+        trigger,
+        action,
+    })
+}
+
+fn expect_device<R: Repository>(
+    repo: &R,
+    id: DeviceId,
+    device_type: DeviceType,
+) -> Result<(), RuleError> {
+    match repo.fetch_device_by_id(id) {
+        Ok((_, info)) if info.device_type == device_type => Ok(()),
+        Ok(_) | Err(FetchError::NotFound) => Err(RuleError::BadRequest),
+        Err(FetchError::Unknown) => Err(RuleError::Unknown),
+    }
+}
+
+/// What the engine remembers between two evaluations.
+#[derive(Default)]
+pub struct RuleState {
+    evaluated_at: Option<u64>,
+    // rules whose condition held at the last evaluation
+    matching: HashSet<RuleId>,
+}
+
+/// Checks every enabled rule once and runs the actions of those that fire,
+/// returns the ids of the rules that fired.
+///
+/// A rule fires when its condition starts to hold, it does not fire again
+/// until the condition stopped holding in between, so a cold room turns the
+/// heater on once instead of on every evaluation. Statuses come from the
+/// cache the poller keeps, a device that can't be read leaves its rules as
+/// they were. A time of day fires when the clock passed it since the last
+/// evaluation.
+pub async fn evaluate_rules<R: Repository + HistoryStore + RuleStore>(
+    repo: &R,
+    client: &DeviceClient,
+    cache: &StatusCache,
+    state: &mut RuleState,
+    now: u64,
+) -> Result<Vec<RuleId>, RuleError> {
+    let rules = match repo.fetch_rules() {
+        Ok(rules) => rules,
+        Err(FetchError::NotFound) => return Err(RuleError::NotFound),
+        Err(FetchError::Unknown) => return Err(RuleError::Unknown),
+    };
+    let ids: HashSet<RuleId> = rules.iter().map(|r| r.id).collect();
+    state.matching.retain(|id| ids.contains(id));
+
+    let source = StatusSource {
+        client,
+        cache,
+        fresh: false,
+    };
+    let mut fired = Vec::new();
+    for rule in rules {
+        if !rule.enabled {
+            state.matching.remove(&rule.id);
+            continue;
+        }
+
+        let matches = match rule.trigger.clone() {
+            RuleTrigger::TimeOfDay(at) => state.evaluated_at.map(|last| passed(at, last, now)),
+            trigger => device_condition(repo, source, trigger).await,
+        };
+        match matches {
+            Some(true) if state.matching.insert(rule.id) => {
+                publish_fired(repo, &rule);
+                if let Some(action) = rule.action {
+                    run_action(repo, client, cache, action).await;
+                }
+                fired.push(rule.id);
+            }
+            Some(false) => {
+                state.matching.remove(&rule.id);
+            }
+            _ => {}
+        }
+    }
+
+    state.evaluated_at = Some(now);
+    Ok(fired)
+}
+
+/// Evaluates the rules every `interval` until the runtime stops.
+pub async fn run_rules<R: Repository + HistoryStore + RuleStore>(
+    repo: Arc<R>,
+    client: DeviceClient,
+    cache: StatusCache,
+    interval: Duration,
+) {
+    let mut state = RuleState::default();
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        evaluate_rules(
+            repo.as_ref(),
+            &client,
+            &cache,
+            &mut state,
+            history::now_millis(),
+        )
+        .await
+        .ok();
+    }
+}
+
+// whether the last occurrence of `at` lies in `(last, now]`
+fn passed(at: TimeOfDay, last: u64, now: u64) -> bool {
+    let offset = at.minutes() as u64 * MINUTE_MS;
+    if now < offset {
+        return false;
+    }
+    let latest = (now - offset) / DAY_MS * DAY_MS + offset;
+    last < latest && latest <= now
+}
+
+// `None` if the device is gone, can't be read or reports another kind of status
+async fn device_condition<R: Repository + HistoryStore>(
+    repo: &R,
+    source: StatusSource<'_>,
+    trigger: RuleTrigger,
+) -> Option<bool> {
+    let device_id = match &trigger {
+        RuleTrigger::TemperatureBelow { device_id, .. }
+        | RuleTrigger::TemperatureAbove { device_id, .. }
+        | RuleTrigger::PowerAbove { device_id, .. }
+        | RuleTrigger::PowerBelow { device_id, .. } => *device_id,
+        RuleTrigger::TimeOfDay(_) => return None,
+    };
+    let (_, info) = repo.fetch_device_by_id(device_id).ok()?;
+    let status = read_device(repo, source, &info).await.result.ok()?;
+
+    match (trigger, status) {
+        (RuleTrigger::TemperatureBelow { threshold, .. }, DeviceStatus::Thermo(status)) => {
+            Some(status.temperature < threshold)
+        }
+        (RuleTrigger::TemperatureAbove { threshold, .. }, DeviceStatus::Thermo(status)) => {
+            Some(status.temperature > threshold)
+        }
+        (RuleTrigger::PowerAbove { threshold, .. }, DeviceStatus::Socket(status)) => {
+            Some(status.power > threshold)
+        }
+        (RuleTrigger::PowerBelow { threshold, .. }, DeviceStatus::Socket(status)) => {
+            Some(status.power < threshold)
+        }
+        _ => None,
+    }
+}
+
+fn publish_fired<R: Repository>(repo: &R, rule: &Rule) {
+    let device_id = match &rule.trigger {
+        RuleTrigger::TemperatureBelow { device_id, .. }
+        | RuleTrigger::TemperatureAbove { device_id, .. }
+        | RuleTrigger::PowerAbove { device_id, .. }
+        | RuleTrigger::PowerBelow { device_id, .. } => *device_id,
+        RuleTrigger::TimeOfDay(_) => match &rule.action {
+            Some(action) => action.device_id,
+            None => return,
+        },
+    };
+    if let Ok((room_name, info)) = repo.fetch_device_by_id(device_id) {
+        events::publish(Event::RuleFired {
+            room_id: room_name.into(),
+            device_id: info.name.into(),
+            id: info.id.into(),
+            rule_id: rule.id.into(),
+            rule: rule.name.clone().into(),
+        });
+    }
+}
+
+// a failed command ends up in the history like any other command
+async fn run_action<R: Repository + HistoryStore>(
+    repo: &R,
+    client: &DeviceClient,
+    cache: &StatusCache,
+    action: RuleAction,
+) {
+    if let Ok((
+        _,
+        info @ DeviceInfo {
+            device_type: DeviceType::TcpSocket,
+            ..
+        },
+    )) = repo.fetch_device_by_id(action.device_id)
+    {
+        command_socket(repo, client, cache, info.id, info.address, action.command)
+            .await
+            .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::cache::CachedReading;
+    use crate::domain::entity::{DeviceEvent, RoomName, ThermoStatus};
+    use crate::domain::fixture::{add_device, unused_tcp_address};
+    use crate::repository::room::InMemoryRepository;
+
+    struct House {
+        repo: Arc<InMemoryRepository>,
+        thermo: DeviceId,
+        socket: DeviceId,
+    }
+
+    fn house() -> House {
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::bathroom()).ok();
+        let thermo = add_device(
+            repo.as_ref(),
+            RoomName::bathroom(),
+            "thermo",
+            "127.0.0.1:9001".parse().unwrap(),
+            DeviceType::UdpThermo,
+        );
+        let socket = add_device(
+            repo.as_ref(),
+            RoomName::bathroom(),
+            "socket",
+            unused_tcp_address(),
+            DeviceType::TcpSocket,
+        );
+        House {
+            repo,
+            thermo,
+            socket,
+        }
+    }
+
+    fn request(name: &str, trigger: TriggerSpec, action_device: DeviceId) -> RuleRequest {
+        RuleRequest {
+            name: name.to_string(),
+            enabled: true,
+            trigger,
+            action: Some(ActionSpec {
+                device_id: action_device.into(),
+                command: "on".to_string(),
+            }),
+        }
+    }
+
+    fn below(device_id: DeviceId, threshold: f32) -> TriggerSpec {
+        TriggerSpec {
+            kind: "temperature_below".to_string(),
+            device_id: Some(device_id.into()),
+            threshold: Some(threshold),
+            at: None,
+        }
+    }
+
+    fn at(time: &str) -> TriggerSpec {
+        TriggerSpec {
+            kind: "time_of_day".to_string(),
+            device_id: None,
+            threshold: None,
+            at: Some(time.to_string()),
+        }
+    }
+
+    fn set_temperature(cache: &StatusCache, id: DeviceId, temperature: f32) {
+        cache.insert(
+            id,
+            CachedReading {
+                result: Ok(DeviceStatus::Thermo(ThermoStatus { temperature })),
+                read_at: history::now_millis(),
+            },
+        );
+    }
+
+    #[test]
+    fn add_rule_checks_device_types() {
+        let house = house();
+        let wrong_trigger = request("heater", below(house.socket, 18.0), house.socket);
+        let wrong_action = request("heater", below(house.thermo, 18.0), house.thermo);
+        let missing_device = request("heater", below(DeviceId::generate(), 18.0), house.socket);
+        let untold_time = RuleRequest {
+            action: None,
+            ..request("morning", at("07:00"), house.socket)
+        };
+
+        for request in [wrong_trigger, wrong_action, missing_device, untold_time] {
+            match add_rule(house.repo.clone(), request) {
+                Err(RuleError::BadRequest) => {}
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    #[test]
+    fn add_rule_returns_conflict_if_name_is_taken() {
+        let house = house();
+        add_rule(
+            house.repo.clone(),
+            request("heater", below(house.thermo, 18.0), house.socket),
+        )
+        .ok();
+
+        match add_rule(house.repo, request("heater", at("07:00"), house.socket)) {
+            Err(RuleError::Conflict) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn update_rule_keeps_id_and_delete_rule_removes_it() {
+        let house = house();
+        let id = match add_rule(
+            house.repo.clone(),
+            request("heater", below(house.thermo, 18.0), house.socket),
+        ) {
+            Ok(rule) => rule.id,
+            _ => unreachable!(),
+        };
+
+        match update_rule(
+            house.repo.clone(),
+            id.clone(),
+            request("morning", at("07:00"), house.socket),
+        ) {
+            Ok(rule) => {
+                assert_eq!(rule.id, id);
+                assert_eq!(rule.name, "morning");
+                assert_eq!(rule.trigger.at.as_deref(), Some("07:00"));
+            }
+            _ => unreachable!(),
+        }
+        delete_rule(house.repo.clone(), id.clone()).ok();
+        match fetch_rule(house.repo, id, &Principal::SYSTEM) {
+            Err(RuleError::NotFound) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn evaluate_rules_fires_once_when_condition_starts_to_hold() {
+        let house = house();
+        add_rule(
+            house.repo.clone(),
+            request("heater", below(house.thermo, 18.0), house.socket),
+        )
+        .ok();
+        let client = DeviceClient::default();
+        let cache = StatusCache::default();
+        let mut state = RuleState::default();
+
+        for (temperature, expected) in [(20.0, 0), (17.0, 1), (16.0, 0), (19.0, 0), (17.0, 1)] {
+            set_temperature(&cache, house.thermo, temperature);
+            match evaluate_rules(house.repo.as_ref(), &client, &cache, &mut state, 0).await {
+                Ok(fired) => assert_eq!(fired.len(), expected),
+                _ => unreachable!(),
+            }
+        }
+
+        // the socket is offline, the attempts still end up in its history
+        match house.repo.fetch_history(house.socket, 0, u64::MAX) {
+            Ok(entries) => {
+                let commands = entries
+                    .iter()
+                    .filter(|e| matches!(e.event, DeviceEvent::Command { .. }))
+                    .count();
+                assert_eq!(commands, 2);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn evaluate_rules_publishes_fired_rules_with_the_watched_device() {
+        let house = house();
+        add_rule(
+            house.repo.clone(),
+            request("frost warning", below(house.thermo, 5.0), house.socket),
+        )
+        .ok();
+        let cache = StatusCache::default();
+        set_temperature(&cache, house.thermo, 2.0);
+        // other tests publish to the same bus, so look for this rule
+        let mut receiver = events::subscribe();
+
+        evaluate_rules(
+            house.repo.as_ref(),
+            &DeviceClient::default(),
+            &cache,
+            &mut RuleState::default(),
+            0,
+        )
+        .await
+        .ok();
+
+        loop {
+            if let Event::RuleFired {
+                device_id,
+                id,
+                rule,
+                ..
+            } = receiver.recv().await.unwrap()
+            {
+                if rule == "frost warning" {
+                    assert_eq!(device_id, "thermo");
+                    assert_eq!(id, String::from(house.thermo));
+                    break;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn evaluate_rules_fires_rules_without_an_action() {
+        let house = house();
+        let request = RuleRequest {
+            action: None,
+            ..request("too cold", below(house.thermo, 10.0), house.socket)
+        };
+        match add_rule(house.repo.clone(), request) {
+            Ok(rule) => assert!(rule.action.is_none()),
+            _ => unreachable!(),
+        }
+        let cache = StatusCache::default();
+        set_temperature(&cache, house.thermo, 8.0);
+
+        match evaluate_rules(
+            house.repo.as_ref(),
+            &DeviceClient::default(),
+            &cache,
+            &mut RuleState::default(),
+            0,
+        )
+        .await
+        {
+            Ok(fired) => assert_eq!(fired.len(), 1),
+            _ => unreachable!(),
+        }
+        // nothing was switched
+        match house.repo.fetch_history(house.socket, 0, u64::MAX) {
+            Ok(entries) => assert!(entries.is_empty()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn evaluate_rules_fires_time_of_day_once_it_passed() {
+        let house = house();
+        add_rule(
+            house.repo.clone(),
+            request("morning", at("07:00"), house.socket),
+        )
+        .ok();
+        let client = DeviceClient::default();
+        let cache = StatusCache::default();
+        let mut state = RuleState::default();
+        // some midnight UTC
+        let day = 20_000 * DAY_MS;
+        let seven = 7 * 60 * MINUTE_MS;
+
+        for (now, expected) in [
+            (day + seven - MINUTE_MS, 0),
+            (day + seven + MINUTE_MS, 1),
+            (day + seven + 2 * MINUTE_MS, 0),
+            (day + DAY_MS + seven, 1),
+        ] {
+            match evaluate_rules(house.repo.as_ref(), &client, &cache, &mut state, now).await {
+                Ok(fired) => assert_eq!(fired.len(), expected),
+                _ => unreachable!(),
+            }
+        }
+    }
+}
@@ -0,0 +1,556 @@
+use crate::domain::cache::StatusCache;
+use crate::domain::client::DeviceClient;
+use crate::domain::entity::{
+    DeviceCommand, DeviceId, DeviceInfo, DeviceStatus, DeviceType, Role, Scene, SceneName,
+    SceneStep, SocketStatus,
+};
+use crate::domain::service::access::{self, AccessError, Principal};
+use crate::domain::service::device_command::command_socket;
+use crate::domain::service::device_query::{read_device, StatusSource};
+use crate::repository::history::HistoryStore;
+use crate::repository::room::{DeleteError, FetchError, InsertError, Repository, UpdateError};
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+pub struct SceneRequest {
+    pub name: String,
+    pub steps: Vec<StepRequest>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct StepRequest {
+    pub device_id: String,
+    pub command: String,
+}
+
+#[derive(Serialize)]
+pub struct SceneResponse {
+    name: String,
+    steps: Vec<StepRequest>,
+}
+
+impl From<Scene> for SceneResponse {
+    fn from(scene: Scene) -> Self {
+        Self {
+            name: scene.name.into(),
+            steps: scene
+                .steps
+                .into_iter()
+                .map(|step| StepRequest {
+                    device_id: step.device_id.into(),
+                    command: step.command.into(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// `rolled_back` is set when a step failed and the devices switched by
+/// the other steps were put back into their previous state.
+#[derive(Serialize)]
+pub struct ActivationResponse {
+    scene: String,
+    pub applied: bool,
+    rolled_back: bool,
+    steps: Vec<StepResult>,
+}
+
+/// Either `status` or `error` is set, `rolled_back` only for steps that
+/// succeeded and were reverted afterwards.
+#[derive(Serialize)]
+pub struct StepResult {
+    device_id: String,
+    command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<SocketStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    rolled_back: bool,
+}
+
+pub enum SceneError {
+    BadRequest,
+    NotFound,
+    Conflict,
+    Forbidden,
+    Unknown,
+}
+
+impl From<AccessError> for SceneError {
+    fn from(err: AccessError) -> Self {
+        match err {
+            AccessError::Forbidden => Self::Forbidden,
+            AccessError::Unknown => Self::Unknown,
+        }
+    }
+}
+
+pub fn add_scene<R: Repository>(
+    repo: Arc<R>,
+    request: SceneRequest,
+) -> Result<SceneResponse, SceneError> {
+    let scene = parse_scene(repo.as_ref(), request.name, request.steps)?;
+
+    match repo.add_scene(scene) {
+        Ok(scene) => Ok(SceneResponse::from(scene)),
+        Err(InsertError::Conflict) => Err(SceneError::Conflict),
+        Err(InsertError::Unknown) => Err(SceneError::Unknown),
+    }
+}
+
+/// `Forbidden` unless the principal may read every device of the scene.
+pub fn fetch_scene<R: Repository>(
+    repo: Arc<R>,
+    name: String,
+    principal: &Principal,
+) -> Result<SceneResponse, SceneError> {
+    let name = SceneName::try_from(name).map_err(|_| SceneError::BadRequest)?;
+    let scene = match repo.fetch_scene(name) {
+        Ok(scene) => scene,
+        Err(FetchError::NotFound) => return Err(SceneError::NotFound),
+        Err(FetchError::Unknown) => return Err(SceneError::Unknown),
+    };
+    access::check_devices(repo.as_ref(), principal, Role::Read, devices(&scene))?;
+
+    Ok(SceneResponse::from(scene))
+}
+
+/// The scenes the principal may read every device of.
+pub fn fetch_scenes<R: Repository>(
+    repo: Arc<R>,
+    principal: &Principal,
+) -> Result<Vec<SceneResponse>, SceneError> {
+    let scenes = match repo.fetch_scenes() {
+        Ok(scenes) => scenes,
+        Err(FetchError::NotFound) => return Err(SceneError::NotFound),
+        Err(FetchError::Unknown) => return Err(SceneError::Unknown),
+    };
+    let scenes = access::readable(repo.as_ref(), principal, scenes, devices)?;
+
+    Ok(scenes.into_iter().map(SceneResponse::from).collect())
+}
+
+fn devices(scene: &Scene) -> Vec<DeviceId> {
+    scene.steps.iter().map(|step| step.device_id).collect()
+}
+
+pub fn update_scene<R: Repository>(
+    repo: Arc<R>,
+    name: String,
+    steps: Vec<StepRequest>,
+) -> Result<SceneResponse, SceneError> {
+    let scene = parse_scene(repo.as_ref(), name, steps)?;
+
+    match repo.update_scene(scene) {
+        Ok(scene) => Ok(SceneResponse::from(scene)),
+        Err(UpdateError::NotFound) => Err(SceneError::NotFound),
+        Err(UpdateError::Conflict) => Err(SceneError::Conflict),
+        Err(UpdateError::Unknown) => Err(SceneError::Unknown),
+    }
+}
+
+pub fn delete_scene<R: Repository>(repo: Arc<R>, name: String) -> Result<(), SceneError> {
+    let name = SceneName::try_from(name).map_err(|_| SceneError::BadRequest)?;
+
+    match repo.delete_scene(name) {
+        Ok(()) => Ok(()),
+        Err(DeleteError::NotFound) => Err(SceneError::NotFound),
+        Err(_) => Err(SceneError::Unknown),
+    }
+}
+
+/// Sends every command of the scene at once. With `rollback` the sockets
+/// are read first, and if any step fails the ones that were switched are
+/// set back to the state they were read in.
+///
+/// A failed step is not an error, it is reported in the response. The
+/// scene is `Forbidden` unless the principal may control every device in it.
+pub async fn activate_scene<R: Repository + HistoryStore>(
+    repo: Arc<R>,
+    client: &DeviceClient,
+    cache: &StatusCache,
+    name: String,
+    rollback: bool,
+    principal: &Principal,
+) -> Result<ActivationResponse, SceneError> {
+    let name = SceneName::try_from(name).map_err(|_| SceneError::BadRequest)?;
+    let scene = match repo.fetch_scene(name) {
+        Ok(scene) => scene,
+        Err(FetchError::NotFound) => return Err(SceneError::NotFound),
+        Err(FetchError::Unknown) => return Err(SceneError::Unknown),
+    };
+    let repo = repo.as_ref();
+    for step in &scene.steps {
+        if let Ok((room_name, info)) = repo.fetch_device_by_id(step.device_id) {
+            access::check_device(repo, principal, Role::Control, &room_name, &info)?;
+        }
+    }
+
+    // a device deleted or changed since the scene was saved fails its step
+    let targets: Vec<Result<DeviceInfo, String>> = scene
+        .steps
+        .iter()
+        .map(|step| match repo.fetch_device_by_id(step.device_id) {
+            Ok((_, info)) if info.device_type == DeviceType::TcpSocket => Ok(info),
+            Ok(_) => Err("device is not a socket".to_string()),
+            Err(FetchError::NotFound) => Err("device not found".to_string()),
+            Err(FetchError::Unknown) => Err("device lookup failed".to_string()),
+        })
+        .collect();
+
+    let previous: Vec<Option<bool>> = if rollback {
+        let source = StatusSource {
+            client,
+            cache,
+            fresh: true,
+        };
+        join_all(targets.iter().map(|target| async move {
+            let info = target.as_ref().ok()?;
+            match read_device(repo, source, info).await.result {
+                Ok(DeviceStatus::Socket(status)) => Some(status.enabled),
+                _ => None,
+            }
+        }))
+        .await
+    } else {
+        vec![None; targets.len()]
+    };
+
+    let results: Vec<Result<SocketStatus, String>> = join_all(
+        scene
+            .steps
+            .iter()
+            .zip(&targets)
+            .map(|(step, target)| async move {
+                let info = target.as_ref().map_err(Clone::clone)?;
+                command_socket(
+                    repo,
+                    client,
+                    cache,
+                    info.id,
+                    info.address,
+                    step.command.clone(),
+                )
+                .await
+                .map_err(|e| e.to_string())
+            }),
+    )
+    .await;
+
+    let applied = results.iter().all(Result::is_ok);
+    let rolling_back = rollback && !applied;
+    let restored: Vec<bool> = if rolling_back {
+        join_all(targets.iter().zip(&results).zip(&previous).map(
+            |((target, result), previous)| async move {
+                // a socket whose state could not be read is left as it is
+                match (target, result, previous) {
+                    (Ok(info), Ok(_), Some(enabled)) => {
+                        let command = if *enabled {
+                            DeviceCommand::TurnOn
+                        } else {
+                            DeviceCommand::TurnOff
+                        };
+                        command_socket(repo, client, cache, info.id, info.address, command)
+                            .await
+                            .is_ok()
+                    }
+                    _ => false,
+                }
+            },
+        ))
+        .await
+    } else {
+        vec![false; results.len()]
+    };
+
+    Ok(ActivationResponse {
+        scene: scene.name.into(),
+        applied,
+        rolled_back: rolling_back,
+        steps: scene
+            .steps
+            .into_iter()
+            .zip(results)
+            .zip(restored)
+            .map(|((step, result), rolled_back)| StepResult {
+                device_id: step.device_id.into(),
+                command: step.command.into(),
+                status: result.as_ref().ok().cloned(),
+                error: result.err(),
+                rolled_back,
+            })
+            .collect(),
+    })
+}
+
+// a scene needs at least one step, each for a different socket
+fn parse_scene<R: Repository>(
+    repo: &R,
+    name: String,
+    steps: Vec<StepRequest>,
+) -> Result<Scene, SceneError> {
+    let name = SceneName::try_from(name).map_err(|_| SceneError::BadRequest)?;
+    if steps.is_empty() {
+        return Err(SceneError::BadRequest);
+    }
+
+    let mut seen = HashSet::new();
+    let steps = steps
+        .into_iter()
+        .map(|step| {
+            let device_id =
+                DeviceId::try_from(step.device_id).map_err(|_| SceneError::BadRequest)?;
+            let command =
+                DeviceCommand::try_from(step.command).map_err(|_| SceneError::BadRequest)?;
+            if !seen.insert(device_id) {
+                return Err(SceneError::BadRequest);
+            }
+            match repo.fetch_device_by_id(device_id) {
+                Ok((_, info)) if info.device_type == DeviceType::TcpSocket => {}
+                Ok(_) | Err(FetchError::NotFound) => return Err(SceneError::BadRequest),
+                Err(FetchError::Unknown) => return Err(SceneError::Unknown),
+            }
+            Ok(SceneStep { device_id, command })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Scene { name, steps })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::entity::RoomName;
+    use crate::domain::fixture::{add_device, unused_tcp_address};
+    use crate::repository::room::InMemoryRepository;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::Mutex;
+    use std::thread;
+
+    // a socket that keeps its state and remembers the commands it got
+    fn online_socket(enabled: bool) -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = received.clone();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut enabled = enabled;
+            let mut buf = [0; 10];
+            while let Ok(bytes_read) = stream.read(&mut buf) {
+                if bytes_read == 0 {
+                    return;
+                }
+                let query = String::from_utf8_lossy(&buf[..bytes_read]).to_string();
+                match query.as_str() {
+                    "SET1" => enabled = true,
+                    "SET0" => enabled = false,
+                    _ => {}
+                }
+                log.lock().unwrap().push(query);
+                let reply = format!("{{\"enabled\":{},\"power\":0.0}}\n", enabled);
+                if stream.write_all(reply.as_bytes()).is_err() {
+                    return;
+                }
+            }
+        });
+        (address, received)
+    }
+
+    fn request(name: &str, steps: &[(DeviceId, &str)]) -> SceneRequest {
+        SceneRequest {
+            name: name.to_string(),
+            steps: steps
+                .iter()
+                .map(|(device_id, command)| StepRequest {
+                    device_id: (*device_id).into(),
+                    command: command.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn add_scene_validates_steps() {
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::kitchen()).ok();
+        let socket = add_device(
+            repo.as_ref(),
+            RoomName::kitchen(),
+            "socket",
+            unused_tcp_address(),
+            DeviceType::TcpSocket,
+        );
+        let thermo = add_device(
+            repo.as_ref(),
+            RoomName::kitchen(),
+            "thermo",
+            unused_tcp_address(),
+            DeviceType::UdpThermo,
+        );
+
+        for request in [
+            request("", &[(socket, "on")]),
+            request("movie night", &[]),
+            request("movie night", &[(socket, "toggle")]),
+            request("movie night", &[(thermo, "on")]),
+            request("movie night", &[(DeviceId::generate(), "on")]),
+            request("movie night", &[(socket, "on"), (socket, "off")]),
+        ] {
+            match add_scene(repo.clone(), request) {
+                Err(SceneError::BadRequest) => {}
+                _ => unreachable!(),
+            }
+        }
+
+        match add_scene(repo.clone(), request("movie night", &[(socket, "off")])) {
+            Ok(_) => {}
+            _ => unreachable!(),
+        }
+        match add_scene(repo, request("movie night", &[(socket, "on")])) {
+            Err(SceneError::Conflict) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn update_scene_returns_not_found_for_missing_scene() {
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::kitchen()).ok();
+        let socket = add_device(
+            repo.as_ref(),
+            RoomName::kitchen(),
+            "socket",
+            unused_tcp_address(),
+            DeviceType::TcpSocket,
+        );
+
+        match update_scene(
+            repo,
+            "movie night".to_string(),
+            request("", &[(socket, "on")]).steps,
+        ) {
+            Err(SceneError::NotFound) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn activate_scene_reports_failed_steps_and_rolls_back_the_others() {
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::kitchen()).ok();
+        let (address, received) = online_socket(true);
+        let lamp = add_device(
+            repo.as_ref(),
+            RoomName::kitchen(),
+            "lamp",
+            address,
+            DeviceType::TcpSocket,
+        );
+        let tv = add_device(
+            repo.as_ref(),
+            RoomName::kitchen(),
+            "tv",
+            unused_tcp_address(),
+            DeviceType::TcpSocket,
+        );
+        add_scene(
+            repo.clone(),
+            request("movie night", &[(lamp, "off"), (tv, "on")]),
+        )
+        .ok();
+
+        let client = DeviceClient::default();
+        let cache = StatusCache::default();
+        match activate_scene(
+            repo,
+            &client,
+            &cache,
+            "movie night".to_string(),
+            true,
+            &Principal::SYSTEM,
+        )
+        .await
+        {
+            Ok(result) => {
+                assert!(!result.applied);
+                assert!(result.rolled_back);
+                assert!(result.steps[0].status.is_some());
+                assert!(result.steps[0].rolled_back);
+                assert!(result.steps[1].error.is_some());
+                assert!(!result.steps[1].rolled_back);
+            }
+            _ => unreachable!(),
+        }
+        assert_eq!(*received.lock().unwrap(), vec!["GET", "SET0", "SET1"]);
+    }
+
+    #[tokio::test]
+    async fn activate_scene_keeps_successful_steps_without_rollback() {
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::kitchen()).ok();
+        let (address, received) = online_socket(true);
+        let lamp = add_device(
+            repo.as_ref(),
+            RoomName::kitchen(),
+            "lamp",
+            address,
+            DeviceType::TcpSocket,
+        );
+        let tv = add_device(
+            repo.as_ref(),
+            RoomName::kitchen(),
+            "tv",
+            unused_tcp_address(),
+            DeviceType::TcpSocket,
+        );
+        add_scene(
+            repo.clone(),
+            request("movie night", &[(lamp, "off"), (tv, "on")]),
+        )
+        .ok();
+
+        let client = DeviceClient::default();
+        let cache = StatusCache::default();
+        match activate_scene(
+            repo,
+            &client,
+            &cache,
+            "movie night".to_string(),
+            false,
+            &Principal::SYSTEM,
+        )
+        .await
+        {
+            Ok(result) => {
+                assert!(!result.applied);
+                assert!(!result.rolled_back);
+                assert!(!result.steps[0].rolled_back);
+            }
+            _ => unreachable!(),
+        }
+        assert_eq!(*received.lock().unwrap(), vec!["SET0"]);
+    }
+
+    #[tokio::test]
+    async fn activate_scene_returns_not_found_for_missing_scene() {
+        let repo = Arc::new(InMemoryRepository::new());
+
+        match activate_scene(
+            repo,
+            &DeviceClient::default(),
+            &StatusCache::default(),
+            "movie night".to_string(),
+            true,
+            &Principal::SYSTEM,
+        )
+        .await
+        {
+            Err(SceneError::NotFound) => {}
+            _ => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,426 @@
+use crate::domain::cache::StatusCache;
+use crate::domain::client::DeviceClient;
+use crate::domain::cron::CronExpr;
+use crate::domain::entity::{
+    DeviceCommand, DeviceId, DeviceInfo, DeviceType, Role, Schedule, ScheduleId, ScheduleRun,
+    SocketStatus,
+};
+use crate::domain::service::access::{self, AccessError, Principal};
+use crate::domain::service::device_command::command_socket;
+use crate::domain::service::history;
+use crate::repository::history::HistoryStore;
+use crate::repository::room::{DeleteError, FetchError, InsertError, Repository};
+use crate::repository::schedule::ScheduleStore;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+
+const MINUTE_MS: u64 = 60 * 1000;
+
+// how often the scheduler looks for due schedules, well below a minute
+// so a schedule fires within seconds of its minute starting
+const TICK: Duration = Duration::from_secs(5);
+
+// after the host slept for longer, only the last hour is caught up on
+const MAX_CATCH_UP_MINUTES: u64 = 60;
+
+/// `cron` is a five field expression in UTC, see `CronExpr`.
+#[derive(Deserialize)]
+pub struct ScheduleRequest {
+    pub cron: String,
+    pub device_id: String,
+    pub command: String,
+}
+
+#[derive(Serialize)]
+pub struct ScheduleResponse {
+    id: String,
+    cron: String,
+    device_id: String,
+    command: String,
+}
+
+impl From<Schedule> for ScheduleResponse {
+    fn from(schedule: Schedule) -> Self {
+        Self {
+            id: schedule.id.into(),
+            cron: schedule.cron.into(),
+            device_id: schedule.device_id.into(),
+            command: schedule.command.into(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct RunsResponse {
+    schedule_id: String,
+    runs: Vec<RunResponse>,
+}
+
+#[derive(Serialize)]
+pub struct RunResponse {
+    timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<SocketStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+pub enum ScheduleError {
+    BadRequest,
+    NotFound,
+    Forbidden,
+    Unknown,
+}
+
+impl From<AccessError> for ScheduleError {
+    fn from(err: AccessError) -> Self {
+        match err {
+            AccessError::Forbidden => Self::Forbidden,
+            AccessError::Unknown => Self::Unknown,
+        }
+    }
+}
+
+pub fn add_schedule<R: Repository + ScheduleStore>(
+    repo: Arc<R>,
+    request: ScheduleRequest,
+) -> Result<ScheduleResponse, ScheduleError> {
+    let schedule = Schedule {
+        id: ScheduleId::generate(),
+        cron: CronExpr::try_from(request.cron).map_err(|_| ScheduleError::BadRequest)?,
+        device_id: DeviceId::try_from(request.device_id).map_err(|_| ScheduleError::BadRequest)?,
+        command: DeviceCommand::try_from(request.command).map_err(|_| ScheduleError::BadRequest)?,
+    };
+    // only sockets take commands
+    match repo.fetch_device_by_id(schedule.device_id) {
+        Ok((_, info)) if info.device_type == DeviceType::TcpSocket => {}
+        Ok(_) | Err(FetchError::NotFound) => return Err(ScheduleError::BadRequest),
+        Err(FetchError::Unknown) => return Err(ScheduleError::Unknown),
+    }
+
+    match repo.add_schedule(schedule) {
+        Ok(schedule) => Ok(ScheduleResponse::from(schedule)),
+        Err(InsertError::Conflict) | Err(InsertError::Unknown) => Err(ScheduleError::Unknown),
+    }
+}
+
+/// `Forbidden` unless the principal may read the device of the schedule.
+pub fn fetch_schedule<R: Repository + ScheduleStore>(
+    repo: Arc<R>,
+    id: String,
+    principal: &Principal,
+) -> Result<ScheduleResponse, ScheduleError> {
+    let id = ScheduleId::try_from(id).map_err(|_| ScheduleError::BadRequest)?;
+    let schedule = readable_schedule(repo.as_ref(), id, principal)?;
+
+    Ok(ScheduleResponse::from(schedule))
+}
+
+/// The schedules of the devices the principal may read.
+pub fn fetch_schedules<R: Repository + ScheduleStore>(
+    repo: Arc<R>,
+    principal: &Principal,
+) -> Result<Vec<ScheduleResponse>, ScheduleError> {
+    let schedules = match repo.fetch_schedules() {
+        Ok(schedules) => schedules,
+        Err(FetchError::NotFound) => return Err(ScheduleError::NotFound),
+        Err(FetchError::Unknown) => return Err(ScheduleError::Unknown),
+    };
+    let schedules = access::readable(repo.as_ref(), principal, schedules, |schedule| {
+        vec![schedule.device_id]
+    })?;
+
+    Ok(schedules.into_iter().map(ScheduleResponse::from).collect())
+}
+
+pub fn delete_schedule<R: ScheduleStore>(repo: Arc<R>, id: String) -> Result<(), ScheduleError> {
+    let id = ScheduleId::try_from(id).map_err(|_| ScheduleError::BadRequest)?;
+
+    match repo.delete_schedule(id) {
+        Ok(()) => Ok(()),
+        Err(DeleteError::NotFound) => Err(ScheduleError::NotFound),
+        Err(_) => Err(ScheduleError::Unknown),
+    }
+}
+
+/// `Forbidden` unless the principal may read the device of the schedule,
+/// the runs tell its status.
+pub fn fetch_runs<R: Repository + ScheduleStore>(
+    repo: Arc<R>,
+    id: String,
+    principal: &Principal,
+) -> Result<RunsResponse, ScheduleError> {
+    let schedule_id = ScheduleId::try_from(id.clone()).map_err(|_| ScheduleError::BadRequest)?;
+    readable_schedule(repo.as_ref(), schedule_id, principal)?;
+
+    match repo.fetch_runs(schedule_id) {
+        Ok(runs) => Ok(RunsResponse {
+            schedule_id: id,
+            runs: runs
+                .into_iter()
+                .map(|run| RunResponse {
+                    timestamp: run.timestamp,
+                    status: run.status,
+                    error: run.error,
+                })
+                .collect(),
+        }),
+        Err(FetchError::NotFound) => Err(ScheduleError::NotFound),
+        Err(FetchError::Unknown) => Err(ScheduleError::Unknown),
+    }
+}
+
+fn readable_schedule<R: Repository + ScheduleStore>(
+    repo: &R,
+    id: ScheduleId,
+    principal: &Principal,
+) -> Result<Schedule, ScheduleError> {
+    let schedule = match repo.fetch_schedule(id) {
+        Ok(schedule) => schedule,
+        Err(FetchError::NotFound) => return Err(ScheduleError::NotFound),
+        Err(FetchError::Unknown) => return Err(ScheduleError::Unknown),
+    };
+    access::check_devices(repo, principal, Role::Read, [schedule.device_id])?;
+    Ok(schedule)
+}
+
+/// Runs every schedule whose expression matched a minute in `(last, now]`,
+/// once even if it matched several, and returns how many ran. Every run,
+/// failed or not, goes to the log of its schedule.
+pub async fn run_due_schedules<R: Repository + HistoryStore + ScheduleStore>(
+    repo: &R,
+    client: &DeviceClient,
+    cache: &StatusCache,
+    last: u64,
+    now: u64,
+) -> Result<usize, ScheduleError> {
+    let last_minute = last / MINUTE_MS;
+    let now_minute = now / MINUTE_MS;
+    if now_minute <= last_minute {
+        return Ok(0);
+    }
+    let first_minute = (last_minute + 1).max(now_minute.saturating_sub(MAX_CATCH_UP_MINUTES - 1));
+
+    let schedules = match repo.fetch_schedules() {
+        Ok(schedules) => schedules,
+        Err(FetchError::NotFound) => return Err(ScheduleError::NotFound),
+        Err(FetchError::Unknown) => return Err(ScheduleError::Unknown),
+    };
+    let mut ran = 0;
+    for schedule in schedules {
+        let due =
+            (first_minute..=now_minute).any(|minute| schedule.cron.matches(minute * MINUTE_MS));
+        if due {
+            run_schedule(repo, client, cache, schedule).await;
+            ran += 1;
+        }
+    }
+    Ok(ran)
+}
+
+/// Fires the due schedules every few seconds until the runtime stops,
+/// minutes that passed while the server was down are not caught up on.
+pub async fn run_scheduler<R: Repository + HistoryStore + ScheduleStore>(
+    repo: Arc<R>,
+    client: DeviceClient,
+    cache: StatusCache,
+) {
+    let mut last = history::now_millis();
+    let mut ticks = tokio::time::interval(TICK);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let now = history::now_millis();
+        // minutes whose schedules could not be read are tried again on the
+        // next tick, `MAX_CATCH_UP_MINUTES` bounds how far back
+        if run_due_schedules(repo.as_ref(), &client, &cache, last, now)
+            .await
+            .is_ok()
+        {
+            last = now;
+        }
+    }
+}
+
+async fn run_schedule<R: Repository + HistoryStore + ScheduleStore>(
+    repo: &R,
+    client: &DeviceClient,
+    cache: &StatusCache,
+    schedule: Schedule,
+) {
+    let result = match repo.fetch_device_by_id(schedule.device_id) {
+        Ok((
+            _,
+            DeviceInfo {
+                id,
+                address,
+                device_type: DeviceType::TcpSocket,
+                ..
+            },
+        )) => command_socket(repo, client, cache, id, address, schedule.command)
+            .await
+            .map_err(|e| e.to_string()),
+        Ok(_) => Err("device is not a socket".to_string()),
+        Err(FetchError::NotFound) => Err("device not found".to_string()),
+        Err(FetchError::Unknown) => Err("device lookup failed".to_string()),
+    };
+
+    let run = ScheduleRun {
+        schedule_id: schedule.id,
+        timestamp: history::now_millis(),
+        status: result.as_ref().ok().cloned(),
+        error: result.err(),
+    };
+    // the schedule may have been deleted meanwhile
+    repo.record_run(run).ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::entity::{Grant, GrantTarget, RoomName, User, UserId, UserName};
+    use crate::domain::fixture::{add_device, unused_tcp_address};
+    use crate::repository::room::InMemoryRepository;
+
+    // 2024-01-01T00:00:00Z, a Monday
+    const NEW_YEAR_2024: u64 = 1_704_067_200_000;
+
+    // a kitchen with one device, offline if it is a socket
+    fn kitchen_with(device_type: DeviceType) -> (Arc<InMemoryRepository>, DeviceId) {
+        let repo = Arc::new(InMemoryRepository::new());
+        repo.add_room(RoomName::kitchen()).ok();
+        let name = match device_type {
+            DeviceType::TcpSocket => "socket",
+            DeviceType::UdpThermo => "thermo",
+        };
+        let id = add_device(
+            repo.as_ref(),
+            RoomName::kitchen(),
+            name,
+            unused_tcp_address(),
+            device_type,
+        );
+        (repo, id)
+    }
+
+    fn request(cron: &str, device_id: DeviceId) -> ScheduleRequest {
+        ScheduleRequest {
+            cron: cron.to_string(),
+            device_id: device_id.into(),
+            command: "on".to_string(),
+        }
+    }
+
+    #[test]
+    fn add_schedule_rejects_bad_cron_and_non_sockets() {
+        let (repo, socket) = kitchen_with(DeviceType::TcpSocket);
+        let (thermo_repo, thermo) = kitchen_with(DeviceType::UdpThermo);
+
+        match add_schedule(repo.clone(), request("0 7 * *", socket)) {
+            Err(ScheduleError::BadRequest) => {}
+            _ => unreachable!(),
+        }
+        match add_schedule(repo, request("0 7 * * 1-5", DeviceId::generate())) {
+            Err(ScheduleError::BadRequest) => {}
+            _ => unreachable!(),
+        }
+        match add_schedule(thermo_repo, request("0 7 * * 1-5", thermo)) {
+            Err(ScheduleError::BadRequest) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn run_due_schedules_fires_once_per_matching_minute_and_logs_failures() {
+        let (repo, socket) = kitchen_with(DeviceType::TcpSocket);
+        let id = match add_schedule(repo.clone(), request("0 7 * * 1-5", socket)) {
+            Ok(schedule) => schedule.id,
+            _ => unreachable!(),
+        };
+        let client = DeviceClient::default();
+        let cache = StatusCache::default();
+        let seven = NEW_YEAR_2024 + 7 * 60 * MINUTE_MS;
+
+        for (last, now, expected) in [
+            (seven - 10_000, seven - 5_000, 0),
+            (seven - 5_000, seven + 1_000, 1),
+            (seven + 1_000, seven + 6_000, 0),
+            // a tick that spans several minutes still fires once
+            (seven - 5 * MINUTE_MS, seven + 5 * MINUTE_MS, 1),
+        ] {
+            match run_due_schedules(repo.as_ref(), &client, &cache, last, now).await {
+                Ok(ran) => assert_eq!(ran, expected),
+                _ => unreachable!(),
+            }
+        }
+
+        match fetch_runs(repo, id, &Principal::SYSTEM) {
+            Ok(result) => {
+                assert_eq!(result.runs.len(), 2);
+                assert!(result.runs.iter().all(|r| r.error.is_some()));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn users_only_see_the_schedules_of_the_devices_they_may_read() {
+        let (repo, socket) = kitchen_with(DeviceType::TcpSocket);
+        let id = match add_schedule(repo.clone(), request("*/5 * * * *", socket)) {
+            Ok(schedule) => schedule.id,
+            _ => unreachable!(),
+        };
+        let kid = |grants| {
+            Principal::User(User {
+                id: UserId::generate(),
+                name: UserName::try_from("kid".to_string()).unwrap(),
+                password_hash: String::new(),
+                admin: false,
+                grants,
+            })
+        };
+        let stranger = kid(vec![Grant {
+            target: GrantTarget::Device(DeviceId::generate()),
+            role: Role::Control,
+        }]);
+        let reader = kid(vec![Grant {
+            target: GrantTarget::Device(socket),
+            role: Role::Read,
+        }]);
+
+        match fetch_schedules(repo.clone(), &stranger) {
+            Ok(schedules) => assert!(schedules.is_empty()),
+            _ => unreachable!(),
+        }
+        match fetch_schedule(repo.clone(), id.clone(), &stranger) {
+            Err(ScheduleError::Forbidden) => {}
+            _ => unreachable!(),
+        }
+        match fetch_runs(repo.clone(), id.clone(), &stranger) {
+            Err(ScheduleError::Forbidden) => {}
+            _ => unreachable!(),
+        }
+        match fetch_schedules(repo.clone(), &reader) {
+            Ok(schedules) => assert_eq!(schedules.len(), 1),
+            _ => unreachable!(),
+        }
+        assert!(fetch_runs(repo, id, &reader).is_ok());
+    }
+
+    #[test]
+    fn fetch_runs_returns_not_found_after_schedule_is_deleted() {
+        let (repo, socket) = kitchen_with(DeviceType::TcpSocket);
+        let id = match add_schedule(repo.clone(), request("*/5 * * * *", socket)) {
+            Ok(schedule) => schedule.id,
+            _ => unreachable!(),
+        };
+        delete_schedule(repo.clone(), id.clone()).ok();
+
+        match fetch_runs(repo, id, &Principal::SYSTEM) {
+            Err(ScheduleError::NotFound) => {}
+            _ => unreachable!(),
+        }
+    }
+}
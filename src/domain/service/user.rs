@@ -0,0 +1,571 @@
+use crate::domain::entity::{
+    DeviceId, Grant, GrantTarget, Role, RoomId, Scope, User, UserId, UserName,
+};
+use crate::domain::service::access::{self, AuthError};
+use crate::domain::service::history;
+use crate::repository::api_key::ApiKeyStore;
+use crate::repository::room::{DeleteError, FetchError, InsertError, Repository, UpdateError};
+use crate::repository::user::UserStore;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::{Arc, OnceLock};
+use uuid::Uuid;
+
+const MIN_PASSWORD_LEN: usize = 8;
+const SESSION_TTL_MS: u64 = 24 * 60 * 60 * 1000;
+/// Starts every session token, so that no API key is taken for one.
+pub const SESSION_PREFIX: &str = "session:";
+
+#[derive(Deserialize)]
+pub struct UserRequest {
+    pub name: String,
+    pub password: String,
+    #[serde(default)]
+    pub admin: bool,
+}
+
+/// Changes to a user, `None` keeps the current value.
+#[derive(Deserialize)]
+pub struct UpdateRequest {
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub admin: Option<bool>,
+}
+
+/// Either `room_id` or `device_id` is set, both are the stable ids from
+/// the room and device responses. `role` is `read` or `control`.
+#[derive(Deserialize, Serialize)]
+pub struct GrantSpec {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    pub role: String,
+}
+
+#[derive(Serialize)]
+pub struct UserResponse {
+    id: String,
+    name: String,
+    admin: bool,
+    grants: Vec<GrantSpec>,
+}
+
+impl From<User> for UserResponse {
+    fn from(user: User) -> Self {
+        Self {
+            id: user.id.into(),
+            name: user.name.into(),
+            admin: user.admin,
+            grants: user
+                .grants
+                .into_iter()
+                .map(|grant| {
+                    let (room_id, device_id) = match grant.target {
+                        GrantTarget::Room(id) => (Some(id.into()), None),
+                        GrantTarget::Device(id) => (None, Some(id.into())),
+                    };
+                    GrantSpec {
+                        room_id,
+                        device_id,
+                        role: grant.role.into(),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    pub name: String,
+    pub password: String,
+}
+
+/// `token` goes into the `Authorization: Bearer` header like an API key.
+#[derive(Serialize)]
+pub struct SessionResponse {
+    pub token: String,
+    /// milliseconds since the unix epoch
+    pub expires: u64,
+}
+
+pub enum UserError {
+    BadRequest,
+    NotFound,
+    Conflict,
+    /// the first user of an open house has to be an admin
+    NotAdmin,
+    /// the change would leave no admin user and no admin key
+    LastAdmin,
+    Unknown,
+}
+
+/// Signs the session tokens, `session:<user id>.<expiry>.<signature>`, so they are
+/// checked without being stored. The signature also covers the password
+/// hash of the user, a new password ends the sessions made with the old.
+pub struct Sessions {
+    secret: Vec<u8>,
+}
+
+impl Sessions {
+    /// Without a secret a random one is made, the sessions then end with
+    /// the server.
+    pub fn new(secret: Option<String>) -> Self {
+        let secret = secret.map(String::into_bytes).unwrap_or_else(|| {
+            [Uuid::new_v4(), Uuid::new_v4()]
+                .iter()
+                .flat_map(|uuid| *uuid.as_bytes())
+                .collect()
+        });
+        Self { secret }
+    }
+
+    fn mac(&self, payload: &str, password_hash: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC takes keys of any length");
+        mac.update(payload.as_bytes());
+        mac.update(b".");
+        mac.update(password_hash.as_bytes());
+        mac
+    }
+
+    fn issue(&self, user: &User, expires: u64) -> String {
+        let payload = format!("{}.{}", String::from(user.id), expires);
+        let signature = hex::encode(
+            self.mac(&payload, &user.password_hash)
+                .finalize()
+                .into_bytes(),
+        );
+        format!("{}{}.{}", SESSION_PREFIX, payload, signature)
+    }
+}
+
+pub fn add_user<R: ApiKeyStore + UserStore>(
+    repo: Arc<R>,
+    admin_key: Option<&str>,
+    request: UserRequest,
+) -> Result<UserResponse, UserError> {
+    let name = UserName::try_from(request.name).map_err(|_| UserError::BadRequest)?;
+    if !request.admin
+        && access::is_open(repo.as_ref(), admin_key).map_err(|_| UserError::Unknown)?
+    {
+        return Err(UserError::NotAdmin);
+    }
+    let user = User {
+        id: UserId::generate(),
+        name,
+        password_hash: hash_password(&request.password)?,
+        admin: request.admin,
+        grants: Vec::new(),
+    };
+
+    match repo.add_user(user) {
+        Ok(user) => Ok(UserResponse::from(user)),
+        Err(InsertError::Conflict) => Err(UserError::Conflict),
+        Err(InsertError::Unknown) => Err(UserError::Unknown),
+    }
+}
+
+pub fn fetch_users<R: UserStore>(repo: Arc<R>) -> Result<Vec<UserResponse>, UserError> {
+    match repo.fetch_users() {
+        Ok(users) => Ok(users.into_iter().map(UserResponse::from).collect()),
+        Err(FetchError::NotFound) => Err(UserError::NotFound),
+        Err(FetchError::Unknown) => Err(UserError::Unknown),
+    }
+}
+
+pub fn fetch_user<R: UserStore>(repo: Arc<R>, id: String) -> Result<UserResponse, UserError> {
+    fetch(repo.as_ref(), id).map(UserResponse::from)
+}
+
+/// A new password ends the sessions of the user, see `Sessions`.
+pub fn update_user<R: ApiKeyStore + UserStore>(
+    repo: Arc<R>,
+    admin_key: Option<&str>,
+    id: String,
+    request: UpdateRequest,
+) -> Result<UserResponse, UserError> {
+    let mut user = fetch(repo.as_ref(), id)?;
+    if request.admin == Some(false) && is_last_admin(repo.as_ref(), admin_key, &user)? {
+        return Err(UserError::LastAdmin);
+    }
+    if let Some(password) = request.password {
+        user.password_hash = hash_password(&password)?;
+    }
+    if let Some(admin) = request.admin {
+        user.admin = admin;
+    }
+
+    store(repo.as_ref(), user)
+}
+
+/// Replaces the grants of the user, every room and device has to exist.
+pub fn set_grants<R: Repository + UserStore>(
+    repo: Arc<R>,
+    id: String,
+    grants: Vec<GrantSpec>,
+) -> Result<UserResponse, UserError> {
+    let mut user = fetch(repo.as_ref(), id)?;
+    user.grants = grants
+        .into_iter()
+        .map(|grant| parse_grant(repo.as_ref(), grant))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    store(repo.as_ref(), user)
+}
+
+pub fn delete_user<R: ApiKeyStore + UserStore>(
+    repo: Arc<R>,
+    admin_key: Option<&str>,
+    id: String,
+) -> Result<(), UserError> {
+    let user = fetch(repo.as_ref(), id)?;
+    if is_last_admin(repo.as_ref(), admin_key, &user)? {
+        return Err(UserError::LastAdmin);
+    }
+
+    match repo.delete_user(user.id) {
+        Ok(()) => Ok(()),
+        Err(DeleteError::NotFound) => Err(UserError::NotFound),
+        Err(_) => Err(UserError::Unknown),
+    }
+}
+
+// without the admin key of the settings, an admin key or another admin
+// user, nobody could manage the house once this user is no admin
+fn is_last_admin<R: ApiKeyStore + UserStore>(
+    repo: &R,
+    admin_key: Option<&str>,
+    user: &User,
+) -> Result<bool, UserError> {
+    if !user.admin || admin_key.is_some() {
+        return Ok(false);
+    }
+    match (repo.fetch_keys(), repo.fetch_users()) {
+        (Ok(keys), Ok(users)) => Ok(!keys.iter().any(|key| key.scope == Scope::Admin)
+            && !users.iter().any(|other| other.admin && other.id != user.id)),
+        _ => Err(UserError::Unknown),
+    }
+}
+
+/// Checks the password and starts a session, a wrong name and a wrong
+/// password are both `Invalid`. An unknown name is checked against a dummy
+/// hash, so it takes as long as a wrong password and does not tell the
+/// names apart.
+pub fn login<R: UserStore>(
+    repo: Arc<R>,
+    sessions: &Sessions,
+    request: LoginRequest,
+) -> Result<SessionResponse, AuthError> {
+    let name = UserName::try_from(request.name).map_err(|_| AuthError::Invalid)?;
+    let user = match repo.fetch_user_by_name(&name) {
+        Ok(user) => user,
+        Err(FetchError::NotFound) => {
+            verify_password(&request.password, dummy_hash());
+            return Err(AuthError::Invalid);
+        }
+        Err(FetchError::Unknown) => return Err(AuthError::Unknown),
+    };
+    if !verify_password(&request.password, &user.password_hash) {
+        return Err(AuthError::Invalid);
+    }
+
+    let expires = history::now_millis() + SESSION_TTL_MS;
+    Ok(SessionResponse {
+        token: sessions.issue(&user, expires),
+        expires,
+    })
+}
+
+/// Stored keys are plain hex and the admin key may not take the prefix.
+pub fn is_session_token(token: &str) -> bool {
+    token.starts_with(SESSION_PREFIX)
+}
+
+/// The user of a session token that is signed, not expired and whose user
+/// still exists with the password the session was made with.
+pub fn verify_session<R: UserStore>(
+    repo: &R,
+    sessions: &Sessions,
+    token: &str,
+) -> Result<User, AuthError> {
+    let token = token
+        .strip_prefix(SESSION_PREFIX)
+        .ok_or(AuthError::Invalid)?;
+    let (payload, signature) = token.rsplit_once('.').ok_or(AuthError::Invalid)?;
+    let signature = hex::decode(signature).map_err(|_| AuthError::Invalid)?;
+    let (id, expires) = payload.split_once('.').ok_or(AuthError::Invalid)?;
+    let expires: u64 = expires.parse().map_err(|_| AuthError::Invalid)?;
+    if expires <= history::now_millis() {
+        return Err(AuthError::Invalid);
+    }
+    let id = UserId::try_from(id.to_string()).map_err(|_| AuthError::Invalid)?;
+    let user = match repo.fetch_user(id) {
+        Ok(user) => user,
+        Err(FetchError::NotFound) => return Err(AuthError::Invalid),
+        Err(FetchError::Unknown) => return Err(AuthError::Unknown),
+    };
+
+    sessions
+        .mac(payload, &user.password_hash)
+        .verify_slice(&signature)
+        .map_err(|_| AuthError::Invalid)?;
+    Ok(user)
+}
+
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+// made once with the same parameters as the real hashes
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| hash_password("not anyone's password").unwrap_or_default())
+}
+
+fn hash_password(password: &str) -> Result<String, UserError> {
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(UserError::BadRequest);
+    }
+    let salt = SaltString::encode_b64(Uuid::new_v4().as_bytes()).map_err(|_| UserError::Unknown)?;
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| UserError::Unknown)
+}
+
+fn fetch<R: UserStore>(repo: &R, id: String) -> Result<User, UserError> {
+    let id = UserId::try_from(id).map_err(|_| UserError::BadRequest)?;
+
+    match repo.fetch_user(id) {
+        Ok(user) => Ok(user),
+        Err(FetchError::NotFound) => Err(UserError::NotFound),
+        Err(FetchError::Unknown) => Err(UserError::Unknown),
+    }
+}
+
+fn store<R: UserStore>(repo: &R, user: User) -> Result<UserResponse, UserError> {
+    match repo.update_user(user) {
+        Ok(user) => Ok(UserResponse::from(user)),
+        Err(UpdateError::NotFound) => Err(UserError::NotFound),
+        Err(UpdateError::Conflict) => Err(UserError::Conflict),
+        Err(UpdateError::Unknown) => Err(UserError::Unknown),
+    }
+}
+
+fn parse_grant<R: Repository>(repo: &R, grant: GrantSpec) -> Result<Grant, UserError> {
+    let role = Role::try_from(grant.role).map_err(|_| UserError::BadRequest)?;
+    let target = match (grant.room_id, grant.device_id) {
+        (Some(id), None) => {
+            let id = RoomId::try_from(id).map_err(|_| UserError::BadRequest)?;
+            match repo.fetch_rooms() {
+                Ok(rooms) if rooms.iter().any(|room| room.id == id) => GrantTarget::Room(id),
+                Ok(_) => return Err(UserError::BadRequest),
+                Err(_) => return Err(UserError::Unknown),
+            }
+        }
+        (None, Some(id)) => {
+            let id = DeviceId::try_from(id).map_err(|_| UserError::BadRequest)?;
+            match repo.fetch_device_by_id(id) {
+                Ok(_) => GrantTarget::Device(id),
+                Err(FetchError::NotFound) => return Err(UserError::BadRequest),
+                Err(FetchError::Unknown) => return Err(UserError::Unknown),
+            }
+        }
+        _ => return Err(UserError::BadRequest),
+    };
+
+    Ok(Grant { target, role })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::entity::RoomName;
+    use crate::repository::room::InMemoryRepository;
+
+    const ADMIN_KEY: &str = "a long enough admin key";
+
+    fn request(name: &str) -> UserRequest {
+        UserRequest {
+            name: name.to_string(),
+            password: "correct horse".to_string(),
+            admin: false,
+        }
+    }
+
+    fn login_as(name: &str, password: &str) -> LoginRequest {
+        LoginRequest {
+            name: name.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    #[test]
+    fn login_checks_the_password_and_signs_the_session() {
+        let repo = Arc::new(InMemoryRepository::new());
+        let sessions = Sessions::new(None);
+        match add_user(
+            repo.clone(),
+            Some(ADMIN_KEY),
+            UserRequest {
+                password: "short".to_string(),
+                ..request("kid")
+            },
+        ) {
+            Err(UserError::BadRequest) => {}
+            _ => unreachable!(),
+        }
+        if add_user(repo.clone(), Some(ADMIN_KEY), request("kid")).is_err() {
+            unreachable!()
+        }
+        match repo.fetch_user_by_name(&UserName::try_from("kid".to_string()).unwrap()) {
+            Ok(user) => assert!(!user.password_hash.contains("correct horse")),
+            _ => unreachable!(),
+        }
+
+        match login(repo.clone(), &sessions, login_as("kid", "wrong horse")) {
+            Err(AuthError::Invalid) => {}
+            _ => unreachable!(),
+        }
+        match login(repo.clone(), &sessions, login_as("nobody", "correct horse")) {
+            Err(AuthError::Invalid) => {}
+            _ => unreachable!(),
+        }
+        let token = match login(repo.clone(), &sessions, login_as("kid", "correct horse")) {
+            Ok(session) => session.token,
+            _ => unreachable!(),
+        };
+        assert!(is_session_token(&token));
+        assert!(verify_session(repo.as_ref(), &sessions, &token).is_ok());
+
+        // another server secret, a changed expiry and a lapsed session
+        match verify_session(repo.as_ref(), &Sessions::new(None), &token) {
+            Err(AuthError::Invalid) => {}
+            _ => unreachable!(),
+        }
+        let (id, rest) = token
+            .strip_prefix(SESSION_PREFIX)
+            .and_then(|token| token.split_once('.'))
+            .unwrap();
+        let (_, signature) = rest.split_once('.').unwrap();
+        let forged = format!("{}{}.{}.{}", SESSION_PREFIX, id, u64::MAX, signature);
+        match verify_session(repo.as_ref(), &sessions, &forged) {
+            Err(AuthError::Invalid) => {}
+            _ => unreachable!(),
+        }
+        let user = repo
+            .fetch_user(UserId::try_from(id.to_string()).unwrap())
+            .ok()
+            .unwrap();
+        match verify_session(repo.as_ref(), &sessions, &sessions.issue(&user, 1)) {
+            Err(AuthError::Invalid) => {}
+            _ => unreachable!(),
+        }
+
+        // a new password ends the sessions made with the old one
+        let update = UpdateRequest {
+            password: Some("battery staple".to_string()),
+            admin: None,
+        };
+        if update_user(repo.clone(), Some(ADMIN_KEY), id.to_string(), update).is_err() {
+            unreachable!()
+        }
+        match verify_session(repo.as_ref(), &sessions, &token) {
+            Err(AuthError::Invalid) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn set_grants_only_takes_existing_rooms_and_devices() {
+        let repo = Arc::new(InMemoryRepository::new());
+        let room = match repo.add_room(RoomName::bathroom()) {
+            Ok(room) => room,
+            _ => unreachable!(),
+        };
+        let id = match add_user(repo.clone(), Some(ADMIN_KEY), request("kid")) {
+            Ok(user) => user.id,
+            _ => unreachable!(),
+        };
+        let grant = |room_id: Option<String>, device_id: Option<String>, role: &str| GrantSpec {
+            room_id,
+            device_id,
+            role: role.to_string(),
+        };
+
+        for invalid in [
+            grant(Some(room.id.into()), None, "admin"),
+            grant(Some(RoomId::generate().into()), None, "read"),
+            grant(None, Some(DeviceId::generate().into()), "read"),
+            grant(None, None, "read"),
+        ] {
+            match set_grants(repo.clone(), id.clone(), vec![invalid]) {
+                Err(UserError::BadRequest) => {}
+                _ => unreachable!(),
+            }
+        }
+        match set_grants(
+            repo.clone(),
+            id.clone(),
+            vec![grant(Some(room.id.into()), None, "control")],
+        ) {
+            Ok(user) => assert_eq!(user.grants.len(), 1),
+            _ => unreachable!(),
+        }
+        match repo.fetch_user(UserId::try_from(id).unwrap()) {
+            Ok(user) => assert_eq!(
+                user.grants,
+                vec![Grant {
+                    target: GrantTarget::Room(room.id),
+                    role: Role::Control,
+                }]
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn the_last_admin_user_stays_an_admin_without_an_admin_key() {
+        let repo = Arc::new(InMemoryRepository::new());
+        let admin = UserRequest {
+            admin: true,
+            ..request("parent")
+        };
+        let id = match add_user(repo.clone(), None, admin) {
+            Ok(user) => user.id,
+            _ => unreachable!(),
+        };
+        add_user(repo.clone(), None, request("kid")).ok();
+        let demote = || UpdateRequest {
+            password: None,
+            admin: Some(false),
+        };
+
+        match update_user(repo.clone(), None, id.clone(), demote()) {
+            Err(UserError::LastAdmin) => {}
+            _ => unreachable!(),
+        }
+        match delete_user(repo.clone(), None, id.clone()) {
+            Err(UserError::LastAdmin) => {}
+            _ => unreachable!(),
+        }
+
+        // the admin key of the settings can still manage the house
+        match update_user(repo.clone(), Some(ADMIN_KEY), id.clone(), demote()) {
+            Ok(user) => assert!(!user.admin),
+            _ => unreachable!(),
+        }
+        assert!(delete_user(repo, Some(ADMIN_KEY), id).is_ok());
+    }
+}
@@ -0,0 +1,637 @@
+use crate::domain::entity::{
+    DeviceId, RoomId, RoomInfo, Webhook, WebhookDelivery, WebhookId, WebhookUrl,
+};
+use crate::domain::events::{self, Event, EventFilter, KINDS};
+use crate::domain::service::history;
+use crate::repository::room::{DeleteError, FetchError, InsertError, Repository};
+use crate::repository::webhook::WebhookStore;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use uuid::Uuid;
+
+/// Names the kind of the delivered event.
+pub const EVENT_HEADER: &str = "X-Smart-Home-Event";
+/// `sha256=` followed by the hex HMAC-SHA256 of the body, keyed with the secret.
+pub const SIGNATURE_HEADER: &str = "X-Smart-Home-Signature";
+
+// a receiver that takes longer counts as a failed attempt
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `secret` is generated when missing, an empty `events` subscribes to
+/// every kind of event. `room_id` and `device_id` are the stable ids of an
+/// existing room and device, so a rename keeps the webhook matching.
+#[derive(Deserialize)]
+pub struct WebhookRequest {
+    pub url: String,
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default)]
+    pub events: Vec<String>,
+    pub room_id: Option<String>,
+    pub device_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct WebhookResponse {
+    id: String,
+    url: String,
+    events: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    /// only told once, when the webhook is added
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+}
+
+impl From<Webhook> for WebhookResponse {
+    fn from(webhook: Webhook) -> Self {
+        Self {
+            id: webhook.id.into(),
+            url: webhook.url.into(),
+            events: webhook.events,
+            room_id: webhook.room_id.map(String::from),
+            device_id: webhook.device_id.map(String::from),
+            secret: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct DeliveriesResponse {
+    webhook_id: String,
+    deliveries: Vec<DeliveryResponse>,
+}
+
+/// `status` is the HTTP status of the last attempt, `error` is set unless
+/// it was a success.
+#[derive(Serialize)]
+pub struct DeliveryResponse {
+    timestamp: u64,
+    event: String,
+    attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// The body posted to a webhook.
+#[derive(Serialize)]
+struct Payload<'a> {
+    webhook_id: String,
+    /// milliseconds since the unix epoch, of the first attempt
+    timestamp: u64,
+    event: &'a Event,
+}
+
+/// How often a delivery is attempted, the delay doubles after every failed attempt.
+#[derive(Clone, Copy)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub first_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            first_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+pub enum WebhookError {
+    BadRequest,
+    NotFound,
+    Unknown,
+}
+
+pub fn add_webhook<R: Repository + WebhookStore>(
+    repo: Arc<R>,
+    request: WebhookRequest,
+) -> Result<WebhookResponse, WebhookError> {
+    let secret = match request.secret {
+        Some(secret) if secret.is_empty() => return Err(WebhookError::BadRequest),
+        Some(secret) => secret,
+        None => Uuid::new_v4().simple().to_string(),
+    };
+    let mut events = Vec::new();
+    for kind in request.events {
+        if !KINDS.contains(&kind.as_str()) {
+            return Err(WebhookError::BadRequest);
+        }
+        if !events.contains(&kind) {
+            events.push(kind);
+        }
+    }
+    let room_id = match request.room_id {
+        Some(id) => {
+            let id = RoomId::try_from(id).map_err(|_| WebhookError::BadRequest)?;
+            match repo.fetch_rooms() {
+                Ok(rooms) if rooms.iter().any(|room| room.id == id) => Some(id),
+                Ok(_) => return Err(WebhookError::BadRequest),
+                Err(_) => return Err(WebhookError::Unknown),
+            }
+        }
+        None => None,
+    };
+    let device_id = match request.device_id {
+        Some(id) => {
+            let id = DeviceId::try_from(id).map_err(|_| WebhookError::BadRequest)?;
+            match repo.fetch_device_by_id(id) {
+                Ok(_) => Some(id),
+                Err(FetchError::NotFound) => return Err(WebhookError::BadRequest),
+                Err(FetchError::Unknown) => return Err(WebhookError::Unknown),
+            }
+        }
+        None => None,
+    };
+    let webhook = Webhook {
+        id: WebhookId::generate(),
+        url: WebhookUrl::try_from(request.url).map_err(|_| WebhookError::BadRequest)?,
+        secret: secret.clone(),
+        events,
+        room_id,
+        device_id,
+    };
+
+    match repo.add_webhook(webhook) {
+        Ok(webhook) => Ok(WebhookResponse {
+            secret: Some(secret),
+            ..WebhookResponse::from(webhook)
+        }),
+        Err(InsertError::Conflict) | Err(InsertError::Unknown) => Err(WebhookError::Unknown),
+    }
+}
+
+pub fn fetch_webhook<R: WebhookStore>(
+    repo: Arc<R>,
+    id: String,
+) -> Result<WebhookResponse, WebhookError> {
+    let id = WebhookId::try_from(id).map_err(|_| WebhookError::BadRequest)?;
+
+    match repo.fetch_webhook(id) {
+        Ok(webhook) => Ok(WebhookResponse::from(webhook)),
+        Err(FetchError::NotFound) => Err(WebhookError::NotFound),
+        Err(FetchError::Unknown) => Err(WebhookError::Unknown),
+    }
+}
+
+pub fn fetch_webhooks<R: WebhookStore>(repo: Arc<R>) -> Result<Vec<WebhookResponse>, WebhookError> {
+    match repo.fetch_webhooks() {
+        Ok(webhooks) => Ok(webhooks.into_iter().map(WebhookResponse::from).collect()),
+        Err(FetchError::NotFound) => Err(WebhookError::NotFound),
+        Err(FetchError::Unknown) => Err(WebhookError::Unknown),
+    }
+}
+
+pub fn delete_webhook<R: WebhookStore>(repo: Arc<R>, id: String) -> Result<(), WebhookError> {
+    let id = WebhookId::try_from(id).map_err(|_| WebhookError::BadRequest)?;
+
+    match repo.delete_webhook(id) {
+        Ok(()) => Ok(()),
+        Err(DeleteError::NotFound) => Err(WebhookError::NotFound),
+        Err(_) => Err(WebhookError::Unknown),
+    }
+}
+
+pub fn fetch_deliveries<R: WebhookStore>(
+    repo: Arc<R>,
+    id: String,
+) -> Result<DeliveriesResponse, WebhookError> {
+    let webhook_id = WebhookId::try_from(id.clone()).map_err(|_| WebhookError::BadRequest)?;
+
+    match repo.fetch_deliveries(webhook_id) {
+        Ok(deliveries) => Ok(DeliveriesResponse {
+            webhook_id: id,
+            deliveries: deliveries
+                .into_iter()
+                .map(|delivery| DeliveryResponse {
+                    timestamp: delivery.timestamp,
+                    event: delivery.event,
+                    attempts: delivery.attempts,
+                    status: delivery.status,
+                    error: delivery.error,
+                })
+                .collect(),
+        }),
+        Err(FetchError::NotFound) => Err(WebhookError::NotFound),
+        Err(FetchError::Unknown) => Err(WebhookError::Unknown),
+    }
+}
+
+/// Whether the event passes the filters of the webhook. The events name
+/// their room, `rooms` tells the current name of the one the webhook
+/// watches, once that room is deleted the webhook hears nothing more.
+pub fn accepts(webhook: &Webhook, rooms: &[RoomInfo], event: &Event) -> bool {
+    let kind_matches =
+        webhook.events.is_empty() || webhook.events.iter().any(|kind| kind == event.kind());
+    let room_matches = match webhook.room_id {
+        Some(id) => rooms.iter().find(|room| room.id == id).is_some_and(|room| {
+            let filter = EventFilter {
+                room_id: Some(room.name.clone().into()),
+                device_id: None,
+            };
+            filter.matches(event)
+        }),
+        None => true,
+    };
+    let device_matches = match webhook.device_id {
+        Some(id) => event.id() == Some(String::from(id).as_str()),
+        None => true,
+    };
+    kind_matches && room_matches && device_matches
+}
+
+/// The value of `SIGNATURE_HEADER` for the body.
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Posts the event to the webhook until it answers with a 2xx status or
+/// the attempts run out, and logs the outcome with the webhook.
+pub async fn deliver<R: WebhookStore>(
+    repo: &R,
+    http: &reqwest::Client,
+    retry: RetryPolicy,
+    webhook: &Webhook,
+    event: &Event,
+) -> WebhookDelivery {
+    let timestamp = history::now_millis();
+    let payload = Payload {
+        webhook_id: webhook.id.into(),
+        timestamp,
+        event,
+    };
+    // the events are plain data, serializing them can't fail
+    let body = serde_json::to_vec(&payload).unwrap_or_default();
+    let signature = signature(&webhook.secret, &body);
+
+    let mut attempts = 0;
+    let mut delay = retry.first_delay;
+    let (status, error) = loop {
+        attempts += 1;
+        let sent = http
+            .post(String::from(webhook.url.clone()))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event.kind())
+            .header(SIGNATURE_HEADER, &signature)
+            .body(body.clone())
+            .send()
+            .await;
+        let (status, error) = match sent {
+            Ok(response) if response.status().is_success() => {
+                break (Some(response.status().as_u16()), None)
+            }
+            Ok(response) => (
+                Some(response.status().as_u16()),
+                format!("receiver answered {}", response.status()),
+            ),
+            Err(e) => (None, e.to_string()),
+        };
+        if attempts >= retry.attempts {
+            break (status, Some(error));
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    };
+
+    let delivery = WebhookDelivery {
+        webhook_id: webhook.id,
+        timestamp,
+        event: event.kind().to_string(),
+        attempts,
+        status,
+        error,
+    };
+    // the webhook may have been deleted meanwhile
+    repo.record_delivery(delivery.clone()).ok();
+    delivery
+}
+
+/// Delivers every published event to the webhooks that accept it until the
+/// runtime stops. Deliveries run on their own, so a slow receiver only holds
+/// up itself, events missed while the dispatcher lagged are not delivered.
+pub async fn run_webhooks<R: Repository + WebhookStore>(repo: Arc<R>, retry: RetryPolicy) {
+    let http = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .unwrap_or_default();
+    let mut receiver = events::subscribe();
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        let webhooks = match repo.fetch_webhooks() {
+            Ok(webhooks) => webhooks,
+            Err(_) => continue,
+        };
+        let rooms = if webhooks.iter().any(|webhook| webhook.room_id.is_some()) {
+            repo.fetch_rooms().unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        for webhook in webhooks
+            .into_iter()
+            .filter(|webhook| accepts(webhook, &rooms, &event))
+        {
+            let (repo, http, event) = (repo.clone(), http.clone(), event.clone());
+            tokio::spawn(async move {
+                deliver(repo.as_ref(), &http, retry, &webhook, &event).await;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::entity::{DeviceInfo, DeviceName, DeviceType, RoomName};
+    use crate::repository::room::InMemoryRepository;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    // a stand-in for the service behind the webhook, like the one in
+    // examples/run_repo.rs, failing its first `failures` requests
+    #[derive(Default)]
+    struct Receiver {
+        failures: AtomicUsize,
+        requests: Mutex<Vec<(Option<String>, Vec<u8>)>>,
+    }
+
+    async fn receive(
+        req: HttpRequest,
+        body: web::Bytes,
+        receiver: web::Data<Receiver>,
+    ) -> HttpResponse {
+        let signature = req
+            .headers()
+            .get(SIGNATURE_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        receiver
+            .requests
+            .lock()
+            .unwrap()
+            .push((signature, body.to_vec()));
+
+        let failing = receiver
+            .failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |f| f.checked_sub(1))
+            .is_ok();
+        if failing {
+            HttpResponse::InternalServerError().finish()
+        } else {
+            HttpResponse::Ok().finish()
+        }
+    }
+
+    fn spawn_receiver(failures: usize) -> (String, Arc<Receiver>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let receiver = Arc::new(Receiver {
+            failures: AtomicUsize::new(failures),
+            ..Receiver::default()
+        });
+        let data = web::Data::from(receiver.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route("/hook", web::post().to(receive))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap()
+        .run();
+        tokio::spawn(server);
+        (url, receiver)
+    }
+
+    fn request(url: &str) -> WebhookRequest {
+        WebhookRequest {
+            url: url.to_string(),
+            secret: Some("secret".to_string()),
+            events: vec!["status_changed".to_string()],
+            room_id: None,
+            device_id: None,
+        }
+    }
+
+    fn register(repo: &Arc<InMemoryRepository>, url: &str) -> Webhook {
+        let id = match add_webhook(repo.clone(), request(url)) {
+            Ok(webhook) => WebhookId::try_from(webhook.id).unwrap(),
+            _ => unreachable!(),
+        };
+        repo.fetch_webhook(id).ok().unwrap()
+    }
+
+    fn went_offline(room_id: &str, id: &str) -> Event {
+        Event::StatusChanged {
+            room_id: room_id.to_string(),
+            device_id: "socket".to_string(),
+            id: id.to_string(),
+            reachable: false,
+            status: None,
+        }
+    }
+
+    fn retry(attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            first_delay: Duration::from_millis(10),
+        }
+    }
+
+    #[test]
+    fn add_webhook_checks_the_request_and_tells_the_secret_once() {
+        let repo = Arc::new(InMemoryRepository::new());
+        let unknown_event = WebhookRequest {
+            events: vec!["door_opened".to_string()],
+            ..request("http://127.0.0.1:9000/hook")
+        };
+        let room_name = WebhookRequest {
+            room_id: Some("kitchen".to_string()),
+            ..request("http://127.0.0.1:9000/hook")
+        };
+        let unknown_room = WebhookRequest {
+            room_id: Some(RoomId::generate().into()),
+            ..request("http://127.0.0.1:9000/hook")
+        };
+        let unknown_device = WebhookRequest {
+            device_id: Some(DeviceId::generate().into()),
+            ..request("http://127.0.0.1:9000/hook")
+        };
+        let generated_secret = WebhookRequest {
+            secret: None,
+            ..request("https://example.com/hook")
+        };
+
+        for bad in [
+            request("ftp://example.com"),
+            unknown_event,
+            room_name,
+            unknown_room,
+            unknown_device,
+        ] {
+            match add_webhook(repo.clone(), bad) {
+                Err(WebhookError::BadRequest) => {}
+                _ => unreachable!(),
+            }
+        }
+        let id = match add_webhook(repo.clone(), generated_secret) {
+            Ok(webhook) => {
+                assert_eq!(webhook.secret.map(|s| s.len()), Some(32));
+                webhook.id
+            }
+            _ => unreachable!(),
+        };
+        match fetch_webhook(repo, id) {
+            Ok(webhook) => assert!(webhook.secret.is_none()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn accepts_filters_by_kind_room_and_device_ids() {
+        let repo = Arc::new(InMemoryRepository::new());
+        let room_id = repo.add_room(RoomName::kitchen()).ok().unwrap().id;
+        let device = DeviceInfo {
+            id: DeviceId::generate(),
+            name: DeviceName::socket(),
+            address: "127.0.0.1:9001".parse().unwrap(),
+            device_type: DeviceType::TcpSocket,
+            groups: Vec::new(),
+        };
+        let device_id = String::from(device.id);
+        repo.add_device(RoomName::kitchen(), device).ok();
+        let in_kitchen = match add_webhook(
+            repo.clone(),
+            WebhookRequest {
+                room_id: Some(room_id.into()),
+                ..request("http://127.0.0.1:9000/hook")
+            },
+        ) {
+            Ok(webhook) => repo
+                .fetch_webhook(WebhookId::try_from(webhook.id).unwrap())
+                .ok()
+                .unwrap(),
+            _ => unreachable!(),
+        };
+        let on_socket = match add_webhook(
+            repo.clone(),
+            WebhookRequest {
+                device_id: Some(device_id.clone()),
+                ..request("http://127.0.0.1:9000/hook")
+            },
+        ) {
+            Ok(webhook) => repo
+                .fetch_webhook(WebhookId::try_from(webhook.id).unwrap())
+                .ok()
+                .unwrap(),
+            _ => unreachable!(),
+        };
+        let room_added = Event::RoomAdded {
+            room_id: "kitchen".to_string(),
+        };
+        let rooms = repo.fetch_rooms().ok().unwrap();
+
+        assert!(accepts(&in_kitchen, &rooms, &went_offline("kitchen", "id")));
+        assert!(!accepts(
+            &in_kitchen,
+            &rooms,
+            &went_offline("bathroom", "id")
+        ));
+        assert!(!accepts(&in_kitchen, &rooms, &room_added));
+        assert!(accepts(
+            &on_socket,
+            &rooms,
+            &went_offline("bathroom", &device_id)
+        ));
+        assert!(!accepts(&on_socket, &rooms, &went_offline("kitchen", "id")));
+
+        // the filters follow a renamed room
+        repo.rename_room(RoomName::kitchen(), RoomName::bathroom())
+            .ok();
+        let rooms = repo.fetch_rooms().ok().unwrap();
+        assert!(accepts(
+            &in_kitchen,
+            &rooms,
+            &went_offline("bathroom", "id")
+        ));
+        assert!(!accepts(
+            &in_kitchen,
+            &rooms,
+            &went_offline("kitchen", "id")
+        ));
+    }
+
+    #[tokio::test]
+    async fn deliver_posts_a_signed_payload() {
+        let repo = Arc::new(InMemoryRepository::new());
+        let (url, receiver) = spawn_receiver(0);
+        let webhook = register(&repo, &url);
+        let event = went_offline("kitchen", "id");
+
+        let delivery = deliver(
+            repo.as_ref(),
+            &reqwest::Client::new(),
+            retry(1),
+            &webhook,
+            &event,
+        )
+        .await;
+
+        assert_eq!((delivery.attempts, delivery.status), (1, Some(200)));
+        let requests = receiver.requests.lock().unwrap();
+        let (received_signature, body) = &requests[0];
+        assert_eq!(received_signature, &Some(signature("secret", body)));
+        let payload: serde_json::Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(payload["event"]["type"], "status_changed");
+        assert_eq!(payload["event"]["reachable"], false);
+    }
+
+    #[tokio::test]
+    async fn deliver_retries_failed_attempts_and_logs_the_outcome() {
+        let repo = Arc::new(InMemoryRepository::new());
+        let (url, _) = spawn_receiver(2);
+        let flaky = register(&repo, &url);
+        let (url, _) = spawn_receiver(usize::MAX);
+        let broken = register(&repo, &url);
+        let http = reqwest::Client::new();
+        let event = went_offline("kitchen", "id");
+
+        deliver(repo.as_ref(), &http, retry(3), &flaky, &event).await;
+        deliver(repo.as_ref(), &http, retry(2), &broken, &event).await;
+
+        match fetch_deliveries(repo.clone(), flaky.id.into()) {
+            Ok(log) => {
+                assert_eq!(log.deliveries.len(), 1);
+                let delivery = &log.deliveries[0];
+                assert_eq!((delivery.attempts, delivery.status), (3, Some(200)));
+                assert!(delivery.error.is_none());
+            }
+            _ => unreachable!(),
+        }
+        match fetch_deliveries(repo, broken.id.into()) {
+            Ok(log) => {
+                let delivery = &log.deliveries[0];
+                assert_eq!((delivery.attempts, delivery.status), (2, Some(500)));
+                assert!(delivery.error.is_some());
+            }
+            _ => unreachable!(),
+        }
+    }
+}
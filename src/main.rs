@@ -1,11 +1,106 @@
 use smart_home_backend::api;
-use smart_home_backend::repository::room::InMemoryRepository;
+use smart_home_backend::api::auth::AuthConfig;
+use smart_home_backend::config::{Backend, Settings};
+use smart_home_backend::domain::cache::StatusCache;
+use smart_home_backend::domain::client::DeviceClient;
+use smart_home_backend::domain::service::user::Sessions;
+use smart_home_backend::domain::service::{device, device_query, history, rule, schedule, webhook};
+use smart_home_backend::repository::api_key::ApiKeyStore;
+use smart_home_backend::repository::file::FileRepository;
+use smart_home_backend::repository::history::HistoryStore;
+use smart_home_backend::repository::room::{InMemoryRepository, Repository};
+use smart_home_backend::repository::rule::RuleStore;
+use smart_home_backend::repository::schedule::ScheduleStore;
+use smart_home_backend::repository::sqlite::SqliteRepository;
+use smart_home_backend::repository::user::UserStore;
+use smart_home_backend::repository::webhook::WebhookStore;
 use std::net::TcpListener;
+use std::process;
 use std::sync::Arc;
 
+fn fail(message: String) -> ! {
+    eprintln!("smart_home_backend: {}", message);
+    process::exit(2)
+}
+
+async fn serve<
+    R: Repository + HistoryStore + RuleStore + ScheduleStore + WebhookStore + ApiKeyStore + UserStore,
+>(
+    repo: R,
+    settings: Settings,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(settings.bind)
+        .unwrap_or_else(|e| fail(format!("unable to bind to {}: {}", settings.bind, e)));
+    let client = DeviceClient::new(settings.connect_timeout, settings.read_timeout);
+    let cache = StatusCache::default();
+    if device::listen_thermometers(&repo).is_err() {
+        fail("unable to read the thermometers".to_string());
+    }
+    let repo = Arc::new(repo);
+    tokio::spawn(history::run_retention(
+        repo.clone(),
+        settings.history_retention,
+    ));
+    tokio::spawn(device_query::run_poller(
+        repo.clone(),
+        client.clone(),
+        cache.clone(),
+        settings.poll_interval,
+    ));
+    // the rules read the statuses the poller caches, so they share its pace
+    tokio::spawn(rule::run_rules(
+        repo.clone(),
+        client.clone(),
+        cache.clone(),
+        settings.poll_interval,
+    ));
+    tokio::spawn(schedule::run_scheduler(
+        repo.clone(),
+        client.clone(),
+        cache.clone(),
+    ));
+    tokio::spawn(webhook::run_webhooks(
+        repo.clone(),
+        webhook::RetryPolicy::default(),
+    ));
+    api::spawn(
+        listener,
+        repo,
+        client,
+        cache,
+        settings.workers,
+        settings.poll_interval,
+        AuthConfig {
+            admin_key: settings.admin_key,
+            sessions: Sessions::new(settings.session_secret),
+        },
+    )?
+    .await
+}
+
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let repo = Arc::new(InMemoryRepository::new());
-    let listener = TcpListener::bind("127.0.0.1:8888").expect("Undable to bind to port");
-    api::spawn(listener, repo)?.await
+    let settings = Settings::load().unwrap_or_else(|e| fail(e.to_string()));
+    env_logger::Builder::new()
+        .parse_filters(&settings.log_level)
+        .init();
+
+    let path = settings.repository_path.clone();
+    match settings.backend {
+        Backend::Memory => serve(InMemoryRepository::new(), settings).await,
+        Backend::File => {
+            let repo = FileRepository::open(&path).unwrap_or_else(|e| {
+                fail(format!(
+                    "unable to load house layout from {:?}: {}",
+                    path, e
+                ))
+            });
+            serve(repo, settings).await
+        }
+        Backend::Sqlite => {
+            let repo = SqliteRepository::open(&path)
+                .unwrap_or_else(|e| fail(format!("unable to open database {:?}: {}", path, e)));
+            serve(repo, settings).await
+        }
+    }
 }
@@ -0,0 +1,46 @@
+use crate::domain::entity::{ApiKey, ApiKeyId};
+use crate::repository::room::{DeleteError, FetchError, InsertError};
+
+/// Keeps the API keys, by the hash of their token.
+pub trait ApiKeyStore: Send + Sync + 'static {
+    /// `Conflict` if a key with the same token hash exists.
+    fn add_key(&self, key: ApiKey) -> Result<ApiKey, InsertError>;
+
+    /// Every key, in the order they were added.
+    fn fetch_keys(&self) -> Result<Vec<ApiKey>, FetchError>;
+
+    fn fetch_key_by_hash(&self, token_hash: &str) -> Result<ApiKey, FetchError>;
+
+    fn delete_key(&self, id: ApiKeyId) -> Result<(), DeleteError>;
+}
+
+// key rules shared by the stores that keep the keys in memory
+
+pub(crate) fn insert_key(keys: &mut Vec<ApiKey>, key: ApiKey) -> Result<ApiKey, InsertError> {
+    if keys
+        .iter()
+        .any(|k| k.id == key.id || k.token_hash == key.token_hash)
+    {
+        return Err(InsertError::Conflict);
+    }
+
+    keys.push(key.clone());
+    Ok(key)
+}
+
+pub(crate) fn find_key_by_hash(keys: &[ApiKey], token_hash: &str) -> Result<ApiKey, FetchError> {
+    keys.iter()
+        .find(|k| k.token_hash == token_hash)
+        .cloned()
+        .ok_or(FetchError::NotFound)
+}
+
+pub(crate) fn remove_key(keys: &mut Vec<ApiKey>, id: ApiKeyId) -> Result<(), DeleteError> {
+    match keys.iter().position(|k| k.id == id) {
+        Some(idx) => {
+            keys.remove(idx);
+            Ok(())
+        }
+        None => Err(DeleteError::NotFound),
+    }
+}
@@ -0,0 +1,1833 @@
+use crate::domain::cron::CronExpr;
+use crate::domain::entity::{
+    ApiKey, ApiKeyId, DeviceCommand, DeviceEvent, DeviceId, DeviceInfo, DeviceName, DeviceType,
+    DeviceUpdate, Grant, GrantTarget, GroupName, HistoryEntry, Role, RoomId, RoomInfo, RoomName,
+    Rule, RuleAction, RuleId, RuleName, RuleTrigger, Scene, SceneName, SceneStep, Schedule,
+    ScheduleId, ScheduleRun, Scope, SocketStatus, TimeOfDay, TriggerParts, User, UserId, UserName,
+    Webhook, WebhookDelivery, WebhookId, WebhookUrl,
+};
+use crate::repository::api_key::{self, ApiKeyStore};
+use crate::repository::history::{self, HistoryStore};
+use crate::repository::room::{
+    self, DeleteError, FetchError, InsertError, Repository, UpdateError,
+};
+use crate::repository::rule::{self, RuleStore};
+use crate::repository::schedule::{ScheduleBook, ScheduleStore};
+use crate::repository::user::{self, UserStore};
+use crate::repository::webhook::{WebhookBook, WebhookStore};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(thiserror::Error, Debug)]
+pub enum OpenError {
+    #[error("IoError: {0}")]
+    IoError(#[from] io::Error),
+    #[error("FormatError: {0}")]
+    FormatError(String),
+}
+
+// on-disk layout, kept separate from the entities so that
+// the file format does not change by accident
+#[derive(Default, Serialize, Deserialize)]
+struct Document {
+    #[serde(default)]
+    rooms: Vec<RoomRecord>,
+    #[serde(default)]
+    scenes: Vec<SceneRecord>,
+}
+
+// ids are optional so that files written before ids existed still load,
+// the missing ones are generated once and written back on open
+#[derive(Serialize, Deserialize)]
+struct RoomRecord {
+    #[serde(default)]
+    id: Option<String>,
+    name: String,
+    devices: Vec<DeviceRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeviceRecord {
+    #[serde(default)]
+    id: Option<String>,
+    name: String,
+    address: SocketAddr,
+    device_type: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    groups: Vec<String>,
+}
+
+impl From<RoomInfo> for RoomRecord {
+    fn from(inner: RoomInfo) -> Self {
+        Self {
+            id: Some(inner.id.into()),
+            name: inner.name.into(),
+            devices: inner.devices.into_iter().map(DeviceRecord::from).collect(),
+        }
+    }
+}
+
+impl From<DeviceInfo> for DeviceRecord {
+    fn from(inner: DeviceInfo) -> Self {
+        Self {
+            id: Some(inner.id.into()),
+            name: inner.name.into(),
+            address: inner.address,
+            device_type: inner.device_type.into(),
+            groups: inner.groups.into_iter().map(String::from).collect(),
+        }
+    }
+}
+
+impl TryFrom<RoomRecord> for RoomInfo {
+    type Error = OpenError;
+
+    fn try_from(record: RoomRecord) -> Result<Self, Self::Error> {
+        let id = match record.id {
+            Some(id) => RoomId::try_from(id.clone())
+                .map_err(|_| OpenError::FormatError(format!("invalid room id {}", id)))?,
+            None => RoomId::generate(),
+        };
+        let name = RoomName::try_from(record.name)
+            .map_err(|_| OpenError::FormatError("empty room name".into()))?;
+        let devices = record
+            .devices
+            .into_iter()
+            .map(DeviceInfo::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { id, name, devices })
+    }
+}
+
+impl TryFrom<DeviceRecord> for DeviceInfo {
+    type Error = OpenError;
+
+    fn try_from(record: DeviceRecord) -> Result<Self, Self::Error> {
+        let id = match record.id {
+            Some(id) => DeviceId::try_from(id.clone())
+                .map_err(|_| OpenError::FormatError(format!("invalid device id {}", id)))?,
+            None => DeviceId::generate(),
+        };
+        let name = DeviceName::try_from(record.name)
+            .map_err(|_| OpenError::FormatError("empty device name".into()))?;
+        let device_type = DeviceType::try_from(record.device_type.clone()).map_err(|_| {
+            OpenError::FormatError(format!("unknown device type {}", record.device_type))
+        })?;
+        let groups = record
+            .groups
+            .into_iter()
+            .map(|group| {
+                GroupName::try_from(group)
+                    .map_err(|_| OpenError::FormatError("empty group name".into()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            id,
+            name,
+            address: record.address,
+            device_type,
+            groups: GroupName::normalize(groups),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SceneRecord {
+    name: String,
+    steps: Vec<SceneStepRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SceneStepRecord {
+    device_id: String,
+    command: String,
+}
+
+impl From<Scene> for SceneRecord {
+    fn from(inner: Scene) -> Self {
+        Self {
+            name: inner.name.into(),
+            steps: inner
+                .steps
+                .into_iter()
+                .map(|step| SceneStepRecord {
+                    device_id: step.device_id.into(),
+                    command: step.command.into(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<SceneRecord> for Scene {
+    type Error = OpenError;
+
+    fn try_from(record: SceneRecord) -> Result<Self, Self::Error> {
+        let name = SceneName::try_from(record.name)
+            .map_err(|_| OpenError::FormatError("empty scene name".into()))?;
+        let steps = record
+            .steps
+            .into_iter()
+            .map(|step| {
+                Ok(SceneStep {
+                    device_id: DeviceId::try_from(step.device_id.clone()).map_err(|_| {
+                        OpenError::FormatError(format!("invalid device id {}", step.device_id))
+                    })?,
+                    command: DeviceCommand::try_from(step.command.clone()).map_err(|_| {
+                        OpenError::FormatError(format!("unknown command {}", step.command))
+                    })?,
+                })
+            })
+            .collect::<Result<Vec<_>, OpenError>>()?;
+        Ok(Self { name, steps })
+    }
+}
+
+// one line of the history file
+#[derive(Serialize, Deserialize)]
+struct HistoryRecord {
+    device_id: String,
+    timestamp: u64,
+    event: DeviceEvent,
+}
+
+impl From<HistoryEntry> for HistoryRecord {
+    fn from(inner: HistoryEntry) -> Self {
+        Self {
+            device_id: inner.device_id.into(),
+            timestamp: inner.timestamp,
+            event: inner.event,
+        }
+    }
+}
+
+impl TryFrom<HistoryRecord> for HistoryEntry {
+    type Error = ();
+
+    fn try_from(record: HistoryRecord) -> Result<Self, Self::Error> {
+        Ok(Self {
+            device_id: DeviceId::try_from(record.device_id)?,
+            timestamp: record.timestamp,
+            event: record.event,
+        })
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct RulesDocument {
+    #[serde(default)]
+    rules: Vec<RuleRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RuleRecord {
+    id: String,
+    name: String,
+    enabled: bool,
+    trigger: TriggerRecord,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    action: Option<ActionRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TriggerRecord {
+    kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    threshold: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    at: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ActionRecord {
+    device_id: String,
+    command: String,
+}
+
+impl From<Rule> for RuleRecord {
+    fn from(inner: Rule) -> Self {
+        let trigger = TriggerParts::from(inner.trigger);
+        Self {
+            id: inner.id.into(),
+            name: inner.name.into(),
+            enabled: inner.enabled,
+            trigger: TriggerRecord {
+                kind: trigger.kind,
+                device_id: trigger.device_id.map(String::from),
+                threshold: trigger.threshold,
+                at: trigger.at.map(String::from),
+            },
+            action: inner.action.map(|action| ActionRecord {
+                device_id: action.device_id.into(),
+                command: action.command.into(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<RuleRecord> for Rule {
+    type Error = OpenError;
+
+    fn try_from(record: RuleRecord) -> Result<Self, Self::Error> {
+        let invalid = || OpenError::FormatError(format!("invalid rule {}", record.id));
+        let trigger = TriggerParts {
+            kind: record.trigger.kind.clone(),
+            device_id: match record.trigger.device_id.clone() {
+                Some(id) => Some(DeviceId::try_from(id).map_err(|_| invalid())?),
+                None => None,
+            },
+            threshold: record.trigger.threshold,
+            at: match record.trigger.at.clone() {
+                Some(at) => Some(TimeOfDay::try_from(at).map_err(|_| invalid())?),
+                None => None,
+            },
+        };
+        Ok(Self {
+            id: RuleId::try_from(record.id.clone()).map_err(|_| invalid())?,
+            name: RuleName::try_from(record.name.clone()).map_err(|_| invalid())?,
+            enabled: record.enabled,
+            trigger: RuleTrigger::try_from(trigger).map_err(|_| invalid())?,
+            action: match &record.action {
+                Some(action) => Some(RuleAction {
+                    device_id: DeviceId::try_from(action.device_id.clone())
+                        .map_err(|_| invalid())?,
+                    command: DeviceCommand::try_from(action.command.clone())
+                        .map_err(|_| invalid())?,
+                }),
+                None => None,
+            },
+        })
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct SchedulesDocument {
+    #[serde(default)]
+    schedules: Vec<ScheduleRecord>,
+    #[serde(default)]
+    runs: Vec<RunRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScheduleRecord {
+    id: String,
+    cron: String,
+    device_id: String,
+    command: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RunRecord {
+    schedule_id: String,
+    timestamp: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status: Option<SocketStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl From<Schedule> for ScheduleRecord {
+    fn from(inner: Schedule) -> Self {
+        Self {
+            id: inner.id.into(),
+            cron: inner.cron.into(),
+            device_id: inner.device_id.into(),
+            command: inner.command.into(),
+        }
+    }
+}
+
+impl TryFrom<ScheduleRecord> for Schedule {
+    type Error = OpenError;
+
+    fn try_from(record: ScheduleRecord) -> Result<Self, Self::Error> {
+        let invalid = || OpenError::FormatError(format!("invalid schedule {}", record.id));
+        Ok(Self {
+            id: ScheduleId::try_from(record.id.clone()).map_err(|_| invalid())?,
+            cron: CronExpr::try_from(record.cron.clone()).map_err(|_| invalid())?,
+            device_id: DeviceId::try_from(record.device_id.clone()).map_err(|_| invalid())?,
+            command: DeviceCommand::try_from(record.command.clone()).map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl From<ScheduleRun> for RunRecord {
+    fn from(inner: ScheduleRun) -> Self {
+        Self {
+            schedule_id: inner.schedule_id.into(),
+            timestamp: inner.timestamp,
+            status: inner.status,
+            error: inner.error,
+        }
+    }
+}
+
+impl TryFrom<RunRecord> for ScheduleRun {
+    type Error = OpenError;
+
+    fn try_from(record: RunRecord) -> Result<Self, Self::Error> {
+        Ok(Self {
+            schedule_id: ScheduleId::try_from(record.schedule_id.clone()).map_err(|_| {
+                OpenError::FormatError(format!("invalid schedule id {}", record.schedule_id))
+            })?,
+            timestamp: record.timestamp,
+            status: record.status,
+            error: record.error,
+        })
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct WebhooksDocument {
+    #[serde(default)]
+    webhooks: Vec<WebhookRecord>,
+    #[serde(default)]
+    deliveries: Vec<DeliveryRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WebhookRecord {
+    id: String,
+    url: String,
+    secret: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    events: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    room_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeliveryRecord {
+    webhook_id: String,
+    timestamp: u64,
+    event: String,
+    attempts: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl From<Webhook> for WebhookRecord {
+    fn from(inner: Webhook) -> Self {
+        Self {
+            id: inner.id.into(),
+            url: inner.url.into(),
+            secret: inner.secret,
+            events: inner.events,
+            room_id: inner.room_id.map(String::from),
+            device_id: inner.device_id.map(String::from),
+        }
+    }
+}
+
+impl WebhookRecord {
+    // files written before the filters moved to ids name the room and the
+    // device instead
+    fn names_filter(&self) -> bool {
+        self.room_id
+            .iter()
+            .any(|id| RoomId::try_from(id.clone()).is_err())
+            || self
+                .device_id
+                .iter()
+                .any(|id| DeviceId::try_from(id.clone()).is_err())
+    }
+
+    // a name that no longer resolves gets a random id of no room or device,
+    // so the webhook stays narrow and goes quiet like one whose room was
+    // deleted
+    fn into_webhook(self, rooms: &[RoomInfo]) -> Result<Webhook, OpenError> {
+        let invalid = || OpenError::FormatError(format!("invalid webhook {}", self.id));
+        let mut named_room = None;
+        let room_id = match self.room_id.clone() {
+            Some(id) => Some(match RoomId::try_from(id.clone()) {
+                Ok(id) => id,
+                Err(_) => {
+                    let name = RoomName::try_from(id).map_err(|_| invalid())?;
+                    let room = rooms.iter().find(|r| r.name == name);
+                    named_room = Some(name);
+                    room.map_or_else(RoomId::generate, |r| r.id)
+                }
+            }),
+            None => None,
+        };
+        let device_id = match self.device_id.clone() {
+            Some(id) => Some(match DeviceId::try_from(id.clone()) {
+                Ok(id) => id,
+                Err(_) => {
+                    let name = DeviceName::try_from(id).map_err(|_| invalid())?;
+                    rooms
+                        .iter()
+                        .filter(|r| named_room.as_ref().is_none_or(|n| r.name == *n))
+                        .flat_map(|r| &r.devices)
+                        .find(|d| d.name == name)
+                        .map_or_else(DeviceId::generate, |d| d.id)
+                }
+            }),
+            None => None,
+        };
+        Ok(Webhook {
+            id: WebhookId::try_from(self.id.clone()).map_err(|_| invalid())?,
+            url: WebhookUrl::try_from(self.url).map_err(|_| invalid())?,
+            secret: self.secret,
+            events: self.events,
+            room_id,
+            device_id,
+        })
+    }
+}
+
+impl From<WebhookDelivery> for DeliveryRecord {
+    fn from(inner: WebhookDelivery) -> Self {
+        Self {
+            webhook_id: inner.webhook_id.into(),
+            timestamp: inner.timestamp,
+            event: inner.event,
+            attempts: inner.attempts,
+            status: inner.status,
+            error: inner.error,
+        }
+    }
+}
+
+impl TryFrom<DeliveryRecord> for WebhookDelivery {
+    type Error = OpenError;
+
+    fn try_from(record: DeliveryRecord) -> Result<Self, Self::Error> {
+        Ok(Self {
+            webhook_id: WebhookId::try_from(record.webhook_id.clone()).map_err(|_| {
+                OpenError::FormatError(format!("invalid webhook id {}", record.webhook_id))
+            })?,
+            timestamp: record.timestamp,
+            event: record.event,
+            attempts: record.attempts,
+            status: record.status,
+            error: record.error,
+        })
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct KeysDocument {
+    #[serde(default)]
+    keys: Vec<KeyRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyRecord {
+    id: String,
+    name: String,
+    scope: String,
+    token_hash: String,
+}
+
+impl From<ApiKey> for KeyRecord {
+    fn from(inner: ApiKey) -> Self {
+        Self {
+            id: inner.id.into(),
+            name: inner.name,
+            scope: inner.scope.into(),
+            token_hash: inner.token_hash,
+        }
+    }
+}
+
+impl TryFrom<KeyRecord> for ApiKey {
+    type Error = OpenError;
+
+    fn try_from(record: KeyRecord) -> Result<Self, Self::Error> {
+        let invalid = || OpenError::FormatError(format!("invalid API key {}", record.id));
+        Ok(Self {
+            id: ApiKeyId::try_from(record.id.clone()).map_err(|_| invalid())?,
+            scope: Scope::try_from(record.scope.clone()).map_err(|_| invalid())?,
+            name: record.name,
+            token_hash: record.token_hash,
+        })
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct UsersDocument {
+    #[serde(default)]
+    users: Vec<UserRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserRecord {
+    id: String,
+    name: String,
+    password_hash: String,
+    #[serde(default)]
+    admin: bool,
+    #[serde(default)]
+    grants: Vec<GrantRecord>,
+}
+
+/// Either `room_id` or `device_id` is set, both are stable ids.
+#[derive(Serialize, Deserialize)]
+struct GrantRecord {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    room_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    role: String,
+}
+
+impl From<User> for UserRecord {
+    fn from(inner: User) -> Self {
+        Self {
+            id: inner.id.into(),
+            name: inner.name.into(),
+            password_hash: inner.password_hash,
+            admin: inner.admin,
+            grants: inner
+                .grants
+                .into_iter()
+                .map(|grant| {
+                    let (room_id, device_id) = match grant.target {
+                        GrantTarget::Room(id) => (Some(id.into()), None),
+                        GrantTarget::Device(id) => (None, Some(id.into())),
+                    };
+                    GrantRecord {
+                        room_id,
+                        device_id,
+                        role: grant.role.into(),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<UserRecord> for User {
+    type Error = OpenError;
+
+    fn try_from(record: UserRecord) -> Result<Self, Self::Error> {
+        let invalid = || OpenError::FormatError(format!("invalid user {}", record.id));
+        let grants = record
+            .grants
+            .into_iter()
+            .map(|grant| {
+                let target = match (grant.room_id, grant.device_id) {
+                    (Some(id), None) => GrantTarget::Room(RoomId::try_from(id).map_err(|_| ())?),
+                    (None, Some(id)) => {
+                        GrantTarget::Device(DeviceId::try_from(id).map_err(|_| ())?)
+                    }
+                    _ => return Err(()),
+                };
+                Ok(Grant {
+                    target,
+                    role: Role::try_from(grant.role)?,
+                })
+            })
+            .collect::<Result<Vec<_>, ()>>()
+            .map_err(|_| invalid())?;
+        Ok(Self {
+            id: UserId::try_from(record.id.clone()).map_err(|_| invalid())?,
+            name: UserName::try_from(record.name).map_err(|_| invalid())?,
+            password_hash: record.password_hash,
+            admin: record.admin,
+            grants,
+        })
+    }
+}
+
+/// Keeps the house layout and the scenes in memory and mirrors them into
+/// a JSON file after every mutation, so they survive restarts.
+///
+/// The device history goes to a sibling `.history.jsonl` file,
+/// one JSON entry per line, so recording is a cheap append, the automation
+/// rules to a sibling `.rules.json` file, the schedules together with
+/// their runs to a sibling `.schedules.json` file and the webhooks together
+/// with their deliveries to a sibling `.webhooks.json` file. The API keys,
+/// only the hashes of their tokens, go to a sibling `.keys.json` file, the
+/// users with their grants to a sibling `.users.json` file.
+pub struct FileRepository {
+    path: PathBuf,
+    rooms: Mutex<Vec<RoomInfo>>,
+    // always locked after `rooms`, both go to the same file
+    scenes: Mutex<Vec<Scene>>,
+    history_path: PathBuf,
+    history: Mutex<Vec<HistoryEntry>>,
+    rules_path: PathBuf,
+    rules: Mutex<Vec<Rule>>,
+    schedules_path: PathBuf,
+    schedules: Mutex<ScheduleBook>,
+    webhooks_path: PathBuf,
+    webhooks: Mutex<WebhookBook>,
+    keys_path: PathBuf,
+    keys: Mutex<Vec<ApiKey>>,
+    users_path: PathBuf,
+    users: Mutex<Vec<User>>,
+}
+
+impl FileRepository {
+    /// Loads the layout from `path`, a missing file is an empty house.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, OpenError> {
+        let path = path.as_ref().to_path_buf();
+        let document = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice::<Document>(&bytes)
+                .map_err(|e| OpenError::FormatError(e.to_string()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Document::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let missing_ids = document
+            .rooms
+            .iter()
+            .any(|r| r.id.is_none() || r.devices.iter().any(|d| d.id.is_none()));
+        let rooms = document
+            .rooms
+            .into_iter()
+            .map(RoomInfo::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        let scenes = document
+            .scenes
+            .into_iter()
+            .map(Scene::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let history_path = path.with_extension("history.jsonl");
+        let history = load_history(&history_path)?;
+        let rules_path = path.with_extension("rules.json");
+        let rules = load_rules(&rules_path)?;
+        let schedules_path = path.with_extension("schedules.json");
+        let schedules = load_schedules(&schedules_path)?;
+        let webhooks_path = path.with_extension("webhooks.json");
+        let (webhooks, names_filter) = load_webhooks(&webhooks_path, &rooms)?;
+        let keys_path = path.with_extension("keys.json");
+        let keys = load_keys(&keys_path)?;
+        let users_path = path.with_extension("users.json");
+        let users = load_users(&users_path)?;
+
+        let mut repo = Self {
+            path,
+            rooms: Mutex::new(Vec::new()),
+            scenes: Mutex::new(Vec::new()),
+            history_path,
+            history: Mutex::new(history),
+            rules_path,
+            rules: Mutex::new(rules),
+            schedules_path,
+            schedules: Mutex::new(schedules),
+            webhooks_path,
+            webhooks: Mutex::new(WebhookBook::default()),
+            keys_path,
+            keys: Mutex::new(keys),
+            users_path,
+            users: Mutex::new(users),
+        };
+        if missing_ids {
+            repo.persist(&rooms, &scenes)?;
+        }
+        if names_filter {
+            repo.persist_webhooks(&webhooks)?;
+        }
+        repo.rooms = Mutex::new(rooms);
+        repo.scenes = Mutex::new(scenes);
+        repo.webhooks = Mutex::new(webhooks);
+        Ok(repo)
+    }
+
+    fn persist(&self, rooms: &[RoomInfo], scenes: &[Scene]) -> io::Result<()> {
+        let document = Document {
+            rooms: rooms.iter().cloned().map(RoomRecord::from).collect(),
+            scenes: scenes.iter().cloned().map(SceneRecord::from).collect(),
+        };
+        replace_file(&self.path, &serde_json::to_vec_pretty(&document)?)
+    }
+
+    fn mutate<T, E>(
+        &self,
+        unknown: E,
+        mutation: impl FnOnce(&mut Vec<RoomInfo>) -> Result<T, E>,
+    ) -> Result<T, E> {
+        mutate_persisted(&self.rooms, unknown, mutation, |rooms| {
+            let scenes = self
+                .scenes
+                .lock()
+                .map_err(|_| io::Error::other("poisoned lock"))?;
+            self.persist(rooms, &scenes)
+        })
+    }
+
+    fn mutate_scenes<T, E>(
+        &self,
+        unknown: E,
+        mutation: impl FnOnce(&mut Vec<Scene>) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let rooms = match self.rooms.lock() {
+            Ok(rooms) => rooms,
+            _ => return Err(unknown),
+        };
+
+        mutate_persisted(&self.scenes, unknown, mutation, |scenes| {
+            self.persist(&rooms, scenes)
+        })
+    }
+
+    fn mutate_rules<T, E>(
+        &self,
+        unknown: E,
+        mutation: impl FnOnce(&mut Vec<Rule>) -> Result<T, E>,
+    ) -> Result<T, E> {
+        mutate_persisted(&self.rules, unknown, mutation, |rules| {
+            let document = RulesDocument {
+                rules: rules.iter().cloned().map(RuleRecord::from).collect(),
+            };
+            replace_file(&self.rules_path, &serde_json::to_vec_pretty(&document)?)
+        })
+    }
+
+    fn mutate_keys<T, E>(
+        &self,
+        unknown: E,
+        mutation: impl FnOnce(&mut Vec<ApiKey>) -> Result<T, E>,
+    ) -> Result<T, E> {
+        mutate_persisted(&self.keys, unknown, mutation, |keys| {
+            let document = KeysDocument {
+                keys: keys.iter().cloned().map(KeyRecord::from).collect(),
+            };
+            replace_file(&self.keys_path, &serde_json::to_vec_pretty(&document)?)
+        })
+    }
+
+    fn mutate_users<T, E>(
+        &self,
+        unknown: E,
+        mutation: impl FnOnce(&mut Vec<User>) -> Result<T, E>,
+    ) -> Result<T, E> {
+        mutate_persisted(&self.users, unknown, mutation, |users| {
+            let document = UsersDocument {
+                users: users.iter().cloned().map(UserRecord::from).collect(),
+            };
+            replace_file(&self.users_path, &serde_json::to_vec_pretty(&document)?)
+        })
+    }
+
+    fn mutate_schedules<T, E>(
+        &self,
+        unknown: E,
+        mutation: impl FnOnce(&mut ScheduleBook) -> Result<T, E>,
+    ) -> Result<T, E> {
+        mutate_persisted(&self.schedules, unknown, mutation, |book| {
+            let document = SchedulesDocument {
+                schedules: book
+                    .schedules
+                    .iter()
+                    .cloned()
+                    .map(ScheduleRecord::from)
+                    .collect(),
+                runs: book.runs.iter().cloned().map(RunRecord::from).collect(),
+            };
+            replace_file(&self.schedules_path, &serde_json::to_vec_pretty(&document)?)
+        })
+    }
+
+    fn persist_webhooks(&self, book: &WebhookBook) -> io::Result<()> {
+        let document = WebhooksDocument {
+            webhooks: book
+                .webhooks
+                .iter()
+                .cloned()
+                .map(WebhookRecord::from)
+                .collect(),
+            deliveries: book
+                .deliveries
+                .iter()
+                .cloned()
+                .map(DeliveryRecord::from)
+                .collect(),
+        };
+        replace_file(&self.webhooks_path, &serde_json::to_vec_pretty(&document)?)
+    }
+
+    fn mutate_webhooks<T, E>(
+        &self,
+        unknown: E,
+        mutation: impl FnOnce(&mut WebhookBook) -> Result<T, E>,
+    ) -> Result<T, E> {
+        mutate_persisted(&self.webhooks, unknown, mutation, |book| {
+            self.persist_webhooks(book)
+        })
+    }
+}
+
+// applies the mutation to a copy and only keeps it once it is on disk
+fn mutate_persisted<S: Clone, T, E>(
+    state: &Mutex<S>,
+    unknown: E,
+    mutation: impl FnOnce(&mut S) -> Result<T, E>,
+    persist: impl FnOnce(&S) -> io::Result<()>,
+) -> Result<T, E> {
+    let mut state = match state.lock() {
+        Ok(state) => state,
+        _ => return Err(unknown),
+    };
+
+    let mut updated = state.clone();
+    let result = mutation(&mut updated)?;
+    if persist(&updated).is_err() {
+        return Err(unknown);
+    }
+
+    *state = updated;
+    Ok(result)
+}
+
+// the new content goes to a sibling file first and is then renamed over
+// the old one, so a crash mid-write never leaves a truncated file
+fn replace_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_name);
+
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+fn load_history(path: &Path) -> Result<Vec<HistoryEntry>, OpenError> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entries = Vec::new();
+    for line in io::BufReader::new(file).lines() {
+        // a crash mid-append leaves a partial last line, the history
+        // is best effort so such lines are skipped instead of failing
+        let entry = serde_json::from_str::<HistoryRecord>(&line?)
+            .ok()
+            .and_then(|record| HistoryEntry::try_from(record).ok());
+        if let Some(entry) = entry {
+            history::insert_entry(&mut entries, entry);
+        }
+    }
+    Ok(entries)
+}
+
+fn load_rules(path: &Path) -> Result<Vec<Rule>, OpenError> {
+    let document = match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice::<RulesDocument>(&bytes)
+            .map_err(|e| OpenError::FormatError(e.to_string()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => RulesDocument::default(),
+        Err(e) => return Err(e.into()),
+    };
+
+    document.rules.into_iter().map(Rule::try_from).collect()
+}
+
+fn load_keys(path: &Path) -> Result<Vec<ApiKey>, OpenError> {
+    let document = match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice::<KeysDocument>(&bytes)
+            .map_err(|e| OpenError::FormatError(e.to_string()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => KeysDocument::default(),
+        Err(e) => return Err(e.into()),
+    };
+
+    document.keys.into_iter().map(ApiKey::try_from).collect()
+}
+
+fn load_users(path: &Path) -> Result<Vec<User>, OpenError> {
+    let document = match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice::<UsersDocument>(&bytes)
+            .map_err(|e| OpenError::FormatError(e.to_string()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => UsersDocument::default(),
+        Err(e) => return Err(e.into()),
+    };
+
+    document.users.into_iter().map(User::try_from).collect()
+}
+
+fn load_schedules(path: &Path) -> Result<ScheduleBook, OpenError> {
+    let document = match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice::<SchedulesDocument>(&bytes)
+            .map_err(|e| OpenError::FormatError(e.to_string()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => SchedulesDocument::default(),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(ScheduleBook {
+        schedules: document
+            .schedules
+            .into_iter()
+            .map(Schedule::try_from)
+            .collect::<Result<Vec<_>, _>>()?,
+        runs: document
+            .runs
+            .into_iter()
+            .map(ScheduleRun::try_from)
+            .collect::<Result<Vec<_>, _>>()?,
+    })
+}
+
+// also tells whether the file still filters on names and needs rewriting
+fn load_webhooks(path: &Path, rooms: &[RoomInfo]) -> Result<(WebhookBook, bool), OpenError> {
+    let document = match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice::<WebhooksDocument>(&bytes)
+            .map_err(|e| OpenError::FormatError(e.to_string()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => WebhooksDocument::default(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let names_filter = document.webhooks.iter().any(WebhookRecord::names_filter);
+    let book = WebhookBook {
+        webhooks: document
+            .webhooks
+            .into_iter()
+            .map(|record| record.into_webhook(rooms))
+            .collect::<Result<Vec<_>, _>>()?,
+        deliveries: document
+            .deliveries
+            .into_iter()
+            .map(WebhookDelivery::try_from)
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    Ok((book, names_filter))
+}
+
+impl Repository for FileRepository {
+    fn add_room(&self, name: RoomName) -> Result<RoomInfo, InsertError> {
+        self.mutate(InsertError::Unknown, |rooms| room::insert_room(rooms, name))
+    }
+
+    fn delete_room(&self, name: RoomName, cascade: bool) -> Result<RoomInfo, DeleteError> {
+        self.mutate(DeleteError::Unknown, |rooms| {
+            room::remove_room(rooms, name, cascade)
+        })
+    }
+
+    fn rename_room(&self, name: RoomName, new_name: RoomName) -> Result<RoomInfo, UpdateError> {
+        self.mutate(UpdateError::Unknown, |rooms| {
+            room::modify_room(rooms, name, new_name)
+        })
+    }
+
+    fn fetch_room(&self, name: RoomName) -> Result<RoomInfo, FetchError> {
+        let rooms = match self.rooms.lock() {
+            Ok(rooms) => rooms,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        room::find_room(&rooms, name)
+    }
+
+    fn fetch_rooms(&self) -> Result<Vec<RoomInfo>, FetchError> {
+        let rooms = match self.rooms.lock() {
+            Ok(rooms) => rooms,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        Ok(rooms.to_vec())
+    }
+
+    fn add_device(
+        &self,
+        room_name: RoomName,
+        device_info: DeviceInfo,
+    ) -> Result<DeviceInfo, InsertError> {
+        self.mutate(InsertError::Unknown, |rooms| {
+            room::insert_device(rooms, room_name, device_info)
+        })
+    }
+
+    fn delete_device(
+        &self,
+        room_name: RoomName,
+        device_name: DeviceName,
+    ) -> Result<(), DeleteError> {
+        self.mutate(DeleteError::Unknown, |rooms| {
+            room::remove_device(rooms, room_name, device_name)
+        })
+    }
+
+    fn fetch_device(
+        &self,
+        room_name: RoomName,
+        device_name: DeviceName,
+    ) -> Result<DeviceInfo, FetchError> {
+        let rooms = match self.rooms.lock() {
+            Ok(rooms) => rooms,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        room::find_device(&rooms, room_name, device_name)
+    }
+
+    fn fetch_devices(&self, room_name: RoomName) -> Result<Vec<DeviceInfo>, FetchError> {
+        let rooms = match self.rooms.lock() {
+            Ok(rooms) => rooms,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        room::find_room(&rooms, room_name).map(|room| room.devices)
+    }
+
+    fn fetch_device_by_id(&self, id: DeviceId) -> Result<(RoomName, DeviceInfo), FetchError> {
+        let rooms = match self.rooms.lock() {
+            Ok(rooms) => rooms,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        room::find_device_by_id(&rooms, id)
+    }
+
+    fn update_device(
+        &self,
+        room_name: RoomName,
+        device_name: DeviceName,
+        update: DeviceUpdate,
+    ) -> Result<DeviceInfo, UpdateError> {
+        self.mutate(UpdateError::Unknown, |rooms| {
+            room::modify_device(rooms, room_name, device_name, update)
+        })
+    }
+
+    fn add_scene(&self, scene: Scene) -> Result<Scene, InsertError> {
+        self.mutate_scenes(InsertError::Unknown, |scenes| {
+            room::insert_scene(scenes, scene)
+        })
+    }
+
+    fn fetch_scene(&self, name: SceneName) -> Result<Scene, FetchError> {
+        let scenes = match self.scenes.lock() {
+            Ok(scenes) => scenes,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        room::find_scene(&scenes, name)
+    }
+
+    fn fetch_scenes(&self) -> Result<Vec<Scene>, FetchError> {
+        let scenes = match self.scenes.lock() {
+            Ok(scenes) => scenes,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        Ok(scenes.to_vec())
+    }
+
+    fn update_scene(&self, scene: Scene) -> Result<Scene, UpdateError> {
+        self.mutate_scenes(UpdateError::Unknown, |scenes| {
+            room::replace_scene(scenes, scene)
+        })
+    }
+
+    fn delete_scene(&self, name: SceneName) -> Result<(), DeleteError> {
+        self.mutate_scenes(DeleteError::Unknown, |scenes| {
+            room::remove_scene(scenes, name)
+        })
+    }
+}
+
+impl HistoryStore for FileRepository {
+    fn record_event(&self, entry: HistoryEntry) -> Result<(), InsertError> {
+        let mut entries = match self.history.lock() {
+            Ok(entries) => entries,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        let mut line = serde_json::to_vec(&HistoryRecord::from(entry.clone()))
+            .map_err(|_| InsertError::Unknown)?;
+        line.push(b'\n');
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.history_path)
+            .and_then(|mut file| file.write_all(&line))
+            .map_err(|_| InsertError::Unknown)?;
+
+        history::insert_entry(&mut entries, entry);
+        Ok(())
+    }
+
+    fn fetch_history(
+        &self,
+        device_id: DeviceId,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<HistoryEntry>, FetchError> {
+        let entries = match self.history.lock() {
+            Ok(entries) => entries,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        Ok(history::select_entries(&entries, device_id, from, to))
+    }
+
+    fn prune_history(&self, before: u64) -> Result<usize, DeleteError> {
+        let mut entries = match self.history.lock() {
+            Ok(entries) => entries,
+            _ => return Err(DeleteError::Unknown),
+        };
+
+        let mut kept = entries.clone();
+        let pruned = history::remove_entries(&mut kept, before);
+        if pruned == 0 {
+            return Ok(0);
+        }
+
+        let mut bytes = Vec::new();
+        for entry in kept.iter().cloned() {
+            serde_json::to_writer(&mut bytes, &HistoryRecord::from(entry))
+                .map_err(|_| DeleteError::Unknown)?;
+            bytes.push(b'\n');
+        }
+        replace_file(&self.history_path, &bytes).map_err(|_| DeleteError::Unknown)?;
+
+        *entries = kept;
+        Ok(pruned)
+    }
+}
+
+impl RuleStore for FileRepository {
+    fn add_rule(&self, new_rule: Rule) -> Result<Rule, InsertError> {
+        self.mutate_rules(InsertError::Unknown, |rules| {
+            rule::insert_rule(rules, new_rule)
+        })
+    }
+
+    fn fetch_rule(&self, id: RuleId) -> Result<Rule, FetchError> {
+        let rules = match self.rules.lock() {
+            Ok(rules) => rules,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        rule::find_rule(&rules, id)
+    }
+
+    fn fetch_rules(&self) -> Result<Vec<Rule>, FetchError> {
+        let rules = match self.rules.lock() {
+            Ok(rules) => rules,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        Ok(rules.to_vec())
+    }
+
+    fn update_rule(&self, updated: Rule) -> Result<Rule, UpdateError> {
+        self.mutate_rules(UpdateError::Unknown, |rules| {
+            rule::replace_rule(rules, updated)
+        })
+    }
+
+    fn delete_rule(&self, id: RuleId) -> Result<(), DeleteError> {
+        self.mutate_rules(DeleteError::Unknown, |rules| rule::remove_rule(rules, id))
+    }
+}
+
+impl ScheduleStore for FileRepository {
+    fn add_schedule(&self, schedule: Schedule) -> Result<Schedule, InsertError> {
+        self.mutate_schedules(InsertError::Unknown, |book| book.insert_schedule(schedule))
+    }
+
+    fn fetch_schedule(&self, id: ScheduleId) -> Result<Schedule, FetchError> {
+        let book = match self.schedules.lock() {
+            Ok(book) => book,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        book.find_schedule(id)
+    }
+
+    fn fetch_schedules(&self) -> Result<Vec<Schedule>, FetchError> {
+        let book = match self.schedules.lock() {
+            Ok(book) => book,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        Ok(book.schedules.to_vec())
+    }
+
+    fn delete_schedule(&self, id: ScheduleId) -> Result<(), DeleteError> {
+        self.mutate_schedules(DeleteError::Unknown, |book| book.remove_schedule(id))
+    }
+
+    fn record_run(&self, run: ScheduleRun) -> Result<(), InsertError> {
+        self.mutate_schedules(InsertError::Unknown, |book| book.insert_run(run))
+    }
+
+    fn fetch_runs(&self, id: ScheduleId) -> Result<Vec<ScheduleRun>, FetchError> {
+        let book = match self.schedules.lock() {
+            Ok(book) => book,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        book.select_runs(id)
+    }
+}
+
+impl ApiKeyStore for FileRepository {
+    fn add_key(&self, key: ApiKey) -> Result<ApiKey, InsertError> {
+        self.mutate_keys(InsertError::Unknown, |keys| api_key::insert_key(keys, key))
+    }
+
+    fn fetch_keys(&self) -> Result<Vec<ApiKey>, FetchError> {
+        let keys = match self.keys.lock() {
+            Ok(keys) => keys,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        Ok(keys.to_vec())
+    }
+
+    fn fetch_key_by_hash(&self, token_hash: &str) -> Result<ApiKey, FetchError> {
+        let keys = match self.keys.lock() {
+            Ok(keys) => keys,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        api_key::find_key_by_hash(&keys, token_hash)
+    }
+
+    fn delete_key(&self, id: ApiKeyId) -> Result<(), DeleteError> {
+        self.mutate_keys(DeleteError::Unknown, |keys| api_key::remove_key(keys, id))
+    }
+}
+
+impl UserStore for FileRepository {
+    fn add_user(&self, new_user: User) -> Result<User, InsertError> {
+        self.mutate_users(InsertError::Unknown, |users| {
+            user::insert_user(users, new_user)
+        })
+    }
+
+    fn fetch_users(&self) -> Result<Vec<User>, FetchError> {
+        let users = match self.users.lock() {
+            Ok(users) => users,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        Ok(users.to_vec())
+    }
+
+    fn fetch_user(&self, id: UserId) -> Result<User, FetchError> {
+        let users = match self.users.lock() {
+            Ok(users) => users,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        user::find_user(&users, id)
+    }
+
+    fn fetch_user_by_name(&self, name: &UserName) -> Result<User, FetchError> {
+        let users = match self.users.lock() {
+            Ok(users) => users,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        user::find_user_by_name(&users, name)
+    }
+
+    fn update_user(&self, updated: User) -> Result<User, UpdateError> {
+        self.mutate_users(UpdateError::Unknown, |users| {
+            user::replace_user(users, updated)
+        })
+    }
+
+    fn delete_user(&self, id: UserId) -> Result<(), DeleteError> {
+        self.mutate_users(DeleteError::Unknown, |users| user::remove_user(users, id))
+    }
+}
+
+impl WebhookStore for FileRepository {
+    fn add_webhook(&self, webhook: Webhook) -> Result<Webhook, InsertError> {
+        self.mutate_webhooks(InsertError::Unknown, |book| book.insert_webhook(webhook))
+    }
+
+    fn fetch_webhook(&self, id: WebhookId) -> Result<Webhook, FetchError> {
+        let book = match self.webhooks.lock() {
+            Ok(book) => book,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        book.find_webhook(id)
+    }
+
+    fn fetch_webhooks(&self) -> Result<Vec<Webhook>, FetchError> {
+        let book = match self.webhooks.lock() {
+            Ok(book) => book,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        Ok(book.webhooks.to_vec())
+    }
+
+    fn delete_webhook(&self, id: WebhookId) -> Result<(), DeleteError> {
+        self.mutate_webhooks(DeleteError::Unknown, |book| book.remove_webhook(id))
+    }
+
+    fn record_delivery(&self, delivery: WebhookDelivery) -> Result<(), InsertError> {
+        self.mutate_webhooks(InsertError::Unknown, |book| book.insert_delivery(delivery))
+    }
+
+    fn fetch_deliveries(&self, id: WebhookId) -> Result<Vec<WebhookDelivery>, FetchError> {
+        let book = match self.webhooks.lock() {
+            Ok(book) => book,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        book.select_deliveries(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::events::Event;
+    use crate::domain::service::access::Principal;
+    use crate::domain::service::{device, room as room_service, webhook};
+    use crate::repository::suite::repository_suite;
+    use std::sync::Arc;
+
+    fn open_repo(dir: &tempfile::TempDir) -> Arc<FileRepository> {
+        Arc::new(FileRepository::open(dir.path().join("house.json")).unwrap())
+    }
+
+    repository_suite!(|| {
+        let dir = tempfile::tempdir().unwrap();
+        (open_repo(&dir), dir)
+    });
+
+    fn room_request(name: RoomName) -> room_service::RoomRequest {
+        room_service::RoomRequest { name: name.into() }
+    }
+
+    fn delete_request(name: RoomName, cascade: bool) -> room_service::DeleteRequest {
+        room_service::DeleteRequest {
+            name: name.into(),
+            cascade,
+        }
+    }
+
+    fn socket_request(room_name: RoomName, address: &str) -> device::AddRequest {
+        device::AddRequest {
+            room_name: room_name.into(),
+            device_name: DeviceName::socket().into(),
+            address: address.to_string(),
+            device_type: DeviceType::TcpSocket.into(),
+        }
+    }
+
+    #[test]
+    fn open_returns_empty_house_if_file_is_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = open_repo(&dir);
+
+        match room_service::fetch_rooms(repo, &Principal::SYSTEM) {
+            Ok(result) => assert_eq!(result, vec![]),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn open_returns_format_error_on_corrupted_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("house.json");
+        fs::write(&path, "{\"rooms\": [{\"name\": \"\", \"devices\": []}]}").unwrap();
+
+        match FileRepository::open(path) {
+            Err(OpenError::FormatError(_)) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn open_keeps_ids_generated_for_file_without_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("house.json");
+        fs::write(
+            &path,
+            r#"{"rooms": [{"name": "kitchen", "devices": [
+                {"name": "socket", "address": "127.0.0.1:8888", "device_type": "tcp_socket"}
+            ]}]}"#,
+        )
+        .unwrap();
+
+        let first = open_repo(&dir).fetch_room(RoomName::kitchen());
+        let second = open_repo(&dir).fetch_room(RoomName::kitchen());
+        match (first, second) {
+            (Ok(first), Ok(second)) => {
+                assert_eq!(first.id, second.id);
+                assert_eq!(first.devices[0].id, second.devices[0].id);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn layout_survives_reopening_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = open_repo(&dir);
+        let room_id = match room_service::add_room(repo.clone(), room_request(RoomName::kitchen()))
+        {
+            Ok(result) => result.id,
+            _ => unreachable!(),
+        };
+        room_service::add_room(repo.clone(), room_request(RoomName::bathroom())).ok();
+        let device_id = match device::add_device(
+            repo.clone(),
+            socket_request(RoomName::kitchen(), "127.0.0.1:8888"),
+        ) {
+            Ok(result) => result.id,
+            _ => unreachable!(),
+        };
+        room_service::delete_room(repo, delete_request(RoomName::bathroom(), false)).ok();
+
+        let reopened = open_repo(&dir);
+        match room_service::fetch_rooms(reopened, &Principal::SYSTEM) {
+            Ok(result) => assert_eq!(
+                result,
+                vec![room_service::RoomResponse {
+                    id: room_id,
+                    name: "kitchen".to_string(),
+                    devices: vec![room_service::DeviceResponse {
+                        id: device_id,
+                        name: "socket".to_string(),
+                        address: "127.0.0.1:8888".to_string(),
+                        device_type: "tcp_socket".to_string(),
+                    }]
+                }]
+            ),
+            _ => unreachable!(),
+        }
+        assert!(!dir.path().join("house.json.tmp").exists());
+    }
+
+    fn reading(device_id: DeviceId, timestamp: u64) -> HistoryEntry {
+        HistoryEntry {
+            device_id,
+            timestamp,
+            event: DeviceEvent::Reading {
+                status: None,
+                error: Some("timeout".to_string()),
+            },
+        }
+    }
+
+    #[test]
+    fn history_survives_reopening_and_pruning() {
+        let dir = tempfile::tempdir().unwrap();
+        let device_id = DeviceId::generate();
+        let repo = open_repo(&dir);
+        for timestamp in [100, 200, 300] {
+            repo.record_event(reading(device_id, timestamp)).ok();
+        }
+        match repo.prune_history(200) {
+            Ok(pruned) => assert_eq!(pruned, 1),
+            _ => unreachable!(),
+        }
+
+        let reopened = open_repo(&dir);
+        match reopened.fetch_history(device_id, 0, u64::MAX) {
+            Ok(entries) => assert_eq!(
+                entries,
+                vec![reading(device_id, 200), reading(device_id, 300)]
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn open_skips_partial_history_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let device_id = DeviceId::generate();
+        open_repo(&dir).record_event(reading(device_id, 100)).ok();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("house.history.jsonl"))
+            .and_then(|mut file| file.write_all(b"{\"device_id\": "))
+            .unwrap();
+
+        match open_repo(&dir).fetch_history(device_id, 0, u64::MAX) {
+            Ok(entries) => assert_eq!(entries.len(), 1),
+            _ => unreachable!(),
+        }
+    }
+
+    fn heater_rule(name: &str, threshold: f32) -> Rule {
+        Rule {
+            id: RuleId::generate(),
+            name: RuleName::try_from(name.to_string()).unwrap(),
+            enabled: true,
+            trigger: RuleTrigger::TemperatureBelow {
+                device_id: DeviceId::generate(),
+                threshold,
+            },
+            action: Some(RuleAction {
+                device_id: DeviceId::generate(),
+                command: DeviceCommand::TurnOn,
+            }),
+        }
+    }
+
+    #[test]
+    fn rules_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = open_repo(&dir);
+        let rule = heater_rule("heater", 18.0);
+        repo.add_rule(rule.clone()).ok();
+        repo.update_rule(Rule {
+            enabled: false,
+            ..rule.clone()
+        })
+        .ok();
+        let other = heater_rule("fan", 30.0);
+        repo.add_rule(other.clone()).ok();
+        repo.delete_rule(other.id).ok();
+
+        match open_repo(&dir).fetch_rules() {
+            Ok(rules) => {
+                assert_eq!(rules.len(), 1);
+                assert_eq!(rules[0].id, rule.id);
+                assert!(!rules[0].enabled);
+                assert_eq!(rules[0].trigger, rule.trigger);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn schedule(cron: &str) -> Schedule {
+        Schedule {
+            id: ScheduleId::generate(),
+            cron: CronExpr::try_from(cron.to_string()).unwrap(),
+            device_id: DeviceId::generate(),
+            command: DeviceCommand::TurnOn,
+        }
+    }
+
+    fn run(schedule_id: ScheduleId, timestamp: u64) -> ScheduleRun {
+        ScheduleRun {
+            schedule_id,
+            timestamp,
+            status: Some(SocketStatus {
+                enabled: true,
+                power: 0.0,
+            }),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn schedules_and_runs_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = open_repo(&dir);
+        let weekdays = schedule("0 7 * * 1-5");
+        repo.add_schedule(weekdays.clone()).ok();
+        repo.record_run(run(weekdays.id, 100)).ok();
+
+        let reopened = open_repo(&dir);
+        match (
+            reopened.fetch_schedule(weekdays.id),
+            reopened.fetch_runs(weekdays.id),
+        ) {
+            (Ok(schedule), Ok(runs)) => {
+                assert_eq!(schedule.cron, weekdays.cron);
+                assert_eq!(runs, vec![run(weekdays.id, 100)]);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn webhooks_and_deliveries_survive_reopening() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = open_repo(&dir);
+        let webhook = Webhook {
+            id: WebhookId::generate(),
+            url: WebhookUrl::try_from("http://127.0.0.1:9000/hook".to_string()).unwrap(),
+            secret: "secret".to_string(),
+            events: vec!["status_changed".to_string()],
+            room_id: Some(RoomId::generate()),
+            device_id: None,
+        };
+        let delivery = WebhookDelivery {
+            webhook_id: webhook.id,
+            timestamp: 100,
+            event: "status_changed".to_string(),
+            attempts: 2,
+            status: Some(200),
+            error: None,
+        };
+        repo.add_webhook(webhook.clone()).ok();
+        repo.record_delivery(delivery.clone()).ok();
+
+        let reopened = open_repo(&dir);
+        match (
+            reopened.fetch_webhook(webhook.id),
+            reopened.fetch_deliveries(webhook.id),
+        ) {
+            (Ok(stored), Ok(deliveries)) => {
+                assert_eq!(stored.url, webhook.url);
+                assert_eq!(stored.events, webhook.events);
+                assert!(stored.room_id == webhook.room_id);
+                assert_eq!(deliveries, vec![delivery]);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn open_resolves_legacy_webhook_filters_and_keeps_the_stale_webhooks() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("house.json"),
+            r#"{"rooms": [{"id": "6d5c1c3e-7c43-4a8e-9a3b-0c9d2f0e8a11", "name": "kitchen",
+                "devices": [{"id": "0b7e2f4c-1d2a-4c5b-8e9f-3a4b5c6d7e8f", "name": "socket",
+                    "address": "127.0.0.1:8888", "device_type": "tcp_socket"}]}]}"#,
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("house.webhooks.json"),
+            r#"{"webhooks": [
+                {"id": "a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d", "url": "http://127.0.0.1:9000/hook",
+                 "secret": "secret", "events": [], "room_id": "kitchen", "device_id": "socket"},
+                {"id": "b1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d", "url": "http://127.0.0.1:9000/hook",
+                 "secret": "secret", "events": [], "room_id": "bathroom"},
+                {"id": "c1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d", "url": "http://127.0.0.1:9000/hook",
+                 "secret": "secret", "events": [], "device_id": "kettle"}
+            ], "deliveries": [
+                {"webhook_id": "b1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d", "timestamp": 1000,
+                 "event": "room_added", "attempts": 1, "status": 200}
+            ]}"#,
+        )
+        .unwrap();
+
+        let first = open_repo(&dir).fetch_webhooks();
+        let reopened = open_repo(&dir);
+        match (first, reopened.fetch_webhooks()) {
+            (Ok(first), Ok(webhooks)) => {
+                assert_eq!(webhooks.len(), 3);
+                assert_eq!(
+                    webhooks[0].room_id.map(String::from).as_deref(),
+                    Some("6d5c1c3e-7c43-4a8e-9a3b-0c9d2f0e8a11")
+                );
+                assert_eq!(
+                    webhooks[0].device_id.map(String::from).as_deref(),
+                    Some("0b7e2f4c-1d2a-4c5b-8e9f-3a4b5c6d7e8f")
+                );
+                // the random ids are written back, not drawn again on every open
+                assert!(first[1].room_id == webhooks[1].room_id);
+                assert!(first[2].device_id == webhooks[2].device_id);
+                // the stale filters match nothing instead of everything
+                let rooms = reopened.fetch_rooms().ok().unwrap();
+                let event = Event::DeviceAdded {
+                    room_id: "kitchen".to_string(),
+                    device_id: "socket".to_string(),
+                    id: "0b7e2f4c-1d2a-4c5b-8e9f-3a4b5c6d7e8f".to_string(),
+                };
+                assert!(webhook::accepts(&webhooks[0], &rooms, &event));
+                assert!(webhooks[1].room_id.is_some());
+                assert!(!webhook::accepts(&webhooks[1], &rooms, &event));
+                assert!(webhooks[2].device_id.is_some());
+                assert!(!webhook::accepts(&webhooks[2], &rooms, &event));
+                match reopened.fetch_deliveries(webhooks[1].id) {
+                    Ok(deliveries) => assert_eq!(deliveries.len(), 1),
+                    _ => unreachable!(),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn api_keys_survive_reopening_without_their_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = open_repo(&dir);
+        let key = ApiKey {
+            id: ApiKeyId::generate(),
+            name: "dashboard".to_string(),
+            scope: Scope::Read,
+            token_hash: "hash".to_string(),
+        };
+        repo.add_key(key.clone()).ok();
+
+        let reopened = open_repo(&dir);
+        match reopened.fetch_key_by_hash("hash") {
+            Ok(stored) => assert_eq!(stored, key),
+            _ => unreachable!(),
+        }
+        reopened.delete_key(key.id).ok();
+        match open_repo(&dir).fetch_keys() {
+            Ok(keys) => assert!(keys.is_empty()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn users_survive_reopening_with_their_grants() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = open_repo(&dir);
+        let mut user = User {
+            id: UserId::generate(),
+            name: UserName::try_from("kid".to_string()).unwrap(),
+            password_hash: "hash".to_string(),
+            admin: false,
+            grants: vec![Grant {
+                target: GrantTarget::Room(RoomId::generate()),
+                role: Role::Control,
+            }],
+        };
+        repo.add_user(user.clone()).ok();
+        user.grants.push(Grant {
+            target: GrantTarget::Device(DeviceId::generate()),
+            role: Role::Read,
+        });
+        repo.update_user(user.clone()).ok();
+
+        let reopened = open_repo(&dir);
+        match reopened.fetch_user_by_name(&user.name) {
+            Ok(stored) => assert_eq!(stored, user),
+            _ => unreachable!(),
+        }
+        reopened.delete_user(user.id).ok();
+        match open_repo(&dir).fetch_users() {
+            Ok(users) => assert!(users.is_empty()),
+            _ => unreachable!(),
+        }
+    }
+
+    fn scene(name: &str, command: DeviceCommand) -> Scene {
+        Scene {
+            name: SceneName::try_from(name.to_string()).unwrap(),
+            steps: vec![SceneStep {
+                device_id: DeviceId::generate(),
+                command,
+            }],
+        }
+    }
+
+    #[test]
+    fn scenes_survive_reopening_next_to_rooms() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = open_repo(&dir);
+        let movie_night = scene("movie night", DeviceCommand::TurnOn);
+        repo.add_scene(movie_night.clone()).ok();
+        repo.update_scene(scene("movie night", DeviceCommand::TurnOff))
+            .ok();
+        repo.add_room(RoomName::kitchen()).ok();
+
+        let reopened = open_repo(&dir);
+        match (reopened.fetch_scenes(), reopened.fetch_rooms()) {
+            (Ok(scenes), Ok(rooms)) => {
+                assert_eq!(scenes.len(), 1);
+                assert!(scenes[0].name == movie_night.name);
+                assert!(matches!(scenes[0].steps[0].command, DeviceCommand::TurnOff));
+                assert_eq!(rooms.len(), 1);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
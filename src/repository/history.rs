@@ -0,0 +1,46 @@
+use crate::domain::entity::{DeviceId, HistoryEntry};
+use crate::repository::room::{DeleteError, FetchError, InsertError};
+
+/// Keeps what happened to the devices over time, entries are keyed by the
+/// stable device id so they outlive renames and moves.
+pub trait HistoryStore: Send + Sync + 'static {
+    fn record_event(&self, entry: HistoryEntry) -> Result<(), InsertError>;
+
+    /// Entries of the device with `from <= timestamp <= to`, oldest first.
+    fn fetch_history(
+        &self,
+        device_id: DeviceId,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<HistoryEntry>, FetchError>;
+
+    /// Drops every entry older than `before`, returns how many were dropped.
+    fn prune_history(&self, before: u64) -> Result<usize, DeleteError>;
+}
+
+// history rules shared by the stores that keep the entries in memory
+
+pub(crate) fn insert_entry(entries: &mut Vec<HistoryEntry>, entry: HistoryEntry) {
+    // readings of concurrent queries may finish out of order
+    let idx = entries.partition_point(|e| e.timestamp <= entry.timestamp);
+    entries.insert(idx, entry);
+}
+
+pub(crate) fn select_entries(
+    entries: &[HistoryEntry],
+    device_id: DeviceId,
+    from: u64,
+    to: u64,
+) -> Vec<HistoryEntry> {
+    entries
+        .iter()
+        .filter(|e| e.device_id == device_id && from <= e.timestamp && e.timestamp <= to)
+        .cloned()
+        .collect()
+}
+
+pub(crate) fn remove_entries(entries: &mut Vec<HistoryEntry>, before: u64) -> usize {
+    let len = entries.len();
+    entries.retain(|e| e.timestamp >= before);
+    len - entries.len()
+}
@@ -1 +1,11 @@
+pub mod api_key;
+pub mod file;
+pub mod history;
 pub mod room;
+pub mod rule;
+pub mod schedule;
+pub mod sqlite;
+#[cfg(test)]
+pub(crate) mod suite;
+pub mod user;
+pub mod webhook;
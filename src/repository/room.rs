@@ -1,4 +1,14 @@
-use crate::domain::entity::{DeviceInfo, DeviceName, RoomInfo, RoomName};
+use crate::domain::entity::{
+    ApiKey, ApiKeyId, DeviceId, DeviceInfo, DeviceName, DeviceUpdate, GroupName, HistoryEntry,
+    RoomId, RoomInfo, RoomName, Rule, RuleId, Scene, SceneName, Schedule, ScheduleId, ScheduleRun,
+    User, UserId, UserName, Webhook, WebhookDelivery, WebhookId,
+};
+use crate::repository::api_key::{self, ApiKeyStore};
+use crate::repository::history::{self, HistoryStore};
+use crate::repository::rule::{self, RuleStore};
+use crate::repository::schedule::{ScheduleBook, ScheduleStore};
+use crate::repository::user::{self, UserStore};
+use crate::repository::webhook::{WebhookBook, WebhookStore};
 use std::sync::Mutex;
 
 pub enum InsertError {
@@ -13,13 +23,26 @@ pub enum FetchError {
 
 pub enum DeleteError {
     NotFound,
+    NotEmpty,
+    Unknown,
+}
+
+pub enum UpdateError {
+    NotFound,
+    Conflict,
     Unknown,
 }
 
 pub trait Repository: Send + Sync + 'static {
     fn add_room(&self, name: RoomName) -> Result<RoomInfo, InsertError>;
 
-    fn delete_room(&self, name: RoomName) -> Result<(), DeleteError>;
+    /// Refuses to delete a room that still has devices, unless `cascade`
+    /// is set, then the devices are deleted together with the room. The
+    /// room is returned as it was deleted, with the devices that went too.
+    fn delete_room(&self, name: RoomName, cascade: bool) -> Result<RoomInfo, DeleteError>;
+
+    /// Renames the room, its devices stay in it.
+    fn rename_room(&self, name: RoomName, new_name: RoomName) -> Result<RoomInfo, UpdateError>;
 
     fn fetch_room(&self, name: RoomName) -> Result<RoomInfo, FetchError>;
 
@@ -44,11 +67,56 @@ pub trait Repository: Send + Sync + 'static {
     ) -> Result<DeviceInfo, FetchError>;
 
     fn fetch_devices(&self, room_name: RoomName) -> Result<Vec<DeviceInfo>, FetchError>;
+
+    /// Looks the device up by its stable id, regardless of the room it is in.
+    fn fetch_device_by_id(&self, id: DeviceId) -> Result<(RoomName, DeviceInfo), FetchError>;
+
+    /// Applies the update in one step, a new name or address must not
+    /// conflict with other devices the same way as in `add_device`.
+    fn update_device(
+        &self,
+        room_name: RoomName,
+        device_name: DeviceName,
+        update: DeviceUpdate,
+    ) -> Result<DeviceInfo, UpdateError>;
+
+    fn move_device(
+        &self,
+        room_name: RoomName,
+        device_name: DeviceName,
+        target_room_name: RoomName,
+    ) -> Result<DeviceInfo, UpdateError> {
+        let update = DeviceUpdate {
+            room_name: Some(target_room_name),
+            ..DeviceUpdate::default()
+        };
+        self.update_device(room_name, device_name, update)
+    }
+
+    /// Scene names are unique across the house.
+    fn add_scene(&self, scene: Scene) -> Result<Scene, InsertError>;
+
+    fn fetch_scene(&self, name: SceneName) -> Result<Scene, FetchError>;
+
+    /// Every scene, in the order they were added.
+    fn fetch_scenes(&self) -> Result<Vec<Scene>, FetchError>;
+
+    /// Replaces the steps of the scene with the same name.
+    fn update_scene(&self, scene: Scene) -> Result<Scene, UpdateError>;
+
+    fn delete_scene(&self, name: SceneName) -> Result<(), DeleteError>;
 }
 
 pub struct InMemoryRepository {
     returns_error: bool,
     rooms: Mutex<Vec<RoomInfo>>,
+    history: Mutex<Vec<HistoryEntry>>,
+    scenes: Mutex<Vec<Scene>>,
+    rules: Mutex<Vec<Rule>>,
+    schedules: Mutex<ScheduleBook>,
+    webhooks: Mutex<WebhookBook>,
+    keys: Mutex<Vec<ApiKey>>,
+    users: Mutex<Vec<User>>,
 }
 
 impl Default for InMemoryRepository {
@@ -62,6 +130,13 @@ impl InMemoryRepository {
         Self {
             returns_error: false,
             rooms: Mutex::new(Vec::new()),
+            history: Mutex::new(Vec::new()),
+            scenes: Mutex::new(Vec::new()),
+            rules: Mutex::new(Vec::new()),
+            schedules: Mutex::new(ScheduleBook::default()),
+            webhooks: Mutex::new(WebhookBook::default()),
+            keys: Mutex::new(Vec::new()),
+            users: Mutex::new(Vec::new()),
         }
     }
 
@@ -85,17 +160,7 @@ impl Repository for InMemoryRepository {
             _ => return Err(InsertError::Unknown),
         };
 
-        if rooms.iter().any(|room| room.name == name) {
-            return Err(InsertError::Conflict);
-        }
-
-        let new_room = RoomInfo {
-            name,
-            devices: Vec::new(),
-        };
-        rooms.push(new_room.clone());
-
-        Ok(new_room)
+        insert_room(&mut rooms, name)
     }
 
     fn fetch_room(&self, name: RoomName) -> Result<RoomInfo, FetchError> {
@@ -108,10 +173,7 @@ impl Repository for InMemoryRepository {
             _ => return Err(FetchError::Unknown),
         };
 
-        match rooms.iter().find(|room| room.name == name) {
-            Some(room) => Ok(room.clone()),
-            _ => Err(FetchError::NotFound),
-        }
+        find_room(&rooms, name)
     }
 
     fn fetch_rooms(&self) -> Result<Vec<RoomInfo>, FetchError> {
@@ -127,7 +189,7 @@ impl Repository for InMemoryRepository {
         Ok(rooms.to_vec())
     }
 
-    fn delete_room(&self, name: RoomName) -> Result<(), DeleteError> {
+    fn delete_room(&self, name: RoomName, cascade: bool) -> Result<RoomInfo, DeleteError> {
         if self.returns_error {
             return Err(DeleteError::Unknown);
         }
@@ -137,13 +199,20 @@ impl Repository for InMemoryRepository {
             _ => return Err(DeleteError::Unknown),
         };
 
-        let del_idx = match rooms.iter().position(|r| r.name == name) {
-            Some(idx) => idx,
-            None => return Err(DeleteError::NotFound),
+        remove_room(&mut rooms, name, cascade)
+    }
+
+    fn rename_room(&self, name: RoomName, new_name: RoomName) -> Result<RoomInfo, UpdateError> {
+        if self.returns_error {
+            return Err(UpdateError::Unknown);
+        }
+
+        let mut rooms = match self.rooms.lock() {
+            Ok(rooms) => rooms,
+            _ => return Err(UpdateError::Unknown),
         };
 
-        rooms.remove(del_idx);
-        Ok(())
+        modify_room(&mut rooms, name, new_name)
     }
 
     fn add_device(
@@ -160,34 +229,7 @@ impl Repository for InMemoryRepository {
             _ => return Err(InsertError::Unknown),
         };
 
-        // check device with the same address cant in the same house
-        let conflict_addresses = rooms
-            .iter()
-            .filter(|room| {
-                room.devices
-                    .iter()
-                    .any(|d| d.address == device_info.address)
-            })
-            .count();
-        if conflict_addresses > 0 {
-            return Err(InsertError::Conflict);
-        }
-
-        // check device with the same name cant be in the same room
-        match rooms.iter_mut().find(|room| room.name == room_name) {
-            Some(room) => {
-                if room
-                    .devices
-                    .iter()
-                    .any(|d| d.name == device_info.name || d.address == device_info.address)
-                {
-                    return Err(InsertError::Conflict);
-                }
-                room.devices.push(device_info.clone());
-                Ok(device_info)
-            }
-            _ => Err(InsertError::Conflict),
-        }
+        insert_device(&mut rooms, room_name, device_info)
     }
 
     fn fetch_device(
@@ -204,13 +246,7 @@ impl Repository for InMemoryRepository {
             _ => return Err(FetchError::Unknown),
         };
 
-        match rooms.iter().find(|r| r.name == room_name) {
-            Some(room) => match room.devices.iter().find(|d| d.name == device_name) {
-                Some(device) => Ok(device.clone()),
-                None => Err(FetchError::NotFound),
-            },
-            None => Err(FetchError::NotFound),
-        }
+        find_device(&rooms, room_name, device_name)
     }
 
     fn fetch_devices(&self, room_name: RoomName) -> Result<Vec<DeviceInfo>, FetchError> {
@@ -223,10 +259,20 @@ impl Repository for InMemoryRepository {
             _ => return Err(FetchError::Unknown),
         };
 
-        match rooms.iter().find(|room| room.name == room_name) {
-            Some(room) => Ok(room.devices.to_vec()),
-            _ => Err(FetchError::NotFound),
+        find_room(&rooms, room_name).map(|room| room.devices)
+    }
+
+    fn fetch_device_by_id(&self, id: DeviceId) -> Result<(RoomName, DeviceInfo), FetchError> {
+        if self.returns_error {
+            return Err(FetchError::Unknown);
         }
+
+        let rooms = match self.rooms.lock() {
+            Ok(rooms) => rooms,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        find_device_by_id(&rooms, id)
     }
 
     fn delete_device(
@@ -243,13 +289,745 @@ impl Repository for InMemoryRepository {
             _ => return Err(DeleteError::Unknown),
         };
 
-        match rooms.iter_mut().find(|r| r.name == room_name) {
-            Some(room) => match room.devices.iter().position(|d| d.name == device_name) {
-                Some(idx) => room.devices.remove(idx),
-                None => return Err(DeleteError::NotFound),
-            },
-            None => return Err(DeleteError::NotFound),
+        remove_device(&mut rooms, room_name, device_name)
+    }
+
+    fn update_device(
+        &self,
+        room_name: RoomName,
+        device_name: DeviceName,
+        update: DeviceUpdate,
+    ) -> Result<DeviceInfo, UpdateError> {
+        if self.returns_error {
+            return Err(UpdateError::Unknown);
+        }
+
+        let mut rooms = match self.rooms.lock() {
+            Ok(rooms) => rooms,
+            _ => return Err(UpdateError::Unknown),
+        };
+
+        modify_device(&mut rooms, room_name, device_name, update)
+    }
+
+    fn add_scene(&self, scene: Scene) -> Result<Scene, InsertError> {
+        if self.returns_error {
+            return Err(InsertError::Unknown);
+        }
+
+        let mut scenes = match self.scenes.lock() {
+            Ok(scenes) => scenes,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        insert_scene(&mut scenes, scene)
+    }
+
+    fn fetch_scene(&self, name: SceneName) -> Result<Scene, FetchError> {
+        if self.returns_error {
+            return Err(FetchError::Unknown);
+        }
+
+        let scenes = match self.scenes.lock() {
+            Ok(scenes) => scenes,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        find_scene(&scenes, name)
+    }
+
+    fn fetch_scenes(&self) -> Result<Vec<Scene>, FetchError> {
+        if self.returns_error {
+            return Err(FetchError::Unknown);
+        }
+
+        let scenes = match self.scenes.lock() {
+            Ok(scenes) => scenes,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        Ok(scenes.to_vec())
+    }
+
+    fn update_scene(&self, scene: Scene) -> Result<Scene, UpdateError> {
+        if self.returns_error {
+            return Err(UpdateError::Unknown);
+        }
+
+        let mut scenes = match self.scenes.lock() {
+            Ok(scenes) => scenes,
+            _ => return Err(UpdateError::Unknown),
+        };
+
+        replace_scene(&mut scenes, scene)
+    }
+
+    fn delete_scene(&self, name: SceneName) -> Result<(), DeleteError> {
+        if self.returns_error {
+            return Err(DeleteError::Unknown);
+        }
+
+        let mut scenes = match self.scenes.lock() {
+            Ok(scenes) => scenes,
+            _ => return Err(DeleteError::Unknown),
+        };
+
+        remove_scene(&mut scenes, name)
+    }
+}
+
+impl HistoryStore for InMemoryRepository {
+    fn record_event(&self, entry: HistoryEntry) -> Result<(), InsertError> {
+        if self.returns_error {
+            return Err(InsertError::Unknown);
+        }
+
+        let mut entries = match self.history.lock() {
+            Ok(entries) => entries,
+            _ => return Err(InsertError::Unknown),
         };
+
+        history::insert_entry(&mut entries, entry);
         Ok(())
     }
+
+    fn fetch_history(
+        &self,
+        device_id: DeviceId,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<HistoryEntry>, FetchError> {
+        if self.returns_error {
+            return Err(FetchError::Unknown);
+        }
+
+        let entries = match self.history.lock() {
+            Ok(entries) => entries,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        Ok(history::select_entries(&entries, device_id, from, to))
+    }
+
+    fn prune_history(&self, before: u64) -> Result<usize, DeleteError> {
+        if self.returns_error {
+            return Err(DeleteError::Unknown);
+        }
+
+        let mut entries = match self.history.lock() {
+            Ok(entries) => entries,
+            _ => return Err(DeleteError::Unknown),
+        };
+
+        Ok(history::remove_entries(&mut entries, before))
+    }
+}
+
+impl RuleStore for InMemoryRepository {
+    fn add_rule(&self, new_rule: Rule) -> Result<Rule, InsertError> {
+        if self.returns_error {
+            return Err(InsertError::Unknown);
+        }
+
+        let mut rules = match self.rules.lock() {
+            Ok(rules) => rules,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        rule::insert_rule(&mut rules, new_rule)
+    }
+
+    fn fetch_rule(&self, id: RuleId) -> Result<Rule, FetchError> {
+        if self.returns_error {
+            return Err(FetchError::Unknown);
+        }
+
+        let rules = match self.rules.lock() {
+            Ok(rules) => rules,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        rule::find_rule(&rules, id)
+    }
+
+    fn fetch_rules(&self) -> Result<Vec<Rule>, FetchError> {
+        if self.returns_error {
+            return Err(FetchError::Unknown);
+        }
+
+        let rules = match self.rules.lock() {
+            Ok(rules) => rules,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        Ok(rules.to_vec())
+    }
+
+    fn update_rule(&self, updated: Rule) -> Result<Rule, UpdateError> {
+        if self.returns_error {
+            return Err(UpdateError::Unknown);
+        }
+
+        let mut rules = match self.rules.lock() {
+            Ok(rules) => rules,
+            _ => return Err(UpdateError::Unknown),
+        };
+
+        rule::replace_rule(&mut rules, updated)
+    }
+
+    fn delete_rule(&self, id: RuleId) -> Result<(), DeleteError> {
+        if self.returns_error {
+            return Err(DeleteError::Unknown);
+        }
+
+        let mut rules = match self.rules.lock() {
+            Ok(rules) => rules,
+            _ => return Err(DeleteError::Unknown),
+        };
+
+        rule::remove_rule(&mut rules, id)
+    }
+}
+
+impl ScheduleStore for InMemoryRepository {
+    fn add_schedule(&self, schedule: Schedule) -> Result<Schedule, InsertError> {
+        if self.returns_error {
+            return Err(InsertError::Unknown);
+        }
+
+        let mut book = match self.schedules.lock() {
+            Ok(book) => book,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        book.insert_schedule(schedule)
+    }
+
+    fn fetch_schedule(&self, id: ScheduleId) -> Result<Schedule, FetchError> {
+        if self.returns_error {
+            return Err(FetchError::Unknown);
+        }
+
+        let book = match self.schedules.lock() {
+            Ok(book) => book,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        book.find_schedule(id)
+    }
+
+    fn fetch_schedules(&self) -> Result<Vec<Schedule>, FetchError> {
+        if self.returns_error {
+            return Err(FetchError::Unknown);
+        }
+
+        let book = match self.schedules.lock() {
+            Ok(book) => book,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        Ok(book.schedules.to_vec())
+    }
+
+    fn delete_schedule(&self, id: ScheduleId) -> Result<(), DeleteError> {
+        if self.returns_error {
+            return Err(DeleteError::Unknown);
+        }
+
+        let mut book = match self.schedules.lock() {
+            Ok(book) => book,
+            _ => return Err(DeleteError::Unknown),
+        };
+
+        book.remove_schedule(id)
+    }
+
+    fn record_run(&self, run: ScheduleRun) -> Result<(), InsertError> {
+        if self.returns_error {
+            return Err(InsertError::Unknown);
+        }
+
+        let mut book = match self.schedules.lock() {
+            Ok(book) => book,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        book.insert_run(run)
+    }
+
+    fn fetch_runs(&self, id: ScheduleId) -> Result<Vec<ScheduleRun>, FetchError> {
+        if self.returns_error {
+            return Err(FetchError::Unknown);
+        }
+
+        let book = match self.schedules.lock() {
+            Ok(book) => book,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        book.select_runs(id)
+    }
+}
+
+impl WebhookStore for InMemoryRepository {
+    fn add_webhook(&self, webhook: Webhook) -> Result<Webhook, InsertError> {
+        if self.returns_error {
+            return Err(InsertError::Unknown);
+        }
+
+        let mut book = match self.webhooks.lock() {
+            Ok(book) => book,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        book.insert_webhook(webhook)
+    }
+
+    fn fetch_webhook(&self, id: WebhookId) -> Result<Webhook, FetchError> {
+        if self.returns_error {
+            return Err(FetchError::Unknown);
+        }
+
+        let book = match self.webhooks.lock() {
+            Ok(book) => book,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        book.find_webhook(id)
+    }
+
+    fn fetch_webhooks(&self) -> Result<Vec<Webhook>, FetchError> {
+        if self.returns_error {
+            return Err(FetchError::Unknown);
+        }
+
+        let book = match self.webhooks.lock() {
+            Ok(book) => book,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        Ok(book.webhooks.to_vec())
+    }
+
+    fn delete_webhook(&self, id: WebhookId) -> Result<(), DeleteError> {
+        if self.returns_error {
+            return Err(DeleteError::Unknown);
+        }
+
+        let mut book = match self.webhooks.lock() {
+            Ok(book) => book,
+            _ => return Err(DeleteError::Unknown),
+        };
+
+        book.remove_webhook(id)
+    }
+
+    fn record_delivery(&self, delivery: WebhookDelivery) -> Result<(), InsertError> {
+        if self.returns_error {
+            return Err(InsertError::Unknown);
+        }
+
+        let mut book = match self.webhooks.lock() {
+            Ok(book) => book,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        book.insert_delivery(delivery)
+    }
+
+    fn fetch_deliveries(&self, id: WebhookId) -> Result<Vec<WebhookDelivery>, FetchError> {
+        if self.returns_error {
+            return Err(FetchError::Unknown);
+        }
+
+        let book = match self.webhooks.lock() {
+            Ok(book) => book,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        book.select_deliveries(id)
+    }
+}
+
+impl ApiKeyStore for InMemoryRepository {
+    fn add_key(&self, key: ApiKey) -> Result<ApiKey, InsertError> {
+        if self.returns_error {
+            return Err(InsertError::Unknown);
+        }
+
+        let mut keys = match self.keys.lock() {
+            Ok(keys) => keys,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        api_key::insert_key(&mut keys, key)
+    }
+
+    fn fetch_keys(&self) -> Result<Vec<ApiKey>, FetchError> {
+        if self.returns_error {
+            return Err(FetchError::Unknown);
+        }
+
+        let keys = match self.keys.lock() {
+            Ok(keys) => keys,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        Ok(keys.to_vec())
+    }
+
+    fn fetch_key_by_hash(&self, token_hash: &str) -> Result<ApiKey, FetchError> {
+        if self.returns_error {
+            return Err(FetchError::Unknown);
+        }
+
+        let keys = match self.keys.lock() {
+            Ok(keys) => keys,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        api_key::find_key_by_hash(&keys, token_hash)
+    }
+
+    fn delete_key(&self, id: ApiKeyId) -> Result<(), DeleteError> {
+        if self.returns_error {
+            return Err(DeleteError::Unknown);
+        }
+
+        let mut keys = match self.keys.lock() {
+            Ok(keys) => keys,
+            _ => return Err(DeleteError::Unknown),
+        };
+
+        api_key::remove_key(&mut keys, id)
+    }
+}
+
+impl UserStore for InMemoryRepository {
+    fn add_user(&self, new_user: User) -> Result<User, InsertError> {
+        if self.returns_error {
+            return Err(InsertError::Unknown);
+        }
+
+        let mut users = match self.users.lock() {
+            Ok(users) => users,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        user::insert_user(&mut users, new_user)
+    }
+
+    fn fetch_users(&self) -> Result<Vec<User>, FetchError> {
+        if self.returns_error {
+            return Err(FetchError::Unknown);
+        }
+
+        let users = match self.users.lock() {
+            Ok(users) => users,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        Ok(users.to_vec())
+    }
+
+    fn fetch_user(&self, id: UserId) -> Result<User, FetchError> {
+        if self.returns_error {
+            return Err(FetchError::Unknown);
+        }
+
+        let users = match self.users.lock() {
+            Ok(users) => users,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        user::find_user(&users, id)
+    }
+
+    fn fetch_user_by_name(&self, name: &UserName) -> Result<User, FetchError> {
+        if self.returns_error {
+            return Err(FetchError::Unknown);
+        }
+
+        let users = match self.users.lock() {
+            Ok(users) => users,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        user::find_user_by_name(&users, name)
+    }
+
+    fn update_user(&self, updated: User) -> Result<User, UpdateError> {
+        if self.returns_error {
+            return Err(UpdateError::Unknown);
+        }
+
+        let mut users = match self.users.lock() {
+            Ok(users) => users,
+            _ => return Err(UpdateError::Unknown),
+        };
+
+        user::replace_user(&mut users, updated)
+    }
+
+    fn delete_user(&self, id: UserId) -> Result<(), DeleteError> {
+        if self.returns_error {
+            return Err(DeleteError::Unknown);
+        }
+
+        let mut users = match self.users.lock() {
+            Ok(users) => users,
+            _ => return Err(DeleteError::Unknown),
+        };
+
+        user::remove_user(&mut users, id)
+    }
+}
+
+// house layout rules shared by the repositories that keep
+// the whole layout in memory, regardless of how they persist it
+
+pub(crate) fn insert_room(
+    rooms: &mut Vec<RoomInfo>,
+    name: RoomName,
+) -> Result<RoomInfo, InsertError> {
+    if rooms.iter().any(|room| room.name == name) {
+        return Err(InsertError::Conflict);
+    }
+
+    let new_room = RoomInfo {
+        id: RoomId::generate(),
+        name,
+        devices: Vec::new(),
+    };
+    rooms.push(new_room.clone());
+
+    Ok(new_room)
+}
+
+pub(crate) fn find_room(rooms: &[RoomInfo], name: RoomName) -> Result<RoomInfo, FetchError> {
+    match rooms.iter().find(|room| room.name == name) {
+        Some(room) => Ok(room.clone()),
+        _ => Err(FetchError::NotFound),
+    }
+}
+
+pub(crate) fn remove_room(
+    rooms: &mut Vec<RoomInfo>,
+    name: RoomName,
+    cascade: bool,
+) -> Result<RoomInfo, DeleteError> {
+    let del_idx = match rooms.iter().position(|r| r.name == name) {
+        Some(idx) => idx,
+        None => return Err(DeleteError::NotFound),
+    };
+    if !cascade && !rooms[del_idx].devices.is_empty() {
+        return Err(DeleteError::NotEmpty);
+    }
+
+    Ok(rooms.remove(del_idx))
+}
+
+pub(crate) fn modify_room(
+    rooms: &mut [RoomInfo],
+    name: RoomName,
+    new_name: RoomName,
+) -> Result<RoomInfo, UpdateError> {
+    if name != new_name && rooms.iter().any(|room| room.name == new_name) {
+        return Err(UpdateError::Conflict);
+    }
+
+    match rooms.iter_mut().find(|room| room.name == name) {
+        Some(room) => {
+            room.name = new_name;
+            Ok(room.clone())
+        }
+        None => Err(UpdateError::NotFound),
+    }
+}
+
+pub(crate) fn insert_device(
+    rooms: &mut [RoomInfo],
+    room_name: RoomName,
+    device_info: DeviceInfo,
+) -> Result<DeviceInfo, InsertError> {
+    // check device with the same address cant in the same house
+    let conflict_addresses = rooms
+        .iter()
+        .filter(|room| {
+            room.devices
+                .iter()
+                .any(|d| d.address == device_info.address)
+        })
+        .count();
+    if conflict_addresses > 0 {
+        return Err(InsertError::Conflict);
+    }
+
+    // check device with the same name cant be in the same room
+    match rooms.iter_mut().find(|room| room.name == room_name) {
+        Some(room) => {
+            if room
+                .devices
+                .iter()
+                .any(|d| d.name == device_info.name || d.address == device_info.address)
+            {
+                return Err(InsertError::Conflict);
+            }
+            room.devices.push(device_info.clone());
+            Ok(device_info)
+        }
+        _ => Err(InsertError::Conflict),
+    }
+}
+
+pub(crate) fn find_device(
+    rooms: &[RoomInfo],
+    room_name: RoomName,
+    device_name: DeviceName,
+) -> Result<DeviceInfo, FetchError> {
+    match rooms.iter().find(|r| r.name == room_name) {
+        Some(room) => match room.devices.iter().find(|d| d.name == device_name) {
+            Some(device) => Ok(device.clone()),
+            None => Err(FetchError::NotFound),
+        },
+        None => Err(FetchError::NotFound),
+    }
+}
+
+pub(crate) fn find_device_by_id(
+    rooms: &[RoomInfo],
+    id: DeviceId,
+) -> Result<(RoomName, DeviceInfo), FetchError> {
+    rooms
+        .iter()
+        .find_map(|room| {
+            room.devices
+                .iter()
+                .find(|d| d.id == id)
+                .map(|device| (room.name.clone(), device.clone()))
+        })
+        .ok_or(FetchError::NotFound)
+}
+
+pub(crate) fn remove_device(
+    rooms: &mut [RoomInfo],
+    room_name: RoomName,
+    device_name: DeviceName,
+) -> Result<(), DeleteError> {
+    match rooms.iter_mut().find(|r| r.name == room_name) {
+        Some(room) => match room.devices.iter().position(|d| d.name == device_name) {
+            Some(idx) => room.devices.remove(idx),
+            None => return Err(DeleteError::NotFound),
+        },
+        None => return Err(DeleteError::NotFound),
+    };
+    Ok(())
+}
+
+pub(crate) fn modify_device(
+    rooms: &mut [RoomInfo],
+    room_name: RoomName,
+    device_name: DeviceName,
+    update: DeviceUpdate,
+) -> Result<DeviceInfo, UpdateError> {
+    let (room_idx, device_idx) = rooms
+        .iter()
+        .enumerate()
+        .find(|(_, room)| room.name == room_name)
+        .and_then(|(room_idx, room)| {
+            room.devices
+                .iter()
+                .position(|d| d.name == device_name)
+                .map(|device_idx| (room_idx, device_idx))
+        })
+        .ok_or(UpdateError::NotFound)?;
+
+    let current = rooms[room_idx].devices[device_idx].clone();
+    let updated = DeviceInfo {
+        id: current.id,
+        name: update.name.unwrap_or(current.name),
+        address: update.address.unwrap_or(current.address),
+        device_type: update.device_type.unwrap_or(current.device_type),
+        groups: update
+            .groups
+            .map(GroupName::normalize)
+            .unwrap_or(current.groups),
+    };
+    let target_idx = match update.room_name {
+        Some(target) => rooms
+            .iter()
+            .position(|room| room.name == target)
+            .ok_or(UpdateError::Conflict)?,
+        None => room_idx,
+    };
+
+    // same rules as for a new device, except the device can't conflict with itself
+    let is_self = |idx: usize, d: &DeviceInfo| idx == room_idx && d.name == device_name;
+    let address_taken = rooms.iter().enumerate().any(|(idx, room)| {
+        room.devices
+            .iter()
+            .any(|d| !is_self(idx, d) && d.address == updated.address)
+    });
+    let name_taken = rooms[target_idx]
+        .devices
+        .iter()
+        .any(|d| !is_self(target_idx, d) && d.name == updated.name);
+    if address_taken || name_taken {
+        return Err(UpdateError::Conflict);
+    }
+
+    if target_idx == room_idx {
+        rooms[room_idx].devices[device_idx] = updated.clone();
+    } else {
+        rooms[room_idx].devices.remove(device_idx);
+        rooms[target_idx].devices.push(updated.clone());
+    }
+    Ok(updated)
+}
+
+pub(crate) fn insert_scene(scenes: &mut Vec<Scene>, scene: Scene) -> Result<Scene, InsertError> {
+    if scenes.iter().any(|s| s.name == scene.name) {
+        return Err(InsertError::Conflict);
+    }
+
+    scenes.push(scene.clone());
+    Ok(scene)
+}
+
+pub(crate) fn find_scene(scenes: &[Scene], name: SceneName) -> Result<Scene, FetchError> {
+    scenes
+        .iter()
+        .find(|s| s.name == name)
+        .cloned()
+        .ok_or(FetchError::NotFound)
+}
+
+pub(crate) fn replace_scene(scenes: &mut [Scene], scene: Scene) -> Result<Scene, UpdateError> {
+    match scenes.iter_mut().find(|s| s.name == scene.name) {
+        Some(current) => {
+            *current = scene.clone();
+            Ok(scene)
+        }
+        None => Err(UpdateError::NotFound),
+    }
+}
+
+pub(crate) fn remove_scene(scenes: &mut Vec<Scene>, name: SceneName) -> Result<(), DeleteError> {
+    match scenes.iter().position(|s| s.name == name) {
+        Some(idx) => {
+            scenes.remove(idx);
+            Ok(())
+        }
+        None => Err(DeleteError::NotFound),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::suite::repository_suite;
+    use std::sync::Arc;
+
+    repository_suite!(|| (Arc::new(InMemoryRepository::new()), ()));
 }
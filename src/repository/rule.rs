@@ -0,0 +1,60 @@
+use crate::domain::entity::{Rule, RuleId};
+use crate::repository::room::{DeleteError, FetchError, InsertError, UpdateError};
+
+/// Keeps the automation rules, rule names are unique across the house.
+pub trait RuleStore: Send + Sync + 'static {
+    fn add_rule(&self, rule: Rule) -> Result<Rule, InsertError>;
+
+    fn fetch_rule(&self, id: RuleId) -> Result<Rule, FetchError>;
+
+    /// Every rule, in the order they were added.
+    fn fetch_rules(&self) -> Result<Vec<Rule>, FetchError>;
+
+    /// Replaces the rule with the same id.
+    fn update_rule(&self, rule: Rule) -> Result<Rule, UpdateError>;
+
+    fn delete_rule(&self, id: RuleId) -> Result<(), DeleteError>;
+}
+
+// rule rules shared by the stores that keep the rules in memory
+
+pub(crate) fn insert_rule(rules: &mut Vec<Rule>, rule: Rule) -> Result<Rule, InsertError> {
+    if rules.iter().any(|r| r.name == rule.name) {
+        return Err(InsertError::Conflict);
+    }
+
+    rules.push(rule.clone());
+    Ok(rule)
+}
+
+pub(crate) fn find_rule(rules: &[Rule], id: RuleId) -> Result<Rule, FetchError> {
+    rules
+        .iter()
+        .find(|r| r.id == id)
+        .cloned()
+        .ok_or(FetchError::NotFound)
+}
+
+pub(crate) fn replace_rule(rules: &mut [Rule], rule: Rule) -> Result<Rule, UpdateError> {
+    if rules.iter().any(|r| r.id != rule.id && r.name == rule.name) {
+        return Err(UpdateError::Conflict);
+    }
+
+    match rules.iter_mut().find(|r| r.id == rule.id) {
+        Some(current) => {
+            *current = rule.clone();
+            Ok(rule)
+        }
+        None => Err(UpdateError::NotFound),
+    }
+}
+
+pub(crate) fn remove_rule(rules: &mut Vec<Rule>, id: RuleId) -> Result<(), DeleteError> {
+    match rules.iter().position(|r| r.id == id) {
+        Some(idx) => {
+            rules.remove(idx);
+            Ok(())
+        }
+        None => Err(DeleteError::NotFound),
+    }
+}
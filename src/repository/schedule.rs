@@ -0,0 +1,93 @@
+use crate::domain::entity::{Schedule, ScheduleId, ScheduleRun};
+use crate::repository::room::{DeleteError, FetchError, InsertError};
+
+/// Only this many runs are kept per schedule, older ones are dropped.
+pub const MAX_RUNS: usize = 100;
+
+/// Keeps the schedules and the log of their runs, deleting a schedule
+/// deletes its runs too.
+pub trait ScheduleStore: Send + Sync + 'static {
+    fn add_schedule(&self, schedule: Schedule) -> Result<Schedule, InsertError>;
+
+    fn fetch_schedule(&self, id: ScheduleId) -> Result<Schedule, FetchError>;
+
+    /// Every schedule, in the order they were added.
+    fn fetch_schedules(&self) -> Result<Vec<Schedule>, FetchError>;
+
+    fn delete_schedule(&self, id: ScheduleId) -> Result<(), DeleteError>;
+
+    /// Appends the run to the log of its schedule, `Conflict` if the schedule is gone.
+    fn record_run(&self, run: ScheduleRun) -> Result<(), InsertError>;
+
+    /// The last `MAX_RUNS` runs of the schedule, oldest first.
+    fn fetch_runs(&self, id: ScheduleId) -> Result<Vec<ScheduleRun>, FetchError>;
+}
+
+/// Schedules and runs of the stores that keep them in memory,
+/// behind one lock so a schedule and its runs change together.
+#[derive(Clone, Default)]
+pub(crate) struct ScheduleBook {
+    pub(crate) schedules: Vec<Schedule>,
+    pub(crate) runs: Vec<ScheduleRun>,
+}
+
+impl ScheduleBook {
+    pub(crate) fn insert_schedule(&mut self, schedule: Schedule) -> Result<Schedule, InsertError> {
+        if self.schedules.iter().any(|s| s.id == schedule.id) {
+            return Err(InsertError::Conflict);
+        }
+
+        self.schedules.push(schedule.clone());
+        Ok(schedule)
+    }
+
+    pub(crate) fn find_schedule(&self, id: ScheduleId) -> Result<Schedule, FetchError> {
+        self.schedules
+            .iter()
+            .find(|s| s.id == id)
+            .cloned()
+            .ok_or(FetchError::NotFound)
+    }
+
+    pub(crate) fn remove_schedule(&mut self, id: ScheduleId) -> Result<(), DeleteError> {
+        match self.schedules.iter().position(|s| s.id == id) {
+            Some(idx) => {
+                self.schedules.remove(idx);
+                self.runs.retain(|r| r.schedule_id != id);
+                Ok(())
+            }
+            None => Err(DeleteError::NotFound),
+        }
+    }
+
+    pub(crate) fn insert_run(&mut self, run: ScheduleRun) -> Result<(), InsertError> {
+        if !self.schedules.iter().any(|s| s.id == run.schedule_id) {
+            return Err(InsertError::Conflict);
+        }
+
+        let id = run.schedule_id;
+        self.runs.push(run);
+        let count = self.runs.iter().filter(|r| r.schedule_id == id).count();
+        if count > MAX_RUNS {
+            let mut excess = count - MAX_RUNS;
+            self.runs.retain(|r| {
+                let drop = excess > 0 && r.schedule_id == id;
+                if drop {
+                    excess -= 1;
+                }
+                !drop
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn select_runs(&self, id: ScheduleId) -> Result<Vec<ScheduleRun>, FetchError> {
+        self.find_schedule(id)?;
+        Ok(self
+            .runs
+            .iter()
+            .filter(|r| r.schedule_id == id)
+            .cloned()
+            .collect())
+    }
+}
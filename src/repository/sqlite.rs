@@ -0,0 +1,2229 @@
+use crate::domain::cron::CronExpr;
+use crate::domain::entity::{
+    ApiKey, ApiKeyId, DeviceCommand, DeviceId, DeviceInfo, DeviceName, DeviceType, DeviceUpdate,
+    Grant, GrantTarget, GroupName, HistoryEntry, Role, RoomId, RoomInfo, RoomName, Rule,
+    RuleAction, RuleId, RuleName, RuleTrigger, Scene, SceneName, SceneStep, Schedule, ScheduleId,
+    ScheduleRun, Scope, TimeOfDay, TriggerParts, User, UserId, UserName, Webhook, WebhookDelivery,
+    WebhookId, WebhookUrl,
+};
+use crate::repository::api_key::ApiKeyStore;
+use crate::repository::history::HistoryStore;
+use crate::repository::room::{DeleteError, FetchError, InsertError, Repository, UpdateError};
+use crate::repository::rule::RuleStore;
+use crate::repository::schedule::{ScheduleStore, MAX_RUNS};
+use crate::repository::user::UserStore;
+use crate::repository::webhook::{WebhookStore, MAX_DELIVERIES};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+#[derive(thiserror::Error, Debug)]
+pub enum OpenError {
+    #[error("SqliteError: {0}")]
+    SqliteError(#[from] rusqlite::Error),
+    #[error("MigrationError: database schema version {0} is newer than this release")]
+    MigrationError(usize),
+}
+
+// every entry upgrades the schema by one version, the applied version
+// is tracked in `PRAGMA user_version`, so entries must never be edited
+// once released, only new ones appended
+const MIGRATIONS: &[&str] = &[
+    // 1: rooms and their devices
+    "CREATE TABLE rooms (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE devices (
+        id INTEGER PRIMARY KEY,
+        room_id INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        address TEXT NOT NULL UNIQUE,
+        device_type TEXT NOT NULL,
+        UNIQUE (room_id, name)
+    );",
+    // 2: stable ids exposed through the API, existing rows get a random v4 uuid
+    "ALTER TABLE rooms ADD COLUMN uuid TEXT;
+    ALTER TABLE devices ADD COLUMN uuid TEXT;
+    UPDATE rooms SET uuid = lower(
+        hex(randomblob(4)) || '-' || hex(randomblob(2)) || '-4' ||
+        substr(hex(randomblob(2)), 2) || '-' ||
+        substr('89ab', 1 + abs(random()) % 4, 1) || substr(hex(randomblob(2)), 2) || '-' ||
+        hex(randomblob(6))
+    );
+    UPDATE devices SET uuid = lower(
+        hex(randomblob(4)) || '-' || hex(randomblob(2)) || '-4' ||
+        substr(hex(randomblob(2)), 2) || '-' ||
+        substr('89ab', 1 + abs(random()) % 4, 1) || substr(hex(randomblob(2)), 2) || '-' ||
+        hex(randomblob(6))
+    );
+    CREATE UNIQUE INDEX rooms_uuid ON rooms (uuid);
+    CREATE UNIQUE INDEX devices_uuid ON devices (uuid);",
+    // 3: device history, not tied to the devices table so that it
+    // stays until the retention drops it, even for deleted devices
+    "CREATE TABLE history (
+        id INTEGER PRIMARY KEY,
+        device_uuid TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        event TEXT NOT NULL
+    );
+    CREATE INDEX history_device_timestamp ON history (device_uuid, timestamp);",
+    // 4: automation rules, the devices they refer to are not foreign
+    // keys, a rule of a deleted device stays and is skipped
+    "CREATE TABLE rules (
+        id INTEGER PRIMARY KEY,
+        uuid TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL UNIQUE,
+        enabled INTEGER NOT NULL,
+        trigger_kind TEXT NOT NULL,
+        trigger_device_uuid TEXT,
+        trigger_threshold REAL,
+        trigger_at TEXT,
+        action_device_uuid TEXT NOT NULL,
+        action_command TEXT NOT NULL
+    );",
+    // 5: scheduled commands and the log of their runs
+    "CREATE TABLE schedules (
+        id INTEGER PRIMARY KEY,
+        uuid TEXT NOT NULL UNIQUE,
+        cron TEXT NOT NULL,
+        device_uuid TEXT NOT NULL,
+        command TEXT NOT NULL
+    );
+    CREATE TABLE schedule_runs (
+        id INTEGER PRIMARY KEY,
+        schedule_id INTEGER NOT NULL REFERENCES schedules(id) ON DELETE CASCADE,
+        timestamp INTEGER NOT NULL,
+        status TEXT,
+        error TEXT
+    );
+    CREATE INDEX schedule_runs_schedule ON schedule_runs (schedule_id, id);",
+    // 6: scenes, like rules their devices are not foreign keys
+    "CREATE TABLE scenes (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE scene_steps (
+        id INTEGER PRIMARY KEY,
+        scene_id INTEGER NOT NULL REFERENCES scenes(id) ON DELETE CASCADE,
+        device_uuid TEXT NOT NULL,
+        command TEXT NOT NULL
+    );
+    CREATE INDEX scene_steps_scene ON scene_steps (scene_id, id);",
+    // 7: device groups, a JSON array of names since groups are only ever
+    // read together with their device
+    "ALTER TABLE devices ADD COLUMN group_names TEXT NOT NULL DEFAULT '[]';",
+    // 8: webhooks and the log of their deliveries, the room and device
+    // filters are names like in the events until 12
+    "CREATE TABLE webhooks (
+        id INTEGER PRIMARY KEY,
+        uuid TEXT NOT NULL UNIQUE,
+        url TEXT NOT NULL,
+        secret TEXT NOT NULL,
+        events TEXT NOT NULL,
+        room_name TEXT,
+        device_name TEXT
+    );
+    CREATE TABLE webhook_deliveries (
+        id INTEGER PRIMARY KEY,
+        webhook_id INTEGER NOT NULL REFERENCES webhooks(id) ON DELETE CASCADE,
+        timestamp INTEGER NOT NULL,
+        event TEXT NOT NULL,
+        attempts INTEGER NOT NULL,
+        status INTEGER,
+        error TEXT
+    );
+    CREATE INDEX webhook_deliveries_webhook ON webhook_deliveries (webhook_id, id);",
+    // 9: API keys, only the hashes of their tokens
+    "CREATE TABLE api_keys (
+        id INTEGER PRIMARY KEY,
+        uuid TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL,
+        scope TEXT NOT NULL,
+        token_hash TEXT NOT NULL UNIQUE
+    );",
+    // 10: users and their grants, the rooms and devices are stable ids
+    // and not foreign keys, like the devices of the rules
+    "CREATE TABLE users (
+        id INTEGER PRIMARY KEY,
+        uuid TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL UNIQUE,
+        password_hash TEXT NOT NULL,
+        admin INTEGER NOT NULL
+    );
+    CREATE TABLE user_grants (
+        id INTEGER PRIMARY KEY,
+        user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+        room_uuid TEXT,
+        device_uuid TEXT,
+        role TEXT NOT NULL
+    );
+    CREATE INDEX user_grants_user ON user_grants (user_id, id);",
+    // 11: rules without an action, a NOT NULL can't be dropped in place
+    // so the table is rebuilt
+    "CREATE TABLE rules_new (
+        id INTEGER PRIMARY KEY,
+        uuid TEXT NOT NULL UNIQUE,
+        name TEXT NOT NULL UNIQUE,
+        enabled INTEGER NOT NULL,
+        trigger_kind TEXT NOT NULL,
+        trigger_device_uuid TEXT,
+        trigger_threshold REAL,
+        trigger_at TEXT,
+        action_device_uuid TEXT,
+        action_command TEXT
+    );
+    INSERT INTO rules_new SELECT * FROM rules;
+    DROP TABLE rules;
+    ALTER TABLE rules_new RENAME TO rules;",
+    // 12: webhooks filter on the stable room and device ids, the webhooks
+    // are kept with their deliveries, a name that no longer resolves gets
+    // a random id of no room or device, so the webhook stays narrow and
+    // goes quiet like one whose room was deleted
+    "ALTER TABLE webhooks ADD COLUMN room_uuid TEXT;
+    ALTER TABLE webhooks ADD COLUMN device_uuid TEXT;
+    UPDATE webhooks SET room_uuid = (
+        SELECT uuid FROM rooms WHERE rooms.name = webhooks.room_name
+    );
+    UPDATE webhooks SET device_uuid = (
+        SELECT devices.uuid FROM devices JOIN rooms ON rooms.id = devices.room_id
+        WHERE devices.name = webhooks.device_name
+            AND (webhooks.room_name IS NULL OR rooms.name = webhooks.room_name)
+        ORDER BY devices.id LIMIT 1
+    );
+    UPDATE webhooks SET room_uuid = lower(
+        hex(randomblob(4)) || '-' || hex(randomblob(2)) || '-' || hex(randomblob(2))
+            || '-' || hex(randomblob(2)) || '-' || hex(randomblob(6))
+    ) WHERE room_name IS NOT NULL AND room_uuid IS NULL;
+    UPDATE webhooks SET device_uuid = lower(
+        hex(randomblob(4)) || '-' || hex(randomblob(2)) || '-' || hex(randomblob(2))
+            || '-' || hex(randomblob(2)) || '-' || hex(randomblob(6))
+    ) WHERE device_name IS NOT NULL AND device_uuid IS NULL;
+    ALTER TABLE webhooks DROP COLUMN room_name;
+    ALTER TABLE webhooks DROP COLUMN device_name;",
+];
+
+/// Stores the house layout in a SQLite database.
+pub struct SqliteRepository {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteRepository {
+    /// Opens or creates the database at `path` and upgrades its schema.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, OpenError> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self, OpenError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(mut connection: Connection) -> Result<Self, OpenError> {
+        connection.pragma_update(None, "foreign_keys", true)?;
+        migrate(&mut connection)?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+fn migrate(connection: &mut Connection) -> Result<(), OpenError> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(OpenError::MigrationError(version));
+    }
+
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", idx + 1)?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+fn is_constraint_violation(error: &rusqlite::Error) -> bool {
+    matches!(
+        error,
+        rusqlite::Error::SqliteFailure(e, _) if e.code == ErrorCode::ConstraintViolation
+    )
+}
+
+type DeviceColumns = (String, String, String, String, String);
+
+// expects the columns in `uuid, name, address, device_type, group_names` order
+fn device_from_row(row: &rusqlite::Row) -> rusqlite::Result<DeviceColumns> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+    ))
+}
+
+fn device_from_columns(
+    (id, name, address, device_type, groups): DeviceColumns,
+) -> Result<DeviceInfo, FetchError> {
+    let groups = serde_json::from_str::<Vec<String>>(&groups)
+        .map_err(|_| FetchError::Unknown)?
+        .into_iter()
+        .map(GroupName::try_from)
+        .collect::<Result<Vec<_>, _>>();
+    match (
+        DeviceId::try_from(id),
+        DeviceName::try_from(name),
+        SocketAddr::from_str(&address),
+        DeviceType::try_from(device_type),
+        groups,
+    ) {
+        (Ok(id), Ok(name), Ok(address), Ok(device_type), Ok(groups)) => Ok(DeviceInfo {
+            id,
+            name,
+            address,
+            device_type,
+            groups: GroupName::normalize(groups),
+        }),
+        _ => Err(FetchError::Unknown),
+    }
+}
+
+fn groups_column(groups: &[GroupName]) -> String {
+    let names: Vec<String> = groups.iter().cloned().map(String::from).collect();
+    serde_json::Value::from(names).to_string()
+}
+
+fn select_devices(connection: &Connection, room_id: i64) -> Result<Vec<DeviceInfo>, FetchError> {
+    let mut statement = connection
+        .prepare(
+            "SELECT uuid, name, address, device_type, group_names FROM devices
+             WHERE room_id = ?1 ORDER BY id",
+        )
+        .map_err(|_| FetchError::Unknown)?;
+    let rows = statement
+        .query_map(params![room_id], device_from_row)
+        .map_err(|_| FetchError::Unknown)?;
+
+    rows.map(|row| {
+        row.map_err(|_| FetchError::Unknown)
+            .and_then(device_from_columns)
+    })
+    .collect()
+}
+
+fn select_room_id(connection: &Connection, name: &RoomName) -> Result<i64, FetchError> {
+    select_room(connection, name).map(|(room_id, _)| room_id)
+}
+
+fn select_room(connection: &Connection, name: &RoomName) -> Result<(i64, RoomId), FetchError> {
+    let (room_id, uuid) = connection
+        .query_row(
+            "SELECT id, uuid FROM rooms WHERE name = ?1",
+            params![String::from(name.clone())],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+        )
+        .optional()
+        .map_err(|_| FetchError::Unknown)?
+        .ok_or(FetchError::NotFound)?;
+
+    let uuid = RoomId::try_from(uuid).map_err(|_| FetchError::Unknown)?;
+    Ok((room_id, uuid))
+}
+
+impl Repository for SqliteRepository {
+    fn add_room(&self, name: RoomName) -> Result<RoomInfo, InsertError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        let id = RoomId::generate();
+        match connection.execute(
+            "INSERT INTO rooms (uuid, name) VALUES (?1, ?2)",
+            params![String::from(id), String::from(name.clone())],
+        ) {
+            Ok(_) => Ok(RoomInfo {
+                id,
+                name,
+                devices: Vec::new(),
+            }),
+            Err(e) if is_constraint_violation(&e) => Err(InsertError::Conflict),
+            Err(_) => Err(InsertError::Unknown),
+        }
+    }
+
+    fn delete_room(&self, name: RoomName, cascade: bool) -> Result<RoomInfo, DeleteError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(DeleteError::Unknown),
+        };
+
+        // the connection stays locked, so these are the devices that go
+        let (room_id, id) = match select_room(&connection, &name) {
+            Ok(room) => room,
+            Err(FetchError::NotFound) => return Err(DeleteError::NotFound),
+            Err(FetchError::Unknown) => return Err(DeleteError::Unknown),
+        };
+        let devices = select_devices(&connection, room_id).map_err(|_| DeleteError::Unknown)?;
+        if !cascade && !devices.is_empty() {
+            return Err(DeleteError::NotEmpty);
+        }
+
+        // devices go away with the room through the foreign key cascade
+        match connection.execute("DELETE FROM rooms WHERE id = ?1", params![room_id]) {
+            Ok(_) => Ok(RoomInfo { id, name, devices }),
+            Err(_) => Err(DeleteError::Unknown),
+        }
+    }
+
+    fn rename_room(&self, name: RoomName, new_name: RoomName) -> Result<RoomInfo, UpdateError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(UpdateError::Unknown),
+        };
+
+        let (room_id, uuid) = match connection.query_row(
+            "UPDATE rooms SET name = ?2 WHERE name = ?1 RETURNING id, uuid",
+            params![String::from(name), String::from(new_name.clone())],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+        ) {
+            Ok(row) => row,
+            Err(rusqlite::Error::QueryReturnedNoRows) => return Err(UpdateError::NotFound),
+            Err(e) if is_constraint_violation(&e) => return Err(UpdateError::Conflict),
+            Err(_) => return Err(UpdateError::Unknown),
+        };
+
+        let id = RoomId::try_from(uuid).map_err(|_| UpdateError::Unknown)?;
+        let devices = select_devices(&connection, room_id).map_err(|_| UpdateError::Unknown)?;
+        Ok(RoomInfo {
+            id,
+            name: new_name,
+            devices,
+        })
+    }
+
+    fn fetch_room(&self, name: RoomName) -> Result<RoomInfo, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        let (room_id, id) = select_room(&connection, &name)?;
+        let devices = select_devices(&connection, room_id)?;
+        Ok(RoomInfo { id, name, devices })
+    }
+
+    fn fetch_rooms(&self) -> Result<Vec<RoomInfo>, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        let mut statement = connection
+            .prepare("SELECT id, uuid, name FROM rooms ORDER BY id")
+            .map_err(|_| FetchError::Unknown)?;
+        let rooms = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })
+            .map_err(|_| FetchError::Unknown)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| FetchError::Unknown)?;
+
+        rooms
+            .into_iter()
+            .map(|(room_id, uuid, name)| {
+                Ok(RoomInfo {
+                    id: RoomId::try_from(uuid).map_err(|_| FetchError::Unknown)?,
+                    name: RoomName::try_from(name).map_err(|_| FetchError::Unknown)?,
+                    devices: select_devices(&connection, room_id)?,
+                })
+            })
+            .collect()
+    }
+
+    fn add_device(
+        &self,
+        room_name: RoomName,
+        device_info: DeviceInfo,
+    ) -> Result<DeviceInfo, InsertError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        // missing room is a conflict, same as in the in-memory repository
+        let room_id = match select_room_id(&connection, &room_name) {
+            Ok(room_id) => room_id,
+            Err(FetchError::NotFound) => return Err(InsertError::Conflict),
+            Err(FetchError::Unknown) => return Err(InsertError::Unknown),
+        };
+
+        // unique constraints keep names unique per room and addresses per house
+        match connection.execute(
+            "INSERT INTO devices (room_id, uuid, name, address, device_type, group_names)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                room_id,
+                String::from(device_info.id),
+                String::from(device_info.name.clone()),
+                device_info.address.to_string(),
+                String::from(device_info.device_type.clone()),
+                groups_column(&device_info.groups),
+            ],
+        ) {
+            Ok(_) => Ok(device_info),
+            Err(e) if is_constraint_violation(&e) => Err(InsertError::Conflict),
+            Err(_) => Err(InsertError::Unknown),
+        }
+    }
+
+    fn delete_device(
+        &self,
+        room_name: RoomName,
+        device_name: DeviceName,
+    ) -> Result<(), DeleteError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(DeleteError::Unknown),
+        };
+
+        match connection.execute(
+            "DELETE FROM devices
+             WHERE name = ?2 AND room_id = (SELECT id FROM rooms WHERE name = ?1)",
+            params![String::from(room_name), String::from(device_name)],
+        ) {
+            Ok(0) => Err(DeleteError::NotFound),
+            Ok(_) => Ok(()),
+            Err(_) => Err(DeleteError::Unknown),
+        }
+    }
+
+    fn fetch_device(
+        &self,
+        room_name: RoomName,
+        device_name: DeviceName,
+    ) -> Result<DeviceInfo, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        let columns = connection
+            .query_row(
+                "SELECT d.uuid, d.name, d.address, d.device_type, d.group_names
+                 FROM devices d JOIN rooms r ON r.id = d.room_id
+                 WHERE r.name = ?1 AND d.name = ?2",
+                params![String::from(room_name), String::from(device_name)],
+                device_from_row,
+            )
+            .optional()
+            .map_err(|_| FetchError::Unknown)?
+            .ok_or(FetchError::NotFound)?;
+
+        device_from_columns(columns)
+    }
+
+    fn fetch_device_by_id(&self, id: DeviceId) -> Result<(RoomName, DeviceInfo), FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        let (room_name, columns) = connection
+            .query_row(
+                "SELECT r.name, d.uuid, d.name, d.address, d.device_type, d.group_names
+                 FROM devices d JOIN rooms r ON r.id = d.room_id
+                 WHERE d.uuid = ?1",
+                params![String::from(id)],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        (
+                            row.get(1)?,
+                            row.get(2)?,
+                            row.get(3)?,
+                            row.get(4)?,
+                            row.get(5)?,
+                        ),
+                    ))
+                },
+            )
+            .optional()
+            .map_err(|_| FetchError::Unknown)?
+            .ok_or(FetchError::NotFound)?;
+
+        let room_name = RoomName::try_from(room_name).map_err(|_| FetchError::Unknown)?;
+        Ok((room_name, device_from_columns(columns)?))
+    }
+
+    fn fetch_devices(&self, room_name: RoomName) -> Result<Vec<DeviceInfo>, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        let room_id = select_room_id(&connection, &room_name)?;
+        select_devices(&connection, room_id)
+    }
+
+    fn update_device(
+        &self,
+        room_name: RoomName,
+        device_name: DeviceName,
+        update: DeviceUpdate,
+    ) -> Result<DeviceInfo, UpdateError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(UpdateError::Unknown),
+        };
+
+        let (device_id, room_id, columns) = connection
+            .query_row(
+                "SELECT d.id, d.room_id, d.uuid, d.name, d.address, d.device_type, d.group_names
+                 FROM devices d JOIN rooms r ON r.id = d.room_id
+                 WHERE r.name = ?1 AND d.name = ?2",
+                params![String::from(room_name), String::from(device_name)],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, i64>(1)?,
+                        (
+                            row.get(2)?,
+                            row.get(3)?,
+                            row.get(4)?,
+                            row.get(5)?,
+                            row.get(6)?,
+                        ),
+                    ))
+                },
+            )
+            .optional()
+            .map_err(|_| UpdateError::Unknown)?
+            .ok_or(UpdateError::NotFound)?;
+        let current = device_from_columns(columns).map_err(|_| UpdateError::Unknown)?;
+
+        let updated = DeviceInfo {
+            id: current.id,
+            name: update.name.unwrap_or(current.name),
+            address: update.address.unwrap_or(current.address),
+            device_type: update.device_type.unwrap_or(current.device_type),
+            groups: update
+                .groups
+                .map(GroupName::normalize)
+                .unwrap_or(current.groups),
+        };
+        let target_room_id = match update.room_name {
+            Some(target) => match select_room_id(&connection, &target) {
+                Ok(target_room_id) => target_room_id,
+                Err(FetchError::NotFound) => return Err(UpdateError::Conflict),
+                Err(FetchError::Unknown) => return Err(UpdateError::Unknown),
+            },
+            None => room_id,
+        };
+
+        match connection.execute(
+            "UPDATE devices SET room_id = ?2, name = ?3, address = ?4, device_type = ?5,
+                group_names = ?6
+             WHERE id = ?1",
+            params![
+                device_id,
+                target_room_id,
+                String::from(updated.name.clone()),
+                updated.address.to_string(),
+                String::from(updated.device_type.clone()),
+                groups_column(&updated.groups),
+            ],
+        ) {
+            Ok(_) => Ok(updated),
+            Err(e) if is_constraint_violation(&e) => Err(UpdateError::Conflict),
+            Err(_) => Err(UpdateError::Unknown),
+        }
+    }
+
+    fn add_scene(&self, scene: Scene) -> Result<Scene, InsertError> {
+        let mut connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        let transaction = connection.transaction().map_err(|_| InsertError::Unknown)?;
+        let scene_id = match transaction.query_row(
+            "INSERT INTO scenes (name) VALUES (?1) RETURNING id",
+            params![String::from(scene.name.clone())],
+            |row| row.get::<_, i64>(0),
+        ) {
+            Ok(scene_id) => scene_id,
+            Err(e) if is_constraint_violation(&e) => return Err(InsertError::Conflict),
+            Err(_) => return Err(InsertError::Unknown),
+        };
+        insert_scene_steps(&transaction, scene_id, &scene.steps)
+            .map_err(|_| InsertError::Unknown)?;
+        transaction.commit().map_err(|_| InsertError::Unknown)?;
+        Ok(scene)
+    }
+
+    fn fetch_scene(&self, name: SceneName) -> Result<Scene, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        let scene_id = connection
+            .query_row(
+                "SELECT id FROM scenes WHERE name = ?1",
+                params![String::from(name.clone())],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()
+            .map_err(|_| FetchError::Unknown)?
+            .ok_or(FetchError::NotFound)?;
+
+        let steps = select_scene_steps(&connection, scene_id)?;
+        Ok(Scene { name, steps })
+    }
+
+    fn fetch_scenes(&self) -> Result<Vec<Scene>, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        let mut statement = connection
+            .prepare("SELECT id, name FROM scenes ORDER BY id")
+            .map_err(|_| FetchError::Unknown)?;
+        let scenes = statement
+            .query_map([], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })
+            .map_err(|_| FetchError::Unknown)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| FetchError::Unknown)?;
+
+        scenes
+            .into_iter()
+            .map(|(scene_id, name)| {
+                Ok(Scene {
+                    name: SceneName::try_from(name).map_err(|_| FetchError::Unknown)?,
+                    steps: select_scene_steps(&connection, scene_id)?,
+                })
+            })
+            .collect()
+    }
+
+    fn update_scene(&self, scene: Scene) -> Result<Scene, UpdateError> {
+        let mut connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(UpdateError::Unknown),
+        };
+
+        let transaction = connection.transaction().map_err(|_| UpdateError::Unknown)?;
+        let scene_id = transaction
+            .query_row(
+                "SELECT id FROM scenes WHERE name = ?1",
+                params![String::from(scene.name.clone())],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()
+            .map_err(|_| UpdateError::Unknown)?
+            .ok_or(UpdateError::NotFound)?;
+        transaction
+            .execute(
+                "DELETE FROM scene_steps WHERE scene_id = ?1",
+                params![scene_id],
+            )
+            .map_err(|_| UpdateError::Unknown)?;
+        insert_scene_steps(&transaction, scene_id, &scene.steps)
+            .map_err(|_| UpdateError::Unknown)?;
+        transaction.commit().map_err(|_| UpdateError::Unknown)?;
+        Ok(scene)
+    }
+
+    fn delete_scene(&self, name: SceneName) -> Result<(), DeleteError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(DeleteError::Unknown),
+        };
+
+        // steps go away with the scene through the foreign key cascade
+        match connection.execute(
+            "DELETE FROM scenes WHERE name = ?1",
+            params![String::from(name)],
+        ) {
+            Ok(0) => Err(DeleteError::NotFound),
+            Ok(_) => Ok(()),
+            Err(_) => Err(DeleteError::Unknown),
+        }
+    }
+}
+
+// the steps keep the order they were inserted in
+fn insert_scene_steps(
+    connection: &Connection,
+    scene_id: i64,
+    steps: &[SceneStep],
+) -> rusqlite::Result<()> {
+    for step in steps {
+        connection.execute(
+            "INSERT INTO scene_steps (scene_id, device_uuid, command) VALUES (?1, ?2, ?3)",
+            params![
+                scene_id,
+                String::from(step.device_id),
+                String::from(step.command.clone())
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn select_scene_steps(
+    connection: &Connection,
+    scene_id: i64,
+) -> Result<Vec<SceneStep>, FetchError> {
+    let mut statement = connection
+        .prepare("SELECT device_uuid, command FROM scene_steps WHERE scene_id = ?1 ORDER BY id")
+        .map_err(|_| FetchError::Unknown)?;
+    let rows = statement
+        .query_map(params![scene_id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })
+        .map_err(|_| FetchError::Unknown)?;
+
+    rows.map(|row| {
+        let (device_id, command) = row.map_err(|_| FetchError::Unknown)?;
+        Ok(SceneStep {
+            device_id: DeviceId::try_from(device_id).map_err(|_| FetchError::Unknown)?,
+            command: DeviceCommand::try_from(command).map_err(|_| FetchError::Unknown)?,
+        })
+    })
+    .collect()
+}
+
+// timestamps are stored as INTEGER, which is signed in SQLite
+fn to_sql_timestamp(timestamp: u64) -> i64 {
+    i64::try_from(timestamp).unwrap_or(i64::MAX)
+}
+
+impl HistoryStore for SqliteRepository {
+    fn record_event(&self, entry: HistoryEntry) -> Result<(), InsertError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        let event = serde_json::to_string(&entry.event).map_err(|_| InsertError::Unknown)?;
+        match connection.execute(
+            "INSERT INTO history (device_uuid, timestamp, event) VALUES (?1, ?2, ?3)",
+            params![
+                String::from(entry.device_id),
+                to_sql_timestamp(entry.timestamp),
+                event
+            ],
+        ) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(InsertError::Unknown),
+        }
+    }
+
+    fn fetch_history(
+        &self,
+        device_id: DeviceId,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<HistoryEntry>, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        let mut statement = connection
+            .prepare(
+                "SELECT timestamp, event FROM history
+                 WHERE device_uuid = ?1 AND timestamp BETWEEN ?2 AND ?3
+                 ORDER BY timestamp, id",
+            )
+            .map_err(|_| FetchError::Unknown)?;
+        let rows = statement
+            .query_map(
+                params![
+                    String::from(device_id),
+                    to_sql_timestamp(from),
+                    to_sql_timestamp(to)
+                ],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+            )
+            .map_err(|_| FetchError::Unknown)?;
+
+        rows.map(|row| {
+            let (timestamp, event) = row.map_err(|_| FetchError::Unknown)?;
+            Ok(HistoryEntry {
+                device_id,
+                timestamp: u64::try_from(timestamp).map_err(|_| FetchError::Unknown)?,
+                event: serde_json::from_str(&event).map_err(|_| FetchError::Unknown)?,
+            })
+        })
+        .collect()
+    }
+
+    fn prune_history(&self, before: u64) -> Result<usize, DeleteError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(DeleteError::Unknown),
+        };
+
+        connection
+            .execute(
+                "DELETE FROM history WHERE timestamp < ?1",
+                params![to_sql_timestamp(before)],
+            )
+            .map_err(|_| DeleteError::Unknown)
+    }
+}
+
+const RULE_COLUMNS: &str = "uuid, name, enabled, trigger_kind, trigger_device_uuid,
+    trigger_threshold, trigger_at, action_device_uuid, action_command";
+
+// expects the columns in `RULE_COLUMNS` order
+fn rule_from_row(row: &rusqlite::Row) -> rusqlite::Result<Result<Rule, FetchError>> {
+    let trigger_device_id: Option<String> = row.get(4)?;
+    let trigger_at: Option<String> = row.get(6)?;
+    let action_device_id: Option<String> = row.get(7)?;
+    let action_command: Option<String> = row.get(8)?;
+    let parse = || -> Result<Rule, ()> {
+        let trigger = TriggerParts {
+            kind: row.get(3).map_err(|_| ())?,
+            device_id: trigger_device_id.map(DeviceId::try_from).transpose()?,
+            threshold: row.get(5).map_err(|_| ())?,
+            at: trigger_at.map(TimeOfDay::try_from).transpose()?,
+        };
+        Ok(Rule {
+            id: RuleId::try_from(row.get::<_, String>(0).map_err(|_| ())?)?,
+            name: RuleName::try_from(row.get::<_, String>(1).map_err(|_| ())?)?,
+            enabled: row.get(2).map_err(|_| ())?,
+            trigger: RuleTrigger::try_from(trigger)?,
+            action: match (action_device_id, action_command) {
+                (Some(device_id), Some(command)) => Some(RuleAction {
+                    device_id: DeviceId::try_from(device_id)?,
+                    command: DeviceCommand::try_from(command)?,
+                }),
+                (None, None) => None,
+                _ => return Err(()),
+            },
+        })
+    };
+    Ok(parse().map_err(|_| FetchError::Unknown))
+}
+
+type RuleParams = (
+    String,
+    String,
+    bool,
+    String,
+    Option<String>,
+    Option<f32>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+);
+
+// the values in `RULE_COLUMNS` order
+fn rule_params(rule: Rule) -> RuleParams {
+    let trigger = TriggerParts::from(rule.trigger);
+    (
+        rule.id.into(),
+        rule.name.into(),
+        rule.enabled,
+        trigger.kind,
+        trigger.device_id.map(String::from),
+        trigger.threshold,
+        trigger.at.map(String::from),
+        rule.action.as_ref().map(|action| action.device_id.into()),
+        rule.action.map(|action| action.command.into()),
+    )
+}
+
+impl RuleStore for SqliteRepository {
+    fn add_rule(&self, rule: Rule) -> Result<Rule, InsertError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        let (id, name, enabled, kind, device_id, threshold, at, action_device_id, command) =
+            rule_params(rule.clone());
+        match connection.execute(
+            &format!(
+                "INSERT INTO rules ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                RULE_COLUMNS
+            ),
+            params![
+                id,
+                name,
+                enabled,
+                kind,
+                device_id,
+                threshold,
+                at,
+                action_device_id,
+                command
+            ],
+        ) {
+            Ok(_) => Ok(rule),
+            Err(e) if is_constraint_violation(&e) => Err(InsertError::Conflict),
+            Err(_) => Err(InsertError::Unknown),
+        }
+    }
+
+    fn fetch_rule(&self, id: RuleId) -> Result<Rule, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        match connection
+            .query_row(
+                &format!("SELECT {} FROM rules WHERE uuid = ?1", RULE_COLUMNS),
+                params![String::from(id)],
+                rule_from_row,
+            )
+            .optional()
+        {
+            Ok(Some(rule)) => rule,
+            Ok(None) => Err(FetchError::NotFound),
+            Err(_) => Err(FetchError::Unknown),
+        }
+    }
+
+    fn fetch_rules(&self) -> Result<Vec<Rule>, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        let mut statement = connection
+            .prepare(&format!("SELECT {} FROM rules ORDER BY id", RULE_COLUMNS))
+            .map_err(|_| FetchError::Unknown)?;
+        let rows = statement
+            .query_map([], rule_from_row)
+            .map_err(|_| FetchError::Unknown)?;
+
+        rows.map(|row| row.map_err(|_| FetchError::Unknown)?)
+            .collect()
+    }
+
+    fn update_rule(&self, rule: Rule) -> Result<Rule, UpdateError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(UpdateError::Unknown),
+        };
+
+        let (id, name, enabled, kind, device_id, threshold, at, action_device_id, command) =
+            rule_params(rule.clone());
+        match connection.execute(
+            "UPDATE rules SET name = ?2, enabled = ?3, trigger_kind = ?4,
+                trigger_device_uuid = ?5, trigger_threshold = ?6, trigger_at = ?7,
+                action_device_uuid = ?8, action_command = ?9
+             WHERE uuid = ?1",
+            params![
+                id,
+                name,
+                enabled,
+                kind,
+                device_id,
+                threshold,
+                at,
+                action_device_id,
+                command
+            ],
+        ) {
+            Ok(0) => Err(UpdateError::NotFound),
+            Ok(_) => Ok(rule),
+            Err(e) if is_constraint_violation(&e) => Err(UpdateError::Conflict),
+            Err(_) => Err(UpdateError::Unknown),
+        }
+    }
+
+    fn delete_rule(&self, id: RuleId) -> Result<(), DeleteError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(DeleteError::Unknown),
+        };
+
+        match connection.execute(
+            "DELETE FROM rules WHERE uuid = ?1",
+            params![String::from(id)],
+        ) {
+            Ok(0) => Err(DeleteError::NotFound),
+            Ok(_) => Ok(()),
+            Err(_) => Err(DeleteError::Unknown),
+        }
+    }
+}
+
+// expects the columns in `uuid, cron, device_uuid, command` order
+fn schedule_from_row(row: &rusqlite::Row) -> rusqlite::Result<Result<Schedule, FetchError>> {
+    let columns: (String, String, String, String) =
+        (row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?);
+    let (id, cron, device_id, command) = columns;
+    let parse = || -> Result<Schedule, ()> {
+        Ok(Schedule {
+            id: ScheduleId::try_from(id)?,
+            cron: CronExpr::try_from(cron)?,
+            device_id: DeviceId::try_from(device_id)?,
+            command: DeviceCommand::try_from(command)?,
+        })
+    };
+    Ok(parse().map_err(|_| FetchError::Unknown))
+}
+
+impl ScheduleStore for SqliteRepository {
+    fn add_schedule(&self, schedule: Schedule) -> Result<Schedule, InsertError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        match connection.execute(
+            "INSERT INTO schedules (uuid, cron, device_uuid, command) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                String::from(schedule.id),
+                String::from(schedule.cron.clone()),
+                String::from(schedule.device_id),
+                String::from(schedule.command.clone())
+            ],
+        ) {
+            Ok(_) => Ok(schedule),
+            Err(e) if is_constraint_violation(&e) => Err(InsertError::Conflict),
+            Err(_) => Err(InsertError::Unknown),
+        }
+    }
+
+    fn fetch_schedule(&self, id: ScheduleId) -> Result<Schedule, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        match connection
+            .query_row(
+                "SELECT uuid, cron, device_uuid, command FROM schedules WHERE uuid = ?1",
+                params![String::from(id)],
+                schedule_from_row,
+            )
+            .optional()
+        {
+            Ok(Some(schedule)) => schedule,
+            Ok(None) => Err(FetchError::NotFound),
+            Err(_) => Err(FetchError::Unknown),
+        }
+    }
+
+    fn fetch_schedules(&self) -> Result<Vec<Schedule>, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        let mut statement = connection
+            .prepare("SELECT uuid, cron, device_uuid, command FROM schedules ORDER BY id")
+            .map_err(|_| FetchError::Unknown)?;
+        let rows = statement
+            .query_map([], schedule_from_row)
+            .map_err(|_| FetchError::Unknown)?;
+
+        rows.map(|row| row.map_err(|_| FetchError::Unknown)?)
+            .collect()
+    }
+
+    fn delete_schedule(&self, id: ScheduleId) -> Result<(), DeleteError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(DeleteError::Unknown),
+        };
+
+        match connection.execute(
+            "DELETE FROM schedules WHERE uuid = ?1",
+            params![String::from(id)],
+        ) {
+            Ok(0) => Err(DeleteError::NotFound),
+            Ok(_) => Ok(()),
+            Err(_) => Err(DeleteError::Unknown),
+        }
+    }
+
+    fn record_run(&self, run: ScheduleRun) -> Result<(), InsertError> {
+        let mut connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        let status = run
+            .status
+            .map(|status| serde_json::to_string(&status))
+            .transpose()
+            .map_err(|_| InsertError::Unknown)?;
+        let transaction = connection.transaction().map_err(|_| InsertError::Unknown)?;
+        let schedule_id = transaction
+            .query_row(
+                "SELECT id FROM schedules WHERE uuid = ?1",
+                params![String::from(run.schedule_id)],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()
+            .map_err(|_| InsertError::Unknown)?
+            .ok_or(InsertError::Conflict)?;
+        transaction
+            .execute(
+                "INSERT INTO schedule_runs (schedule_id, timestamp, status, error)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    schedule_id,
+                    to_sql_timestamp(run.timestamp),
+                    status,
+                    run.error
+                ],
+            )
+            .map_err(|_| InsertError::Unknown)?;
+        transaction
+            .execute(
+                "DELETE FROM schedule_runs WHERE schedule_id = ?1 AND id NOT IN (
+                    SELECT id FROM schedule_runs WHERE schedule_id = ?1
+                    ORDER BY id DESC LIMIT ?2
+                )",
+                params![schedule_id, MAX_RUNS as i64],
+            )
+            .map_err(|_| InsertError::Unknown)?;
+        transaction.commit().map_err(|_| InsertError::Unknown)
+    }
+
+    fn fetch_runs(&self, id: ScheduleId) -> Result<Vec<ScheduleRun>, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        let schedule_id = connection
+            .query_row(
+                "SELECT id FROM schedules WHERE uuid = ?1",
+                params![String::from(id)],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()
+            .map_err(|_| FetchError::Unknown)?
+            .ok_or(FetchError::NotFound)?;
+        let mut statement = connection
+            .prepare(
+                "SELECT timestamp, status, error FROM schedule_runs
+                 WHERE schedule_id = ?1 ORDER BY id",
+            )
+            .map_err(|_| FetchError::Unknown)?;
+        let rows = statement
+            .query_map(params![schedule_id], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                ))
+            })
+            .map_err(|_| FetchError::Unknown)?;
+
+        rows.map(|row| {
+            let (timestamp, status, error) = row.map_err(|_| FetchError::Unknown)?;
+            Ok(ScheduleRun {
+                schedule_id: id,
+                timestamp: u64::try_from(timestamp).map_err(|_| FetchError::Unknown)?,
+                status: status
+                    .map(|status| serde_json::from_str(&status))
+                    .transpose()
+                    .map_err(|_| FetchError::Unknown)?,
+                error,
+            })
+        })
+        .collect()
+    }
+}
+
+// expects the columns in `uuid, name, scope, token_hash` order
+fn key_from_row(row: &rusqlite::Row) -> rusqlite::Result<Result<ApiKey, FetchError>> {
+    let columns: (String, String, String, String) =
+        (row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?);
+    let (id, name, scope, token_hash) = columns;
+    let parse = || -> Result<ApiKey, ()> {
+        Ok(ApiKey {
+            id: ApiKeyId::try_from(id)?,
+            name,
+            scope: Scope::try_from(scope)?,
+            token_hash,
+        })
+    };
+    Ok(parse().map_err(|_| FetchError::Unknown))
+}
+
+impl ApiKeyStore for SqliteRepository {
+    fn add_key(&self, key: ApiKey) -> Result<ApiKey, InsertError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        match connection.execute(
+            "INSERT INTO api_keys (uuid, name, scope, token_hash) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                String::from(key.id),
+                key.name,
+                String::from(key.scope),
+                key.token_hash
+            ],
+        ) {
+            Ok(_) => Ok(key),
+            Err(e) if is_constraint_violation(&e) => Err(InsertError::Conflict),
+            Err(_) => Err(InsertError::Unknown),
+        }
+    }
+
+    fn fetch_keys(&self) -> Result<Vec<ApiKey>, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        let mut statement = connection
+            .prepare("SELECT uuid, name, scope, token_hash FROM api_keys ORDER BY id")
+            .map_err(|_| FetchError::Unknown)?;
+        let rows = statement
+            .query_map([], key_from_row)
+            .map_err(|_| FetchError::Unknown)?;
+
+        rows.map(|row| row.map_err(|_| FetchError::Unknown)?)
+            .collect()
+    }
+
+    fn fetch_key_by_hash(&self, token_hash: &str) -> Result<ApiKey, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        match connection
+            .query_row(
+                "SELECT uuid, name, scope, token_hash FROM api_keys WHERE token_hash = ?1",
+                params![token_hash],
+                key_from_row,
+            )
+            .optional()
+        {
+            Ok(Some(key)) => key,
+            Ok(None) => Err(FetchError::NotFound),
+            Err(_) => Err(FetchError::Unknown),
+        }
+    }
+
+    fn delete_key(&self, id: ApiKeyId) -> Result<(), DeleteError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(DeleteError::Unknown),
+        };
+
+        match connection.execute(
+            "DELETE FROM api_keys WHERE uuid = ?1",
+            params![String::from(id)],
+        ) {
+            Ok(0) => Err(DeleteError::NotFound),
+            Ok(_) => Ok(()),
+            Err(_) => Err(DeleteError::Unknown),
+        }
+    }
+}
+
+// expects the columns in `id, uuid, name, password_hash, admin` order,
+// the id is the row id the grants refer to
+fn user_from_row(row: &rusqlite::Row) -> rusqlite::Result<(i64, Result<User, FetchError>)> {
+    let columns: (i64, String, String, String, bool) = (
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+    );
+    let (row_id, id, name, password_hash, admin) = columns;
+    let parse = || -> Result<User, ()> {
+        Ok(User {
+            id: UserId::try_from(id)?,
+            name: UserName::try_from(name)?,
+            password_hash,
+            admin,
+            grants: Vec::new(),
+        })
+    };
+    Ok((row_id, parse().map_err(|_| FetchError::Unknown)))
+}
+
+impl SqliteRepository {
+    fn select_users(&self, filter: &str, value: String) -> Result<Vec<User>, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        let mut statement = connection
+            .prepare(&format!(
+                "SELECT id, uuid, name, password_hash, admin FROM users {} ORDER BY id",
+                filter
+            ))
+            .map_err(|_| FetchError::Unknown)?;
+        let params = if filter.is_empty() {
+            params![]
+        } else {
+            params![value]
+        };
+        let users = statement
+            .query_map(params, user_from_row)
+            .map_err(|_| FetchError::Unknown)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| FetchError::Unknown)?;
+
+        users
+            .into_iter()
+            .map(|(row_id, user)| {
+                Ok(User {
+                    grants: select_user_grants(&connection, row_id)?,
+                    ..user?
+                })
+            })
+            .collect()
+    }
+}
+
+impl UserStore for SqliteRepository {
+    fn add_user(&self, user: User) -> Result<User, InsertError> {
+        let mut connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        let transaction = connection.transaction().map_err(|_| InsertError::Unknown)?;
+        let user_id = match transaction.query_row(
+            "INSERT INTO users (uuid, name, password_hash, admin) VALUES (?1, ?2, ?3, ?4)
+            RETURNING id",
+            params![
+                String::from(user.id),
+                String::from(user.name.clone()),
+                user.password_hash,
+                user.admin
+            ],
+            |row| row.get::<_, i64>(0),
+        ) {
+            Ok(user_id) => user_id,
+            Err(e) if is_constraint_violation(&e) => return Err(InsertError::Conflict),
+            Err(_) => return Err(InsertError::Unknown),
+        };
+        insert_user_grants(&transaction, user_id, &user.grants)
+            .map_err(|_| InsertError::Unknown)?;
+        transaction.commit().map_err(|_| InsertError::Unknown)?;
+        Ok(user)
+    }
+
+    fn fetch_users(&self) -> Result<Vec<User>, FetchError> {
+        self.select_users("", String::new())
+    }
+
+    fn fetch_user(&self, id: UserId) -> Result<User, FetchError> {
+        self.select_users("WHERE uuid = ?1", id.into())?
+            .pop()
+            .ok_or(FetchError::NotFound)
+    }
+
+    fn fetch_user_by_name(&self, name: &UserName) -> Result<User, FetchError> {
+        self.select_users("WHERE name = ?1", name.clone().into())?
+            .pop()
+            .ok_or(FetchError::NotFound)
+    }
+
+    fn update_user(&self, user: User) -> Result<User, UpdateError> {
+        let mut connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(UpdateError::Unknown),
+        };
+
+        let transaction = connection.transaction().map_err(|_| UpdateError::Unknown)?;
+        let user_id = match transaction
+            .query_row(
+                "UPDATE users SET name = ?2, password_hash = ?3, admin = ?4 WHERE uuid = ?1
+                RETURNING id",
+                params![
+                    String::from(user.id),
+                    String::from(user.name.clone()),
+                    user.password_hash,
+                    user.admin
+                ],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()
+        {
+            Ok(Some(user_id)) => user_id,
+            Ok(None) => return Err(UpdateError::NotFound),
+            Err(e) if is_constraint_violation(&e) => return Err(UpdateError::Conflict),
+            Err(_) => return Err(UpdateError::Unknown),
+        };
+        transaction
+            .execute(
+                "DELETE FROM user_grants WHERE user_id = ?1",
+                params![user_id],
+            )
+            .map_err(|_| UpdateError::Unknown)?;
+        insert_user_grants(&transaction, user_id, &user.grants)
+            .map_err(|_| UpdateError::Unknown)?;
+        transaction.commit().map_err(|_| UpdateError::Unknown)?;
+        Ok(user)
+    }
+
+    fn delete_user(&self, id: UserId) -> Result<(), DeleteError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(DeleteError::Unknown),
+        };
+
+        // grants go away with the user through the foreign key cascade
+        match connection.execute(
+            "DELETE FROM users WHERE uuid = ?1",
+            params![String::from(id)],
+        ) {
+            Ok(0) => Err(DeleteError::NotFound),
+            Ok(_) => Ok(()),
+            Err(_) => Err(DeleteError::Unknown),
+        }
+    }
+}
+
+fn insert_user_grants(
+    connection: &Connection,
+    user_id: i64,
+    grants: &[Grant],
+) -> rusqlite::Result<()> {
+    for grant in grants {
+        let (room_id, device_id): (Option<String>, Option<String>) = match grant.target {
+            GrantTarget::Room(id) => (Some(id.into()), None),
+            GrantTarget::Device(id) => (None, Some(id.into())),
+        };
+        connection.execute(
+            "INSERT INTO user_grants (user_id, room_uuid, device_uuid, role)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![user_id, room_id, device_id, String::from(grant.role)],
+        )?;
+    }
+    Ok(())
+}
+
+fn select_user_grants(connection: &Connection, user_id: i64) -> Result<Vec<Grant>, FetchError> {
+    let mut statement = connection
+        .prepare(
+            "SELECT room_uuid, device_uuid, role FROM user_grants WHERE user_id = ?1 ORDER BY id",
+        )
+        .map_err(|_| FetchError::Unknown)?;
+    let rows = statement
+        .query_map(params![user_id], |row| {
+            Ok((
+                row.get::<_, Option<String>>(0)?,
+                row.get::<_, Option<String>>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })
+        .map_err(|_| FetchError::Unknown)?;
+
+    rows.map(|row| {
+        let (room_id, device_id, role) = row.map_err(|_| FetchError::Unknown)?;
+        let target = match (room_id, device_id) {
+            (Some(id), None) => {
+                GrantTarget::Room(RoomId::try_from(id).map_err(|_| FetchError::Unknown)?)
+            }
+            (None, Some(id)) => {
+                GrantTarget::Device(DeviceId::try_from(id).map_err(|_| FetchError::Unknown)?)
+            }
+            _ => return Err(FetchError::Unknown),
+        };
+        Ok(Grant {
+            target,
+            role: Role::try_from(role).map_err(|_| FetchError::Unknown)?,
+        })
+    })
+    .collect()
+}
+
+const WEBHOOK_COLUMNS: &str = "uuid, url, secret, events, room_uuid, device_uuid";
+
+// expects the columns in `WEBHOOK_COLUMNS` order
+fn webhook_from_row(row: &rusqlite::Row) -> rusqlite::Result<Result<Webhook, FetchError>> {
+    let columns: (
+        String,
+        String,
+        String,
+        String,
+        Option<String>,
+        Option<String>,
+    ) = (
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+        row.get(5)?,
+    );
+    let (id, url, secret, events, room_id, device_id) = columns;
+    let parse = || -> Result<Webhook, ()> {
+        Ok(Webhook {
+            id: WebhookId::try_from(id)?,
+            url: WebhookUrl::try_from(url)?,
+            secret,
+            events: serde_json::from_str(&events).map_err(|_| ())?,
+            room_id: room_id.map(RoomId::try_from).transpose()?,
+            device_id: device_id.map(DeviceId::try_from).transpose()?,
+        })
+    };
+    Ok(parse().map_err(|_| FetchError::Unknown))
+}
+
+impl WebhookStore for SqliteRepository {
+    fn add_webhook(&self, webhook: Webhook) -> Result<Webhook, InsertError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        let events = serde_json::to_string(&webhook.events).map_err(|_| InsertError::Unknown)?;
+        match connection.execute(
+            &format!(
+                "INSERT INTO webhooks ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                WEBHOOK_COLUMNS
+            ),
+            params![
+                String::from(webhook.id),
+                String::from(webhook.url.clone()),
+                webhook.secret,
+                events,
+                webhook.room_id.map(String::from),
+                webhook.device_id.map(String::from)
+            ],
+        ) {
+            Ok(_) => Ok(webhook),
+            Err(e) if is_constraint_violation(&e) => Err(InsertError::Conflict),
+            Err(_) => Err(InsertError::Unknown),
+        }
+    }
+
+    fn fetch_webhook(&self, id: WebhookId) -> Result<Webhook, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        match connection
+            .query_row(
+                &format!("SELECT {} FROM webhooks WHERE uuid = ?1", WEBHOOK_COLUMNS),
+                params![String::from(id)],
+                webhook_from_row,
+            )
+            .optional()
+        {
+            Ok(Some(webhook)) => webhook,
+            Ok(None) => Err(FetchError::NotFound),
+            Err(_) => Err(FetchError::Unknown),
+        }
+    }
+
+    fn fetch_webhooks(&self) -> Result<Vec<Webhook>, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        let mut statement = connection
+            .prepare(&format!(
+                "SELECT {} FROM webhooks ORDER BY id",
+                WEBHOOK_COLUMNS
+            ))
+            .map_err(|_| FetchError::Unknown)?;
+        let rows = statement
+            .query_map([], webhook_from_row)
+            .map_err(|_| FetchError::Unknown)?;
+
+        rows.map(|row| row.map_err(|_| FetchError::Unknown)?)
+            .collect()
+    }
+
+    fn delete_webhook(&self, id: WebhookId) -> Result<(), DeleteError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(DeleteError::Unknown),
+        };
+
+        match connection.execute(
+            "DELETE FROM webhooks WHERE uuid = ?1",
+            params![String::from(id)],
+        ) {
+            Ok(0) => Err(DeleteError::NotFound),
+            Ok(_) => Ok(()),
+            Err(_) => Err(DeleteError::Unknown),
+        }
+    }
+
+    fn record_delivery(&self, delivery: WebhookDelivery) -> Result<(), InsertError> {
+        let mut connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(InsertError::Unknown),
+        };
+
+        let transaction = connection.transaction().map_err(|_| InsertError::Unknown)?;
+        let webhook_id = transaction
+            .query_row(
+                "SELECT id FROM webhooks WHERE uuid = ?1",
+                params![String::from(delivery.webhook_id)],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()
+            .map_err(|_| InsertError::Unknown)?
+            .ok_or(InsertError::Conflict)?;
+        transaction
+            .execute(
+                "INSERT INTO webhook_deliveries
+                 (webhook_id, timestamp, event, attempts, status, error)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    webhook_id,
+                    to_sql_timestamp(delivery.timestamp),
+                    delivery.event,
+                    delivery.attempts,
+                    delivery.status,
+                    delivery.error
+                ],
+            )
+            .map_err(|_| InsertError::Unknown)?;
+        transaction
+            .execute(
+                "DELETE FROM webhook_deliveries WHERE webhook_id = ?1 AND id NOT IN (
+                    SELECT id FROM webhook_deliveries WHERE webhook_id = ?1
+                    ORDER BY id DESC LIMIT ?2
+                )",
+                params![webhook_id, MAX_DELIVERIES as i64],
+            )
+            .map_err(|_| InsertError::Unknown)?;
+        transaction.commit().map_err(|_| InsertError::Unknown)
+    }
+
+    fn fetch_deliveries(&self, id: WebhookId) -> Result<Vec<WebhookDelivery>, FetchError> {
+        let connection = match self.connection.lock() {
+            Ok(connection) => connection,
+            _ => return Err(FetchError::Unknown),
+        };
+
+        let webhook_id = connection
+            .query_row(
+                "SELECT id FROM webhooks WHERE uuid = ?1",
+                params![String::from(id)],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()
+            .map_err(|_| FetchError::Unknown)?
+            .ok_or(FetchError::NotFound)?;
+        let mut statement = connection
+            .prepare(
+                "SELECT timestamp, event, attempts, status, error FROM webhook_deliveries
+                 WHERE webhook_id = ?1 ORDER BY id",
+            )
+            .map_err(|_| FetchError::Unknown)?;
+        let rows = statement
+            .query_map(params![webhook_id], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, u32>(2)?,
+                    row.get::<_, Option<u16>>(3)?,
+                    row.get::<_, Option<String>>(4)?,
+                ))
+            })
+            .map_err(|_| FetchError::Unknown)?;
+
+        rows.map(|row| {
+            let (timestamp, event, attempts, status, error) =
+                row.map_err(|_| FetchError::Unknown)?;
+            Ok(WebhookDelivery {
+                webhook_id: id,
+                timestamp: u64::try_from(timestamp).map_err(|_| FetchError::Unknown)?,
+                event,
+                attempts,
+                status,
+                error,
+            })
+        })
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::entity::SocketStatus;
+    use crate::domain::events::Event;
+    use crate::domain::service::webhook;
+    use crate::repository::suite::repository_suite;
+    use std::sync::Arc;
+
+    fn open_repo() -> Arc<SqliteRepository> {
+        Arc::new(SqliteRepository::open_in_memory().unwrap())
+    }
+
+    repository_suite!(|| (open_repo(), ()));
+
+    #[test]
+    fn open_migrates_new_database_to_latest_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("house.db");
+        SqliteRepository::open(&path).unwrap();
+
+        // reopening must not try to apply the migrations again
+        let repo = SqliteRepository::open(&path).unwrap();
+        let connection = repo.connection.lock().unwrap();
+        let version: usize = connection
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn open_generates_ids_for_rows_created_before_ids_existed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("house.db");
+        let mut connection = Connection::open(&path).unwrap();
+        let transaction = connection.transaction().unwrap();
+        transaction.execute_batch(MIGRATIONS[0]).unwrap();
+        transaction
+            .execute_batch(
+                "INSERT INTO rooms (id, name) VALUES (1, 'kitchen');
+                 INSERT INTO devices (room_id, name, address, device_type)
+                 VALUES (1, 'socket', '127.0.0.1:8888', 'tcp_socket');",
+            )
+            .unwrap();
+        transaction.pragma_update(None, "user_version", 1).unwrap();
+        transaction.commit().unwrap();
+        drop(connection);
+
+        let repo = SqliteRepository::open(&path).unwrap();
+        let device_id = match repo.fetch_device(RoomName::kitchen(), DeviceName::socket()) {
+            Ok(device_info) => device_info.id,
+            _ => unreachable!(),
+        };
+        match repo.fetch_device_by_id(device_id) {
+            Ok((room_name, _)) => assert!(room_name == RoomName::kitchen()),
+            _ => unreachable!(),
+        }
+        assert!(repo.fetch_room(RoomName::kitchen()).is_ok());
+    }
+
+    #[test]
+    fn open_resolves_webhook_filters_to_ids_and_keeps_the_stale_webhooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("house.db");
+        let mut connection = Connection::open(&path).unwrap();
+        let transaction = connection.transaction().unwrap();
+        for migration in &MIGRATIONS[..11] {
+            transaction.execute_batch(migration).unwrap();
+        }
+        transaction
+            .execute_batch(
+                "INSERT INTO rooms (id, name, uuid)
+                 VALUES (1, 'kitchen', '6d5c1c3e-7c43-4a8e-9a3b-0c9d2f0e8a11');
+                 INSERT INTO devices (room_id, name, address, device_type, uuid)
+                 VALUES (1, 'socket', '127.0.0.1:8888', 'tcp_socket',
+                         '0b7e2f4c-1d2a-4c5b-8e9f-3a4b5c6d7e8f');
+                 INSERT INTO webhooks (uuid, url, secret, events, room_name, device_name)
+                 VALUES ('a1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d', 'http://127.0.0.1:9000/hook',
+                         'secret', '[]', 'kitchen', 'socket'),
+                        ('b1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d', 'http://127.0.0.1:9000/hook',
+                         'secret', '[]', 'bathroom', NULL),
+                        ('c1b2c3d4-e5f6-4a7b-8c9d-0e1f2a3b4c5d', 'http://127.0.0.1:9000/hook',
+                         'secret', '[]', NULL, 'kettle');
+                 INSERT INTO webhook_deliveries (webhook_id, timestamp, event, attempts, status)
+                 VALUES (2, 1000, 'room_added', 1, 200);",
+            )
+            .unwrap();
+        transaction.pragma_update(None, "user_version", 11).unwrap();
+        transaction.commit().unwrap();
+        drop(connection);
+
+        let repo = SqliteRepository::open(&path).unwrap();
+        match repo.fetch_webhooks() {
+            Ok(webhooks) => {
+                assert_eq!(webhooks.len(), 3);
+                assert_eq!(
+                    webhooks[0].room_id.map(String::from).as_deref(),
+                    Some("6d5c1c3e-7c43-4a8e-9a3b-0c9d2f0e8a11")
+                );
+                assert_eq!(
+                    webhooks[0].device_id.map(String::from).as_deref(),
+                    Some("0b7e2f4c-1d2a-4c5b-8e9f-3a4b5c6d7e8f")
+                );
+                // the stale filters match nothing instead of everything
+                let rooms = repo.fetch_rooms().ok().unwrap();
+                let event = Event::DeviceAdded {
+                    room_id: "kitchen".to_string(),
+                    device_id: "socket".to_string(),
+                    id: "0b7e2f4c-1d2a-4c5b-8e9f-3a4b5c6d7e8f".to_string(),
+                };
+                assert!(webhook::accepts(&webhooks[0], &rooms, &event));
+                assert!(webhooks[1].room_id.is_some());
+                assert!(!webhook::accepts(&webhooks[1], &rooms, &event));
+                assert!(webhooks[2].device_id.is_some());
+                assert!(!webhook::accepts(&webhooks[2], &rooms, &event));
+                match repo.fetch_deliveries(webhooks[1].id) {
+                    Ok(deliveries) => assert_eq!(deliveries.len(), 1),
+                    _ => unreachable!(),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn open_refuses_database_from_newer_release() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("house.db");
+        let connection = Connection::open(&path).unwrap();
+        connection
+            .pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        drop(connection);
+
+        match SqliteRepository::open(&path) {
+            Err(OpenError::MigrationError(_)) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn history_is_filtered_by_device_and_range_and_pruned() {
+        let repo = open_repo();
+        let device_id = DeviceId::generate();
+        let entry = |device_id, timestamp| HistoryEntry {
+            device_id,
+            timestamp,
+            event: crate::domain::entity::DeviceEvent::Command {
+                command: "on".to_string(),
+                status: Some(crate::domain::entity::SocketStatus {
+                    enabled: true,
+                    power: 0.0,
+                }),
+                error: None,
+            },
+        };
+        for timestamp in [300, 100, 200] {
+            repo.record_event(entry(device_id, timestamp)).ok();
+        }
+        repo.record_event(entry(DeviceId::generate(), 200)).ok();
+
+        match repo.fetch_history(device_id, 150, u64::MAX) {
+            Ok(entries) => assert_eq!(entries, vec![entry(device_id, 200), entry(device_id, 300)]),
+            _ => unreachable!(),
+        }
+        match repo.prune_history(250) {
+            Ok(pruned) => assert_eq!(pruned, 3),
+            _ => unreachable!(),
+        }
+        match repo.fetch_history(device_id, 0, u64::MAX) {
+            Ok(entries) => assert_eq!(entries, vec![entry(device_id, 300)]),
+            _ => unreachable!(),
+        }
+    }
+
+    fn heater_rule(name: &str, threshold: f32) -> Rule {
+        Rule {
+            id: RuleId::generate(),
+            name: RuleName::try_from(name.to_string()).unwrap(),
+            enabled: true,
+            trigger: RuleTrigger::TemperatureBelow {
+                device_id: DeviceId::generate(),
+                threshold,
+            },
+            action: Some(RuleAction {
+                device_id: DeviceId::generate(),
+                command: DeviceCommand::TurnOn,
+            }),
+        }
+    }
+
+    #[test]
+    fn rules_are_stored_and_names_stay_unique() {
+        let repo = open_repo();
+        let heater = heater_rule("heater", 18.0);
+        let fan = heater_rule("fan", 30.0);
+        repo.add_rule(heater.clone()).ok();
+        repo.add_rule(fan.clone()).ok();
+
+        match repo.add_rule(heater_rule("heater", 20.0)) {
+            Err(InsertError::Conflict) => {}
+            _ => unreachable!(),
+        }
+        match repo.update_rule(Rule {
+            name: heater.name.clone(),
+            ..fan.clone()
+        }) {
+            Err(UpdateError::Conflict) => {}
+            _ => unreachable!(),
+        }
+        match repo.update_rule(heater_rule("unknown", 20.0)) {
+            Err(UpdateError::NotFound) => {}
+            _ => unreachable!(),
+        }
+        repo.delete_rule(fan.id).ok();
+
+        match repo.fetch_rule(heater.id) {
+            Ok(rule) => assert_eq!(rule.trigger, heater.trigger),
+            _ => unreachable!(),
+        }
+        let warning = Rule {
+            action: None,
+            ..heater_rule("frost warning", 5.0)
+        };
+        repo.add_rule(warning.clone()).ok();
+        match repo.fetch_rule(warning.id) {
+            Ok(rule) => assert!(rule.action.is_none()),
+            _ => unreachable!(),
+        }
+        match repo.fetch_rules() {
+            Ok(rules) => assert_eq!(rules.len(), 2),
+            _ => unreachable!(),
+        }
+    }
+
+    fn schedule(cron: &str) -> Schedule {
+        Schedule {
+            id: ScheduleId::generate(),
+            cron: CronExpr::try_from(cron.to_string()).unwrap(),
+            device_id: DeviceId::generate(),
+            command: DeviceCommand::TurnOn,
+        }
+    }
+
+    fn run(schedule_id: ScheduleId, timestamp: u64) -> ScheduleRun {
+        ScheduleRun {
+            schedule_id,
+            timestamp,
+            status: Some(SocketStatus {
+                enabled: true,
+                power: 0.0,
+            }),
+            error: None,
+        }
+    }
+
+    #[test]
+    fn schedule_runs_are_capped_and_deleted_with_their_schedule() {
+        let repo = open_repo();
+        let every_minute = schedule("* * * * *");
+        repo.add_schedule(every_minute.clone()).ok();
+        for timestamp in 0..=MAX_RUNS as u64 {
+            repo.record_run(run(every_minute.id, timestamp)).ok();
+        }
+
+        match repo.fetch_runs(every_minute.id) {
+            Ok(runs) => {
+                assert_eq!(runs.len(), MAX_RUNS);
+                assert_eq!(runs[0], run(every_minute.id, 1));
+            }
+            _ => unreachable!(),
+        }
+        repo.delete_schedule(every_minute.id).ok();
+        match repo.record_run(run(every_minute.id, 1000)) {
+            Err(InsertError::Conflict) => {}
+            _ => unreachable!(),
+        }
+        let connection = repo.connection.lock().unwrap();
+        let runs: i64 = connection
+            .query_row("SELECT COUNT(*) FROM schedule_runs", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(runs, 0);
+    }
+
+    fn webhook(room_id: Option<RoomId>) -> Webhook {
+        Webhook {
+            id: WebhookId::generate(),
+            url: WebhookUrl::try_from("https://example.com/hook".to_string()).unwrap(),
+            secret: "secret".to_string(),
+            events: vec!["status_changed".to_string(), "rule_fired".to_string()],
+            room_id,
+            device_id: None,
+        }
+    }
+
+    fn delivery(webhook_id: WebhookId, timestamp: u64) -> WebhookDelivery {
+        WebhookDelivery {
+            webhook_id,
+            timestamp,
+            event: "status_changed".to_string(),
+            attempts: 3,
+            status: Some(500),
+            error: Some("HTTP 500".to_string()),
+        }
+    }
+
+    #[test]
+    fn webhook_deliveries_are_capped_and_deleted_with_their_webhook() {
+        let repo = open_repo();
+        let kitchen = webhook(Some(RoomId::generate()));
+        repo.add_webhook(kitchen.clone()).ok();
+        repo.add_webhook(webhook(None)).ok();
+        for timestamp in 0..=MAX_DELIVERIES as u64 {
+            repo.record_delivery(delivery(kitchen.id, timestamp)).ok();
+        }
+
+        match repo.fetch_webhooks() {
+            Ok(webhooks) => {
+                assert_eq!(webhooks.len(), 2);
+                assert_eq!(webhooks[0].events, kitchen.events);
+                assert!(webhooks[0].room_id == kitchen.room_id);
+                assert!(webhooks[1].room_id.is_none());
+            }
+            _ => unreachable!(),
+        }
+        match repo.fetch_deliveries(kitchen.id) {
+            Ok(deliveries) => {
+                assert_eq!(deliveries.len(), MAX_DELIVERIES);
+                assert_eq!(deliveries[0], delivery(kitchen.id, 1));
+            }
+            _ => unreachable!(),
+        }
+        repo.delete_webhook(kitchen.id).ok();
+        match repo.record_delivery(delivery(kitchen.id, 1000)) {
+            Err(InsertError::Conflict) => {}
+            _ => unreachable!(),
+        }
+        let connection = repo.connection.lock().unwrap();
+        let deliveries: i64 = connection
+            .query_row("SELECT COUNT(*) FROM webhook_deliveries", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(deliveries, 0);
+    }
+
+    #[test]
+    fn api_keys_are_found_by_their_hash_only_once() {
+        let repo = open_repo();
+        let key = ApiKey {
+            id: ApiKeyId::generate(),
+            name: "dashboard".to_string(),
+            scope: Scope::Control,
+            token_hash: "hash".to_string(),
+        };
+        repo.add_key(key.clone()).ok();
+
+        match repo.add_key(ApiKey {
+            id: ApiKeyId::generate(),
+            ..key.clone()
+        }) {
+            Err(InsertError::Conflict) => {}
+            _ => unreachable!(),
+        }
+        match repo.fetch_key_by_hash("hash") {
+            Ok(stored) => assert_eq!(stored, key),
+            _ => unreachable!(),
+        }
+        repo.delete_key(key.id).ok();
+        match repo.fetch_key_by_hash("hash") {
+            Err(FetchError::NotFound) => {}
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn users_keep_their_grants_and_names_stay_unique() {
+        let repo = open_repo();
+        let mut user = User {
+            id: UserId::generate(),
+            name: UserName::try_from("kid".to_string()).unwrap(),
+            password_hash: "hash".to_string(),
+            admin: false,
+            grants: vec![
+                Grant {
+                    target: GrantTarget::Room(RoomId::generate()),
+                    role: Role::Control,
+                },
+                Grant {
+                    target: GrantTarget::Device(DeviceId::generate()),
+                    role: Role::Read,
+                },
+            ],
+        };
+        repo.add_user(user.clone()).ok();
+
+        match repo.add_user(User {
+            id: UserId::generate(),
+            ..user.clone()
+        }) {
+            Err(InsertError::Conflict) => {}
+            _ => unreachable!(),
+        }
+        user.grants.remove(0);
+        repo.update_user(user.clone()).ok();
+        match repo.fetch_user_by_name(&user.name) {
+            Ok(stored) => assert_eq!(stored, user),
+            _ => unreachable!(),
+        }
+        repo.delete_user(user.id).ok();
+        match repo.fetch_user(user.id) {
+            Err(FetchError::NotFound) => {}
+            _ => unreachable!(),
+        }
+        let grants: i64 = repo
+            .connection
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM user_grants", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(grants, 0);
+    }
+
+    #[test]
+    fn scenes_keep_step_order_and_names_stay_unique() {
+        let repo = open_repo();
+        let name = SceneName::try_from("movie night".to_string()).unwrap();
+        let steps: Vec<SceneStep> = (0..3)
+            .map(|_| SceneStep {
+                device_id: DeviceId::generate(),
+                command: DeviceCommand::TurnOff,
+            })
+            .collect();
+        let scene = Scene {
+            name: name.clone(),
+            steps: steps.clone(),
+        };
+        repo.add_scene(scene.clone()).ok();
+
+        match repo.add_scene(scene.clone()) {
+            Err(InsertError::Conflict) => {}
+            _ => unreachable!(),
+        }
+        repo.update_scene(Scene {
+            name: name.clone(),
+            steps: steps[1..].to_vec(),
+        })
+        .ok();
+        match repo.fetch_scene(name.clone()) {
+            Ok(scene) => {
+                let ids: Vec<_> = scene.steps.iter().map(|s| s.device_id).collect();
+                assert_eq!(ids, vec![steps[1].device_id, steps[2].device_id]);
+            }
+            _ => unreachable!(),
+        }
+
+        repo.delete_scene(name.clone()).ok();
+        match repo.fetch_scene(name) {
+            Err(FetchError::NotFound) => {}
+            _ => unreachable!(),
+        }
+    }
+}
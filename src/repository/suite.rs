@@ -0,0 +1,756 @@
+//! The service-level tests every `Repository` has to pass. Each backend
+//! runs all of them with [`repository_suite!`], handing it a function that
+//! opens an empty repository.
+
+use crate::domain::entity::{
+    self, DeviceId, DeviceName, DeviceType, DeviceUpdate, GroupName, Role, RoomName,
+};
+use crate::domain::service::access::Principal;
+use crate::domain::service::device::{
+    self, AddRequest, FetchByIdRequest, FetchRequest, UpdateRequest,
+};
+use crate::domain::service::room::{self, DeleteRequest, RenameRequest, RoomRequest};
+use crate::repository::room::{DeleteError, FetchError, Repository, UpdateError};
+use std::sync::Arc;
+
+/// Expands to a `#[test]` for every test of the suite. `$open` returns the
+/// repository and whatever has to live as long as it, like its directory.
+macro_rules! repository_suite {
+    ($open:expr) => {
+        $crate::repository::suite::repository_suite!(
+            @tests $open;
+            add_room_returns_bad_request_error_on_invalid_input,
+            add_room_returns_conflict_error_if_room_already_exists,
+            add_room_returns_empty_room_on_success,
+            fetch_room_returns_not_found_error_if_repo_doesnt_contain_room,
+            fetch_room_returns_room_on_success,
+            fetch_rooms_returns_zero_rooms,
+            fetch_rooms_returns_two_rooms_in_insertion_order,
+            fetch_rooms_shows_users_only_their_devices,
+            delete_room_errors_if_room_doesnt_exist,
+            delete_room_deletes_room,
+            delete_room_returns_not_empty_error_if_room_has_devices,
+            delete_room_with_cascade_returns_the_deleted_devices,
+            rename_room_returns_bad_request_error_on_invalid_input,
+            rename_room_errors_if_room_doesnt_exist,
+            rename_room_returns_conflict_error_if_new_name_is_taken,
+            rename_room_keeps_id_and_devices,
+            add_device_returns_bad_request_on_invalid_input,
+            add_device_returns_conflict_if_target_room_not_found,
+            add_device_returns_conflict_if_device_already_exists_in_the_same_room,
+            add_device_returns_conflict_if_new_device_is_on_the_same_address_across_entire_home,
+            add_device_returns_device_info_on_success,
+            fetch_device_returns_not_found_if_repo_doesnt_contain_device,
+            fetch_device_returns_device_info_on_success,
+            delete_device_errors_if_device_doesnt_exist,
+            delete_device_errors_if_room_doesnt_exist,
+            delete_device_success,
+            update_device_returns_bad_request_on_invalid_or_empty_input,
+            update_device_returns_not_found_if_repo_doesnt_contain_device,
+            update_device_returns_conflict_if_new_name_is_taken_in_the_room,
+            update_device_returns_conflict_if_new_address_is_taken_across_entire_home,
+            update_device_returns_conflict_if_target_room_not_found,
+            update_device_keeps_own_address_when_renamed,
+            update_device_moves_device_to_another_room,
+            update_device_moves_device_and_keeps_conflict_rules,
+            fetch_device_by_id_returns_bad_request_on_invalid_id,
+            fetch_device_by_id_returns_not_found_if_repo_doesnt_contain_device,
+            fetch_device_by_id_follows_renamed_and_moved_device,
+            device_groups_are_stored_sorted,
+        );
+    };
+    (@tests $open:expr; $($name:ident),* $(,)?) => {
+        $(
+            #[test]
+            fn $name() {
+                let (repo, _guard) = $open();
+                $crate::repository::suite::$name(repo);
+            }
+        )*
+    };
+}
+pub(crate) use repository_suite;
+
+fn room_request(name: RoomName) -> RoomRequest {
+    RoomRequest { name: name.into() }
+}
+
+fn delete_request(name: RoomName, cascade: bool) -> DeleteRequest {
+    DeleteRequest {
+        name: name.into(),
+        cascade,
+    }
+}
+
+fn socket_request(room_name: RoomName, device_name: &str, address: &str) -> AddRequest {
+    AddRequest::new(
+        &String::from(room_name),
+        device_name,
+        address,
+        &String::from(DeviceType::TcpSocket),
+    )
+}
+
+fn add_socket<R: Repository>(repo: Arc<R>, room_name: RoomName, name: &str, address: &str) {
+    device::add_device(repo, socket_request(room_name, name, address)).ok();
+}
+
+fn kitchen_with_socket<R: Repository>(repo: Arc<R>) -> Arc<R> {
+    repo.add_room(RoomName::kitchen()).ok();
+    add_socket(
+        repo.clone(),
+        RoomName::kitchen(),
+        "socket",
+        "127.0.0.1:8888",
+    );
+    repo
+}
+
+fn fetch_request(room_name: RoomName, device_name: DeviceName) -> FetchRequest {
+    FetchRequest {
+        room_name: room_name.into(),
+        device_name: device_name.into(),
+    }
+}
+
+fn update_request(room_name: RoomName, device_name: DeviceName) -> UpdateRequest {
+    UpdateRequest {
+        room_name: room_name.into(),
+        device_name: device_name.into(),
+        new_room_name: None,
+        new_device_name: None,
+        address: None,
+        device_type: None,
+    }
+}
+
+pub fn add_room_returns_bad_request_error_on_invalid_input<R: Repository>(repo: Arc<R>) {
+    // invalid input is empty room name
+    match room::add_room(repo, room_request(RoomName::empty())) {
+        Err(room::Error::BadRequest) => {}
+        _ => unreachable!(),
+    };
+}
+
+pub fn add_room_returns_conflict_error_if_room_already_exists<R: Repository>(repo: Arc<R>) {
+    repo.add_room(RoomName::kitchen()).ok();
+
+    match room::add_room(repo, room_request(RoomName::kitchen())) {
+        Err(room::Error::Conflict) => {}
+        _ => unreachable!(),
+    };
+}
+
+pub fn add_room_returns_empty_room_on_success<R: Repository>(repo: Arc<R>) {
+    match room::add_room(repo, room_request(RoomName::kitchen())) {
+        Ok(result) => {
+            assert_eq!(result.name, String::from(RoomName::kitchen()));
+            assert_eq!(result.devices, Vec::new());
+        }
+        _ => unreachable!(),
+    };
+}
+
+pub fn fetch_room_returns_not_found_error_if_repo_doesnt_contain_room<R: Repository>(repo: Arc<R>) {
+    match room::fetch_room(repo, room_request(RoomName::kitchen()), &Principal::SYSTEM) {
+        Err(room::Error::NotFound) => {}
+        _ => unreachable!(),
+    };
+}
+
+pub fn fetch_room_returns_room_on_success<R: Repository>(repo: Arc<R>) {
+    repo.add_room(RoomName::kitchen()).ok();
+    repo.add_room(RoomName::bathroom()).ok();
+
+    match room::fetch_room(repo, room_request(RoomName::kitchen()), &Principal::SYSTEM) {
+        Ok(result) => {
+            assert_eq!(result.name, String::from(RoomName::kitchen()));
+            assert_eq!(result.devices, Vec::new());
+        }
+        _ => unreachable!(),
+    };
+}
+
+pub fn fetch_rooms_returns_zero_rooms<R: Repository>(repo: Arc<R>) {
+    match room::fetch_rooms(repo, &Principal::SYSTEM) {
+        Ok(result) => assert_eq!(result, vec![]),
+        _ => unreachable!(),
+    };
+}
+
+pub fn fetch_rooms_returns_two_rooms_in_insertion_order<R: Repository>(repo: Arc<R>) {
+    repo.add_room(RoomName::kitchen()).ok();
+    repo.add_room(RoomName::bathroom()).ok();
+
+    match room::fetch_rooms(repo, &Principal::SYSTEM) {
+        Ok(result) => {
+            let names: Vec<_> = result.iter().map(|r| r.name.as_str()).collect();
+            assert_eq!(names, vec!["kitchen", "bathroom"]);
+            assert!(result.iter().all(|r| r.devices.is_empty()));
+            assert_ne!(result[0].id, result[1].id);
+        }
+        _ => unreachable!(),
+    };
+}
+
+pub fn fetch_rooms_shows_users_only_their_devices<R: Repository>(repo: Arc<R>) {
+    repo.add_room(RoomName::kitchen()).ok();
+    repo.add_room(RoomName::bathroom()).ok();
+    let socket = entity::DeviceInfo {
+        id: DeviceId::generate(),
+        name: DeviceName::socket(),
+        address: "127.0.0.1:8888".parse().unwrap(),
+        device_type: DeviceType::TcpSocket,
+        groups: Vec::new(),
+    };
+    repo.add_device(RoomName::kitchen(), socket.clone()).ok();
+    repo.add_device(
+        RoomName::kitchen(),
+        entity::DeviceInfo {
+            id: DeviceId::generate(),
+            name: DeviceName::thermo(),
+            address: "127.0.0.1:9999".parse().unwrap(),
+            ..socket.clone()
+        },
+    )
+    .ok();
+    let kid = Principal::User(entity::User {
+        id: entity::UserId::generate(),
+        name: entity::UserName::try_from("kid".to_string()).unwrap(),
+        password_hash: String::new(),
+        admin: false,
+        grants: vec![entity::Grant {
+            target: entity::GrantTarget::Device(socket.id),
+            role: Role::Read,
+        }],
+    });
+
+    match room::fetch_rooms(repo.clone(), &kid) {
+        Ok(result) => {
+            assert_eq!(result.len(), 1);
+            assert_eq!(result[0].name, String::from(RoomName::kitchen()));
+            assert_eq!(result[0].devices.len(), 1);
+            assert_eq!(result[0].devices[0].id, String::from(socket.id));
+        }
+        _ => unreachable!(),
+    };
+    match room::fetch_room(repo, room_request(RoomName::bathroom()), &kid) {
+        Err(room::Error::Forbidden) => {}
+        _ => unreachable!(),
+    };
+}
+
+pub fn delete_room_errors_if_room_doesnt_exist<R: Repository>(repo: Arc<R>) {
+    match room::delete_room(repo, delete_request(RoomName::kitchen(), false)) {
+        Err(room::Error::NotFound) => {}
+        _ => unreachable!(),
+    };
+}
+
+pub fn delete_room_deletes_room<R: Repository>(repo: Arc<R>) {
+    repo.add_room(RoomName::kitchen()).ok();
+    room::delete_room(repo.clone(), delete_request(RoomName::kitchen(), false)).ok();
+
+    match room::fetch_rooms(repo, &Principal::SYSTEM) {
+        Ok(result) => assert_eq!(result, vec![]),
+        _ => unreachable!(),
+    };
+}
+
+pub fn delete_room_returns_not_empty_error_if_room_has_devices<R: Repository>(repo: Arc<R>) {
+    let repo = kitchen_with_socket(repo);
+    match room::delete_room(repo.clone(), delete_request(RoomName::kitchen(), false)) {
+        Err(room::Error::NotEmpty) => {}
+        _ => unreachable!(),
+    };
+
+    match room::fetch_rooms(repo, &Principal::SYSTEM) {
+        Ok(result) => assert_eq!(result.len(), 1),
+        _ => unreachable!(),
+    };
+}
+
+pub fn delete_room_with_cascade_returns_the_deleted_devices<R: Repository>(repo: Arc<R>) {
+    let repo = kitchen_with_socket(repo);
+    let device_id = match repo.fetch_device(RoomName::kitchen(), DeviceName::socket()) {
+        Ok(device_info) => device_info.id,
+        _ => unreachable!(),
+    };
+    match repo.delete_room(RoomName::kitchen(), false) {
+        Err(DeleteError::NotEmpty) => {}
+        _ => unreachable!(),
+    }
+
+    match repo.delete_room(RoomName::kitchen(), true) {
+        Ok(room_info) => {
+            assert!(room_info.name == RoomName::kitchen());
+            assert_eq!(room_info.devices.len(), 1);
+            assert_eq!(room_info.devices[0].id, device_id);
+        }
+        _ => unreachable!(),
+    }
+    match repo.fetch_device_by_id(device_id) {
+        Err(FetchError::NotFound) => {}
+        _ => unreachable!(),
+    }
+    match room::fetch_rooms(repo, &Principal::SYSTEM) {
+        Ok(result) => assert_eq!(result, vec![]),
+        _ => unreachable!(),
+    };
+}
+
+pub fn rename_room_returns_bad_request_error_on_invalid_input<R: Repository>(repo: Arc<R>) {
+    repo.add_room(RoomName::kitchen()).ok();
+    let request = RenameRequest {
+        name: RoomName::kitchen().into(),
+        new_name: RoomName::empty().into(),
+    };
+    match room::rename_room(repo, request) {
+        Err(room::Error::BadRequest) => {}
+        _ => unreachable!(),
+    };
+}
+
+pub fn rename_room_errors_if_room_doesnt_exist<R: Repository>(repo: Arc<R>) {
+    let request = RenameRequest {
+        name: RoomName::kitchen().into(),
+        new_name: RoomName::bathroom().into(),
+    };
+    match room::rename_room(repo, request) {
+        Err(room::Error::NotFound) => {}
+        _ => unreachable!(),
+    };
+}
+
+pub fn rename_room_returns_conflict_error_if_new_name_is_taken<R: Repository>(repo: Arc<R>) {
+    repo.add_room(RoomName::kitchen()).ok();
+    repo.add_room(RoomName::bathroom()).ok();
+    let request = RenameRequest {
+        name: RoomName::kitchen().into(),
+        new_name: RoomName::bathroom().into(),
+    };
+    match room::rename_room(repo, request) {
+        Err(room::Error::Conflict) => {}
+        _ => unreachable!(),
+    };
+}
+
+pub fn rename_room_keeps_id_and_devices<R: Repository>(repo: Arc<R>) {
+    let room_id = match repo.add_room(RoomName::kitchen()) {
+        Ok(room_info) => String::from(room_info.id),
+        _ => unreachable!(),
+    };
+    add_socket(
+        repo.clone(),
+        RoomName::kitchen(),
+        "socket",
+        "127.0.0.1:8888",
+    );
+
+    let request = RenameRequest {
+        name: RoomName::kitchen().into(),
+        new_name: RoomName::bathroom().into(),
+    };
+    match room::rename_room(repo.clone(), request) {
+        Ok(result) => {
+            assert_eq!(result.id, room_id);
+            assert_eq!(result.name, String::from(RoomName::bathroom()));
+            assert_eq!(result.devices.len(), 1);
+        }
+        _ => unreachable!(),
+    };
+    assert_eq!(
+        repo.fetch_devices(RoomName::bathroom())
+            .map(|d| d.len())
+            .ok(),
+        Some(1)
+    );
+
+    match room::fetch_room(repo, room_request(RoomName::kitchen()), &Principal::SYSTEM) {
+        Err(room::Error::NotFound) => {}
+        _ => unreachable!(),
+    };
+}
+
+pub fn add_device_returns_bad_request_on_invalid_input<R: Repository>(repo: Arc<R>) {
+    repo.add_room(RoomName::kitchen()).ok();
+
+    for request in [
+        // empty device name
+        socket_request(RoomName::kitchen(), "", "127.0.0.1:8888"),
+        // incorrect ip adress
+        socket_request(RoomName::kitchen(), "socket", "127.0.0:8888"),
+        // incorrect device type
+        AddRequest::new("kitchen", "socket", "127.0.0.1:8888", "dumb_socket"),
+    ] {
+        match device::add_device(repo.clone(), request) {
+            Err(device::Error::BadRequest) => {}
+            _ => unreachable!(),
+        }
+    }
+}
+
+pub fn add_device_returns_conflict_if_target_room_not_found<R: Repository>(repo: Arc<R>) {
+    repo.add_room(RoomName::kitchen()).ok();
+
+    let request = socket_request(RoomName::bathroom(), "socket", "127.0.0.1:8888");
+    match device::add_device(repo, request) {
+        Err(device::Error::Conflict) => {}
+        _ => unreachable!(),
+    }
+}
+
+pub fn add_device_returns_conflict_if_device_already_exists_in_the_same_room<R: Repository>(
+    repo: Arc<R>,
+) {
+    let repo = kitchen_with_socket(repo);
+
+    let request = socket_request(RoomName::kitchen(), "socket", "127.0.0.1:9999");
+    match device::add_device(repo, request) {
+        Err(device::Error::Conflict) => {}
+        _ => unreachable!(),
+    }
+}
+
+pub fn add_device_returns_conflict_if_new_device_is_on_the_same_address_across_entire_home<
+    R: Repository,
+>(
+    repo: Arc<R>,
+) {
+    repo.add_room(RoomName::kitchen()).ok();
+    repo.add_room(RoomName::bathroom()).ok();
+    add_socket(
+        repo.clone(),
+        RoomName::bathroom(),
+        "socket",
+        "127.0.0.1:8888",
+    );
+
+    let request = socket_request(RoomName::kitchen(), "thermo", "127.0.0.1:8888");
+    match device::add_device(repo, request) {
+        Err(device::Error::Conflict) => {}
+        _ => unreachable!(),
+    }
+}
+
+pub fn add_device_returns_device_info_on_success<R: Repository>(repo: Arc<R>) {
+    repo.add_room(RoomName::kitchen()).ok();
+
+    let request = socket_request(RoomName::kitchen(), "socket", "127.0.0.1:8888");
+    match device::add_device(repo, request) {
+        Ok(result) => {
+            assert_eq!(result.device_name, String::from(DeviceName::socket()));
+            assert_eq!(result.address, String::from("127.0.0.1:8888"));
+            assert_eq!(result.device_type, String::from(DeviceType::TcpSocket));
+        }
+        _ => unreachable!(),
+    }
+}
+
+pub fn fetch_device_returns_not_found_if_repo_doesnt_contain_device<R: Repository>(repo: Arc<R>) {
+    repo.add_room(RoomName::kitchen()).ok();
+
+    let request = fetch_request(RoomName::kitchen(), DeviceName::socket());
+    match device::fetch_device(repo, request, &Principal::SYSTEM) {
+        Err(device::Error::NotFound) => {}
+        _ => unreachable!(),
+    }
+}
+
+pub fn fetch_device_returns_device_info_on_success<R: Repository>(repo: Arc<R>) {
+    let repo = kitchen_with_socket(repo);
+
+    let request = fetch_request(RoomName::kitchen(), DeviceName::socket());
+    match device::fetch_device(repo, request, &Principal::SYSTEM) {
+        Ok(result) => {
+            assert_eq!(result.device_name, String::from(DeviceName::socket()));
+            assert_eq!(result.address, String::from("127.0.0.1:8888"));
+            assert_eq!(result.device_type, String::from(DeviceType::TcpSocket));
+        }
+        _ => unreachable!(),
+    }
+}
+
+pub fn delete_device_errors_if_device_doesnt_exist<R: Repository>(repo: Arc<R>) {
+    repo.add_room(RoomName::kitchen()).ok();
+
+    let request = fetch_request(RoomName::kitchen(), DeviceName::socket());
+    match device::delete_device(repo, request) {
+        Err(device::Error::NotFound) => {}
+        _ => unreachable!(),
+    }
+}
+
+pub fn delete_device_errors_if_room_doesnt_exist<R: Repository>(repo: Arc<R>) {
+    let request = fetch_request(RoomName::kitchen(), DeviceName::socket());
+    match device::delete_device(repo, request) {
+        Err(device::Error::NotFound) => {}
+        _ => unreachable!(),
+    }
+}
+
+pub fn delete_device_success<R: Repository>(repo: Arc<R>) {
+    let repo = kitchen_with_socket(repo);
+
+    let request = fetch_request(RoomName::kitchen(), DeviceName::socket());
+    match device::delete_device(repo.clone(), request) {
+        Ok(_) => {}
+        _ => unreachable!(),
+    }
+    assert_eq!(
+        repo.fetch_devices(RoomName::kitchen())
+            .map(|d| d.len())
+            .ok(),
+        Some(0)
+    );
+}
+
+pub fn update_device_returns_bad_request_on_invalid_or_empty_input<R: Repository>(repo: Arc<R>) {
+    let repo = kitchen_with_socket(repo);
+
+    // nothing to update
+    let request = update_request(RoomName::kitchen(), DeviceName::socket());
+    match device::update_device(repo.clone(), request) {
+        Err(device::Error::BadRequest) => {}
+        _ => unreachable!(),
+    }
+
+    // incorrect ip adress
+    let request = UpdateRequest {
+        address: Some("127.0.0:8888".to_string()),
+        ..update_request(RoomName::kitchen(), DeviceName::socket())
+    };
+    match device::update_device(repo, request) {
+        Err(device::Error::BadRequest) => {}
+        _ => unreachable!(),
+    }
+}
+
+pub fn update_device_returns_not_found_if_repo_doesnt_contain_device<R: Repository>(repo: Arc<R>) {
+    repo.add_room(RoomName::kitchen()).ok();
+
+    let request = UpdateRequest {
+        new_device_name: Some("socket_2".to_string()),
+        ..update_request(RoomName::kitchen(), DeviceName::socket())
+    };
+    match device::update_device(repo, request) {
+        Err(device::Error::NotFound) => {}
+        _ => unreachable!(),
+    }
+}
+
+pub fn update_device_returns_conflict_if_new_name_is_taken_in_the_room<R: Repository>(
+    repo: Arc<R>,
+) {
+    let repo = kitchen_with_socket(repo);
+    add_socket(
+        repo.clone(),
+        RoomName::kitchen(),
+        "socket_2",
+        "127.0.0.1:9999",
+    );
+
+    let request = UpdateRequest {
+        new_device_name: Some("socket_2".to_string()),
+        ..update_request(RoomName::kitchen(), DeviceName::socket())
+    };
+    match device::update_device(repo, request) {
+        Err(device::Error::Conflict) => {}
+        _ => unreachable!(),
+    }
+}
+
+pub fn update_device_returns_conflict_if_new_address_is_taken_across_entire_home<R: Repository>(
+    repo: Arc<R>,
+) {
+    let repo = kitchen_with_socket(repo);
+    repo.add_room(RoomName::bathroom()).ok();
+    add_socket(
+        repo.clone(),
+        RoomName::bathroom(),
+        "socket",
+        "127.0.0.1:9999",
+    );
+
+    let request = UpdateRequest {
+        address: Some("127.0.0.1:9999".to_string()),
+        ..update_request(RoomName::kitchen(), DeviceName::socket())
+    };
+    match device::update_device(repo, request) {
+        Err(device::Error::Conflict) => {}
+        _ => unreachable!(),
+    }
+}
+
+pub fn update_device_returns_conflict_if_target_room_not_found<R: Repository>(repo: Arc<R>) {
+    let repo = kitchen_with_socket(repo);
+
+    let request = UpdateRequest {
+        new_room_name: Some(RoomName::bathroom().into()),
+        ..update_request(RoomName::kitchen(), DeviceName::socket())
+    };
+    match device::update_device(repo, request) {
+        Err(device::Error::Conflict) => {}
+        _ => unreachable!(),
+    }
+}
+
+pub fn update_device_keeps_own_address_when_renamed<R: Repository>(repo: Arc<R>) {
+    let repo = kitchen_with_socket(repo);
+
+    let request = UpdateRequest {
+        new_device_name: Some("socket_2".to_string()),
+        address: Some("127.0.0.1:8888".to_string()),
+        ..update_request(RoomName::kitchen(), DeviceName::socket())
+    };
+    match device::update_device(repo, request) {
+        Ok(result) => {
+            assert_eq!(result.device_name, "socket_2");
+            assert_eq!(result.address, "127.0.0.1:8888");
+        }
+        _ => unreachable!(),
+    }
+}
+
+pub fn update_device_moves_device_to_another_room<R: Repository>(repo: Arc<R>) {
+    let repo = kitchen_with_socket(repo);
+    repo.add_room(RoomName::bathroom()).ok();
+
+    let request = UpdateRequest {
+        new_room_name: Some(RoomName::bathroom().into()),
+        ..update_request(RoomName::kitchen(), DeviceName::socket())
+    };
+    match device::update_device(repo.clone(), request) {
+        Ok(result) => assert_eq!(result.room_name, String::from(RoomName::bathroom())),
+        _ => unreachable!(),
+    }
+
+    assert!(repo
+        .fetch_device(RoomName::kitchen(), DeviceName::socket())
+        .is_err());
+    assert!(repo
+        .fetch_device(RoomName::bathroom(), DeviceName::socket())
+        .is_ok());
+}
+
+pub fn update_device_moves_device_and_keeps_conflict_rules<R: Repository>(repo: Arc<R>) {
+    let repo = kitchen_with_socket(repo);
+    repo.add_room(RoomName::bathroom()).ok();
+    add_socket(
+        repo.clone(),
+        RoomName::bathroom(),
+        "socket",
+        "127.0.0.1:9999",
+    );
+
+    // bathroom already has a device with the same name
+    match repo.move_device(
+        RoomName::kitchen(),
+        DeviceName::socket(),
+        RoomName::bathroom(),
+    ) {
+        Err(UpdateError::Conflict) => {}
+        _ => unreachable!(),
+    }
+
+    let update = DeviceUpdate {
+        room_name: Some(RoomName::bathroom()),
+        name: Some(DeviceName::thermo()),
+        ..DeviceUpdate::default()
+    };
+    match repo.update_device(RoomName::kitchen(), DeviceName::socket(), update) {
+        Ok(result) => assert_eq!(result.address.to_string(), "127.0.0.1:8888"),
+        _ => unreachable!(),
+    }
+    assert_eq!(
+        repo.fetch_devices(RoomName::kitchen())
+            .map(|d| d.len())
+            .ok(),
+        Some(0)
+    );
+    assert_eq!(
+        repo.fetch_devices(RoomName::bathroom())
+            .map(|d| d.len())
+            .ok(),
+        Some(2)
+    );
+}
+
+pub fn fetch_device_by_id_returns_bad_request_on_invalid_id<R: Repository>(repo: Arc<R>) {
+    let request = FetchByIdRequest {
+        id: "socket".to_string(),
+    };
+
+    match device::fetch_device_by_id(repo, request, &Principal::SYSTEM) {
+        Err(device::Error::BadRequest) => {}
+        _ => unreachable!(),
+    }
+}
+
+pub fn fetch_device_by_id_returns_not_found_if_repo_doesnt_contain_device<R: Repository>(
+    repo: Arc<R>,
+) {
+    let request = FetchByIdRequest {
+        id: DeviceId::generate().into(),
+    };
+
+    match device::fetch_device_by_id(repo, request, &Principal::SYSTEM) {
+        Err(device::Error::NotFound) => {}
+        _ => unreachable!(),
+    }
+}
+
+pub fn fetch_device_by_id_follows_renamed_and_moved_device<R: Repository>(repo: Arc<R>) {
+    repo.add_room(RoomName::kitchen()).ok();
+    repo.add_room(RoomName::bathroom()).ok();
+    let request = socket_request(RoomName::kitchen(), "socket", "127.0.0.1:8888");
+    let id = match device::add_device(repo.clone(), request) {
+        Ok(result) => result.id,
+        _ => unreachable!(),
+    };
+
+    let request = UpdateRequest {
+        new_room_name: Some(RoomName::bathroom().into()),
+        new_device_name: Some("socket_2".to_string()),
+        ..update_request(RoomName::kitchen(), DeviceName::socket())
+    };
+    match device::update_device(repo.clone(), request) {
+        Ok(result) => assert_eq!(result.id, id),
+        _ => unreachable!(),
+    }
+
+    match device::fetch_device_by_id(
+        repo,
+        FetchByIdRequest { id: id.clone() },
+        &Principal::SYSTEM,
+    ) {
+        Ok(result) => {
+            assert_eq!(result.id, id);
+            assert_eq!(result.room_name, String::from(RoomName::bathroom()));
+            assert_eq!(result.device_name, "socket_2");
+        }
+        _ => unreachable!(),
+    }
+}
+
+pub fn device_groups_are_stored_sorted<R: Repository>(repo: Arc<R>) {
+    let repo = kitchen_with_socket(repo);
+    let groups = ["outdoor", "heaters", "outdoor"]
+        .iter()
+        .map(|g| GroupName::try_from(g.to_string()).unwrap())
+        .collect();
+    let update = DeviceUpdate {
+        groups: Some(groups),
+        ..DeviceUpdate::default()
+    };
+    repo.update_device(RoomName::kitchen(), DeviceName::socket(), update)
+        .ok();
+
+    match repo.fetch_device(RoomName::kitchen(), DeviceName::socket()) {
+        Ok(info) => {
+            let groups: Vec<String> = info.groups.into_iter().map(String::from).collect();
+            assert_eq!(groups, vec!["heaters", "outdoor"]);
+        }
+        _ => unreachable!(),
+    }
+}
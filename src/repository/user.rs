@@ -0,0 +1,71 @@
+use crate::domain::entity::{User, UserId, UserName};
+use crate::repository::room::{DeleteError, FetchError, InsertError, UpdateError};
+
+/// Keeps the user accounts together with their grants.
+pub trait UserStore: Send + Sync + 'static {
+    /// `Conflict` if a user with the same name exists.
+    fn add_user(&self, user: User) -> Result<User, InsertError>;
+
+    /// Every user, in the order they were added.
+    fn fetch_users(&self) -> Result<Vec<User>, FetchError>;
+
+    fn fetch_user(&self, id: UserId) -> Result<User, FetchError>;
+
+    fn fetch_user_by_name(&self, name: &UserName) -> Result<User, FetchError>;
+
+    /// Replaces the user with the same id.
+    fn update_user(&self, user: User) -> Result<User, UpdateError>;
+
+    fn delete_user(&self, id: UserId) -> Result<(), DeleteError>;
+}
+
+// user rules shared by the stores that keep the users in memory
+
+pub(crate) fn insert_user(users: &mut Vec<User>, user: User) -> Result<User, InsertError> {
+    if users.iter().any(|u| u.id == user.id || u.name == user.name) {
+        return Err(InsertError::Conflict);
+    }
+
+    users.push(user.clone());
+    Ok(user)
+}
+
+pub(crate) fn find_user(users: &[User], id: UserId) -> Result<User, FetchError> {
+    users
+        .iter()
+        .find(|u| u.id == id)
+        .cloned()
+        .ok_or(FetchError::NotFound)
+}
+
+pub(crate) fn find_user_by_name(users: &[User], name: &UserName) -> Result<User, FetchError> {
+    users
+        .iter()
+        .find(|u| &u.name == name)
+        .cloned()
+        .ok_or(FetchError::NotFound)
+}
+
+pub(crate) fn replace_user(users: &mut [User], user: User) -> Result<User, UpdateError> {
+    if users.iter().any(|u| u.id != user.id && u.name == user.name) {
+        return Err(UpdateError::Conflict);
+    }
+
+    match users.iter_mut().find(|u| u.id == user.id) {
+        Some(existing) => {
+            *existing = user.clone();
+            Ok(user)
+        }
+        None => Err(UpdateError::NotFound),
+    }
+}
+
+pub(crate) fn remove_user(users: &mut Vec<User>, id: UserId) -> Result<(), DeleteError> {
+    match users.iter().position(|u| u.id == id) {
+        Some(idx) => {
+            users.remove(idx);
+            Ok(())
+        }
+        None => Err(DeleteError::NotFound),
+    }
+}
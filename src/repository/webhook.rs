@@ -0,0 +1,100 @@
+use crate::domain::entity::{Webhook, WebhookDelivery, WebhookId};
+use crate::repository::room::{DeleteError, FetchError, InsertError};
+
+/// Only this many deliveries are kept per webhook, older ones are dropped.
+pub const MAX_DELIVERIES: usize = 100;
+
+/// Keeps the webhooks and the log of their deliveries, deleting a webhook
+/// deletes its deliveries too.
+pub trait WebhookStore: Send + Sync + 'static {
+    fn add_webhook(&self, webhook: Webhook) -> Result<Webhook, InsertError>;
+
+    fn fetch_webhook(&self, id: WebhookId) -> Result<Webhook, FetchError>;
+
+    /// Every webhook, in the order they were added.
+    fn fetch_webhooks(&self) -> Result<Vec<Webhook>, FetchError>;
+
+    fn delete_webhook(&self, id: WebhookId) -> Result<(), DeleteError>;
+
+    /// Appends the delivery to the log of its webhook, `Conflict` if the webhook is gone.
+    fn record_delivery(&self, delivery: WebhookDelivery) -> Result<(), InsertError>;
+
+    /// The last `MAX_DELIVERIES` deliveries of the webhook, oldest first.
+    fn fetch_deliveries(&self, id: WebhookId) -> Result<Vec<WebhookDelivery>, FetchError>;
+}
+
+/// Webhooks and deliveries of the stores that keep them in memory,
+/// behind one lock so a webhook and its deliveries change together.
+#[derive(Clone, Default)]
+pub(crate) struct WebhookBook {
+    pub(crate) webhooks: Vec<Webhook>,
+    pub(crate) deliveries: Vec<WebhookDelivery>,
+}
+
+impl WebhookBook {
+    pub(crate) fn insert_webhook(&mut self, webhook: Webhook) -> Result<Webhook, InsertError> {
+        if self.webhooks.iter().any(|w| w.id == webhook.id) {
+            return Err(InsertError::Conflict);
+        }
+
+        self.webhooks.push(webhook.clone());
+        Ok(webhook)
+    }
+
+    pub(crate) fn find_webhook(&self, id: WebhookId) -> Result<Webhook, FetchError> {
+        self.webhooks
+            .iter()
+            .find(|w| w.id == id)
+            .cloned()
+            .ok_or(FetchError::NotFound)
+    }
+
+    pub(crate) fn remove_webhook(&mut self, id: WebhookId) -> Result<(), DeleteError> {
+        match self.webhooks.iter().position(|w| w.id == id) {
+            Some(idx) => {
+                self.webhooks.remove(idx);
+                self.deliveries.retain(|d| d.webhook_id != id);
+                Ok(())
+            }
+            None => Err(DeleteError::NotFound),
+        }
+    }
+
+    pub(crate) fn insert_delivery(&mut self, delivery: WebhookDelivery) -> Result<(), InsertError> {
+        if !self.webhooks.iter().any(|w| w.id == delivery.webhook_id) {
+            return Err(InsertError::Conflict);
+        }
+
+        let id = delivery.webhook_id;
+        self.deliveries.push(delivery);
+        let count = self
+            .deliveries
+            .iter()
+            .filter(|d| d.webhook_id == id)
+            .count();
+        if count > MAX_DELIVERIES {
+            let mut excess = count - MAX_DELIVERIES;
+            self.deliveries.retain(|d| {
+                let drop = excess > 0 && d.webhook_id == id;
+                if drop {
+                    excess -= 1;
+                }
+                !drop
+            });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn select_deliveries(
+        &self,
+        id: WebhookId,
+    ) -> Result<Vec<WebhookDelivery>, FetchError> {
+        self.find_webhook(id)?;
+        Ok(self
+            .deliveries
+            .iter()
+            .filter(|d| d.webhook_id == id)
+            .cloned()
+            .collect())
+    }
+}